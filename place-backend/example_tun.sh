@@ -1,5 +1,11 @@
 #!/bin/bash
 
+# Without root, the server can also run in a user and network namespace of its own, where it's
+# allowed to create the interface and routes itself with `configure_interface = true`:
+#   unshare --user --map-root-user --net target/release/place-backend
+# Only packets sent from inside the namespace reach it, which is how the test in
+# src/backend/netns.rs exercises the smoltcp backend.
+
 TUN_NAME=tun0
 
 # Clean up any previous state
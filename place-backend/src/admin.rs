@@ -0,0 +1,435 @@
+use serde::Serialize;
+
+use std::{net::IpAddr, sync::atomic::Ordering};
+
+use crate::{
+    backend::{talkers::TopTalkers, writer::QueueStats},
+    place::describe_save_error,
+    settings::{SaveFailurePolicy, Settings, MAX_PALETTE_SIZE},
+    utils::Color,
+    PResult, SharedContext,
+};
+
+/// Admin commands, shared by the HTTP /admin/* routes and the control socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Saves the canvas to disk.
+    Save,
+    /// Writes the canvas to the configured image file, even if it's memory-mapped.
+    Export,
+    /// Returns current server statistics.
+    Stats,
+    /// Changes the background color, optionally repainting pixels which still have the old one.
+    SetBackground { color: Color, repaint: bool },
+    /// Replaces the palette pixels are snapped to, an empty one keeping colors as they are, and
+    /// the background color along with it if given.
+    SetPalette {
+        colors: Vec<Color>,
+        background: Option<Color>,
+    },
+    /// Returns the `n` source addresses which sent the most pixels recently.
+    Top { n: usize },
+    /// Freezes (`true`) or unfreezes (`false`) the canvas, rejecting all pixels while it's frozen.
+    SetFrozen(bool),
+    /// Returns the sources which wrote the pixels of an area last, the whole canvas if not specified.
+    Sources { rect: Option<[u32; 4]> },
+    /// Reads the settings again and applies the palette and background color from them. Other
+    /// settings only take effect after a restart.
+    Reload,
+    /// Rejects further pixels from the /64 of an address on the canvas, until the server restarts.
+    Ban(IpAddr),
+}
+
+/// Number of sources listed by the "top" command if not specified.
+pub const DEFAULT_TOP: usize = 20;
+
+/// Number of sources listed in the statistics.
+const STATS_TOP: usize = 10;
+
+#[derive(Debug, Clone, Serialize)]
+struct SaveResult {
+    path: String,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SetBackgroundResult {
+    old_color: Color,
+    new_color: Color,
+    repainted: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SetPaletteResult {
+    old_colors: Vec<Color>,
+    new_colors: Vec<Color>,
+    old_background: Color,
+    new_background: Color,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SetFrozenResult {
+    was_frozen: bool,
+    frozen: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct BanResult {
+    /// The banned /64, or the address itself for IPv4.
+    prefix: IpAddr,
+    already_banned: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    pps: u32,
+    smoothed_pps: f32,
+    /// Packets dropped because of an invalid checksum, if checksums are verified.
+    bad_checksums: u64,
+    /// Echo replies held back by the reply limits.
+    suppressed_replies: u64,
+    connections: u32,
+    frozen: bool,
+    queue: QueueStats,
+    /// The busiest sources, `None` if they aren't tracked.
+    top_talkers: Option<TopTalkers>,
+}
+
+impl Stats {
+    pub fn collect(shared_context: &SharedContext) -> Stats {
+        let (pps, smoothed_pps) = shared_context.packet_counter.get_pps();
+        Stats {
+            pps,
+            smoothed_pps,
+            bad_checksums: shared_context.packet_counter.bad_checksums(),
+            suppressed_replies: shared_context.packet_counter.suppressed_replies(),
+            connections: shared_context
+                .connection_count
+                .load(std::sync::atomic::Ordering::Relaxed),
+            frozen: shared_context.queue_monitor.frozen(),
+            queue: shared_context.queue_monitor.stats(),
+            top_talkers: shared_context.queue_monitor.top_talkers(STATS_TOP),
+        }
+    }
+}
+
+/// Parses an area given as "x,y,width,height".
+pub fn parse_rect(rect: &str) -> Option<[u32; 4]> {
+    let mut values = rect.split(',').map(|value| value.trim().parse().ok());
+    let rect = [
+        values.next()??,
+        values.next()??,
+        values.next()??,
+        values.next()??,
+    ];
+    values.next().is_none().then_some(rect)
+}
+
+impl AdminCommand {
+    /// Parses a command in form of `<name> [args...]`, eg. `background #ff00ff repaint`.
+    pub fn parse(command: &str) -> Option<AdminCommand> {
+        let mut args = command.split_whitespace();
+        let command = match args.next()? {
+            "save" => AdminCommand::Save,
+            "export" => AdminCommand::Export,
+            "stats" => AdminCommand::Stats,
+            "reload" => AdminCommand::Reload,
+            "ban" => AdminCommand::Ban(args.next()?.parse().ok()?),
+            "freeze" => AdminCommand::SetFrozen(true),
+            "unfreeze" => AdminCommand::SetFrozen(false),
+            "top" => AdminCommand::Top {
+                n: match args.next() {
+                    Some(n) => n.parse().ok()?,
+                    None => DEFAULT_TOP,
+                },
+            },
+            "sources" => AdminCommand::Sources {
+                rect: match args.next() {
+                    Some(rect) => Some(parse_rect(rect)?),
+                    None => None,
+                },
+            },
+            "background" => {
+                let color = Color::parse(args.next()?)?;
+                let repaint = match args.next() {
+                    Some("repaint") => true,
+                    Some(_) => return None,
+                    None => false,
+                };
+                AdminCommand::SetBackground { color, repaint }
+            }
+            "palette" => AdminCommand::SetPalette {
+                colors: args.by_ref().map(Color::parse).collect::<Option<_>>()?,
+                background: None,
+            },
+            _ => return None,
+        };
+
+        // Reject trailing garbage.
+        match args.next() {
+            Some(_) => None,
+            None => Some(command),
+        }
+    }
+
+    /// Executes the command and returns its result as JSON.
+    pub async fn execute(self, shared_context: &SharedContext) -> PResult<serde_json::Value> {
+        match self {
+            AdminCommand::Save => {
+                let result = shared_context
+                    .place
+                    .write_in_background(|place| {
+                        let path = place.save()?;
+                        let size = std::fs::metadata(path)?.len();
+                        Ok(SaveResult {
+                            path: path.display().to_string(),
+                            size,
+                        })
+                    })
+                    .await;
+                let result = check_saved(shared_context, result)?;
+
+                log::info!("Canvas saved to {} on admin request.", result.path);
+                Ok(serde_json::to_value(result)?)
+            }
+            AdminCommand::Export => {
+                let result = shared_context
+                    .place
+                    .write_in_background(|place| {
+                        place.export()?;
+                        let size = std::fs::metadata(&place.path)?.len();
+                        Ok(SaveResult {
+                            path: place.path.display().to_string(),
+                            size,
+                        })
+                    })
+                    .await;
+                let result = check_saved(shared_context, result)?;
+
+                log::info!("Canvas exported to {} on admin request.", result.path);
+                Ok(serde_json::to_value(result)?)
+            }
+            AdminCommand::SetBackground { color, repaint } => {
+                let place = &shared_context.place;
+                let old_color = place.set_background_color(color);
+                let repainted = if repaint {
+                    place.image.replace_color(old_color, color)
+                } else {
+                    0
+                };
+
+                log::info!(
+                    "Background color changed from {:?} to {:?} on admin request, repainted {} pixels.",
+                    old_color,
+                    color,
+                    repainted
+                );
+                Ok(serde_json::to_value(SetBackgroundResult {
+                    old_color,
+                    new_color: color,
+                    repainted,
+                })?)
+            }
+            AdminCommand::SetPalette { colors, background } => {
+                if colors.len() > MAX_PALETTE_SIZE {
+                    return Err(
+                        format!("A palette can have at most {} colors.", MAX_PALETTE_SIZE).into(),
+                    );
+                }
+                Ok(serde_json::to_value(set_palette(
+                    shared_context,
+                    colors,
+                    background,
+                ))?)
+            }
+            AdminCommand::Reload => {
+                let settings = tokio::task::spawn_blocking(Settings::new).await??;
+                let canvas = match &shared_context.canvas {
+                    Some(name) => settings
+                        .canvases
+                        .iter()
+                        .find(|named| *named.name == **name)
+                        .map(|named| &named.canvas)
+                        .ok_or_else(|| format!("Canvas {} is gone from the settings.", name))?,
+                    None => &settings.canvas,
+                };
+                let result = set_palette(
+                    shared_context,
+                    settings.backend.palette.clone(),
+                    Some(canvas.background_color),
+                );
+                log::info!("Settings reloaded on admin request.");
+                Ok(serde_json::to_value(result)?)
+            }
+            AdminCommand::Stats => Ok(serde_json::to_value(Stats::collect(shared_context))?),
+            AdminCommand::SetFrozen(frozen) => {
+                let was_frozen = shared_context.queue_monitor.set_frozen(frozen);
+                // The admin's decision sticks, even once saving works again.
+                shared_context
+                    .place
+                    .frozen_by_save_failure
+                    .store(false, Ordering::Relaxed);
+                log::info!(
+                    "Canvas {} on admin request.",
+                    if frozen { "frozen" } else { "unfrozen" }
+                );
+                Ok(serde_json::to_value(SetFrozenResult {
+                    was_frozen,
+                    frozen,
+                })?)
+            }
+            AdminCommand::Ban(source) => {
+                let (prefix, already_banned) = shared_context.queue_monitor.ban(source);
+                log::info!("Banned {} on admin request.", prefix);
+                Ok(serde_json::to_value(BanResult {
+                    prefix,
+                    already_banned,
+                })?)
+            }
+            AdminCommand::Top { n } => match shared_context.queue_monitor.top_talkers(n) {
+                Some(top) => Ok(serde_json::to_value(top)?),
+                None => Err("Source tracking is disabled, top_talkers_size is 0.".into()),
+            },
+            AdminCommand::Sources { rect } => {
+                let rect = rect.unwrap_or([0, 0, u32::MAX, u32::MAX]);
+                match shared_context.queue_monitor.attribution(rect) {
+                    Some(attribution) => Ok(serde_json::to_value(attribution)?),
+                    None => Err("Attribution is disabled, track_attribution is false.".into()),
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the palette, and the background color if given.
+fn set_palette(
+    shared_context: &SharedContext,
+    colors: Vec<Color>,
+    background: Option<Color>,
+) -> SetPaletteResult {
+    let place = &shared_context.place;
+    let old_background = match background {
+        Some(color) => place.set_background_color(color),
+        None => place.background_color(),
+    };
+    let old_colors = shared_context.queue_monitor.set_palette(colors.clone());
+
+    log::info!(
+        "Palette changed from {} to {} colors on admin request, background is {:?}.",
+        old_colors.len(),
+        colors.len(),
+        place.background_color()
+    );
+    SetPaletteResult {
+        old_colors,
+        new_colors: colors,
+        old_background,
+        new_background: place.background_color(),
+    }
+}
+
+/// Applies the save failure policy to the result of a save, freezing the canvas on failure if
+/// configured and unfreezing it again once a save succeeds.
+fn check_saved<T>(shared_context: &SharedContext, result: PResult<T>) -> PResult<T> {
+    let place = &shared_context.place;
+    match result {
+        Ok(result) => {
+            if place.frozen_by_save_failure.swap(false, Ordering::Relaxed) {
+                shared_context.queue_monitor.set_frozen(false);
+                log::info!("Saving works again, unfroze the canvas.");
+            }
+            Ok(result)
+        }
+        Err(e) => {
+            let message = describe_save_error(&*e);
+            if place.save_failure_policy == SaveFailurePolicy::Freeze
+                && !shared_context.queue_monitor.set_frozen(true)
+            {
+                place.frozen_by_save_failure.store(true, Ordering::Relaxed);
+                log::warn!("Froze the canvas until it can be saved again.");
+            }
+            Err(message.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(AdminCommand::parse("save"), Some(AdminCommand::Save));
+        assert_eq!(AdminCommand::parse("export"), Some(AdminCommand::Export));
+        assert_eq!(AdminCommand::parse("reload"), Some(AdminCommand::Reload));
+        assert_eq!(AdminCommand::parse("  stats "), Some(AdminCommand::Stats));
+        assert_eq!(
+            AdminCommand::parse("background #ff00ff repaint"),
+            Some(AdminCommand::SetBackground {
+                color: Color::rgb(255, 0, 255),
+                repaint: true
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse("background #000000"),
+            Some(AdminCommand::SetBackground {
+                color: Color::rgb(0, 0, 0),
+                repaint: false
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse("top"),
+            Some(AdminCommand::Top { n: DEFAULT_TOP })
+        );
+        assert_eq!(
+            AdminCommand::parse("top 5"),
+            Some(AdminCommand::Top { n: 5 })
+        );
+        assert_eq!(AdminCommand::parse("top five"), None);
+        assert_eq!(
+            AdminCommand::parse("sources"),
+            Some(AdminCommand::Sources { rect: None })
+        );
+        assert_eq!(
+            AdminCommand::parse("sources 10,20,30,40"),
+            Some(AdminCommand::Sources {
+                rect: Some([10, 20, 30, 40])
+            })
+        );
+        assert_eq!(AdminCommand::parse("sources 10,20,30"), None);
+        assert_eq!(AdminCommand::parse("sources 10,20,30,40,50"), None);
+        assert_eq!(
+            AdminCommand::parse("freeze"),
+            Some(AdminCommand::SetFrozen(true))
+        );
+        assert_eq!(
+            AdminCommand::parse("unfreeze"),
+            Some(AdminCommand::SetFrozen(false))
+        );
+        assert_eq!(
+            AdminCommand::parse("palette #000000 #ffffff"),
+            Some(AdminCommand::SetPalette {
+                colors: vec![Color::rgb(0, 0, 0), Color::rgb(255, 255, 255)],
+                background: None
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse("palette"),
+            Some(AdminCommand::SetPalette {
+                colors: Vec::new(),
+                background: None
+            })
+        );
+        assert_eq!(AdminCommand::parse("palette #000000 black"), None);
+        assert_eq!(AdminCommand::parse("background"), None);
+        assert_eq!(AdminCommand::parse("background #000000 please"), None);
+        assert_eq!(AdminCommand::parse("save now"), None);
+        assert_eq!(
+            AdminCommand::parse("ban 2001:db8::1"),
+            Some(AdminCommand::Ban("2001:db8::1".parse().unwrap()))
+        );
+        assert_eq!(AdminCommand::parse("ban"), None);
+        assert_eq!(AdminCommand::parse("ban everyone"), None);
+        assert_eq!(AdminCommand::parse("nuke"), None);
+    }
+}
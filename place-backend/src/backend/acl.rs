@@ -0,0 +1,199 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use image::RgbaImage;
+use tokio::task::JoinHandle;
+
+use crate::{
+    place::SharedImageHandle,
+    settings::{ProtectedRegion, RegionRule},
+    utils::Color,
+    PResult,
+};
+
+use super::PixelRequest;
+
+/// Checks whether the pixel overlaps `rect`, given as [x, y, width, height].
+#[inline]
+fn touches(rect: [u32; 4], req: &PixelRequest) -> bool {
+    let (x, y) = (req.pos.0 as u32, req.pos.1 as u32);
+    let size = req.size as u32;
+    let [rx, ry, width, height] = rect;
+
+    x < rx.saturating_add(width) && x + size > rx && y < ry.saturating_add(height) && y + size > ry
+}
+
+/// Restricts canvas regions to certain source prefixes. Pixels outside of all regions are always allowed.
+pub struct RegionAcl {
+    rules: Vec<RegionRule>,
+}
+
+impl RegionAcl {
+    /// Returns `None` if there are no rules, so the check can be skipped entirely.
+    pub fn new(rules: &[RegionRule]) -> Option<RegionAcl> {
+        (!rules.is_empty()).then(|| RegionAcl {
+            rules: rules.to_vec(),
+        })
+    }
+
+    /// Checks whether `source` may write the pixel. Pixels touching several regions
+    /// have to be allowed by all of them.
+    #[inline]
+    pub fn allows(&self, source: IpAddr, req: &PixelRequest) -> bool {
+        self.rules.iter().all(|rule| {
+            !touches(rule.rect, req) || rule.allow.iter().any(|prefix| prefix.contains(source))
+        })
+    }
+}
+
+/// Canvas regions nobody may draw on, optionally stamped with an image, eg. an event logo.
+pub struct ProtectedRegions {
+    regions: Vec<([u32; 4], Option<RgbaImage>)>,
+}
+
+impl ProtectedRegions {
+    /// Loads the images of the regions. Returns `None` if there are no regions.
+    pub fn new(regions: &[ProtectedRegion]) -> PResult<Option<Arc<ProtectedRegions>>> {
+        if regions.is_empty() {
+            return Ok(None);
+        }
+
+        let regions = regions
+            .iter()
+            .map(|region| {
+                let image = match &region.image {
+                    Some(path) => Some(
+                        image::open(path)
+                            .map_err(|e| {
+                                format!("Failed to load protected region image {}: {}", path, e)
+                            })?
+                            .into_rgba8(),
+                    ),
+                    None => None,
+                };
+                Ok((region.rect, image))
+            })
+            .collect::<PResult<_>>()?;
+
+        Ok(Some(Arc::new(ProtectedRegions { regions })))
+    }
+
+    /// Checks whether the pixel touches any of the regions.
+    #[inline]
+    pub fn protects(&self, req: &PixelRequest) -> bool {
+        self.regions.iter().any(|(rect, _)| touches(*rect, req))
+    }
+
+    /// Draws the images onto their regions, anchored at the top left corner and cropped to the region.
+    /// Fully transparent pixels are skipped.
+    pub fn stamp(&self, image: &SharedImageHandle) {
+        for ([x, y, width, height], stamp) in &self.regions {
+            let Some(stamp) = stamp else {
+                continue;
+            };
+
+            for (dx, dy, pixel) in stamp.enumerate_pixels() {
+                if dx >= *width || dy >= *height || pixel.0[3] == 0 {
+                    continue;
+                }
+                // Regions may reach past the canvas, up to the end of the coordinate range.
+                if let (Some(px), Some(py)) = (x.checked_add(dx), y.checked_add(dy)) {
+                    image.put(px, py, Color::from_rgba(*pixel), false);
+                }
+            }
+        }
+    }
+
+    /// Stamps the images again every `interval`, in case they got painted over by admin commands.
+    pub fn start_restamp(
+        self: Arc<Self>,
+        image: SharedImageHandle,
+        interval: Duration,
+    ) -> JoinHandle<PResult<()>> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                self.stamp(&image);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::IpPrefix;
+
+    #[test]
+    fn team_zones() {
+        let rule = |rect, allow: &str| RegionRule {
+            rect,
+            allow: vec![IpPrefix::parse(allow).unwrap()],
+        };
+        let acl = RegionAcl::new(&[
+            rule([0, 0, 8, 16], "2001:db8:a::/48"),
+            rule([8, 0, 8, 16], "2001:db8:b::/48"),
+        ])
+        .unwrap();
+        let pixel = |x, size| PixelRequest {
+            pos: (x, 0),
+            color: Color::rgb(0, 0, 0),
+            size,
+            color_low: None,
+        };
+        let a: IpAddr = "2001:db8:a::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:b::1".parse().unwrap();
+
+        assert!(acl.allows(a, &pixel(7, 1)));
+        assert!(!acl.allows(b, &pixel(7, 1)));
+        assert!(acl.allows(b, &pixel(8, 1)));
+        // Straddles both zones.
+        assert!(!acl.allows(a, &pixel(7, 2)));
+        // Unlisted area.
+        assert!(acl.allows(b, &pixel(16, 1)));
+
+        assert!(RegionAcl::new(&[]).is_none());
+    }
+
+    #[test]
+    fn protected_watermark() {
+        let path = std::env::temp_dir().join(format!("place-logo-test-{}.png", std::process::id()));
+        let mut logo = RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255]));
+        logo.put_pixel(1, 1, image::Rgba([0, 0, 0, 0]));
+        logo.save(&path).unwrap();
+
+        let protected = ProtectedRegions::new(&[ProtectedRegion {
+            rect: [2, 2, 3, 3],
+            image: Some(path.to_string_lossy().into_owned()),
+        }])
+        .unwrap()
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let pixel = |x, y, size| PixelRequest {
+            pos: (x, y),
+            color: Color::rgb(0, 0, 0),
+            size,
+            color_low: None,
+        };
+        assert!(protected.protects(&pixel(2, 2, 1)));
+        assert!(protected.protects(&pixel(1, 1, 2)));
+        assert!(!protected.protects(&pixel(5, 2, 1)));
+
+        let image = SharedImageHandle::new(RgbaImage::new(8, 8));
+        protected.stamp(&image);
+        assert_eq!(image.get(2, 2), Some(Color::rgb(255, 0, 0)));
+        assert_eq!(image.get(4, 4), Some(Color::rgb(255, 0, 0)));
+        // Transparent in the logo, and outside of the region.
+        assert_eq!(image.get(3, 3), Some(Color::new(0, 0, 0, 0)));
+        assert_eq!(image.get(5, 5), Some(Color::new(0, 0, 0, 0)));
+
+        // Regions at the end of the coordinate range don't overflow.
+        let edge = ProtectedRegions {
+            regions: vec![([u32::MAX - 1, 0, 4, 4], protected.regions[0].1.clone())],
+        };
+        edge.stamp(&image);
+
+        assert!(ProtectedRegions::new(&[]).unwrap().is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+use serde::Serialize;
+
+use super::{writer::source_key, PixelRequest};
+
+/// Id of pixels not written since attribution started.
+const UNATTRIBUTED: u32 = 0;
+
+/// Most sources remembered at once. Pixels of further sources are left unattributed until some
+/// remembered source no longer owns any pixel.
+pub const MAX_SOURCES: usize = 1 << 20;
+
+/// Rough memory taken by each remembered source, for the memory estimate of the settings.
+pub const BYTES_PER_SOURCE: u64 = 64;
+
+/// Rows counted per lock of the table, so writes aren't held up by large queries.
+const SCAN_ROWS: u32 = 64;
+
+/// Pixels of one source in an area.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SourceShare {
+    pub source: IpAddr,
+    pub pixels: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Attribution {
+    /// Area as [x, y, width, height], clipped to the canvas.
+    pub rect: [u32; 4],
+    /// Pixels not written since the server started.
+    pub unattributed: u64,
+    /// Sources which wrote the pixels of the area last, most pixels first.
+    pub sources: Vec<SourceShare>,
+}
+
+/// Remembers the source which wrote each pixel last, for telling who drew what.
+///
+/// Sources are interned, so every pixel only takes a 4 byte id. Like the pixel queue, sources are
+/// grouped by their /64, which is what a single host usually controls. Ids are reference counted
+/// and reused once a source's last pixel is overwritten, so the table never outgrows the canvas.
+pub struct LastWriters {
+    width: u32,
+    height: u32,
+    ids: Vec<u32>,
+    /// Source of every id, offset by one since id 0 is `UNATTRIBUTED`.
+    sources: Vec<IpAddr>,
+    /// Pixels owned by every id, offset like `sources`.
+    owned: Vec<u32>,
+    /// Ids which no pixel references anymore.
+    free: Vec<u32>,
+    interned: HashMap<IpAddr, u32>,
+}
+
+impl LastWriters {
+    pub fn new(width: u32, height: u32) -> LastWriters {
+        LastWriters {
+            width,
+            height,
+            ids: vec![UNATTRIBUTED; width as usize * height as usize],
+            sources: Vec::new(),
+            owned: Vec::new(),
+            free: Vec::new(),
+            interned: HashMap::new(),
+        }
+    }
+
+    /// Returns the id of `source`, `UNATTRIBUTED` if the table is full.
+    fn intern(&mut self, source: IpAddr) -> u32 {
+        if let Some(&id) = self.interned.get(&source) {
+            return id;
+        }
+
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.sources[id as usize - 1] = source;
+                id
+            }
+            None if self.sources.len() < MAX_SOURCES => {
+                self.sources.push(source);
+                self.owned.push(0);
+                self.sources.len() as u32
+            }
+            None => return UNATTRIBUTED,
+        };
+        self.interned.insert(source, id);
+        id
+    }
+
+    /// Drops a pixel owned by `id`, freeing the id along with its last pixel.
+    fn release(&mut self, id: u32) {
+        if id == UNATTRIBUTED {
+            return;
+        }
+        let owned = &mut self.owned[id as usize - 1];
+        *owned -= 1;
+        if *owned == 0 {
+            self.interned.remove(&self.sources[id as usize - 1]);
+            self.free.push(id);
+        }
+    }
+
+    /// Records `source` as the writer of the block covered by the request.
+    pub fn record(&mut self, source: IpAddr, req: &PixelRequest) {
+        let id = self.intern(source_key(source));
+        let (x, y) = (req.pos.0 as u32, req.pos.1 as u32);
+        let size = req.size as u32;
+        for y in y..(y + size).min(self.height) {
+            let row = (y * self.width) as usize;
+            let start = row + x.min(self.width) as usize;
+            let end = row + (x + size).min(self.width) as usize;
+            for i in start..end {
+                let previous = std::mem::replace(&mut self.ids[i], id);
+                if previous == id {
+                    continue;
+                }
+                if id != UNATTRIBUTED {
+                    self.owned[id as usize - 1] += 1;
+                }
+                self.release(previous);
+            }
+        }
+        // A block entirely outside of the canvas leaves a new id without pixels.
+        if id != UNATTRIBUTED && self.owned[id as usize - 1] == 0 {
+            self.interned.remove(&self.sources[id as usize - 1]);
+            self.free.push(id);
+        }
+    }
+
+    /// Clips an area given as [x, y, width, height] to the canvas.
+    fn clip(&self, rect: [u32; 4]) -> [u32; 4] {
+        let [x, y, width, height] = rect;
+        let (x0, y0) = (x.min(self.width), y.min(self.height));
+        let x1 = x.saturating_add(width).min(self.width);
+        let y1 = y.saturating_add(height).min(self.height);
+        [x0, y0, x1 - x0, y1 - y0]
+    }
+
+    /// Adds the pixels of every source in the clipped area to `counts`.
+    fn count(&self, rect: [u32; 4], counts: &mut HashMap<Option<IpAddr>, u64>) {
+        let [x, y, width, height] = rect;
+        let mut ids: HashMap<u32, u64> = HashMap::new();
+        for y in y..y + height {
+            let row = (y * self.width + x) as usize;
+            for &id in &self.ids[row..row + width as usize] {
+                *ids.entry(id).or_default() += 1;
+            }
+        }
+        // Ids are only meaningful while the lock is held, so they're resolved right away.
+        for (id, pixels) in ids {
+            let source = (id != UNATTRIBUTED).then(|| self.sources[id as usize - 1]);
+            *counts.entry(source).or_default() += pixels;
+        }
+    }
+}
+
+/// Counts the pixels of every source which wrote last in the area given as [x, y, width, height].
+///
+/// The area is scanned a few rows at a time, releasing the lock in between so the canvas writer
+/// isn't blocked by queries of large areas.
+pub fn sources_in(last_writers: &Mutex<LastWriters>, rect: [u32; 4]) -> Attribution {
+    let lock = || last_writers.lock().unwrap_or_else(|e| e.into_inner());
+    let rect = lock().clip(rect);
+    let [x, y, width, height] = rect;
+
+    let mut counts = HashMap::new();
+    let mut row = y;
+    while row < y + height {
+        let rows = SCAN_ROWS.min(y + height - row);
+        lock().count([x, row, width, rows], &mut counts);
+        row += rows;
+    }
+
+    let unattributed = counts.remove(&None).unwrap_or(0);
+    let mut sources: Vec<SourceShare> = counts
+        .into_iter()
+        .filter_map(|(source, pixels)| {
+            Some(SourceShare {
+                source: source?,
+                pixels,
+            })
+        })
+        .collect();
+    sources.sort_unstable_by(|a, b| b.pixels.cmp(&a.pixels).then(a.source.cmp(&b.source)));
+
+    Attribution {
+        rect,
+        unattributed,
+        sources,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::Color;
+
+    #[test]
+    fn last_writers() {
+        let pixel = |x, y, size| PixelRequest {
+            pos: (x, y),
+            color: Color::rgb(0, 0, 0),
+            size,
+            color_low: None,
+        };
+        let a: IpAddr = "2001:db8:a::".parse().unwrap();
+        let b: IpAddr = "2001:db8:b::".parse().unwrap();
+
+        let mut writers = LastWriters::new(4, 4);
+        writers.record(a, &pixel(0, 0, 2));
+        writers.record(b, &pixel(1, 1, 1));
+        // Clipped to the canvas.
+        writers.record(b, &pixel(3, 3, 2));
+        let writers = Mutex::new(writers);
+
+        let all = sources_in(&writers, [0, 0, 4, 4]);
+        assert_eq!(all.unattributed, 11);
+        assert_eq!(
+            all.sources,
+            [
+                SourceShare {
+                    source: a,
+                    pixels: 3
+                },
+                SourceShare {
+                    source: b,
+                    pixels: 2
+                }
+            ]
+        );
+
+        let corner = sources_in(&writers, [1, 1, 100, 100]);
+        assert_eq!(corner.rect, [1, 1, 3, 3]);
+        assert_eq!(corner.unattributed, 7);
+        assert_eq!(corner.sources.len(), 1);
+        assert_eq!(sources_in(&writers, [8, 8, 2, 2]).rect, [4, 4, 0, 0]);
+    }
+
+    #[test]
+    fn overwritten_sources_are_freed() {
+        let pixel = |x, y| PixelRequest {
+            pos: (x, y),
+            color: Color::rgb(0, 0, 0),
+            size: 1,
+            color_low: None,
+        };
+        let source = |i: u16| IpAddr::from([0x2001, 0xdb8, i, 0, 0, 0, 0, 1]);
+
+        let mut writers = LastWriters::new(2, 1);
+        writers.record(source(1), &pixel(0, 0));
+        // Hosts of the same /64 share an id.
+        writers.record("2001:db8:1::2".parse().unwrap(), &pixel(1, 0));
+        assert_eq!(writers.interned.len(), 1);
+
+        for i in 2..100 {
+            writers.record(source(i), &pixel(0, 0));
+            writers.record(source(i), &pixel(1, 0));
+        }
+        assert_eq!(writers.interned.len(), 1);
+        assert_eq!(writers.sources.len(), 2);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::place::SharedImageHandle;
+
+use super::PixelRequest;
+
+/// Merges repeated writes to the same pixel within a time window, so only the last color
+/// written to each coordinate reaches the canvas and the frame encoder.
+///
+/// Owned by the canvas writer, so no locking is needed. With a zero window,
+/// pixels are written straight to the canvas.
+pub struct PixelCoalescer {
+    image: SharedImageHandle,
+    window: Duration,
+    /// Pending pixels in the order they arrived, so overlapping blocks are written in that order.
+    /// Pixels overwritten later in the window are left as `None`.
+    pending: Vec<Option<PixelRequest>>,
+    /// Index of the latest pending pixel at each coordinate.
+    positions: HashMap<(u16, u16), usize>,
+    /// Number of `None` entries in `pending`.
+    overwritten: usize,
+    window_start: Instant,
+}
+
+impl PixelCoalescer {
+    pub fn new(image: SharedImageHandle, window: Duration) -> PixelCoalescer {
+        PixelCoalescer {
+            image,
+            window,
+            pending: Vec::new(),
+            positions: HashMap::new(),
+            overwritten: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    /// Writes the pixel to the canvas, or queues it until the current window ends.
+    #[inline]
+    pub fn put(&mut self, req: PixelRequest) {
+        if self.window.is_zero() {
+            let (x, y) = req.pos;
+            self.image
+                .put_block16(x as _, y as _, req.color16(), req.size as _);
+            return;
+        }
+
+        if self.pending.is_empty() {
+            self.window_start = Instant::now();
+        }
+        let index = self.pending.len();
+        if let Some(previous) = self.positions.insert(req.pos, index) {
+            self.pending[previous] = None;
+            self.overwritten += 1;
+        }
+        self.pending.push(Some(req));
+        // Pixels rewritten over and over shouldn't grow the buffer without bounds.
+        if self.overwritten >= self.positions.len() {
+            self.compact();
+        }
+
+        if self.window_start.elapsed() >= self.window {
+            self.flush();
+        }
+    }
+
+    /// Time left until pending pixels have to be flushed, `None` if there are none.
+    pub fn next_flush(&self) -> Option<Duration> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        Some(self.window.saturating_sub(self.window_start.elapsed()))
+    }
+
+    /// Flushes pending pixels if the current window has ended.
+    pub fn flush_if_due(&mut self) {
+        if self.next_flush().is_some_and(|left| left.is_zero()) {
+            self.flush();
+        }
+    }
+
+    /// Drops overwritten pixels from `pending`, keeping the order of the others.
+    fn compact(&mut self) {
+        self.pending.retain(Option::is_some);
+        for (index, req) in self.pending.iter().flatten().enumerate() {
+            self.positions.insert(req.pos, index);
+        }
+        self.overwritten = 0;
+    }
+
+    /// Writes all pending pixels to the canvas, in the order they arrived.
+    pub fn flush(&mut self) {
+        self.positions.clear();
+        self.overwritten = 0;
+        for req in self.pending.drain(..).flatten() {
+            let (x, y) = req.pos;
+            self.image
+                .put_block16(x as _, y as _, req.color16(), req.size as _);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::RgbaImage;
+
+    use super::*;
+    use crate::utils::Color;
+
+    #[test]
+    fn coalesce_writes() {
+        let image = SharedImageHandle::new(RgbaImage::new(16, 16));
+        let pixel = |color| PixelRequest {
+            pos: (1, 2),
+            color,
+            size: 1,
+            color_low: None,
+        };
+        let get = |image: &SharedImageHandle| unsafe { *image.get_image().get_pixel(1, 2) };
+
+        let mut coalescer = PixelCoalescer::new(image.clone(), Duration::from_secs(3600));
+        coalescer.put(pixel(Color::rgb(255, 0, 0)));
+        coalescer.put(pixel(Color::rgb(0, 255, 0)));
+        assert_eq!(get(&image), Color::new(0, 0, 0, 0).into_rgba());
+        assert_eq!(coalescer.pending.len(), 1);
+        assert!(coalescer.next_flush().is_some());
+
+        coalescer.flush();
+        assert_eq!(get(&image), Color::rgb(0, 255, 0).into_rgba());
+        assert_eq!(coalescer.next_flush(), None);
+
+        let mut coalescer = PixelCoalescer::new(image.clone(), Duration::ZERO);
+        coalescer.put(pixel(Color::rgb(0, 0, 255)));
+        assert_eq!(get(&image), Color::rgb(0, 0, 255).into_rgba());
+    }
+
+    #[test]
+    fn overlapping_blocks() {
+        let image = SharedImageHandle::new(RgbaImage::new(16, 16));
+        let block = |x, color| PixelRequest {
+            pos: (x, 0),
+            color,
+            size: 2,
+            color_low: None,
+        };
+        let get = |x| unsafe { *image.get_image().get_pixel(x, 0) };
+        let (red, green, blue) = (
+            Color::rgb(255, 0, 0),
+            Color::rgb(0, 255, 0),
+            Color::rgb(0, 0, 255),
+        );
+
+        // The later of two overlapping blocks wins, however they're stored.
+        let mut coalescer = PixelCoalescer::new(image.clone(), Duration::from_secs(3600));
+        for x in 0..8 {
+            coalescer.put(block(x, red));
+        }
+        coalescer.put(block(3, green));
+        coalescer.put(block(4, blue));
+        coalescer.flush();
+        assert_eq!(get(3), green.into_rgba());
+        assert_eq!(get(4), blue.into_rgba());
+        assert_eq!(get(5), blue.into_rgba());
+
+        // Rewriting a pixel moves it behind the blocks written in between.
+        coalescer.put(block(0, red));
+        coalescer.put(block(1, green));
+        coalescer.put(block(0, blue));
+        coalescer.flush();
+        assert_eq!(get(1), blue.into_rgba());
+        assert_eq!(get(2), green.into_rgba());
+    }
+}
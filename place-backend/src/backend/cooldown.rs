@@ -0,0 +1,309 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use image::RgbaImage;
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use super::PixelRequest;
+use crate::{
+    place::{self, Frame},
+    utils::Color,
+    PResult,
+};
+
+/// Time between two checks whether the cooldown overlay changed.
+const OVERLAY_TICK: Duration = Duration::from_millis(250);
+/// Largest width or height of the cooldown overlay, cells are grouped to stay within it.
+const MAX_OVERLAY_DIMENSION: u32 = 512;
+
+/// Rejects writes to pixels that were changed less than `cooldown` ago.
+///
+/// Keeps the time of the last write of every cell of `resolution`x`resolution` pixels as u64 milliseconds,
+/// so it takes `(size / resolution)² * 8` bytes. That's 2 MiB for a 512x512 canvas at resolution 1,
+/// but 128 MiB for a 4096x4096 one, where a coarser resolution is a better fit.
+///
+/// Only the writer starts cooldowns, but the times can be read concurrently for a `CooldownOverlay`.
+pub struct PixelCooldown {
+    /// Time of the last write to each cell, offset by `cooldown` so zero is always expired.
+    last_write: Vec<AtomicU64>,
+    resolution: u32,
+    columns: u32,
+    rows: u32,
+    cooldown: u64,
+    start: Instant,
+    /// Set whenever a cooldown starts, for the overlay to know it changed.
+    started: AtomicBool,
+}
+
+impl PixelCooldown {
+    pub fn new(width: u32, height: u32, cooldown: Duration, resolution: u32) -> PixelCooldown {
+        let resolution = resolution.max(1);
+        let columns = width.div_ceil(resolution);
+        let rows = height.div_ceil(resolution);
+
+        PixelCooldown {
+            last_write: (0..columns as usize * rows as usize)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            resolution,
+            columns,
+            rows,
+            cooldown: cooldown.as_millis().min(u64::MAX as u128 / 2) as u64,
+            start: Instant::now(),
+            started: AtomicBool::new(false),
+        }
+    }
+
+    /// Checks whether the pixel may be written now and starts its cooldown if so. Blocks are only
+    /// written if none of the cells they cover is in cooldown. Pixels outside of the canvas are always
+    /// allowed, they're ignored when written anyway.
+    #[inline]
+    pub fn try_write(&self, req: &PixelRequest) -> bool {
+        let (x, y) = (req.pos.0 as u32, req.pos.1 as u32);
+        let last = (x + req.size as u32).saturating_sub(1);
+        let columns = x / self.resolution..(last / self.resolution + 1).min(self.columns);
+        let last = (y + req.size as u32).saturating_sub(1);
+        let rows = y / self.resolution..(last / self.resolution + 1).min(self.rows);
+
+        let now = self.now();
+        let cells = || {
+            rows.clone().flat_map(|row| {
+                columns
+                    .clone()
+                    .map(move |column| &self.last_write[(row * self.columns + column) as usize])
+            })
+        };
+        if cells().any(|last_write| {
+            now.saturating_sub(last_write.load(Ordering::Relaxed)) < self.cooldown
+        }) {
+            return false;
+        }
+
+        let mut started = false;
+        for last_write in cells() {
+            last_write.store(now, Ordering::Relaxed);
+            started = true;
+        }
+        if started {
+            self.started.store(true, Ordering::Relaxed);
+        }
+        true
+    }
+
+    #[inline]
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64 + self.cooldown
+    }
+
+    /// Number of cells grouped into one pixel of the overlay along each axis.
+    fn overlay_scale(&self) -> u32 {
+        self.columns
+            .max(self.rows)
+            .div_ceil(MAX_OVERLAY_DIMENSION)
+            .max(1)
+    }
+
+    /// Renders the cells still in cooldown in `tint` and the others transparent, one pixel per
+    /// `scale`x`scale` cells. Also returns the time until the first of the cooldowns expires.
+    fn render(&self, tint: Color, scale: u32) -> (RgbaImage, Option<Duration>) {
+        let now = self.now();
+        let tint = tint.into_rgba();
+        let mut image = RgbaImage::new(self.columns.div_ceil(scale), self.rows.div_ceil(scale));
+        let mut next_expiry = None;
+        for (i, last_write) in self.last_write.iter().enumerate() {
+            // Cooldowns started after `now` was taken count as just started.
+            let elapsed = now.saturating_sub(last_write.load(Ordering::Relaxed));
+            if elapsed < self.cooldown {
+                let (x, y) = (i as u32 % self.columns, i as u32 / self.columns);
+                image.put_pixel(x / scale, y / scale, tint);
+                let remaining = self.cooldown - elapsed;
+                next_expiry = Some(next_expiry.map_or(remaining, |next: u64| next.min(remaining)));
+            }
+        }
+        (image, next_expiry.map(Duration::from_millis))
+    }
+}
+
+/// Overlay of the pixels which are still in cooldown, for viewers to see what's locked.
+///
+/// Rendered from the cooldown times while someone watches, but only on ticks after a cooldown
+/// started or expired. Frames are PNGs with one pixel per cooldown cell, or per square of cells on
+/// canvases too large for an overlay of `MAX_OVERLAY_DIMENSION` pixels.
+pub struct CooldownOverlay {
+    cooldown: Arc<PixelCooldown>,
+    tint: Color,
+    sender: broadcast::Sender<Frame>,
+    /// Last overlay sent, for viewers joining while it doesn't change.
+    latest: Mutex<Option<Frame>>,
+}
+
+impl CooldownOverlay {
+    pub fn new(cooldown: Arc<PixelCooldown>, tint: Color) -> CooldownOverlay {
+        CooldownOverlay {
+            cooldown,
+            tint,
+            // Clients only care about the latest overlay.
+            sender: broadcast::channel(1).0,
+            latest: Mutex::new(None),
+        }
+    }
+
+    /// Subscribes to changes of the overlay, returning the current one if there is one yet.
+    pub fn subscribe(&self) -> (Option<Frame>, broadcast::Receiver<Frame>) {
+        let latest = self.latest.lock().unwrap_or_else(|e| e.into_inner());
+        (latest.clone(), self.sender.subscribe())
+    }
+
+    /// Side length of the square areas covered by one pixel of the overlay.
+    pub fn cell_size(&self) -> u32 {
+        self.cooldown.resolution * self.cooldown.overlay_scale()
+    }
+
+    async fn ticker_task(self: Arc<Self>) -> PResult<()> {
+        let mut interval = tokio::time::interval(OVERLAY_TICK);
+        // When the overlay changes next without any new cooldown, `None` if it has to be rendered.
+        let mut next_expiry: Option<Option<Instant>> = None;
+        let mut version = 0;
+        loop {
+            interval.tick().await;
+            if self.sender.receiver_count() == 0 {
+                // Don't hand out a stale overlay once someone subscribes again.
+                next_expiry = None;
+                *self.latest.lock().unwrap_or_else(|e| e.into_inner()) = None;
+                continue;
+            }
+
+            let started = self.cooldown.started.swap(false, Ordering::Relaxed);
+            let expired = match next_expiry {
+                Some(Some(expiry)) => Instant::now() >= expiry,
+                Some(None) => false,
+                None => true,
+            };
+            if !started && !expired {
+                continue;
+            }
+
+            let (cooldown, tint) = (self.cooldown.clone(), self.tint);
+            let rendered_at = Instant::now();
+            let (data, expires_in) = tokio::task::spawn_blocking(move || {
+                let (overlay, expires_in) = cooldown.render(tint, cooldown.overlay_scale());
+                place::encode_canvas_png(&overlay, true).map(|data| (data, expires_in))
+            })
+            .await??;
+            next_expiry = Some(expires_in.map(|expires_in| rendered_at + expires_in));
+            version += 1;
+            let frame = Frame {
+                version,
+                sequence: version,
+                crc32: crc32fast::hash(&data),
+                data: data.into(),
+            };
+            // Sent while holding the lock, so subscribers get either this one as the latest or
+            // from the channel.
+            let mut latest = self.latest.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = self.sender.send(frame.clone());
+            *latest = Some(frame);
+        }
+    }
+
+    pub fn start_ticker(self: Arc<Self>) -> JoinHandle<PResult<()>> {
+        tokio::spawn(self.ticker_task())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::Color;
+
+    #[test]
+    fn reject_during_cooldown() {
+        let pixel = |x, y| PixelRequest {
+            pos: (x, y),
+            color: Color::rgb(255, 0, 0),
+            size: 1,
+            color_low: None,
+        };
+
+        let cooldown = PixelCooldown::new(16, 16, Duration::from_secs(3600), 4);
+        assert!(cooldown.try_write(&pixel(1, 1)));
+        assert!(!cooldown.try_write(&pixel(1, 1)));
+        // Same cell.
+        assert!(!cooldown.try_write(&pixel(3, 2)));
+        assert!(cooldown.try_write(&pixel(4, 2)));
+        assert!(cooldown.try_write(&pixel(100, 100)));
+
+        let cooldown = PixelCooldown::new(16, 16, Duration::from_millis(10), 1);
+        assert!(cooldown.try_write(&pixel(1, 1)));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cooldown.try_write(&pixel(1, 1)));
+
+        // Blocks cover up to four cells, all of which have to be free.
+        let block = |x, y| PixelRequest {
+            size: 2,
+            ..pixel(x, y)
+        };
+        let cooldown = PixelCooldown::new(16, 16, Duration::from_secs(3600), 1);
+        assert!(cooldown.try_write(&pixel(3, 3)));
+        assert!(!cooldown.try_write(&block(2, 2)));
+        assert!(cooldown.try_write(&block(4, 4)));
+        assert!(!cooldown.try_write(&pixel(5, 5)));
+        // Clipped to the canvas.
+        assert!(cooldown.try_write(&block(15, 15)));
+        assert!(!cooldown.try_write(&pixel(15, 15)));
+    }
+
+    #[tokio::test]
+    async fn cooldown_overlay() {
+        let tint = Color::new(0, 0, 0, 128);
+        let cooldown = Arc::new(PixelCooldown::new(16, 8, Duration::from_secs(3600), 4));
+        assert!(cooldown.try_write(&PixelRequest {
+            pos: (5, 1),
+            color: Color::rgb(255, 0, 0),
+            size: 1,
+            color_low: None,
+        }));
+
+        let overlay = Arc::new(CooldownOverlay::new(cooldown, tint));
+        let (latest, mut receiver) = overlay.subscribe();
+        assert!(latest.is_none());
+        let ticker = overlay.clone().start_ticker();
+        let frame = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        ticker.abort();
+
+        // One pixel per cell of 4x4 pixels.
+        let image = image::load_from_memory(&frame.data).unwrap().into_rgba8();
+        assert_eq!(image.dimensions(), (4, 2));
+        assert_eq!(*image.get_pixel(1, 0), tint.into_rgba());
+        assert_eq!(image.get_pixel(0, 0).0[3], 0);
+        assert_eq!(overlay.subscribe().0.unwrap().version, frame.version);
+    }
+
+    #[test]
+    fn large_overlays_are_downscaled() {
+        let cooldown = PixelCooldown::new(4096, 2048, Duration::from_secs(3600), 2);
+        assert!(cooldown.try_write(&PixelRequest {
+            pos: (4095, 0),
+            color: Color::rgb(255, 0, 0),
+            size: 1,
+            color_low: None,
+        }));
+
+        // 2048x1024 cells, grouped by 4x4.
+        let tint = Color::rgb(0, 0, 0);
+        let (image, expires_in) = cooldown.render(tint, cooldown.overlay_scale());
+        assert_eq!(image.dimensions(), (512, 256));
+        assert_eq!(*image.get_pixel(511, 0), tint.into_rgba());
+        assert!(expires_in.unwrap() <= Duration::from_secs(3600));
+        let overlay = CooldownOverlay::new(Arc::new(cooldown), tint);
+        assert_eq!(overlay.cell_size(), 8);
+    }
+}
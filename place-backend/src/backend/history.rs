@@ -0,0 +1,105 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use crate::utils::Color;
+
+use super::PixelRequest;
+
+/// A pixel written to the canvas, in canvas coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PixelEvent {
+    pub x: u16,
+    pub y: u16,
+    pub color: Color,
+    pub size: u8,
+    /// Time the pixel was written, in milliseconds since the unix epoch.
+    pub ts: u64,
+}
+
+/// Bounded buffer of recently written pixels, for analytics.
+///
+/// Best-effort: once the buffer is full, the oldest events are dropped.
+pub struct PixelHistory {
+    events: Mutex<VecDeque<PixelEvent>>,
+    capacity: usize,
+}
+
+impl PixelHistory {
+    /// Returns `None` for a zero capacity, which disables the history.
+    pub fn new(capacity: usize) -> Option<Arc<PixelHistory>> {
+        (capacity > 0).then(|| {
+            Arc::new(PixelHistory {
+                events: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+            })
+        })
+    }
+
+    /// Records a batch of pixels written at the same time.
+    pub fn record<'a>(&self, batch: impl ExactSizeIterator<Item = &'a PixelRequest>) {
+        let ts = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let skipped = batch.len().saturating_sub(self.capacity);
+        let batch = batch.skip(skipped);
+
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let overflow = (events.len() + batch.len()).saturating_sub(self.capacity);
+        events.drain(..overflow);
+        events.extend(batch.map(|req| PixelEvent {
+            x: req.pos.0,
+            y: req.pos.1,
+            color: req.color,
+            size: req.size,
+            ts,
+        }));
+    }
+
+    /// Copies out the buffered events written after `since`, in milliseconds since the unix epoch,
+    /// so callers can serialize them without holding the lock.
+    pub fn snapshot_since(&self, since: u64) -> Vec<PixelEvent> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        // Events are recorded in order, so their timestamps are sorted
+        // unless the system clock went backwards.
+        let start = events.partition_point(|event| event.ts <= since);
+        events.range(start..).copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ring_buffer() {
+        let pixel = |x| PixelRequest {
+            pos: (x, 0),
+            color: Color::rgb(0, 0, 0),
+            size: 1,
+            color_low: None,
+        };
+
+        assert!(PixelHistory::new(0).is_none());
+        let history = PixelHistory::new(3).unwrap();
+        history.record([pixel(0), pixel(1)].iter());
+        history.record([pixel(2), pixel(3)].iter());
+
+        let events = history.snapshot_since(0);
+        let xs: Vec<u16> = events.iter().map(|event| event.x).collect();
+        assert_eq!(xs, [1, 2, 3]);
+        assert!(history.snapshot_since(events[2].ts).is_empty());
+
+        history.record([pixel(4), pixel(5), pixel(6), pixel(7)].iter());
+        let xs: Vec<u16> = history
+            .snapshot_since(0)
+            .iter()
+            .map(|event| event.x)
+            .collect();
+        assert_eq!(xs, [5, 6, 7]);
+    }
+}
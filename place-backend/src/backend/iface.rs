@@ -0,0 +1,145 @@
+use std::{
+    io::{self, ErrorKind},
+    net::Ipv6Addr,
+    process::Command,
+};
+
+use crate::PResult;
+
+use super::SUBNET_PREFIX_LEN;
+
+/// Returns the two /52 subnets of the /48 prefix that pixels are placed on, for 1 and 2 pixel sizes.
+pub fn pixel_subnets(prefix48: Ipv6Addr) -> [Ipv6Addr; 2] {
+    let subnet = |size: u16| {
+        let mut segments = prefix48.segments();
+        segments[3] = size << 12;
+        Ipv6Addr::from(segments)
+    };
+
+    [subnet(1), subnet(2)]
+}
+
+/// Returns the commands needed to route the pixel subnets to the given interface.
+fn setup_commands(iface: &str, prefix48: Ipv6Addr) -> Vec<Vec<String>> {
+    let mut commands = vec![vec![
+        "ip".to_string(),
+        "link".to_string(),
+        "set".to_string(),
+        iface.to_string(),
+        "up".to_string(),
+    ]];
+
+    for subnet in pixel_subnets(prefix48) {
+        commands.push(vec![
+            "ip".to_string(),
+            "-6".to_string(),
+            "route".to_string(),
+            "replace".to_string(),
+            format!("{}/{}", subnet, SUBNET_PREFIX_LEN),
+            "dev".to_string(),
+            iface.to_string(),
+        ]);
+    }
+
+    commands
+}
+
+/// Turns an error from opening the tun interface into a message explaining how to fix it.
+pub fn open_error(iface: &str, err: io::Error) -> String {
+    // ENODEV, not mapped to an ErrorKind on stable.
+    const NO_SUCH_DEVICE: i32 = 19;
+
+    let hint = if err.kind() == ErrorKind::PermissionDenied {
+        "Run the server as root or grant it CAP_NET_ADMIN \
+         (`sudo setcap cap_net_admin+ep <binary>`), or create the interface beforehand \
+         and make it owned by the current user."
+            .to_string()
+    } else if err.kind() == ErrorKind::NotFound || err.raw_os_error() == Some(NO_SUCH_DEVICE) {
+        format!(
+            "Make sure the tun driver is loaded (`sudo modprobe tun`) and create the interface \
+             with `sudo ip tuntap add name {} mode tun user $USER`.",
+            iface
+        )
+    } else {
+        return format!("Failed to open tun interface {}: {}", iface, err);
+    };
+
+    format!("Failed to open tun interface {}: {}. {}", iface, err, hint)
+}
+
+/// Brings the interface up and routes the pixel subnets to it.
+pub fn configure(iface: &str, prefix48: Ipv6Addr) -> PResult<()> {
+    for command in setup_commands(iface, prefix48) {
+        log::info!("Running: {}", command.join(" "));
+        let status = Command::new(&command[0]).args(&command[1..]).status()?;
+        if !status.success() {
+            return Err(format!("Command `{}` failed with {}", command.join(" "), status).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Warns if the pixel subnets aren't routed to the interface, along with the commands to fix it.
+pub fn check_routes(iface: &str, prefix48: Ipv6Addr) {
+    let missing = pixel_subnets(prefix48).into_iter().any(|subnet| {
+        let output = Command::new("ip")
+            .args([
+                "-6",
+                "route",
+                "show",
+                &format!("{}/{}", subnet, SUBNET_PREFIX_LEN),
+                "dev",
+                iface,
+            ])
+            .output();
+
+        match output {
+            Ok(output) => output.status.success() && output.stdout.is_empty(),
+            // Can't tell without iproute2, don't bother the user.
+            Err(_) => false,
+        }
+    });
+
+    if missing {
+        log::warn!(
+            "Pixel subnets are not routed to {}, the canvas won't receive any packets. \
+             Set `configure_interface = true` or run:\n{}",
+            iface,
+            setup_commands(iface, prefix48)
+                .iter()
+                .map(|command| format!("    sudo {}", command.join(" ")))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subnets_of_prefix() {
+        let prefix48 = "2602:fa9b:42::".parse().unwrap();
+        assert_eq!(
+            pixel_subnets(prefix48),
+            [
+                "2602:fa9b:42:1000::".parse::<Ipv6Addr>().unwrap(),
+                "2602:fa9b:42:2000::".parse::<Ipv6Addr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn open_error_hints() {
+        let denied = open_error("place0", io::Error::from(ErrorKind::PermissionDenied));
+        assert!(denied.contains("place0") && denied.contains("CAP_NET_ADMIN"));
+
+        let missing = open_error("place0", io::Error::from_raw_os_error(19));
+        assert!(missing.contains("ip tuntap add name place0 mode tun"));
+
+        let other = open_error("place0", io::Error::from(ErrorKind::Other));
+        assert!(!other.contains("ip tuntap"));
+    }
+}
@@ -1,70 +1,305 @@
 use std::{
+    fmt::Write,
     net::Ipv6Addr,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicU32, AtomicU64, Ordering},
         Arc,
     },
 };
 
 use tokio::{sync::broadcast, task::JoinHandle};
 
+use self::writer::PixelQueue;
 use crate::{
-    place::SharedImageHandle,
-    settings::{BackendType, Settings},
-    utils::Color,
-    PResult,
+    settings::{BackendType, CanvasSettings, CoordinateMode, Origin, Settings},
+    utils::{Color, Color16},
+    Event, PResult,
 };
 
+pub mod acl;
+pub mod attribution;
+mod coalesce;
+pub mod cooldown;
+pub mod history;
+pub mod iface;
+#[cfg(feature = "backend-smoltcp")]
+pub mod ndp;
+#[cfg(all(test, feature = "backend-smoltcp"))]
+mod netns;
+#[cfg(feature = "backend-pcap")]
+mod pcap;
+pub mod rate_grid;
+#[cfg(feature = "backend-smoltcp")]
+mod reply_limit;
+pub mod schema;
 #[cfg(feature = "backend-smoltcp")]
 mod smoltcp;
+pub mod talkers;
+mod transform;
 #[cfg(feature = "backend-tun")]
 mod tun;
+pub mod udp_bridge;
+pub mod writer;
 
 #[cfg(not(all(feature = "backend-smoltcp", feature = "backend-tun")))]
 compile_error!(
     "No backends enabled. Please enable at least one backend with the `backend-*` features."
 );
 
+/// Length of the subnets pixels are placed on. The top nibble of the fourth address segment
+/// selects the subnet, and with it the pixel size.
+pub const SUBNET_PREFIX_LEN: u8 = 52;
+/// Mask of the pixel size bits in the fourth address segment.
+pub const SIZE_MASK: u16 = 0x3000;
+/// Mask of the coordinate bits in the fourth (X) and fifth (Y) address segments.
+pub const COORD_MASK: u16 = 0x0fff;
+/// Mask of the color channel bits in the last three address segments.
+pub const COLOR_MASK: u16 = 0x00ff;
+
 pub struct PixelRequest {
     pub pos: (u16, u16),
+    /// Color of the pixel, or the high bytes of its channels on canvases with 16 bits per channel.
     pub color: Color,
     pub size: u8,
+    /// Low bytes of the color channels on canvases with 16 bits per channel, `None` for pixels
+    /// with 8 bits per channel.
+    pub color_low: Option<[u8; 3]>,
 }
 
 impl PixelRequest {
+    /// Size of a single record in the binary format parsed by `from_bytes`.
+    pub const RECORD_SIZE: usize = 8;
+    /// Size of a record in the payload of an ICMPv6 echo request, parsed by `from_payload`.
+    pub const PAYLOAD_RECORD_SIZE: usize = 11;
+    /// Bytes starting a payload record, other payloads don't carry a pixel.
+    pub const PAYLOAD_MAGIC: [u8; 2] = *b"PX";
+
     /// Parses an IP address in form of 2602:fa9b:42:SXXX:YYY:RR:GG:BB into a PixelRequest.
     #[inline]
     pub const fn from_ipv6(ip: &Ipv6Addr) -> Self {
         let octets = ip.segments();
 
         // clamp size to 1 or 2 (without branching)
-        let size = (((octets[3] & 0x3000) >> 13) + 1) as u8;
+        let size = (((octets[3] & SIZE_MASK) >> 13) + 1) as u8;
 
-        let x = octets[3] & 0xfff;
-        let y = octets[4] & 0xfff;
+        let x = octets[3] & COORD_MASK;
+        let y = octets[4] & COORD_MASK;
 
-        let r = (octets[5] & 0xff) as u8;
-        let g = (octets[6] & 0xff) as u8;
-        let b = (octets[7] & 0xff) as u8;
+        let r = (octets[5] & COLOR_MASK) as u8;
+        let g = (octets[6] & COLOR_MASK) as u8;
+        let b = (octets[7] & COLOR_MASK) as u8;
 
         Self {
             pos: (x, y),
             color: Color::rgb(r, g, b),
             size,
+            color_low: None,
+        }
+    }
+
+    /// Parses an IP address like `from_ipv6`, but with 16 bits per color channel:
+    /// 2602:fa9b:42:SXXX:YYY:RRRR:GGGG:BBBB.
+    #[inline]
+    pub const fn from_ipv6_deep(ip: &Ipv6Addr) -> Self {
+        let mut req = Self::from_ipv6(ip);
+        let segments = ip.segments();
+
+        let [r, g, b] = [segments[5], segments[6], segments[7]];
+        req.color = Color::rgb((r >> 8) as u8, (g >> 8) as u8, (b >> 8) as u8);
+        req.color_low = Some([r as u8, g as u8, b as u8]);
+        req
+    }
+
+    /// Parses an IP address with `from_ipv6_deep` or `from_ipv6`, depending on whether the canvas
+    /// keeps 16 bits per color channel.
+    #[inline]
+    pub const fn from_ipv6_with_depth(ip: &Ipv6Addr, deep_color: bool) -> Self {
+        if deep_color {
+            Self::from_ipv6_deep(ip)
+        } else {
+            Self::from_ipv6(ip)
+        }
+    }
+
+    /// Returns the full color of the pixel, including the low bytes on canvases with 16 bits per channel.
+    /// Pixels with 8 bits per channel are widened like `Color16::from_color`, so 0xff becomes 0xffff.
+    #[inline]
+    pub const fn color16(&self) -> Color16 {
+        let Some([lr, lg, lb]) = self.color_low else {
+            return Color16::from_color(self.color);
+        };
+        let Color { r, g, b, a } = self.color;
+        Color16 {
+            r: u16::from_be_bytes([r, lr]),
+            g: u16::from_be_bytes([g, lg]),
+            b: u16::from_be_bytes([b, lb]),
+            a: a as u16 * 257,
+        }
+    }
+
+    /// Parses a UDP bridge record in form of XX YY R G B S, where XX and YY are big endian u16.
+    #[inline]
+    pub const fn from_bytes(bytes: &[u8; Self::RECORD_SIZE]) -> Self {
+        let x = u16::from_be_bytes([bytes[0], bytes[1]]) & COORD_MASK;
+        let y = u16::from_be_bytes([bytes[2], bytes[3]]) & COORD_MASK;
+
+        // clamp size to 1 or 2
+        let size = if bytes[7] >= 2 { 2 } else { 1 };
+
+        Self {
+            pos: (x, y),
+            color: Color::rgb(bytes[4], bytes[5], bytes[6]),
+            size,
+            color_low: None,
+        }
+    }
+
+    /// Parses a record from the payload of an ICMPv6 echo request in form of "PX" XX YY R G B A S,
+    /// where XX and YY are big endian u16. Returns `None` unless the payload starts with a record.
+    ///
+    /// Unlike in addresses, the coordinates use all 16 bits and the alpha channel is written to the
+    /// canvas as is. The size is clamped to 1 or 2 like in the other formats.
+    #[inline]
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        let record = payload.get(..Self::PAYLOAD_RECORD_SIZE)?;
+        if record[..2] != Self::PAYLOAD_MAGIC {
+            return None;
+        }
+
+        let x = u16::from_be_bytes([record[2], record[3]]);
+        let y = u16::from_be_bytes([record[4], record[5]]);
+        let size = if record[10] >= 2 { 2 } else { 1 };
+
+        Some(Self {
+            pos: (x, y),
+            color: Color::new(record[6], record[7], record[8], record[9]),
+            size,
+            color_low: None,
+        })
+    }
+
+    /// Maps the position from the given coordinate mode to canvas coordinates with top-left origin,
+    /// scaling the position and size. Rows outside of the canvas stay outside of it, so they are
+    /// still ignored or rejected.
+    #[inline]
+    pub fn oriented(mut self, mode: CoordinateMode, height: u32) -> Self {
+        if mode.swap_axes {
+            self.pos = (self.pos.1, self.pos.0);
         }
+
+        // The origin is flipped within the unscaled grid, so scaled blocks line up with it.
+        let scale = mode.scale.max(1);
+        let height = height / scale as u32;
+        if mode.origin == Origin::BottomLeft && (self.pos.1 as u32) < height {
+            self.pos.1 = (height - 1 - self.pos.1 as u32) as u16;
+        }
+
+        if scale > 1 {
+            // Saturating keeps overflowing coordinates outside of the canvas.
+            let scale16 = scale as u16;
+            self.pos = (
+                self.pos.0.saturating_mul(scale16),
+                self.pos.1.saturating_mul(scale16),
+            );
+            self.size = self.size.saturating_mul(scale);
+        }
+
+        self
+    }
+
+    /// Checks whether the request targets a pixel on a canvas of given size, with a non-zero size.
+    /// Sizes are only limited to 1 or 2 before `oriented` scales them.
+    #[inline]
+    pub const fn is_within(&self, width: u32, height: u32) -> bool {
+        let (x, y) = self.pos;
+        (x as u32) < width && (y as u32) < height && self.size > 0
+    }
+}
+
+/// ICMPv6 message type of echo requests.
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub(crate) const IP_PROTOCOL_UDP: u8 = 17;
+pub(crate) const IP_PROTOCOL_ICMPV6: u8 = 58;
+
+/// Verifies the checksum of an ICMPv6 message or UDP datagram in an IPv6 packet without extension
+/// headers. Packets of other protocols are accepted as is.
+pub fn checksum_valid(ip: &[u8]) -> bool {
+    let Some(header) = ip.get(..40) else {
+        return false;
+    };
+    let next_header = header[6];
+    if !matches!(next_header, IP_PROTOCOL_UDP | IP_PROTOCOL_ICMPV6) {
+        return true;
+    }
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let Some(payload) = ip.get(40..40 + len) else {
+        return false;
+    };
+    // A zero UDP checksum means "not computed", which IPv6 doesn't allow.
+    if next_header == IP_PROTOCOL_UDP && payload.get(6..8) == Some(&[0, 0]) {
+        return false;
     }
+
+    // Pseudo-header of source and destination address, length and next header, then the payload.
+    let mut sum = len as u32 + next_header as u32;
+    for chunk in header[8..40].chunks(2).chain(payload.chunks(2)) {
+        sum += u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum == 0xffff
+}
+
+/// Checks whether an ICMPv6 message is an echo request whose payload starts with `expected`.
+/// Any message is accepted if no payload is expected.
+#[inline]
+pub fn icmp_payload_matches(icmp: &[u8], expected: Option<&[u8]>) -> bool {
+    let Some(expected) = expected else {
+        return true;
+    };
+
+    // Type, code, checksum, identifier and sequence number precede the payload.
+    icmp.first() == Some(&ICMPV6_ECHO_REQUEST)
+        && icmp
+            .get(8..)
+            .is_some_and(|payload| payload.starts_with(expected))
+}
+
+/// Reads a pixel from the payload of an ICMPv6 echo request with `PixelRequest::from_payload`,
+/// skipping the `skip` bytes of the expected payload prefix.
+#[inline]
+pub fn icmp_payload_pixel(icmp: &[u8], skip: usize) -> Option<PixelRequest> {
+    if icmp.first() != Some(&ICMPV6_ECHO_REQUEST) {
+        return None;
+    }
+    PixelRequest::from_payload(icmp.get(8 + skip..)?)
 }
 
 pub struct PacketCounter {
     pps: AtomicU32,
+    /// Exponential moving average of pps, stored as f32 bits.
+    smoothed_pps: AtomicU32,
     counter: AtomicU32,
+    /// Packets counted up to the last pps update.
+    total: AtomicU64,
+    /// Packets dropped because of an invalid checksum.
+    bad_checksums: AtomicU64,
+    /// Echo replies held back by the reply limits.
+    suppressed_replies: AtomicU64,
+    ema_alpha: f32,
 }
 
 impl PacketCounter {
-    pub fn new() -> Arc<PacketCounter> {
+    pub fn new(settings: &Settings) -> Arc<PacketCounter> {
         Arc::new(PacketCounter {
             pps: AtomicU32::new(0),
+            smoothed_pps: AtomicU32::new(0f32.to_bits()),
             counter: AtomicU32::new(0),
+            total: AtomicU64::new(0),
+            bad_checksums: AtomicU64::new(0),
+            suppressed_replies: AtomicU64::new(0),
+            ema_alpha: settings.backend.pps_ema_alpha,
         })
     }
 
@@ -74,25 +309,147 @@ impl PacketCounter {
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     }
 
-    fn reset_pps(&self) -> u32 {
+    #[inline]
+    pub fn increment_bad_checksum(&self) {
+        self.bad_checksums.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of packets dropped because of an invalid checksum since the server started.
+    pub fn bad_checksums(&self) -> u64 {
+        self.bad_checksums.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn increment_suppressed_reply(&self) {
+        self.suppressed_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of echo replies suppressed by the reply limits since the server started.
+    pub fn suppressed_replies(&self) -> u64 {
+        self.suppressed_replies.load(Ordering::Relaxed)
+    }
+
+    /// Appends the suppressed reply counter in the Prometheus text format to `out`.
+    pub fn render_metrics(&self, out: &mut String) {
+        out.push_str(
+            "# HELP place_suppressed_replies_total Echo replies held back by the reply limits.\n",
+        );
+        out.push_str("# TYPE place_suppressed_replies_total counter\n");
+        let _ = writeln!(
+            out,
+            "place_suppressed_replies_total {}",
+            self.suppressed_replies()
+        );
+    }
+
+    /// Returns the raw and smoothed number of packets received during the last second.
+    pub fn get_pps(&self) -> (u32, f32) {
+        (
+            self.pps.load(Ordering::Relaxed),
+            f32::from_bits(self.smoothed_pps.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// Returns the number of packets received since the server started.
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed) + self.counter.load(Ordering::Relaxed) as u64
+    }
+
+    /// Returns the raw and smoothed number of packets since the last call.
+    fn reset_pps(&self) -> (u32, f32) {
         let pps = self.counter.swap(0, Ordering::Relaxed);
         self.pps.store(pps, Ordering::Relaxed);
-        pps
+        self.total.fetch_add(pps as u64, Ordering::Relaxed);
+
+        let smoothed = f32::from_bits(self.smoothed_pps.load(Ordering::Relaxed));
+        let smoothed = smoothed + self.ema_alpha * (pps as f32 - smoothed);
+        self.smoothed_pps
+            .store(smoothed.to_bits(), Ordering::Relaxed);
+
+        (pps, smoothed)
     }
 
-    async fn pps_counter_task(self: Arc<Self>, pps_sender: broadcast::Sender<u32>) -> PResult<()> {
+    async fn pps_counter_task(
+        self: Arc<Self>,
+        event_sender: broadcast::Sender<Event>,
+    ) -> PResult<()> {
         loop {
             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            let pps = self.reset_pps();
-            pps_sender.send(pps)?;
+            let (raw, smoothed) = self.reset_pps();
+            event_sender.send(Event::Pps { raw, smoothed })?;
         }
     }
 
     pub fn start_pps_counter(
         self: Arc<Self>,
-        pps_sender: broadcast::Sender<u32>,
+        event_sender: broadcast::Sender<Event>,
     ) -> JoinHandle<PResult<()>> {
-        tokio::spawn(self.pps_counter_task(pps_sender))
+        tokio::spawn(self.pps_counter_task(event_sender))
+    }
+}
+
+/// A canvas pixels can be placed on, and the /48 prefix its pixel addresses are in.
+#[derive(Clone)]
+pub struct CanvasRoute {
+    pub prefix48: Ipv6Addr,
+    pub queue: PixelQueue,
+    pub coordinate_mode: CoordinateMode,
+    pub deep_color: bool,
+}
+
+impl CanvasRoute {
+    pub fn new(prefix48: Ipv6Addr, canvas: &CanvasSettings, queue: PixelQueue) -> CanvasRoute {
+        CanvasRoute {
+            prefix48,
+            queue,
+            coordinate_mode: canvas.coordinate_mode,
+            deep_color: canvas.deep_color(),
+        }
+    }
+
+    /// Parses a pixel address into a request in coordinates of this canvas.
+    #[inline]
+    pub fn decode(&self, dst: &Ipv6Addr) -> PixelRequest {
+        self.orient(PixelRequest::from_ipv6_with_depth(dst, self.deep_color))
+    }
+
+    /// Maps a request decoded some other way, eg. from a packet payload, to coordinates of this canvas.
+    #[inline]
+    pub fn orient(&self, req: PixelRequest) -> PixelRequest {
+        let (_, height) = self.queue.dimensions();
+        req.oriented(self.coordinate_mode, height)
+    }
+}
+
+/// Picks the canvas a packet is for by its destination prefix, when several canvases are served.
+#[derive(Clone)]
+pub struct CanvasRouter {
+    /// The main canvas comes first.
+    routes: Vec<CanvasRoute>,
+}
+
+impl CanvasRouter {
+    pub fn new(main: CanvasRoute) -> CanvasRouter {
+        CanvasRouter { routes: vec![main] }
+    }
+
+    pub fn add(&mut self, route: CanvasRoute) {
+        self.routes.push(route);
+    }
+
+    pub fn routes(&self) -> &[CanvasRoute] {
+        &self.routes
+    }
+
+    /// Returns the canvas whose prefix `dst` is in. Packets outside of all prefixes only make it
+    /// here if the interface routes more than the canvas prefixes, they go to the main canvas.
+    #[inline]
+    pub fn route(&self, dst: &Ipv6Addr) -> &CanvasRoute {
+        let prefix = &dst.segments()[..3];
+        self.routes
+            .iter()
+            .find(|route| route.prefix48.segments()[..3] == *prefix)
+            .unwrap_or(&self.routes[0])
     }
 }
 
@@ -102,19 +459,20 @@ pub trait NetworkBackend: Send + Sync {
 
 pub fn backend_factory(
     settings: &Settings,
-    image: SharedImageHandle,
+    router: CanvasRouter,
     packet_counter: Arc<PacketCounter>,
 ) -> PResult<Box<dyn NetworkBackend>> {
     match settings.backend.backend_type {
         #[cfg(feature = "backend-smoltcp")]
         BackendType::Smoltcp => {
-            smoltcp::SmoltcpNetworkBackend::new(&settings, image, packet_counter)
+            smoltcp::SmoltcpNetworkBackend::new(&settings, router, packet_counter)
         }
 
         #[cfg(feature = "backend-tun")]
-        BackendType::Tun => {
-            tun::TunNetworkBackend::new(&settings, image, packet_counter)
-        }
+        BackendType::Tun => tun::TunNetworkBackend::new(&settings, router, packet_counter),
+
+        #[cfg(feature = "backend-pcap")]
+        BackendType::Pcap => pcap::PcapNetworkBackend::new(&settings, router, packet_counter),
 
         #[allow(unreachable_patterns)]
         _ => Err(format!(
@@ -124,3 +482,150 @@ pub fn backend_factory(
         .into()),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pixel_request_from_bytes() {
+        let req = PixelRequest::from_bytes(&[0x01, 0x02, 0x00, 0x10, 0xff, 0x80, 0x00, 2]);
+        assert_eq!(req.pos, (0x102, 0x10));
+        assert_eq!(req.color, Color::rgb(0xff, 0x80, 0x00));
+        assert_eq!(req.size, 2);
+
+        let req = PixelRequest::from_bytes(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 7]);
+        assert_eq!(req.pos, (0xfff, 0xfff));
+        assert_eq!(req.size, 2);
+
+        let req = PixelRequest::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(req.size, 1);
+    }
+
+    #[test]
+    fn pixel_request_from_payload() {
+        let record = *b"PX\x12\x34\x00\x10\xff\x80\x00\x40\x05";
+        let req = PixelRequest::from_payload(&record).unwrap();
+        assert_eq!(req.pos, (0x1234, 0x10));
+        assert_eq!(req.color, Color::new(0xff, 0x80, 0x00, 0x40));
+        assert_eq!(req.size, 2);
+        assert!(PixelRequest::from_payload(&record[..10]).is_none());
+        assert!(PixelRequest::from_payload(&[0; 16]).is_none());
+
+        // Echo request header, the expected payload prefix, then the record.
+        let mut icmp = vec![ICMPV6_ECHO_REQUEST, 0, 0, 0, 0, 0, 0, 0, 1, 1];
+        icmp.extend_from_slice(&record);
+        assert_eq!(icmp_payload_pixel(&icmp, 2).unwrap().pos, (0x1234, 0x10));
+        assert!(icmp_payload_pixel(&icmp, 0).is_none());
+        icmp[0] = 129;
+        assert!(icmp_payload_pixel(&icmp, 2).is_none());
+    }
+
+    #[test]
+    fn pixel_request_deep_color() {
+        let ip: Ipv6Addr = "2602:fa9b:42:1123:45:ff01:8000:1".parse().unwrap();
+
+        let req = PixelRequest::from_ipv6(&ip);
+        assert_eq!(req.color, Color::rgb(0x01, 0x00, 0x01));
+        assert_eq!(req.color16(), Color16::rgb(0x0101, 0, 0x0101));
+
+        let req = PixelRequest::from_ipv6_with_depth(&ip, true);
+        assert_eq!(req.pos, (0x123, 0x45));
+        assert_eq!(req.color, Color::rgb(0xff, 0x80, 0x00));
+        assert_eq!(req.color16(), Color16::rgb(0xff01, 0x8000, 0x0001));
+
+        // Deep colors whose low bytes are zero are kept exactly, not widened.
+        let ip: Ipv6Addr = "2602:fa9b:42:1123:45:ff00:8000:100".parse().unwrap();
+        let req = PixelRequest::from_ipv6_with_depth(&ip, true);
+        assert_eq!(req.color16(), Color16::rgb(0xff00, 0x8000, 0x0100));
+    }
+
+    #[test]
+    fn icmp_payload_filter() {
+        let echo = [128, 0, 0xab, 0xcd, 0, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1];
+        assert!(icmp_payload_matches(&echo, None));
+        assert!(icmp_payload_matches(&echo, Some(&[1; 8])));
+        assert!(icmp_payload_matches(&echo, Some(&[1; 4])));
+        assert!(!icmp_payload_matches(&echo, Some(&[1; 9])));
+        assert!(!icmp_payload_matches(&echo, Some(&[2; 8])));
+
+        // Echo replies and other messages are rejected once a payload is expected.
+        let mut reply = echo;
+        reply[0] = 129;
+        assert!(icmp_payload_matches(&reply, None));
+        assert!(!icmp_payload_matches(&reply, Some(&[1; 8])));
+        assert!(!icmp_payload_matches(&[128, 0], Some(&[])));
+    }
+
+    #[test]
+    fn checksums() {
+        // Echo request from 2001:db8::1 to 2602:fa9b:42:1001:2:ff::.
+        let mut ip = vec![0x60, 0, 0, 0, 0, 16, IP_PROTOCOL_ICMPV6, 64];
+        ip.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ip.extend_from_slice(
+            &"2602:fa9b:42:1001:2:ff::"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        ip.extend_from_slice(&[128, 0, 0x1c, 0x13, 0, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert!(checksum_valid(&ip));
+
+        let mut corrupt = ip.clone();
+        corrupt[45] ^= 1;
+        assert!(!checksum_valid(&corrupt));
+        assert!(!checksum_valid(&ip[..50]));
+
+        // UDP to port 7 with an odd length payload, and without a checksum.
+        ip[5] = 9;
+        ip[6] = IP_PROTOCOL_UDP;
+        ip.truncate(40);
+        ip.extend_from_slice(&[0x30, 0x39, 0, 7, 0, 9, 0x46, 0x00, 0x2a]);
+        assert!(checksum_valid(&ip));
+        ip[46..48].copy_from_slice(&[0, 0]);
+        assert!(!checksum_valid(&ip));
+    }
+
+    #[test]
+    fn pixel_request_oriented() {
+        let req = |x, y| PixelRequest {
+            pos: (x, y),
+            color: Color::rgb(0, 0, 0),
+            size: 1,
+            color_low: None,
+        };
+        let mode = |origin, swap_axes| CoordinateMode {
+            origin,
+            swap_axes,
+            scale: 1,
+        };
+
+        let top_left = req(1, 2).oriented(mode(Origin::TopLeft, false), 512);
+        assert_eq!(top_left.pos, (1, 2));
+
+        let bottom_left = req(1, 2).oriented(mode(Origin::BottomLeft, false), 512);
+        assert_eq!(bottom_left.pos, (1, 509));
+
+        let swapped = req(1, 2).oriented(mode(Origin::BottomLeft, true), 512);
+        assert_eq!(swapped.pos, (2, 510));
+
+        let outside = req(1, 600).oriented(mode(Origin::BottomLeft, false), 512);
+        assert_eq!(outside.pos, (1, 600));
+
+        let scaled = |origin, x, y| {
+            req(x, y).oriented(
+                CoordinateMode {
+                    scale: 4,
+                    ..mode(origin, false)
+                },
+                512,
+            )
+        };
+        let top_left = scaled(Origin::TopLeft, 1, 2);
+        assert_eq!((top_left.pos, top_left.size), ((4, 8), 4));
+        assert_eq!(scaled(Origin::BottomLeft, 1, 2).pos, (4, 500));
+        assert_eq!(scaled(Origin::BottomLeft, 0, 0).pos, (0, 508));
+        assert_eq!(scaled(Origin::TopLeft, 0xfff, 0).pos, (0x3ffc, 0));
+        assert!(!scaled(Origin::TopLeft, 128, 0).is_within(512, 512));
+    }
+}
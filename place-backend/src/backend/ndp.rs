@@ -0,0 +1,310 @@
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Read, Write},
+    net::Ipv6Addr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use tokio::task::JoinHandle;
+
+use crate::{settings::Settings, PResult};
+
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_HEADER_LEN: usize = 14;
+const IPV6_HEADER_LEN: usize = 40;
+const IPPROTO_ICMPV6: u8 = 58;
+const ICMPV6_NEIGHBOR_SOLICIT: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERT: u8 = 136;
+const NDP_OPT_SOURCE_LLADDR: u8 = 1;
+const NDP_OPT_TARGET_LLADDR: u8 = 2;
+/// Solicited and Override flags of a Neighbor Advertisement.
+const NA_FLAGS: u8 = 0x60;
+/// Length of a Neighbor Solicitation/Advertisement without options.
+const NDP_MESSAGE_LEN: usize = 24;
+
+/// Answers Neighbor Solicitations for pixel addresses on the uplink interface.
+///
+/// Needed when the prefix is on-link on the uplink instead of being routed to this host: the router
+/// then resolves every pixel address with NDP, and the kernel only answers for its own addresses.
+/// Advertised packets are routed to the tun interface by the kernel, which requires IPv6 forwarding.
+pub struct NdpResponder {
+    socket: File,
+    mac: [u8; 6],
+    /// Prefixes of the main and the named canvases.
+    prefixes: Vec<Ipv6Addr>,
+    iface: String,
+}
+
+impl NdpResponder {
+    pub fn new(settings: &Settings) -> PResult<Option<NdpResponder>> {
+        let iface = match &settings.backend.smoltcp.ndp_proxy_iface {
+            Some(iface) => iface.clone(),
+            None => return Ok(None),
+        };
+
+        let mac = read_mac(&iface)?;
+        let socket = open_packet_socket(&iface)
+            .map_err(|e| format!("Failed to open packet socket on {}: {}", iface, e))?;
+        log::info!(
+            "Answering neighbor solicitations for pixel addresses on {}",
+            iface
+        );
+
+        Ok(Some(NdpResponder {
+            socket,
+            mac,
+            prefixes: std::iter::once(settings.backend.prefix48)
+                .chain(settings.canvases.iter().map(|named| named.prefix48))
+                .collect(),
+            iface,
+        }))
+    }
+
+    fn run(mut self) -> PResult<()> {
+        let mut buffer = [0u8; 1536];
+        loop {
+            let len = self.socket.read(&mut buffer)?;
+            if let Some(reply) = advertisement(&buffer[..len], self.mac, &self.prefixes) {
+                if let Err(e) = self.socket.write_all(&reply) {
+                    log::warn!(
+                        "Failed to send neighbor advertisement on {}: {}",
+                        self.iface,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<PResult<()>> {
+        tokio::task::spawn_blocking(move || self.run())
+    }
+}
+
+/// Reads the MAC address of an interface from sysfs.
+fn read_mac(iface: &str) -> PResult<[u8; 6]> {
+    let address = std::fs::read_to_string(format!("/sys/class/net/{}/address", iface))
+        .map_err(|e| format!("Failed to read MAC address of {}: {}", iface, e))?;
+
+    let mut mac = [0u8; 6];
+    let mut parts = address.trim().split(':');
+    for byte in mac.iter_mut() {
+        *byte = parts
+            .next()
+            .and_then(|part| u8::from_str_radix(part, 16).ok())
+            .ok_or_else(|| format!("Invalid MAC address of {}: {}", iface, address.trim()))?;
+    }
+
+    Ok(mac)
+}
+
+/// Opens a raw packet socket receiving IPv6 frames of the interface, including all multicast ones,
+/// since solicitations are sent to the solicited-node multicast address of their target.
+fn open_packet_socket(iface: &str) -> io::Result<File> {
+    let name = CString::new(iface).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            ETH_P_IPV6.to_be() as i32,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let raw_fd = fd.as_raw_fd();
+
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = ETH_P_IPV6.to_be();
+    addr.sll_ifindex = ifindex as i32;
+    let result = unsafe {
+        libc::bind(
+            raw_fd,
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as u32,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut membership: libc::packet_mreq = unsafe { std::mem::zeroed() };
+    membership.mr_ifindex = ifindex as i32;
+    membership.mr_type = libc::PACKET_MR_ALLMULTI as u16;
+    let result = unsafe {
+        libc::setsockopt(
+            raw_fd,
+            libc::SOL_PACKET,
+            libc::PACKET_ADD_MEMBERSHIP,
+            &membership as *const libc::packet_mreq as *const libc::c_void,
+            std::mem::size_of::<libc::packet_mreq>() as u32,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(File::from(fd))
+}
+
+/// Checks whether the address lies within one of the pixel subnets of the prefix.
+fn is_pixel_address(prefix48: Ipv6Addr, addr: Ipv6Addr) -> bool {
+    let (prefix, addr) = (prefix48.segments(), addr.segments());
+    prefix[..3] == addr[..3] && matches!(addr[3] >> 12, 1 | 2)
+}
+
+/// Builds the Neighbor Advertisement answering an Ethernet frame, if it's a Neighbor Solicitation
+/// for a pixel address of one of the prefixes.
+fn advertisement(frame: &[u8], mac: [u8; 6], prefixes: &[Ipv6Addr]) -> Option<Vec<u8>> {
+    if frame.len() < ETH_HEADER_LEN + IPV6_HEADER_LEN + NDP_MESSAGE_LEN
+        || frame[12..14] != ETH_P_IPV6.to_be_bytes()
+    {
+        return None;
+    }
+
+    let ip = &frame[ETH_HEADER_LEN..];
+    let payload_len = u16::from_be_bytes([ip[4], ip[5]]) as usize;
+    // NDP messages have to come from the link, with an untouched hop limit of 255.
+    if ip[0] >> 4 != 6 || ip[6] != IPPROTO_ICMPV6 || ip[7] != 255 {
+        return None;
+    }
+    let icmp = ip.get(IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len)?;
+    if icmp.len() < NDP_MESSAGE_LEN || icmp[0] != ICMPV6_NEIGHBOR_SOLICIT || icmp[1] != 0 {
+        return None;
+    }
+
+    let source: [u8; 16] = ip[8..24].try_into().unwrap();
+    let target: [u8; 16] = icmp[8..24].try_into().unwrap();
+    // Duplicate address detection, the address isn't in use by anyone else yet.
+    if Ipv6Addr::from(source).is_unspecified()
+        || !prefixes
+            .iter()
+            .any(|&prefix48| is_pixel_address(prefix48, target.into()))
+    {
+        return None;
+    }
+
+    // Prefer the source link-layer address option over the sender of the frame.
+    let mut destination_mac: [u8; 6] = frame[6..12].try_into().unwrap();
+    let mut options = &icmp[NDP_MESSAGE_LEN..];
+    while options.len() >= 8 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            break;
+        }
+        if options[0] == NDP_OPT_SOURCE_LLADDR {
+            destination_mac = options[2..8].try_into().unwrap();
+        }
+        options = &options[len..];
+    }
+
+    let mut reply_icmp = Vec::with_capacity(NDP_MESSAGE_LEN + 8);
+    reply_icmp.extend_from_slice(&[ICMPV6_NEIGHBOR_ADVERT, 0, 0, 0, NA_FLAGS, 0, 0, 0]);
+    reply_icmp.extend_from_slice(&target);
+    reply_icmp.extend_from_slice(&[NDP_OPT_TARGET_LLADDR, 1]);
+    reply_icmp.extend_from_slice(&mac);
+    let checksum = icmpv6_checksum(&target, &source, &reply_icmp);
+    reply_icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut reply = Vec::with_capacity(ETH_HEADER_LEN + IPV6_HEADER_LEN + reply_icmp.len());
+    reply.extend_from_slice(&destination_mac);
+    reply.extend_from_slice(&mac);
+    reply.extend_from_slice(&ETH_P_IPV6.to_be_bytes());
+    reply.extend_from_slice(&[0x60, 0, 0, 0]);
+    reply.extend_from_slice(&(reply_icmp.len() as u16).to_be_bytes());
+    reply.extend_from_slice(&[IPPROTO_ICMPV6, 255]);
+    reply.extend_from_slice(&target);
+    reply.extend_from_slice(&source);
+    reply.extend_from_slice(&reply_icmp);
+    Some(reply)
+}
+
+/// Computes the ICMPv6 checksum of a message, including the IPv6 pseudo-header.
+fn icmpv6_checksum(source: &[u8; 16], destination: &[u8; 16], message: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut add = |bytes: &[u8]| {
+        for chunk in bytes.chunks(2) {
+            sum += u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32;
+        }
+    };
+
+    add(source);
+    add(destination);
+    add(&(message.len() as u32).to_be_bytes());
+    add(&[0, 0, 0, IPPROTO_ICMPV6]);
+    add(message);
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ROUTER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+    const OUR_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+    fn solicitation(source: Ipv6Addr, target: Ipv6Addr) -> Vec<u8> {
+        let mut icmp = vec![ICMPV6_NEIGHBOR_SOLICIT, 0, 0, 0, 0, 0, 0, 0];
+        icmp.extend_from_slice(&target.octets());
+        icmp.extend_from_slice(&[NDP_OPT_SOURCE_LLADDR, 1]);
+        icmp.extend_from_slice(&ROUTER_MAC);
+
+        let mut frame = vec![0x33, 0x33, 0xff, 0, 0, 0];
+        frame.extend_from_slice(&ROUTER_MAC);
+        frame.extend_from_slice(&ETH_P_IPV6.to_be_bytes());
+        frame.extend_from_slice(&[0x60, 0, 0, 0, 0, icmp.len() as u8, IPPROTO_ICMPV6, 255]);
+        frame.extend_from_slice(&source.octets());
+        frame.extend_from_slice(&[0xff; 16]);
+        frame.extend_from_slice(&icmp);
+        frame
+    }
+
+    #[test]
+    fn advertise_pixel_addresses() {
+        let prefix48: Ipv6Addr = "2602:fa9b:42::".parse().unwrap();
+        let router: Ipv6Addr = "fe80::1".parse().unwrap();
+        let pixel: Ipv6Addr = "2602:fa9b:42:1123:45:ff:80:1".parse().unwrap();
+
+        let reply = advertisement(&solicitation(router, pixel), OUR_MAC, &[prefix48]).unwrap();
+        assert_eq!(reply[..6], ROUTER_MAC);
+        assert_eq!(reply[6..12], OUR_MAC);
+
+        let ip = &reply[ETH_HEADER_LEN..];
+        assert_eq!(ip[6..8], [IPPROTO_ICMPV6, 255]);
+        assert_eq!(ip[8..24], pixel.octets());
+        assert_eq!(ip[24..40], router.octets());
+
+        let icmp = &ip[IPV6_HEADER_LEN..];
+        assert_eq!(icmp[0], ICMPV6_NEIGHBOR_ADVERT);
+        assert_eq!(icmp[8..24], pixel.octets());
+        assert_eq!(
+            icmp[24..],
+            [&[NDP_OPT_TARGET_LLADDR, 1][..], &OUR_MAC].concat()
+        );
+        // A valid checksum sums up to zero.
+        assert_eq!(icmpv6_checksum(&pixel.octets(), &router.octets(), icmp), 0);
+
+        let outside: Ipv6Addr = "2602:fa9b:42:3000::1".parse().unwrap();
+        assert!(advertisement(&solicitation(router, outside), OUR_MAC, &[prefix48]).is_none());
+        let dad = solicitation(Ipv6Addr::UNSPECIFIED, pixel);
+        assert!(advertisement(&dad, OUR_MAC, &[prefix48]).is_none());
+
+        // Pixel addresses of named canvases are answered as well.
+        let named: Ipv6Addr = "2602:fa9b:43:1123:45:ff:80:1".parse().unwrap();
+        let prefixes = [prefix48, "2602:fa9b:43::".parse().unwrap()];
+        assert!(advertisement(&solicitation(router, named), OUR_MAC, &[prefix48]).is_none());
+        assert!(advertisement(&solicitation(router, named), OUR_MAC, &prefixes).is_some());
+    }
+}
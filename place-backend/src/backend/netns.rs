@@ -0,0 +1,145 @@
+//! Test support for exercising the real smoltcp path without root.
+//!
+//! Tests re-run themselves as root of a fresh user and network namespace (`unshare --user
+//! --map-root-user --net`), which grants CAP_NET_ADMIN and CAP_NET_RAW over a network stack of
+//! their own. There the backend creates its tun interface and routes the pixel subnets to it like
+//! it would on a real host, and packets sent from the namespace end up on the canvas. A veth pair
+//! isn't needed since the backend speaks IP over tun. Everything is torn down along with the
+//! namespace once the test exits, nothing touches the host's interfaces or routes.
+//!
+//! The tests are skipped if unprivileged user namespaces are disabled, eg. with
+//! `sysctl kernel.unprivileged_userns_clone=0` or in containers without CAP_SYS_ADMIN, and if
+//! `ip` of iproute2 or `/dev/net/tun` is missing.
+
+use std::{path::Path, process::Command};
+
+/// Set in the environment of tests re-run inside the namespace.
+const NAMESPACE_ENV: &str = "PLACE_TEST_NETNS";
+
+/// Re-runs the test at `path` (eg. "backend::netns::test::packet_to_pixel") of the current test
+/// binary inside a new user and network namespace, panicking if it fails there.
+///
+/// Returns `true` if called from that re-run, in which case the caller should go on with the
+/// actual test, and `false` once the re-run passed or if namespaces aren't available.
+pub fn enter_namespace(path: &str) -> bool {
+    if std::env::var_os(NAMESPACE_ENV).is_some() {
+        // Interfaces other than loopback start out down.
+        let status = Command::new("ip")
+            .args(["link", "set", "lo", "up"])
+            .status()
+            .expect("Failed to run ip, is iproute2 installed?");
+        assert!(status.success(), "Failed to bring up loopback: {}", status);
+        return true;
+    }
+
+    let available = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--net", "true"])
+        .status()
+        .is_ok_and(|status| status.success());
+    if !available {
+        eprintln!(
+            "Skipping {}, unprivileged user namespaces are unavailable",
+            path
+        );
+        return false;
+    }
+    if Command::new("ip").arg("-V").output().is_err() {
+        eprintln!("Skipping {}, ip of iproute2 isn't installed", path);
+        return false;
+    }
+    if !Path::new("/dev/net/tun").exists() {
+        eprintln!("Skipping {}, /dev/net/tun is missing", path);
+        return false;
+    }
+
+    let test_binary = std::env::current_exe().unwrap();
+    let status = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--net", "--"])
+        .arg(test_binary)
+        .args([path, "--exact", "--nocapture", "--test-threads=1"])
+        .env(NAMESPACE_ENV, "1")
+        .status()
+        .unwrap();
+    assert!(status.success(), "{} failed in the namespace", path);
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv6Addr, UdpSocket},
+        time::{Duration, Instant},
+    };
+
+    use config::{Config, File, FileFormat};
+    use image::RgbaImage;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::{
+        backend::{backend_factory, writer, CanvasRoute, CanvasRouter, PacketCounter},
+        place::SharedImageHandle,
+        settings::Settings,
+        utils::Color,
+    };
+
+    #[test]
+    fn packet_to_pixel() {
+        if !enter_namespace("backend::netns::test::packet_to_pixel") {
+            return;
+        }
+
+        let settings: Settings = Config::builder()
+            .add_source(File::from_str(
+                include_str!("../../config.toml.example"),
+                FileFormat::Toml,
+            ))
+            .set_override("backend.backend_type", "smoltcp")
+            .unwrap()
+            .set_override("backend.smoltcp.tun_iface", "placetest0")
+            .unwrap()
+            .set_override("backend.smoltcp.configure_interface", true)
+            .unwrap()
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        // A red pixel of size 1 at (3, 4). Sent over UDP, since raw ICMPv6 sockets for pings may
+        // be denied even to root of the namespace, eg. by seccomp filters of container runtimes.
+        let prefix = settings.backend.prefix48.segments();
+        let addr = Ipv6Addr::new(prefix[0], prefix[1], prefix[2], 0x1003, 4, 0xff, 0, 0);
+        let socket = UdpSocket::bind("[::]:0").unwrap();
+
+        let runtime = Runtime::new().unwrap();
+        let guard = runtime.enter();
+        let image = SharedImageHandle::new(RgbaImage::new(64, 64));
+        let (queue, canvas_writer) = writer::pixel_queue(&settings, image.clone(), &[], None);
+        let router = CanvasRouter::new(CanvasRoute::new(
+            settings.backend.prefix48,
+            &settings.canvas,
+            queue,
+        ));
+        let backend = backend_factory(&settings, router, PacketCounter::new(&settings)).unwrap();
+        let _writer = canvas_writer.start();
+        let backend = backend.start();
+
+        // The backend sets up the interface in the background, keep sending until it's there.
+        let started = Instant::now();
+        let mut placed = false;
+        while !placed && !backend.is_finished() && started.elapsed() < Duration::from_secs(10) {
+            // Fails with "network unreachable" until the route exists.
+            let _ = socket.send_to(&[1; 8], (addr, 7));
+            std::thread::sleep(Duration::from_millis(100));
+            placed = image.get(3, 4) == Some(Color::rgb(0xff, 0, 0));
+        }
+
+        let stopped = backend.is_finished().then(|| runtime.block_on(backend));
+
+        // The backend and the writer never stop, leave them behind along with the namespace.
+        drop(guard);
+        runtime.shutdown_background();
+        assert!(stopped.is_none(), "The backend stopped: {:?}", stopped);
+        assert!(placed, "The packet didn't reach the canvas");
+    }
+}
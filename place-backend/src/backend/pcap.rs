@@ -0,0 +1,655 @@
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind, Read},
+    net::{IpAddr, Ipv6Addr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::task::JoinHandle;
+
+use crate::{backend::PixelRequest, settings::Settings, PResult};
+
+use super::{
+    checksum_valid, icmp_payload_matches, icmp_payload_pixel, CanvasRouter, NetworkBackend,
+    PacketCounter, IP_PROTOCOL_ICMPV6, IP_PROTOCOL_UDP,
+};
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// Largest packet or pcapng block read from a capture, so a corrupt length can't make the reader
+/// allocate gigabytes.
+const MAX_RECORD_SIZE: usize = 256 * 1024;
+
+/// Replays pixel packets from a pcap or pcapng capture, useful for debugging and benchmarking.
+///
+/// Packets are handled the same way as by the smoltcp backend: any ICMPv6 packet or UDP packet to
+/// port 7 sent to one of the pixel subnets places a pixel, unless disabled in the backend settings.
+pub struct PcapNetworkBackend {
+    router: CanvasRouter,
+    packet_counter: Arc<PacketCounter>,
+    path: PathBuf,
+    filter: PixelFilter,
+    verify_checksums: bool,
+    realtime: bool,
+    looped: bool,
+}
+
+impl PcapNetworkBackend {
+    pub fn new(
+        settings: &Settings,
+        router: CanvasRouter,
+        packet_counter: Arc<PacketCounter>,
+    ) -> PResult<Box<dyn NetworkBackend>> {
+        let pcap = &settings.backend.pcap;
+        if pcap.file.is_empty() {
+            return Err("backend.pcap.file must be set to use the pcap backend.".into());
+        }
+
+        let path = PathBuf::from(&pcap.file);
+        if !path.exists() {
+            return Err(format!("Capture file {} does not exist.", path.display()).into());
+        }
+
+        Ok(Box::new(Self {
+            router,
+            packet_counter,
+            path,
+            filter: PixelFilter {
+                enable_icmp: settings.backend.enable_icmp,
+                enable_udp: settings.backend.enable_udp,
+                icmp_payload: settings.backend.icmp_payload.clone(),
+                payload_pixels: settings.backend.payload_pixels,
+            },
+            verify_checksums: settings.backend.verify_checksums,
+            realtime: pcap.realtime,
+            looped: pcap.looped,
+        }))
+    }
+
+    fn replay(&self) -> PResult<u64> {
+        let file = BufReader::new(File::open(&self.path)?);
+        let mut reader = PcapReader::new(file)?;
+        let mut placed = 0;
+        let mut first_timestamp = None;
+        let start = Instant::now();
+
+        while let Some(packet) = reader.next_packet()? {
+            if self.realtime {
+                if let Some(timestamp) = packet.timestamp {
+                    let first_timestamp = *first_timestamp.get_or_insert(timestamp);
+                    let target = timestamp.saturating_sub(first_timestamp);
+                    let elapsed = start.elapsed();
+                    if target > elapsed {
+                        std::thread::sleep(target - elapsed);
+                    }
+                }
+            }
+
+            let Some(ip) = link_payload(packet.linktype, &packet.data) else {
+                continue;
+            };
+            let Some((route, req)) = self.router.routes().iter().find_map(|route| {
+                let req = parse_pixel(ip, route.prefix48, route.deep_color, &self.filter)?;
+                let (_, height) = route.queue.dimensions();
+                Some((route, req.oriented(route.coordinate_mode, height)))
+            }) else {
+                continue;
+            };
+            if self.verify_checksums && !checksum_valid(ip) {
+                self.packet_counter.increment_bad_checksum();
+                continue;
+            }
+            // parse_pixel already checked the header length.
+            let source: [u8; 16] = ip[8..24].try_into().unwrap();
+
+            // Replays should be complete, so wait for the writer instead of dropping pixels.
+            route.queue.push_blocking(IpAddr::V6(source.into()), req);
+            self.packet_counter.increment();
+            placed += 1;
+        }
+
+        Ok(placed)
+    }
+}
+
+impl NetworkBackend for PcapNetworkBackend {
+    fn start(self: Box<Self>) -> JoinHandle<PResult<()>> {
+        tokio::task::spawn_blocking(move || loop {
+            let start = Instant::now();
+            let placed = self.replay()?;
+            log::info!(
+                "Replayed {} pixels from {} in {:?}",
+                placed,
+                self.path.display(),
+                start.elapsed()
+            );
+
+            if !self.looped {
+                return Ok(());
+            }
+        })
+    }
+}
+
+/// Strips the link layer header, returns the IPv6 packet if there's one.
+fn link_payload(linktype: u32, data: &[u8]) -> Option<&[u8]> {
+    let (protocol, payload) = match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV6 => return Some(data),
+        LINKTYPE_ETHERNET => (data.get(12..14)?, data.get(14..)?),
+        LINKTYPE_LINUX_SLL => (data.get(14..16)?, data.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => (data.get(0..2)?, data.get(20..)?),
+        _ => return None,
+    };
+
+    // EtherType of IPv6
+    if protocol == [0x86, 0xdd] {
+        Some(payload)
+    } else {
+        None
+    }
+}
+
+/// Which packets place pixels, from the backend settings.
+struct PixelFilter {
+    enable_icmp: bool,
+    enable_udp: bool,
+    /// Bytes the payload of echo requests has to start with, if any.
+    icmp_payload: Option<Vec<u8>>,
+    /// Whether echo requests carrying a pixel record in their payload place that pixel.
+    payload_pixels: bool,
+}
+
+/// Parses an IPv6 packet into a PixelRequest if it's addressed to one of the pixel subnets and
+/// passes the filter.
+fn parse_pixel(
+    ip: &[u8],
+    prefix48: Ipv6Addr,
+    deep_color: bool,
+    filter: &PixelFilter,
+) -> Option<PixelRequest> {
+    if ip.len() < 40 || ip[0] >> 4 != 6 {
+        return None;
+    }
+
+    let next_header = ip[6];
+    let dst: [u8; 16] = ip[24..40].try_into().ok()?;
+    let dst = Ipv6Addr::from(dst);
+
+    let segments = dst.segments();
+    let prefix = prefix48.segments();
+    if segments[..3] != prefix[..3] || !matches!(segments[3] & 0xf000, 0x1000 | 0x2000) {
+        return None;
+    }
+
+    let payload = &ip[40..];
+    match next_header {
+        IP_PROTOCOL_ICMPV6 if filter.enable_icmp => {
+            let icmp_payload = filter.icmp_payload.as_deref();
+            if !icmp_payload_matches(payload, icmp_payload) {
+                return None;
+            }
+            if filter.payload_pixels {
+                let skip = icmp_payload.map_or(0, <[u8]>::len);
+                if let Some(req) = icmp_payload_pixel(payload, skip) {
+                    return Some(req);
+                }
+            }
+        }
+        IP_PROTOCOL_UDP if filter.enable_udp => {
+            let dst_port = u16::from_be_bytes(payload.get(2..4)?.try_into().ok()?);
+            if dst_port != 7 {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+
+    Some(PixelRequest::from_ipv6_with_depth(&dst, deep_color))
+}
+
+struct Packet {
+    /// None if the capture format doesn't store timestamps for this packet.
+    timestamp: Option<Duration>,
+    linktype: u32,
+    data: Vec<u8>,
+}
+
+struct PcapNgInterface {
+    linktype: u32,
+    /// Number of timestamp units per second.
+    units_per_sec: u64,
+}
+
+enum Format {
+    Pcap {
+        big_endian: bool,
+        nanos: bool,
+        linktype: u32,
+    },
+    PcapNg {
+        big_endian: bool,
+        interfaces: Vec<PcapNgInterface>,
+    },
+}
+
+/// Minimal reader for the classic pcap and pcapng capture formats.
+struct PcapReader<R: Read> {
+    reader: R,
+    format: Format,
+}
+
+impl<R: Read> PcapReader<R> {
+    fn new(mut reader: R) -> PResult<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        let format = match magic {
+            [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => Format::Pcap {
+                big_endian: false,
+                nanos: magic[0] == 0x4d,
+                linktype: 0,
+            },
+            [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => Format::Pcap {
+                big_endian: true,
+                nanos: magic[3] == 0x4d,
+                linktype: 0,
+            },
+            [0x0a, 0x0d, 0x0d, 0x0a] => Format::PcapNg {
+                big_endian: false,
+                interfaces: Vec::new(),
+            },
+            _ => return Err("Not a pcap or pcapng file.".into()),
+        };
+
+        let mut pcap = PcapReader { reader, format };
+        match &mut pcap.format {
+            Format::Pcap {
+                big_endian,
+                linktype,
+                ..
+            } => {
+                // version, thiszone, sigfigs, snaplen, network
+                let mut header = [0u8; 20];
+                pcap.reader.read_exact(&mut header)?;
+                *linktype = read_u32(&header[16..20], *big_endian);
+            }
+            Format::PcapNg { .. } => pcap.read_section_header()?,
+        }
+
+        Ok(pcap)
+    }
+
+    fn next_packet(&mut self) -> PResult<Option<Packet>> {
+        match self.format {
+            Format::Pcap {
+                big_endian, nanos, ..
+            } => self.next_pcap_packet(big_endian, nanos),
+            Format::PcapNg { .. } => self.next_pcapng_packet(),
+        }
+    }
+
+    fn next_pcap_packet(&mut self, big_endian: bool, nanos: bool) -> PResult<Option<Packet>> {
+        let mut header = [0u8; 16];
+        if !read_exact_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+
+        let secs = read_u32(&header[0..4], big_endian) as u64;
+        let frac = read_u32(&header[4..8], big_endian);
+        let len = read_u32(&header[8..12], big_endian) as usize;
+        if len > MAX_RECORD_SIZE {
+            return Err(format!("Invalid pcap packet length {}.", len).into());
+        }
+
+        let mut data = vec![0u8; len];
+        self.reader.read_exact(&mut data)?;
+
+        let frac = if nanos {
+            Duration::from_nanos(frac as u64)
+        } else {
+            Duration::from_micros(frac as u64)
+        };
+        let linktype = match self.format {
+            Format::Pcap { linktype, .. } => linktype,
+            Format::PcapNg { .. } => unreachable!(),
+        };
+
+        Ok(Some(Packet {
+            timestamp: Some(Duration::from_secs(secs) + frac),
+            linktype,
+            data,
+        }))
+    }
+
+    /// Reads the rest of a Section Header Block, after its block type.
+    fn read_section_header(&mut self) -> PResult<()> {
+        let mut header = [0u8; 8];
+        self.reader.read_exact(&mut header)?;
+
+        let big_endian = match header[4..8] {
+            [0x1a, 0x2b, 0x3c, 0x4d] => true,
+            [0x4d, 0x3c, 0x2b, 0x1a] => false,
+            _ => return Err("Invalid pcapng byte order magic.".into()),
+        };
+        let len = read_u32(&header[0..4], big_endian) as usize;
+        if !(12..=MAX_RECORD_SIZE).contains(&len) {
+            return Err("Invalid pcapng section header length.".into());
+        }
+
+        let mut rest = vec![0u8; len - 12];
+        self.reader.read_exact(&mut rest)?;
+
+        // Interface IDs are scoped to a section.
+        self.format = Format::PcapNg {
+            big_endian,
+            interfaces: Vec::new(),
+        };
+        Ok(())
+    }
+
+    fn next_pcapng_packet(&mut self) -> PResult<Option<Packet>> {
+        loop {
+            let mut block_type = [0u8; 4];
+            if !read_exact_or_eof(&mut self.reader, &mut block_type)? {
+                return Ok(None);
+            }
+
+            if block_type == [0x0a, 0x0d, 0x0d, 0x0a] {
+                self.read_section_header()?;
+                continue;
+            }
+
+            let (big_endian, interfaces) = match &mut self.format {
+                Format::PcapNg {
+                    big_endian,
+                    interfaces,
+                } => (*big_endian, interfaces),
+                Format::Pcap { .. } => unreachable!(),
+            };
+
+            let mut len = [0u8; 4];
+            self.reader.read_exact(&mut len)?;
+            let len = read_u32(&len, big_endian) as usize;
+            if !(12..=MAX_RECORD_SIZE).contains(&len) {
+                return Err("Invalid pcapng block length.".into());
+            }
+
+            // Block body and the trailing copy of block length.
+            let mut body = vec![0u8; len - 8];
+            self.reader.read_exact(&mut body)?;
+            let body = &body[..len - 12];
+
+            match read_u32(&block_type, big_endian) {
+                // Interface Description Block
+                1 if body.len() >= 8 => {
+                    let linktype = read_u16(&body[0..2], big_endian) as u32;
+                    let units_per_sec = pcapng_ts_resolution(&body[8..], big_endian);
+                    interfaces.push(PcapNgInterface {
+                        linktype,
+                        units_per_sec,
+                    });
+                }
+                // Enhanced Packet Block
+                6 if body.len() >= 20 => {
+                    let interface = read_u32(&body[0..4], big_endian) as usize;
+                    let ts = ((read_u32(&body[4..8], big_endian) as u64) << 32)
+                        | read_u32(&body[8..12], big_endian) as u64;
+                    let captured_len = read_u32(&body[12..16], big_endian) as usize;
+                    let data = body
+                        .get(20..20 + captured_len)
+                        .ok_or("Invalid pcapng packet length.")?;
+
+                    let interface = interfaces
+                        .get(interface)
+                        .ok_or("Packet refers to an unknown pcapng interface.")?;
+
+                    let units_per_sec = interface.units_per_sec;
+                    let nanos =
+                        (ts % units_per_sec) as u128 * 1_000_000_000 / units_per_sec as u128;
+                    return Ok(Some(Packet {
+                        timestamp: Some(Duration::new(ts / units_per_sec, nanos as u32)),
+                        linktype: interface.linktype,
+                        data: data.to_vec(),
+                    }));
+                }
+                // Simple Packet Block
+                3 if body.len() >= 4 => {
+                    let original_len = read_u32(&body[0..4], big_endian) as usize;
+                    let data = &body[4..(4 + original_len).min(body.len())];
+                    let interface = interfaces
+                        .first()
+                        .ok_or("Packet refers to an unknown pcapng interface.")?;
+
+                    return Ok(Some(Packet {
+                        timestamp: None,
+                        linktype: interface.linktype,
+                        data: data.to_vec(),
+                    }));
+                }
+                // Other blocks aren't relevant for us.
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Reads the if_tsresol option from Interface Description Block options and returns the number
+/// of timestamp units per second, defaults to microseconds.
+fn pcapng_ts_resolution(mut options: &[u8], big_endian: bool) -> u64 {
+    while options.len() >= 4 {
+        let code = read_u16(&options[0..2], big_endian);
+        let len = read_u16(&options[2..4], big_endian) as usize;
+        let value = match options.get(4..4 + len) {
+            Some(value) => value,
+            None => break,
+        };
+
+        // if_tsresol
+        if code == 9 && len == 1 {
+            let resolution = value[0];
+            let units_per_sec = if resolution & 0x80 == 0 {
+                10u64.checked_pow(resolution as u32)
+            } else {
+                1u64.checked_shl((resolution & 0x7f) as u32)
+            };
+
+            // Fall back to the default on nonsensical values.
+            return units_per_sec.unwrap_or(1_000_000);
+        }
+
+        // Options are padded to 32 bits.
+        let padded = 4 + ((len + 3) & !3);
+        options = options.get(padded..).unwrap_or_default();
+    }
+
+    1_000_000
+}
+
+fn read_u16(bytes: &[u8], big_endian: bool) -> u16 {
+    let bytes = [bytes[0], bytes[1]];
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// Like read_exact, but returns false instead of an error on a clean end of file.
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> PResult<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::utils::Color;
+
+    fn icmp_packet(dst: Ipv6Addr) -> Vec<u8> {
+        let mut packet = vec![0u8; 48];
+        packet[0] = 0x60;
+        packet[5] = 8; // payload length
+        packet[6] = IP_PROTOCOL_ICMPV6;
+        packet[24..40].copy_from_slice(&dst.octets());
+        packet[40] = 128; // echo request
+        packet
+    }
+
+    fn filter() -> PixelFilter {
+        PixelFilter {
+            enable_icmp: true,
+            enable_udp: true,
+            icmp_payload: None,
+            payload_pixels: false,
+        }
+    }
+
+    #[test]
+    fn replay_pcap_and_pcapng() {
+        let prefix48: Ipv6Addr = "2602:fa9b:42::".parse().unwrap();
+        let dst: Ipv6Addr = "2602:fa9b:42:1010:20:ff:80:0".parse().unwrap();
+        let packet = icmp_packet(dst);
+
+        let mut pcap = Vec::new();
+        pcap.extend_from_slice(&[0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0]);
+        pcap.extend_from_slice(&[0; 8]);
+        pcap.extend_from_slice(&65535u32.to_le_bytes());
+        pcap.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        for value in [5, 250_000, packet.len() as u32, packet.len() as u32] {
+            pcap.extend_from_slice(&value.to_le_bytes());
+        }
+        let header = pcap.clone();
+        pcap.extend_from_slice(&packet);
+
+        let mut pcapng = Vec::new();
+        let mut block = |block_type: u32, body: &[u8]| {
+            let len = (body.len() + 12) as u32;
+            pcapng.extend_from_slice(&block_type.to_le_bytes());
+            pcapng.extend_from_slice(&len.to_le_bytes());
+            pcapng.extend_from_slice(body);
+            pcapng.extend_from_slice(&len.to_le_bytes());
+        };
+        block(
+            0x0a0d0d0a,
+            &[
+                0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ],
+        );
+        // Interface Description Block with nanosecond resolution.
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&(LINKTYPE_RAW as u16).to_le_bytes());
+        idb.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        idb.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+        block(1, &idb);
+        // Enhanced Packet Block
+        let ts = 5_250_000_000u64;
+        let mut epb = Vec::new();
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&packet);
+        block(6, &epb);
+
+        for capture in [pcap, pcapng] {
+            let mut reader = PcapReader::new(Cursor::new(capture)).unwrap();
+            let packet = reader.next_packet().unwrap().unwrap();
+            assert_eq!(packet.timestamp, Some(Duration::from_millis(5250)));
+            assert_eq!(packet.linktype, LINKTYPE_RAW);
+
+            let ip = link_payload(packet.linktype, &packet.data).unwrap();
+            let req = parse_pixel(ip, prefix48, false, &filter()).unwrap();
+            assert_eq!(req.pos, (0x10, 0x20));
+            assert_eq!(req.color, Color::rgb(0xff, 0x80, 0));
+            assert_eq!(req.size, 1);
+
+            assert!(reader.next_packet().unwrap().is_none());
+        }
+
+        // Corrupt lengths are rejected instead of allocated.
+        let mut corrupt = header[..24].to_vec();
+        for value in [5, 0, u32::MAX, u32::MAX] {
+            corrupt.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut reader = PcapReader::new(Cursor::new(corrupt)).unwrap();
+        assert!(reader.next_packet().is_err());
+
+        // Packets outside of the pixel subnets are ignored.
+        let other = icmp_packet("2602:fa9b:43:1010:20:ff:80:0".parse().unwrap());
+        assert!(parse_pixel(&other, prefix48, false, &filter()).is_none());
+
+        // As well as disabled protocols.
+        let icmp_disabled = PixelFilter {
+            enable_icmp: false,
+            ..filter()
+        };
+        assert!(parse_pixel(&icmp_packet(dst), prefix48, false, &icmp_disabled).is_none());
+        let unexpected_payload = PixelFilter {
+            icmp_payload: Some(vec![1]),
+            ..filter()
+        };
+        assert!(parse_pixel(&icmp_packet(dst), prefix48, false, &unexpected_payload).is_none());
+        let empty_payload = PixelFilter {
+            icmp_payload: Some(Vec::new()),
+            ..filter()
+        };
+        assert!(parse_pixel(&icmp_packet(dst), prefix48, false, &empty_payload).is_some());
+    }
+
+    #[test]
+    fn payload_pixels() {
+        let prefix48: Ipv6Addr = "2602:fa9b:42::".parse().unwrap();
+        let dst: Ipv6Addr = "2602:fa9b:42:1010:20:ff:80:0".parse().unwrap();
+        let mut packet = icmp_packet(dst);
+        packet.extend_from_slice(b"ab");
+        packet.extend_from_slice(&PixelRequest::PAYLOAD_MAGIC);
+        packet.extend_from_slice(&[0x12, 0x34, 0x05, 0x67, 1, 2, 3, 4, 2]);
+        packet[5] = (packet.len() - 40) as u8;
+
+        let filter = PixelFilter {
+            icmp_payload: Some(b"ab".to_vec()),
+            payload_pixels: true,
+            ..filter()
+        };
+        let req = parse_pixel(&packet, prefix48, false, &filter).unwrap();
+        assert_eq!(req.pos, (0x1234, 0x0567));
+        assert_eq!(req.color, Color::new(1, 2, 3, 4));
+        assert_eq!(req.size, 2);
+
+        // Echo requests without a record place the pixel in the address.
+        let mut plain = icmp_packet(dst);
+        plain.extend_from_slice(b"ab");
+        plain[5] = (plain.len() - 40) as u8;
+        let req = parse_pixel(&plain, prefix48, false, &filter).unwrap();
+        assert_eq!(req.pos, (0x10, 0x20));
+
+        // As do all of them unless enabled.
+        let disabled = PixelFilter {
+            payload_pixels: false,
+            ..filter
+        };
+        let req = parse_pixel(&packet, prefix48, false, &disabled).unwrap();
+        assert_eq!(req.pos, (0x10, 0x20));
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::PResult;
+
+use super::PixelRequest;
+
+/// Time between two resets of the counters.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Placement rates of the cells of a `RateGrid` over the last tick.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rates {
+    pub columns: u32,
+    pub rows: u32,
+    /// Size of the canvas as [width, height]. Cell boundaries are at multiples of its size divided
+    /// by the number of columns and rows, rounded up.
+    pub canvas: [u32; 2],
+    /// Length of the tick the rates were measured over.
+    pub interval_ms: u64,
+    /// Pixels placed per second in every cell, row by row.
+    pub rates: Vec<Vec<f32>>,
+}
+
+/// Counts placed pixels in a coarse grid over the canvas, for spotting hotspots like bots or
+/// popular areas much cheaper than a per-pixel heatmap.
+///
+/// The writer increments the counter of a pixel's cell, and a ticker turns the counters into
+/// rates and resets them once per second.
+pub struct RateGrid {
+    width: u32,
+    height: u32,
+    columns: u32,
+    rows: u32,
+    counts: Vec<AtomicU32>,
+    /// Rates of the last tick and when it happened.
+    last: Mutex<(Instant, Rates)>,
+}
+
+impl RateGrid {
+    /// Creates a grid of `cells` x `cells`, or fewer along sides shorter than that many pixels.
+    pub fn new(width: u32, height: u32, cells: u32) -> RateGrid {
+        let columns = cells.min(width).max(1);
+        let rows = cells.min(height).max(1);
+        RateGrid {
+            width,
+            height,
+            columns,
+            rows,
+            counts: (0..columns * rows).map(|_| AtomicU32::new(0)).collect(),
+            last: Mutex::new((
+                Instant::now(),
+                Rates {
+                    columns,
+                    rows,
+                    canvas: [width, height],
+                    interval_ms: TICK.as_millis() as u64,
+                    rates: vec![vec![0.0; columns as usize]; rows as usize],
+                },
+            )),
+        }
+    }
+
+    /// Counts the request towards the cell of its position.
+    #[inline]
+    pub fn record(&self, req: &PixelRequest) {
+        let x = (req.pos.0 as u32).min(self.width - 1);
+        let y = (req.pos.1 as u32).min(self.height - 1);
+        let column = (x as u64 * self.columns as u64 / self.width as u64) as u32;
+        let row = (y as u64 * self.rows as u64 / self.height as u64) as u32;
+        self.counts[(row * self.columns + column) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Turns the counts since the previous tick into rates and resets them.
+    fn tick(&self) {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let (at, rates) = &mut *last;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*at);
+        let secs = elapsed.as_secs_f32().max(f32::EPSILON);
+        for (row, rates) in rates.rates.iter_mut().enumerate() {
+            let counts = &self.counts[row * self.columns as usize..][..self.columns as usize];
+            for (rate, count) in rates.iter_mut().zip(counts) {
+                *rate = count.swap(0, Ordering::Relaxed) as f32 / secs;
+            }
+        }
+        rates.interval_ms = elapsed.as_millis() as u64;
+        *at = now;
+    }
+
+    /// Returns the rates measured at the last tick.
+    pub fn rates(&self) -> Rates {
+        let last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        last.1.clone()
+    }
+
+    async fn ticker_task(self: Arc<Self>) -> PResult<()> {
+        let mut interval = tokio::time::interval(TICK);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.tick();
+        }
+    }
+
+    pub fn start_ticker(self: Arc<Self>) -> JoinHandle<PResult<()>> {
+        tokio::spawn(self.ticker_task())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::Color;
+
+    #[test]
+    fn rate_grid() {
+        let pixel = |x, y| PixelRequest {
+            pos: (x, y),
+            color: Color::rgb(0, 0, 0),
+            size: 1,
+            color_low: None,
+        };
+
+        // 10 pixels wide in 3 columns of 4, 3 and 3 pixels, 2 pixels high in 2 rows.
+        let grid = RateGrid::new(10, 2, 3);
+        for req in [
+            pixel(0, 0),
+            pixel(2, 0),
+            pixel(3, 1),
+            pixel(9, 1),
+            pixel(100, 100),
+        ] {
+            grid.record(&req);
+        }
+        grid.tick();
+
+        let rates = grid.rates();
+        assert_eq!((rates.columns, rates.rows), (3, 2));
+        let counted: Vec<Vec<bool>> = rates
+            .rates
+            .iter()
+            .map(|row| row.iter().map(|&rate| rate > 0.0).collect())
+            .collect();
+        assert_eq!(counted, [[true, false, false], [true, false, true]]);
+        // Out-of-bounds pixels land in the nearest cell, along with the one at (9, 1).
+        assert_eq!(rates.rates[1][2], 2.0 * rates.rates[1][0]);
+
+        // The counters start over after a tick.
+        grid.tick();
+        assert!(grid.rates().rates.iter().flatten().all(|&rate| rate == 0.0));
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::settings::SmoltcpSettings;
+
+use super::writer::source_key;
+
+/// Length of the windows replies are counted in.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Most sources whose replies are counted per window, replies to further ones are suppressed.
+const MAX_TRACKED_SOURCES: usize = 65536;
+
+/// Decides which echo replies get sent, so a flood of pings doesn't turn into an equal flood of
+/// replies saturating the uplink.
+///
+/// Echo requests are first sampled, then replies are counted against the global and per-source
+/// limits in fixed windows of a second.
+pub struct ReplyLimiter {
+    enabled: bool,
+    sample_rate: u32,
+    limit: u32,
+    per_source_limit: u32,
+    /// Echo requests to skip before replying to the next one.
+    until_sample: u32,
+    window_start: Option<Instant>,
+    sent: u32,
+    sent_per_source: HashMap<IpAddr, u32>,
+}
+
+impl ReplyLimiter {
+    pub fn new(settings: &SmoltcpSettings) -> ReplyLimiter {
+        ReplyLimiter {
+            enabled: settings.echo_replies,
+            sample_rate: settings.reply_sample_rate.max(1),
+            limit: settings.reply_rate_limit,
+            per_source_limit: settings.reply_rate_limit_per_source,
+            until_sample: 0,
+            window_start: None,
+            sent: 0,
+            sent_per_source: HashMap::new(),
+        }
+    }
+
+    /// Whether every reply is sent, in which case outgoing packets don't need to be inspected.
+    pub fn is_unlimited(&self) -> bool {
+        self.enabled && self.sample_rate == 1 && self.limit == 0 && self.per_source_limit == 0
+    }
+
+    /// Returns whether to send an echo reply to `destination`, counting it if so.
+    pub fn allow(&mut self, destination: IpAddr, now: Instant) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        if self.until_sample > 0 {
+            self.until_sample -= 1;
+            return false;
+        }
+        self.until_sample = self.sample_rate - 1;
+
+        let window_over = match self.window_start {
+            Some(start) => now.duration_since(start) >= WINDOW,
+            None => true,
+        };
+        if window_over {
+            self.window_start = Some(now);
+            self.sent = 0;
+            self.sent_per_source.clear();
+        }
+
+        if self.limit != 0 && self.sent >= self.limit {
+            return false;
+        }
+        if self.per_source_limit != 0 {
+            let key = source_key(destination);
+            if !self.sent_per_source.contains_key(&key)
+                && self.sent_per_source.len() >= MAX_TRACKED_SOURCES
+            {
+                return false;
+            }
+            let sent = self.sent_per_source.entry(key).or_insert(0);
+            if *sent >= self.per_source_limit {
+                return false;
+            }
+            *sent += 1;
+        }
+        self.sent += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn limiter(sample_rate: u32, limit: u32, per_source_limit: u32) -> ReplyLimiter {
+        ReplyLimiter {
+            enabled: true,
+            sample_rate,
+            limit,
+            per_source_limit,
+            until_sample: 0,
+            window_start: None,
+            sent: 0,
+            sent_per_source: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn reply_limits() {
+        let now = Instant::now();
+        let a = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 1));
+        // Same /64 as `a`.
+        let a2 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 2));
+        let b = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 2, 0, 0, 0, 1));
+
+        let mut sampled = limiter(3, 0, 0);
+        assert!(!sampled.is_unlimited());
+        let allowed: Vec<bool> = (0..6).map(|_| sampled.allow(a, now)).collect();
+        assert_eq!(allowed, [true, false, false, true, false, false]);
+
+        let mut global = limiter(1, 2, 0);
+        assert!(global.allow(a, now));
+        assert!(global.allow(b, now));
+        assert!(!global.allow(b, now));
+        // The counts start over in the next window.
+        assert!(global.allow(b, now + WINDOW));
+
+        let mut per_source = limiter(1, 0, 1);
+        assert!(per_source.allow(a, now));
+        assert!(!per_source.allow(a2, now));
+        assert!(per_source.allow(b, now));
+
+        let mut disabled = limiter(1, 0, 0);
+        assert!(disabled.is_unlimited());
+        disabled.enabled = false;
+        assert!(!disabled.allow(a, now));
+    }
+}
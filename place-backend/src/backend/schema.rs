@@ -0,0 +1,149 @@
+use serde::Serialize;
+
+use crate::settings::{CoordinateMode, Settings};
+
+use super::{iface, COLOR_MASK, COORD_MASK, SIZE_MASK, SUBNET_PREFIX_LEN};
+
+/// Machine-readable description of how pixels are encoded into addresses, so clients can
+/// configure their encoders instead of hardcoding the prefix.
+#[derive(Debug, Clone, Serialize)]
+pub struct EncodingSchema {
+    pub canvas_width: u32,
+    pub canvas_height: u32,
+    pub coordinate_mode: CoordinateMode,
+    pub prefixes: Vec<PrefixSchema>,
+    /// Fields of the lower 80 bits of pixel addresses.
+    pub fields: Vec<FieldSchema>,
+    /// Hex encoded bytes ICMPv6 echo request payloads have to start with, if required.
+    pub icmp_payload: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrefixSchema {
+    /// Subnet in CIDR notation, eg. "2602:fa9b:42:1000::/52".
+    pub prefix: String,
+    /// Size of pixels placed on this subnet.
+    pub size: u8,
+    /// Packets accepted on this subnet, "icmpv6" for pings and "udp/7" for UDP packets to port 7.
+    pub protocols: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    /// Index of the 16-bit address segment holding the field, 0 being the first one.
+    pub segment: u8,
+    /// Bits of the segment holding the field.
+    pub mask: u16,
+    pub description: &'static str,
+}
+
+impl EncodingSchema {
+    pub fn new(settings: &Settings) -> EncodingSchema {
+        let size = settings.canvas.size.get() as u32;
+
+        let mut protocols = Vec::new();
+        if settings.backend.enable_icmp {
+            protocols.push("icmpv6");
+        }
+        if settings.backend.enable_udp {
+            protocols.push("udp/7");
+        }
+
+        let prefixes = iface::pixel_subnets(settings.backend.prefix48)
+            .into_iter()
+            .zip(1..)
+            .map(|(subnet, size)| PrefixSchema {
+                prefix: format!("{}/{}", subnet, SUBNET_PREFIX_LEN),
+                size,
+                protocols: protocols.clone(),
+            })
+            .collect();
+
+        let field = |name, segment, mask, description| FieldSchema {
+            name,
+            segment,
+            mask,
+            description,
+        };
+        // Canvases with 16 bits per channel take whole segments.
+        let color_mask = if settings.canvas.deep_color() {
+            u16::MAX
+        } else {
+            COLOR_MASK
+        };
+        let fields = vec![
+            field(
+                "size",
+                3,
+                SIZE_MASK,
+                "Pixel size, selected by the prefix: 1 for single pixels, 2 for 2x2 blocks.",
+            ),
+            field("x", 3, COORD_MASK, "X coordinate."),
+            field("y", 4, COORD_MASK, "Y coordinate."),
+            field("r", 5, color_mask, "Red channel."),
+            field("g", 6, color_mask, "Green channel."),
+            field("b", 7, color_mask, "Blue channel."),
+        ];
+
+        EncodingSchema {
+            canvas_width: size,
+            canvas_height: size,
+            coordinate_mode: settings.canvas.coordinate_mode,
+            prefixes,
+            fields,
+            icmp_payload: settings
+                .backend
+                .icmp_payload
+                .as_ref()
+                .filter(|_| settings.backend.enable_icmp)
+                .map(|payload| payload.iter().map(|b| format!("{:02x}", b)).collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use config::{Config, File, FileFormat};
+
+    use super::*;
+    use crate::{backend::PixelRequest, utils::Color};
+
+    #[test]
+    fn encode_with_schema() {
+        let settings: Settings = Config::builder()
+            .add_source(File::from_str(
+                include_str!("../../config.toml.example"),
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let schema = EncodingSchema::new(&settings);
+        assert_eq!(schema.prefixes[1].prefix, "2602:fa9b:42:2000::/52");
+        assert_eq!(schema.prefixes[1].protocols, ["icmpv6", "udp/7"]);
+
+        // Encode a pixel the way a client would, using only the schema.
+        let prefix = &schema.prefixes[1];
+        let mut segments = prefix
+            .prefix
+            .split('/')
+            .next()
+            .unwrap()
+            .parse::<Ipv6Addr>()
+            .unwrap()
+            .segments();
+        for (field, value) in schema.fields.iter().zip([0, 0x123, 0x45, 0xff, 0x80, 0x01]) {
+            let shift = field.mask.trailing_zeros();
+            segments[field.segment as usize] |= (value << shift) & field.mask;
+        }
+
+        let req = PixelRequest::from_ipv6(&Ipv6Addr::from(segments));
+        assert_eq!(req.pos, (0x123, 0x45));
+        assert_eq!(req.color, Color::rgb(0xff, 0x80, 0x01));
+        assert_eq!(req.size, prefix.size);
+    }
+}
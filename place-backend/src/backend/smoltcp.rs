@@ -1,23 +1,173 @@
-use super::{NetworkBackend, PacketCounter};
-use crate::{backend::PixelRequest, place::SharedImageHandle, settings::Settings, PResult};
+use super::{
+    checksum_valid, icmp_payload_matches, icmp_payload_pixel, iface, reply_limit::ReplyLimiter,
+    CanvasRouter, NetworkBackend, PacketCounter,
+};
+use crate::{settings::Settings, PResult};
 use smoltcp::{
     iface::{Config, Interface, SocketSet},
-    phy::{self, ChecksumCapabilities, Medium, TunTapInterface},
+    phy::{self, ChecksumCapabilities, DeviceCapabilities, Medium, TunTapInterface},
     socket::raw,
+    time::{Duration, Instant},
     wire::{
-        Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr, IpProtocol, IpVersion, Ipv6Address,
-        Ipv6Packet, Ipv6Repr, UdpPacket, UdpRepr,
+        Icmpv6Message, Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr, IpProtocol, IpVersion,
+        Ipv6Address, Ipv6Packet, Ipv6Repr, UdpPacket, UdpRepr,
     },
 };
-use std::{os::fd::AsRawFd, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    os::fd::AsRawFd,
+    sync::Arc,
+};
 use tokio::task::JoinHandle;
 
 pub struct SmoltcpNetworkBackend {
-    image: SharedImageHandle,
-    device: TunTapInterface,
+    router: CanvasRouter,
+    device: ReplyLimitedDevice<TunTapInterface>,
     interface: Interface,
     packet_counter: Arc<PacketCounter>,
-    recv_buffer_size: usize,
+    /// Receive buffer size in packets and bytes, per socket.
+    recv_buffer: (usize, usize),
+    min_poll_delay: Duration,
+    max_poll_delay: Option<Duration>,
+    enable_icmp: bool,
+    enable_udp: bool,
+    icmp_payload: Option<Vec<u8>>,
+    payload_pixels: bool,
+    verify_checksums: bool,
+    dedicated_thread: bool,
+    cpu_affinity: Option<usize>,
+}
+
+/// Clamps the delay requested by smoltcp to the configured bounds, `None` waiting indefinitely.
+fn bounded_poll_delay(
+    delay: Option<Duration>,
+    min: Duration,
+    max: Option<Duration>,
+) -> Option<Duration> {
+    let delay = match (delay, max) {
+        (Some(delay), Some(max)) => Some(delay.min(max)),
+        (delay, max) => delay.or(max),
+    };
+    delay.map(|delay| delay.max(min))
+}
+
+/// Runs `f` on a new OS thread, optionally pinned to a CPU core, instead of tokio's blocking pool.
+fn spawn_dedicated<F>(name: &str, cpu_affinity: Option<usize>, f: F) -> JoinHandle<PResult<()>>
+where
+    F: FnOnce() -> PResult<()> + Send + 'static,
+{
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    let spawned = std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            if let Some(id) = cpu_affinity {
+                if core_affinity::set_for_current(core_affinity::CoreId { id }) {
+                    log::info!("Pinned the packet loop to CPU core {}.", id);
+                } else {
+                    log::warn!("Failed to pin the packet loop to CPU core {}.", id);
+                }
+            }
+            let _ = sender.send(f());
+        });
+
+    tokio::spawn(async move {
+        spawned?;
+        receiver
+            .await
+            .unwrap_or_else(|_| Err("Packet loop thread panicked".into()))
+    })
+}
+
+/// Wraps the tun device to hold back echo replies as decided by the `ReplyLimiter`, since smoltcp
+/// answers every echo request on its own.
+struct ReplyLimitedDevice<D> {
+    inner: D,
+    limiter: ReplyLimiter,
+    packet_counter: Arc<PacketCounter>,
+    /// Outgoing packets are assembled here first, a consumed token can't be taken back.
+    scratch: Vec<u8>,
+}
+
+struct ReplyLimitedTxToken<'a, T> {
+    inner: T,
+    limiter: &'a mut ReplyLimiter,
+    packet_counter: &'a PacketCounter,
+    scratch: &'a mut Vec<u8>,
+}
+
+impl<D: phy::Device> phy::Device for ReplyLimitedDevice<D> {
+    type RxToken<'a>
+        = D::RxToken<'a>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = ReplyLimitedTxToken<'a, D::TxToken<'a>>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let (rx, tx) = self.inner.receive(timestamp)?;
+        let tx = ReplyLimitedTxToken {
+            inner: tx,
+            limiter: &mut self.limiter,
+            packet_counter: &self.packet_counter,
+            scratch: &mut self.scratch,
+        };
+        Some((rx, tx))
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(ReplyLimitedTxToken {
+            inner: self.inner.transmit(timestamp)?,
+            limiter: &mut self.limiter,
+            packet_counter: &self.packet_counter,
+            scratch: &mut self.scratch,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+impl<T: phy::TxToken> phy::TxToken for ReplyLimitedTxToken<'_, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let ReplyLimitedTxToken {
+            inner,
+            limiter,
+            packet_counter,
+            scratch,
+        } = self;
+        if limiter.is_unlimited() {
+            return inner.consume(len, f);
+        }
+
+        scratch.clear();
+        scratch.resize(len, 0);
+        let result = f(scratch);
+        let suppressed = echo_reply_destination(scratch).is_some_and(|destination| {
+            !limiter.allow(IpAddr::V6(destination), std::time::Instant::now())
+        });
+        if suppressed {
+            packet_counter.increment_suppressed_reply();
+        } else {
+            inner.consume(len, |buffer| buffer.copy_from_slice(scratch));
+        }
+        result
+    }
+}
+
+/// Returns the destination of an IPv6 packet if it's an ICMPv6 echo reply.
+fn echo_reply_destination(packet: &[u8]) -> Option<Ipv6Addr> {
+    let packet = Ipv6Packet::new_checked(packet).ok()?;
+    if packet.next_header() != IpProtocol::Icmpv6 {
+        return None;
+    }
+    let icmp = Icmpv6Packet::new_checked(packet.payload()).ok()?;
+    (icmp.msg_type() == Icmpv6Message::EchoReply).then(|| packet.dst_addr().into())
 }
 
 fn or_addr(addr: Ipv6Address, mask: Ipv6Address) -> Ipv6Address {
@@ -34,33 +184,78 @@ fn or_addr(addr: Ipv6Address, mask: Ipv6Address) -> Ipv6Address {
 impl SmoltcpNetworkBackend {
     pub fn new(
         settings: &Settings,
-        image: SharedImageHandle,
+        router: CanvasRouter,
         packet_counter: Arc<PacketCounter>,
     ) -> PResult<Box<dyn NetworkBackend>> {
         let mut config = Config::new(smoltcp::wire::HardwareAddress::Ip);
         config.random_seed = rand::random();
         // config.hardware_addr = Some(EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]).into());
 
-        let mut device = TunTapInterface::new(&settings.backend.smoltcp.tun_iface, Medium::Ip)?;
+        let tun_iface = &settings.backend.smoltcp.tun_iface;
+        let mut device = ReplyLimitedDevice {
+            inner: TunTapInterface::new(tun_iface, Medium::Ip)
+                .map_err(|err| iface::open_error(tun_iface, err))?,
+            limiter: ReplyLimiter::new(&settings.backend.smoltcp),
+            packet_counter: packet_counter.clone(),
+            scratch: Vec::new(),
+        };
+
+        for route in router.routes() {
+            if settings.backend.smoltcp.configure_interface {
+                iface::configure(tun_iface, route.prefix48)?;
+            } else {
+                iface::check_routes(tun_iface, route.prefix48);
+            }
+        }
 
         let prefix: Ipv6Address = settings.backend.prefix48.into();
 
         let prefix_s1 = or_addr(prefix, Ipv6Address::new(0, 0, 0, 0x1000, 0, 0, 0, 0));
         let prefix_s2 = or_addr(prefix, Ipv6Address::new(0, 0, 0, 0x2000, 0, 0, 0, 0));
 
+        // smoltcp uses the first matching interface address as the source of outgoing packets,
+        // so the reply source address stands in for its subnet and is registered first.
+        let reply_source: Ipv6Address = settings.backend.reply_source_addr().into();
+        let reply_subnet = IpCidr::new(IpAddress::Ipv6(reply_source), 52);
+        let other_subnet = if reply_subnet.contains_addr(&IpAddress::Ipv6(prefix_s1)) {
+            prefix_s2
+        } else {
+            prefix_s1
+        };
+
         let mut interface = Interface::new(config, &mut device);
+        let mut added = true;
         interface.update_ip_addrs(|addrs| {
             // Actually we register two /52 prefixes, for 1 and 2 pixel sizes.
-            let _ = addrs.push(IpCidr::new(IpAddress::Ipv6(prefix_s1), 52));
-            let _ = addrs.push(IpCidr::new(IpAddress::Ipv6(prefix_s2), 52));
+            let _ = addrs.push(reply_subnet);
+            let _ = addrs.push(IpCidr::new(IpAddress::Ipv6(other_subnet), 52));
+            // Replies on additional canvases come from the canvas prefix itself.
+            for route in &router.routes()[1..] {
+                let prefix = IpCidr::new(IpAddress::Ipv6(route.prefix48.into()), 48);
+                added &= addrs.push(prefix).is_ok();
+            }
         });
+        if !added {
+            return Err("Too many canvases for the smoltcp interface addresses.".into());
+        }
 
         Ok(Box::new(Self {
-            image,
+            router,
             device,
             interface,
             packet_counter,
-            recv_buffer_size: settings.backend.smoltcp.recv_buffer_size,
+            recv_buffer: settings.backend.smoltcp.recv_buffer(),
+            min_poll_delay: Duration::from_millis(settings.backend.smoltcp.min_poll_delay_ms),
+            max_poll_delay: (settings.backend.smoltcp.max_poll_delay_ms > 0)
+                .then(|| Duration::from_millis(settings.backend.smoltcp.max_poll_delay_ms)),
+            enable_icmp: settings.backend.enable_icmp,
+            enable_udp: settings.backend.enable_udp,
+            icmp_payload: settings.backend.icmp_payload.clone(),
+            payload_pixels: settings.backend.payload_pixels,
+            verify_checksums: settings.backend.verify_checksums,
+            dedicated_thread: settings.backend.smoltcp.dedicated_thread
+                || settings.backend.smoltcp.cpu_affinity.is_some(),
+            cpu_affinity: settings.backend.smoltcp.cpu_affinity,
         }))
     }
 }
@@ -71,45 +266,68 @@ unsafe impl Sync for SmoltcpNetworkBackend {}
 
 impl NetworkBackend for SmoltcpNetworkBackend {
     fn start(mut self: Box<Self>) -> JoinHandle<PResult<()>> {
-        tokio::task::spawn_blocking(move || {
+        let (dedicated_thread, cpu_affinity) = (self.dedicated_thread, self.cpu_affinity);
+        let run = move || {
             let mut sockets = SocketSet::new(vec![]);
 
-            let icmp_rx_buffer = raw::PacketBuffer::new(
-                vec![raw::PacketMetadata::EMPTY; self.recv_buffer_size],
-                vec![0; self.recv_buffer_size * 512],
-            );
-            let icmp_tx_buffer =
-                raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY], vec![0; 256]);
-            let icmp_socket = raw::Socket::new(
-                IpVersion::Ipv6,
-                IpProtocol::Icmpv6,
-                icmp_rx_buffer,
-                icmp_tx_buffer,
+            let (packets, bytes) = self.recv_buffer;
+            let socket_count = self.enable_icmp as usize + self.enable_udp as usize;
+            let footprint =
+                socket_count * (bytes + packets * std::mem::size_of::<raw::PacketMetadata>());
+            log::info!(
+                "Receive buffers hold {} packets in {} bytes per socket, {:.1} MiB in total.",
+                packets,
+                bytes,
+                footprint as f64 / (1024.0 * 1024.0)
             );
 
-            let udp_rx_buffer = raw::PacketBuffer::new(
-                vec![raw::PacketMetadata::EMPTY; self.recv_buffer_size],
-                vec![0; self.recv_buffer_size * 512],
-            );
-            let udp_tx_buffer =
-                raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY], vec![0; 256]);
-            let udp_socket = raw::Socket::new(
-                IpVersion::Ipv6,
-                IpProtocol::Udp,
-                udp_rx_buffer,
-                udp_tx_buffer,
-            );
+            let icmp_handle = if self.enable_icmp {
+                let icmp_rx_buffer = raw::PacketBuffer::new(
+                    vec![raw::PacketMetadata::EMPTY; self.recv_buffer.0],
+                    vec![0; self.recv_buffer.1],
+                );
+                let icmp_tx_buffer =
+                    raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY], vec![0; 256]);
+                let icmp_socket = raw::Socket::new(
+                    IpVersion::Ipv6,
+                    IpProtocol::Icmpv6,
+                    icmp_rx_buffer,
+                    icmp_tx_buffer,
+                );
+                Some(sockets.add(icmp_socket))
+            } else {
+                None
+            };
 
-            let icmp_handle = sockets.add(icmp_socket);
-            let udp_handle = sockets.add(udp_socket);
-            let fd = self.device.as_raw_fd();
+            let udp_handle = if self.enable_udp {
+                let udp_rx_buffer = raw::PacketBuffer::new(
+                    vec![raw::PacketMetadata::EMPTY; self.recv_buffer.0],
+                    vec![0; self.recv_buffer.1],
+                );
+                let udp_tx_buffer =
+                    raw::PacketBuffer::new(vec![raw::PacketMetadata::EMPTY], vec![0; 256]);
+                let udp_socket = raw::Socket::new(
+                    IpVersion::Ipv6,
+                    IpProtocol::Udp,
+                    udp_rx_buffer,
+                    udp_tx_buffer,
+                );
+                Some(sockets.add(udp_socket))
+            } else {
+                None
+            };
+
+            let fd = self.device.inner.as_raw_fd();
+            // Checksums are verified by checksum_valid if enabled, which also covers ICMPv6
+            // messages that aren't parsed by smoltcp.
             let ignored_caps = ChecksumCapabilities::ignored();
+            let mut last_delay = None;
 
             loop {
                 let timestamp = smoltcp::time::Instant::now();
                 self.interface
                     .poll(timestamp, &mut self.device, &mut sockets);
-                {
+                if let Some(icmp_handle) = icmp_handle {
                     let icmp_socket = sockets.get_mut::<raw::Socket>(icmp_handle);
 
                     while icmp_socket.can_recv() {
@@ -128,6 +346,14 @@ impl NetworkBackend for SmoltcpNetworkBackend {
 
                         // log::trace!("Received packet {:?}", ipv6_parsed);
 
+                        if !icmp_payload_matches(packet.payload(), self.icmp_payload.as_deref()) {
+                            continue;
+                        }
+                        if self.verify_checksums && !checksum_valid(buffer) {
+                            self.packet_counter.increment_bad_checksum();
+                            continue;
+                        }
+
                         // let icmp_packet = match Icmpv6Packet::new_checked(packet.payload()) {
                         //     Ok(packet) => packet,
                         //     Err(_) => continue,
@@ -145,17 +371,29 @@ impl NetworkBackend for SmoltcpNetworkBackend {
 
                         // match icmp_parsed {
                         //     Icmpv6Repr::EchoRequest { .. } => {
-                                let req = PixelRequest::from_ipv6(&ipv6_parsed.dst_addr.into());
-                                let (x, y) = req.pos;
-                                self.image.put(x as _, y as _, req.color, req.size == 2);
-                                self.packet_counter.increment();
+                        let dst = ipv6_parsed.dst_addr.into();
+                        let route = self.router.route(&dst);
+                        let payload_pixel = if self.payload_pixels {
+                            let skip = self.icmp_payload.as_ref().map_or(0, Vec::len);
+                            icmp_payload_pixel(packet.payload(), skip)
+                        } else {
+                            None
+                        };
+                        let req = match payload_pixel {
+                            Some(req) => route.orient(req),
+                            None => route.decode(&dst),
+                        };
+                        route
+                            .queue
+                            .push(IpAddr::V6(ipv6_parsed.src_addr.into()), req);
+                        self.packet_counter.increment();
                         //     }
                         //     _ => {}
                         // }
                     }
                 }
 
-                {
+                if let Some(udp_handle) = udp_handle {
                     let udp_socket = sockets.get_mut::<raw::Socket>(udp_handle);
 
                     while udp_socket.can_recv() {
@@ -190,16 +428,86 @@ impl NetworkBackend for SmoltcpNetworkBackend {
                         };
 
                         if udp_parsed.dst_port == 7 {
-                            let req = PixelRequest::from_ipv6(&ipv6_parsed.dst_addr.into());
-                            let (x, y) = req.pos;
-                            self.image.put(x as _, y as _, req.color, req.size == 2);
+                            if self.verify_checksums && !checksum_valid(buffer) {
+                                self.packet_counter.increment_bad_checksum();
+                                continue;
+                            }
+                            let dst = ipv6_parsed.dst_addr.into();
+                            let route = self.router.route(&dst);
+                            route
+                                .queue
+                                .push(IpAddr::V6(ipv6_parsed.src_addr.into()), route.decode(&dst));
                             self.packet_counter.increment();
                         }
                     }
                 }
 
-                phy::wait(fd, self.interface.poll_delay(timestamp, &sockets))?;
+                let requested = self.interface.poll_delay(timestamp, &sockets);
+                let delay = bounded_poll_delay(requested, self.min_poll_delay, self.max_poll_delay);
+                if delay != last_delay {
+                    log::debug!("Poll delay {:?} (requested {:?})", delay, requested);
+                    last_delay = delay;
+                }
+                phy::wait(fd, delay)?;
             }
-        })
+        };
+
+        if dedicated_thread {
+            spawn_dedicated("smoltcp", cpu_affinity, run)
+        } else {
+            tokio::task::spawn_blocking(run)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn poll_delay_bounds() {
+        let ms = Duration::from_millis;
+        assert_eq!(bounded_poll_delay(None, ms(0), None), None);
+        assert_eq!(bounded_poll_delay(Some(ms(0)), ms(1), None), Some(ms(1)));
+        assert_eq!(bounded_poll_delay(None, ms(1), Some(ms(50))), Some(ms(50)));
+        assert_eq!(
+            bounded_poll_delay(Some(ms(80)), ms(1), Some(ms(50))),
+            Some(ms(50))
+        );
+        assert_eq!(
+            bounded_poll_delay(Some(ms(20)), ms(1), Some(ms(50))),
+            Some(ms(20))
+        );
+    }
+
+    #[test]
+    fn echo_replies() {
+        let mut packet = vec![0; 48];
+        packet[0] = 0x60;
+        packet[5] = 8;
+        packet[6] = 58;
+        packet[24..40].copy_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        packet[40] = 129;
+        assert_eq!(echo_reply_destination(&packet), Some(Ipv6Addr::LOCALHOST));
+
+        // Echo requests and other protocols are sent as they are.
+        packet[40] = 128;
+        assert_eq!(echo_reply_destination(&packet), None);
+        packet[40] = 129;
+        packet[6] = 17;
+        assert_eq!(echo_reply_destination(&packet), None);
+        assert_eq!(echo_reply_destination(&packet[..20]), None);
+    }
+
+    #[tokio::test]
+    async fn dedicated_thread() {
+        let handle = spawn_dedicated("test", None, || {
+            assert_eq!(std::thread::current().name(), Some("test"));
+            Err("done".into())
+        });
+        assert_eq!(handle.await.unwrap().unwrap_err().to_string(), "done");
+
+        let handle = spawn_dedicated("test", None, || panic!("oh no"));
+        assert!(handle.await.unwrap().is_err());
     }
 }
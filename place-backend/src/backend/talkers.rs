@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::writer::source_key;
+
+/// Length of the windows pixel rates are measured over.
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+/// Pixels sent by a single source.
+#[derive(Debug, Default)]
+struct SourceCount {
+    current: u64,
+    previous: u64,
+    total: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Talker {
+    pub source: IpAddr,
+    /// Pixels sent since the source started being tracked.
+    pub pixels: u64,
+    /// Pixels per second over roughly the last `WINDOW`.
+    pub rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopTalkers {
+    pub window_secs: u64,
+    /// Sources currently tracked.
+    pub tracked: usize,
+    /// Pixels of sources which weren't tracked because the table was full.
+    pub untracked: u64,
+    pub sources: Vec<Talker>,
+}
+
+/// Counts pixels per source, to find the busiest senders. Like the pixel queue, sources are grouped
+/// by their /64.
+///
+/// Memory is bounded by `capacity` sources. Counts are kept for two consecutive windows, and sources
+/// which sent nothing for two windows are forgotten. While the table is full, pixels of new sources are
+/// only counted in total, until old sources age out.
+pub struct TalkerTracker {
+    sources: HashMap<IpAddr, SourceCount>,
+    capacity: usize,
+    window_start: Instant,
+    untracked: u64,
+}
+
+impl TalkerTracker {
+    pub fn new(capacity: usize) -> TalkerTracker {
+        TalkerTracker {
+            sources: HashMap::new(),
+            capacity,
+            window_start: Instant::now(),
+            untracked: 0,
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, source: IpAddr, now: Instant) {
+        self.rotate(now);
+        let source = source_key(source);
+
+        if let Some(count) = self.sources.get_mut(&source) {
+            count.current += 1;
+            count.total += 1;
+        } else if self.sources.len() < self.capacity {
+            self.sources.insert(
+                source,
+                SourceCount {
+                    current: 1,
+                    previous: 0,
+                    total: 1,
+                },
+            );
+        } else {
+            self.untracked += 1;
+        }
+    }
+
+    /// Starts a new window if the current one is over, aging out idle sources.
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < WINDOW {
+            return;
+        }
+
+        // Nothing carries over if more than one window has passed.
+        let skipped = elapsed >= WINDOW * 2;
+        self.sources.retain(|_, count| {
+            count.previous = if skipped { 0 } else { count.current };
+            count.current = 0;
+            count.previous > 0
+        });
+        self.window_start = now;
+    }
+
+    /// Returns the `n` sources with the highest rates.
+    pub fn top(&mut self, n: usize, now: Instant) -> TopTalkers {
+        self.rotate(now);
+
+        // The previous window is weighted by how much of it still overlaps a window ending now.
+        let progress = now
+            .saturating_duration_since(self.window_start)
+            .as_secs_f64()
+            / WINDOW.as_secs_f64();
+        let mut sources: Vec<Talker> = self
+            .sources
+            .iter()
+            .map(|(&source, count)| Talker {
+                source,
+                pixels: count.total,
+                rate: (count.previous as f64 * (1.0 - progress) + count.current as f64)
+                    / WINDOW.as_secs_f64(),
+            })
+            .collect();
+        sources.sort_unstable_by(|a, b| b.rate.total_cmp(&a.rate));
+        sources.truncate(n);
+
+        TopTalkers {
+            window_secs: WINDOW.as_secs(),
+            tracked: self.sources.len(),
+            untracked: self.untracked,
+            sources,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn top_talkers() {
+        let a: IpAddr = "2001:db8:0:a::".parse().unwrap();
+        let b: IpAddr = "2001:db8:0:b::".parse().unwrap();
+        let c: IpAddr = "2001:db8:0:c::".parse().unwrap();
+        let mut tracker = TalkerTracker::new(2);
+        let start = tracker.window_start;
+
+        for i in 0..120 {
+            // Hosts of the same /64 count as one source.
+            tracker.record(IpAddr::from([0x2001, 0xdb8, 0, 0xa, 0, 0, 0, i]), start);
+        }
+        tracker.record(b, start);
+        tracker.record(c, start);
+
+        let top = tracker.top(1, start);
+        assert_eq!((top.tracked, top.untracked), (2, 1));
+        assert_eq!(top.sources.len(), 1);
+        assert_eq!((top.sources[0].source, top.sources[0].pixels), (a, 120));
+        assert_eq!(top.sources[0].rate, 2.0);
+
+        // Halfway through the next window, half of the previous one still counts.
+        tracker.record(b, start + WINDOW);
+        let top = tracker.top(2, start + WINDOW + WINDOW / 2);
+        assert_eq!(top.sources[0].rate, 1.0);
+        assert_eq!(top.sources[1].pixels, 2);
+
+        // Idle sources age out, making room for new ones.
+        tracker.record(c, start + WINDOW * 2);
+        tracker.record(c, start + WINDOW * 2);
+        let top = tracker.top(10, start + WINDOW * 2);
+        let sources: Vec<IpAddr> = top.sources.iter().map(|talker| talker.source).collect();
+        assert_eq!(sources, [c, b]);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{settings::ColorTransform, utils::Color};
+
+use super::PixelRequest;
+
+/// Applies a `ColorTransform` to pixels on their way to the canvas.
+///
+/// Everything but grayscale maps channels independently, so it's precomputed as a lookup table
+/// and applying it takes no branches or allocations.
+pub struct ColorTransformer {
+    table: [u8; 256],
+    grayscale: bool,
+}
+
+impl ColorTransformer {
+    /// Returns `None` for the identity transform, which can be skipped altogether.
+    pub fn new(transform: ColorTransform) -> Option<ColorTransformer> {
+        if transform == ColorTransform::Identity {
+            return None;
+        }
+
+        let mut table = [0; 256];
+        for (i, value) in table.iter_mut().enumerate() {
+            *value = match transform {
+                ColorTransform::Invert => 255 - i as u8,
+                ColorTransform::Gamma(gamma) => {
+                    ((i as f32 / 255.0).powf(1.0 / gamma) * 255.0).round() as u8
+                }
+                _ => i as u8,
+            };
+        }
+
+        Some(ColorTransformer {
+            table,
+            grayscale: transform == ColorTransform::Grayscale,
+        })
+    }
+
+    #[inline]
+    pub fn apply(&self, color: Color) -> Color {
+        // Rec. 601 luma with weights summing up to 256.
+        let luma = ((color.r as u32 * 77 + color.g as u32 * 150 + color.b as u32 * 29) >> 8) as u8;
+        let [r, g, b] = if self.grayscale {
+            [luma; 3]
+        } else {
+            [color.r, color.g, color.b]
+        };
+
+        Color {
+            r: self.table[r as usize],
+            g: self.table[g as usize],
+            b: self.table[b as usize],
+            a: color.a,
+        }
+    }
+
+    /// Transforms the color of the request. The low bytes of 16-bit colors are dropped, since the
+    /// transforms work on 8 bits per channel.
+    #[inline]
+    pub fn apply_to(&self, req: &mut PixelRequest) {
+        req.color = self.apply(req.color);
+        req.color_low = None;
+    }
+}
+
+/// Snaps pixels to the nearest color of a palette by distance in RGB, after any `ColorTransformer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaletteSnapper {
+    colors: Vec<Color>,
+}
+
+impl PaletteSnapper {
+    /// Returns `None` for an empty palette, which keeps colors as they are.
+    pub fn new(colors: Vec<Color>) -> Option<PaletteSnapper> {
+        (!colors.is_empty()).then_some(PaletteSnapper { colors })
+    }
+
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    /// Returns the palette color closest to `color`, the first one of several equally close ones.
+    #[inline]
+    pub fn nearest(&self, color: Color) -> Color {
+        let distance = |other: &Color| {
+            let [dr, dg, db] = [
+                color.r as i32 - other.r as i32,
+                color.g as i32 - other.g as i32,
+                color.b as i32 - other.b as i32,
+            ];
+            dr * dr + dg * dg + db * db
+        };
+        // `new` doesn't allow empty palettes.
+        *self.colors.iter().min_by_key(|c| distance(c)).unwrap()
+    }
+
+    /// Snaps the color of the request, dropping the low bytes of 16-bit colors.
+    #[inline]
+    pub fn apply_to(&self, req: &mut PixelRequest) {
+        req.color = self.nearest(req.color);
+        req.color_low = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transforms() {
+        let color = Color::rgb(255, 128, 0);
+        assert!(ColorTransformer::new(ColorTransform::Identity).is_none());
+
+        let invert = ColorTransformer::new(ColorTransform::Invert).unwrap();
+        assert_eq!(invert.apply(color), Color::rgb(0, 127, 255));
+
+        let grayscale = ColorTransformer::new(ColorTransform::Grayscale).unwrap();
+        assert_eq!(grayscale.apply(color), Color::rgb(151, 151, 151));
+        assert_eq!(
+            grayscale.apply(Color::rgb(255, 255, 255)),
+            Color::rgb(255, 255, 255)
+        );
+
+        let gamma = ColorTransformer::new(ColorTransform::Gamma(2.0)).unwrap();
+        assert_eq!(gamma.apply(color), Color::rgb(255, 181, 0));
+        assert_eq!(gamma.apply(color).a, color.a);
+    }
+
+    #[test]
+    fn palette_snapping() {
+        assert!(PaletteSnapper::new(Vec::new()).is_none());
+
+        let black = Color::rgb(0, 0, 0);
+        let red = Color::rgb(255, 0, 0);
+        let white = Color::rgb(255, 255, 255);
+        let palette = PaletteSnapper::new(vec![black, red, white]).unwrap();
+        assert_eq!(palette.nearest(Color::rgb(200, 30, 40)), red);
+        assert_eq!(palette.nearest(Color::rgb(60, 60, 60)), black);
+        assert_eq!(palette.nearest(Color::rgb(250, 240, 245)), white);
+        assert_eq!(palette.nearest(red), red);
+    }
+}
@@ -1,19 +1,17 @@
 use std::sync::Arc;
 
-use crate::{place::SharedImageHandle, settings::Settings, PResult};
+use crate::{settings::Settings, PResult};
 
-use super::{NetworkBackend, PacketCounter};
+use super::{CanvasRouter, NetworkBackend, PacketCounter};
 
 pub struct TunNetworkBackend {}
 
 impl TunNetworkBackend {
     pub fn new(
         settings: &Settings,
-        image: SharedImageHandle,
+        router: CanvasRouter,
         packet_counter: Arc<PacketCounter>,
     ) -> PResult<Box<dyn NetworkBackend>> {
-        
-
         Ok(Box::new(Self {}))
     }
 }
@@ -22,4 +20,4 @@ impl NetworkBackend for TunNetworkBackend {
     fn start(self: Box<Self>) -> tokio::task::JoinHandle<PResult<()>> {
         todo!()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::{
+    backend::PixelRequest,
+    settings::{CoordinateMode, Settings},
+    PResult,
+};
+
+use super::{writer::PixelQueue, NetworkBackend, PacketCounter};
+
+/// Accepts pixels over plain UDP (usually IPv4), for clients that can't reach the IPv6 prefix.
+///
+/// Runs alongside the configured IPv6 backend and writes to the same canvas.
+pub struct UdpBridge {
+    queue: PixelQueue,
+    coordinate_mode: CoordinateMode,
+    socket: std::net::UdpSocket,
+    packet_counter: Arc<PacketCounter>,
+}
+
+impl UdpBridge {
+    pub fn new(
+        settings: &Settings,
+        queue: PixelQueue,
+        packet_counter: Arc<PacketCounter>,
+    ) -> PResult<Box<dyn NetworkBackend>> {
+        let socket = std::net::UdpSocket::bind(&settings.udp_bridge.listen_addr)?;
+        socket.set_nonblocking(true)?;
+        log::info!("UDP bridge listening on {}", socket.local_addr()?);
+
+        Ok(Box::new(Self {
+            queue,
+            coordinate_mode: settings.canvas.coordinate_mode,
+            socket,
+            packet_counter,
+        }))
+    }
+
+    async fn run(self) -> PResult<()> {
+        let socket = UdpSocket::from_std(self.socket)?;
+        let mut buffer = vec![0u8; 65536];
+        let (_, height) = self.queue.dimensions();
+
+        loop {
+            let (len, addr) = socket.recv_from(&mut buffer).await?;
+
+            for record in buffer[..len].chunks_exact(PixelRequest::RECORD_SIZE) {
+                // chunks_exact guarantees the record length.
+                let req = PixelRequest::from_bytes(record.try_into().unwrap())
+                    .oriented(self.coordinate_mode, height);
+                self.queue.push(addr.ip(), req);
+                self.packet_counter.increment();
+            }
+        }
+    }
+}
+
+impl NetworkBackend for UdpBridge {
+    fn start(self: Box<Self>) -> JoinHandle<PResult<()>> {
+        tokio::spawn(self.run())
+    }
+}
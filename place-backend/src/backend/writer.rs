@@ -0,0 +1,999 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Write},
+    net::{IpAddr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::{
+    place::SharedImageHandle,
+    settings::{OutOfBoundsPolicy, RegionRule, Settings},
+    utils::Color,
+    PResult,
+};
+
+use super::{
+    acl::{ProtectedRegions, RegionAcl},
+    attribution::{self, Attribution, LastWriters},
+    coalesce::PixelCoalescer,
+    cooldown::{CooldownOverlay, PixelCooldown},
+    history::PixelHistory,
+    rate_grid::RateGrid,
+    talkers::{TalkerTracker, TopTalkers},
+    transform::{ColorTransformer, PaletteSnapper},
+    PixelRequest,
+};
+
+/// Maximum number of pixels written between checks of the coalescing window.
+const WRITE_BATCH: usize = 4096;
+/// Number of sources listed in queue stats.
+const TOP_SOURCES: usize = 10;
+
+/// Pixels queued by a single source.
+#[derive(Default)]
+struct SourceQueue {
+    pending: VecDeque<PixelRequest>,
+    /// Pixels of this source dropped since its backlog started.
+    dropped: u64,
+}
+
+/// Pixel queue split into per-source sub-queues, which are drained round-robin.
+///
+/// When the writer can't keep up, every source gets an equal share of the capacity and the write path,
+/// so one aggressive sender can't starve the others.
+struct FairQueue {
+    /// Sources with pending pixels.
+    sources: HashMap<IpAddr, SourceQueue>,
+    /// Keys of `sources` in round-robin order.
+    order: VecDeque<IpAddr>,
+    /// Keys of `sources` by the number of pixels they have pending, so the longest sub-queue is
+    /// found without scanning all of them. Never ends with an empty set.
+    by_len: Vec<HashSet<IpAddr>>,
+    len: usize,
+    capacity: usize,
+    /// Pixels dropped since the last report.
+    dropped: u64,
+    dropped_total: u64,
+    senders: usize,
+    writer_alive: bool,
+}
+
+impl FairQueue {
+    fn new(capacity: usize) -> FairQueue {
+        FairQueue {
+            sources: HashMap::new(),
+            order: VecDeque::new(),
+            by_len: Vec::new(),
+            len: 0,
+            capacity: capacity.max(1),
+            dropped: 0,
+            dropped_total: 0,
+            senders: 1,
+            writer_alive: true,
+        }
+    }
+
+    /// Queues a pixel, returns false if it was dropped.
+    fn push(&mut self, source: IpAddr, req: PixelRequest) -> bool {
+        if self.len >= self.capacity && !self.evict_for(source) {
+            self.dropped += 1;
+            self.dropped_total += 1;
+            if let Some(queue) = self.sources.get_mut(&source) {
+                queue.dropped += 1;
+            }
+            return false;
+        }
+
+        let mut is_new = false;
+        let queue = self.sources.entry(source).or_insert_with(|| {
+            is_new = true;
+            self.order.push_back(source);
+            SourceQueue::default()
+        });
+        queue.pending.push_back(req);
+        let len = queue.pending.len();
+        self.len += 1;
+        self.resize_source(source, (!is_new).then_some(len - 1), Some(len));
+        true
+    }
+
+    /// Moves `source` from the set of sources with `from` pending pixels to the one with `to`.
+    fn resize_source(&mut self, source: IpAddr, from: Option<usize>, to: Option<usize>) {
+        if let Some(from) = from {
+            self.by_len[from].remove(&source);
+        }
+        if let Some(to) = to {
+            if self.by_len.len() <= to {
+                self.by_len.resize_with(to + 1, HashSet::new);
+            }
+            self.by_len[to].insert(source);
+        }
+        while self.by_len.last().is_some_and(HashSet::is_empty) {
+            self.by_len.pop();
+        }
+    }
+
+    /// Makes room for a pixel of `source` by dropping the newest pixel of the longest sub-queue,
+    /// unless `source` already uses its fair share of the capacity.
+    fn evict_for(&mut self, source: IpAddr) -> bool {
+        let own = self
+            .sources
+            .get(&source)
+            .map_or(0, |queue| queue.pending.len());
+        let active = self.sources.len() + (own == 0) as usize;
+        if own >= self.capacity / active {
+            return false;
+        }
+
+        // The queue is full and `source` is below its share, so some other sub-queue is above it.
+        // That one has at least two pixels, so evicting never empties a sub-queue and `order`
+        // stays as it is.
+        let longest = self.by_len.len() - 1;
+        debug_assert!(longest > 1);
+        let evicted = *self.by_len[longest].iter().next().unwrap();
+        let queue = self.sources.get_mut(&evicted).unwrap();
+        queue.pending.pop_back();
+        queue.dropped += 1;
+        self.dropped += 1;
+        self.dropped_total += 1;
+        self.len -= 1;
+        self.resize_source(evicted, Some(longest), Some(longest - 1));
+        true
+    }
+
+    /// Moves up to `max` pixels into `out` along with their source, taking one pixel from each
+    /// source in turn.
+    fn pop_batch(&mut self, out: &mut Vec<(IpAddr, PixelRequest)>, max: usize) {
+        while out.len() < max {
+            let Some(source) = self.order.pop_front() else {
+                break;
+            };
+            // `order` only contains sources with pending pixels.
+            let queue = self.sources.get_mut(&source).unwrap();
+            let len = queue.pending.len();
+            out.push((source, queue.pending.pop_front().unwrap()));
+            self.len -= 1;
+
+            if queue.pending.is_empty() {
+                self.sources.remove(&source);
+                self.resize_source(source, Some(len), None);
+            } else {
+                self.order.push_back(source);
+                self.resize_source(source, Some(len), Some(len - 1));
+            }
+        }
+    }
+
+    fn stats(&self) -> QueueStats {
+        let mut top_sources: Vec<SourceStats> = self
+            .sources
+            .iter()
+            .map(|(&source, queue)| SourceStats {
+                source,
+                pending: queue.pending.len(),
+                dropped: queue.dropped,
+            })
+            .collect();
+        top_sources.sort_unstable_by_key(|source| std::cmp::Reverse(source.pending));
+        top_sources.truncate(TOP_SOURCES);
+
+        QueueStats {
+            pending: self.len,
+            dropped: self.dropped_total,
+            cooldown_rejected: 0,
+            region_rejected: 0,
+            protected_rejected: 0,
+            out_of_bounds: 0,
+            frozen_rejected: 0,
+            banned_rejected: 0,
+            sources: self.sources.len(),
+            top_sources,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStats {
+    pub source: IpAddr,
+    pub pending: usize,
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueueStats {
+    /// Pixels waiting to be written.
+    pub pending: usize,
+    /// Pixels dropped since startup because the queue was full.
+    pub dropped: u64,
+    /// Pixels rejected since startup because of the pixel cooldown.
+    pub cooldown_rejected: u64,
+    /// Pixels rejected since startup because their source isn't allowed to draw in the region.
+    pub region_rejected: u64,
+    /// Pixels rejected since startup because they target a protected region.
+    pub protected_rejected: u64,
+    /// Pixels outside of the canvas since startup, dropped, wrapped or clamped depending on the policy.
+    pub out_of_bounds: u64,
+    /// Pixels rejected since startup because the canvas was frozen.
+    pub frozen_rejected: u64,
+    /// Pixels rejected since startup because their source was banned.
+    pub banned_rejected: u64,
+    /// Number of sources with pending pixels.
+    pub sources: usize,
+    /// Sources with the most pending pixels.
+    pub top_sources: Vec<SourceStats>,
+}
+
+struct Shared {
+    queue: Mutex<FairQueue>,
+    /// Signalled when pixels are queued or the last sender is gone.
+    not_empty: Condvar,
+    /// Signalled when the writer took pixels out of the queue or is gone.
+    not_full: Condvar,
+    dimensions: (u32, u32),
+    out_of_bounds_policy: OutOfBoundsPolicy,
+    out_of_bounds: AtomicU64,
+    cooldown_rejected: AtomicU64,
+    acl: Option<RegionAcl>,
+    region_rejected: AtomicU64,
+    protected: Option<Arc<ProtectedRegions>>,
+    protected_rejected: AtomicU64,
+    talkers: Option<Mutex<TalkerTracker>>,
+    last_writers: Option<Mutex<LastWriters>>,
+    rate_grid: Option<Arc<RateGrid>>,
+    cooldown_overlay: Option<Arc<CooldownOverlay>>,
+    /// Swapped at runtime by admins, the writer picks it up with the next batch.
+    palette: Mutex<Option<Arc<PaletteSnapper>>>,
+    frozen: AtomicBool,
+    frozen_rejected: AtomicU64,
+    dry_run: AtomicBool,
+    /// Source keys banned by admins, see `source_key`.
+    banned: RwLock<HashSet<IpAddr>>,
+    /// Set once anyone is banned, so pixels don't take the lock before that.
+    any_banned: AtomicBool,
+    banned_rejected: AtomicU64,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, FairQueue> {
+        // The queue stays consistent even if a sender panicked.
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Checks whether the canvas is frozen, counting the rejected pixel if it is.
+    #[inline]
+    fn is_frozen(&self) -> bool {
+        let frozen = self.frozen.load(Ordering::Relaxed);
+        if frozen {
+            self.frozen_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        frozen
+    }
+
+    /// Checks whether pixels are only logged instead of written, logging the pixel if they are.
+    #[inline]
+    fn is_dry_run(&self, source: IpAddr, req: &PixelRequest) -> bool {
+        let dry_run = self.dry_run.load(Ordering::Relaxed);
+        if dry_run {
+            log_dry_run(source, req);
+        }
+        dry_run
+    }
+
+    /// Applies the out-of-bounds policy to pixels outside of the canvas, `None` if they're dropped.
+    #[inline]
+    fn bounded(&self, mut req: PixelRequest) -> Option<PixelRequest> {
+        let (width, height) = self.dimensions;
+        let (x, y) = (req.pos.0 as u32, req.pos.1 as u32);
+        if x < width && y < height {
+            return Some(req);
+        }
+
+        self.out_of_bounds.fetch_add(1, Ordering::Relaxed);
+        req.pos = match self.out_of_bounds_policy {
+            OutOfBoundsPolicy::Drop => return None,
+            OutOfBoundsPolicy::Wrap => ((x % width) as u16, (y % height) as u16),
+            OutOfBoundsPolicy::Clamp => (x.min(width - 1) as u16, y.min(height - 1) as u16),
+        };
+        Some(req)
+    }
+
+    /// Checks the bans, the protected regions and the region ACL, counting rejected pixels.
+    #[inline]
+    fn is_allowed(&self, source: IpAddr, req: &PixelRequest) -> bool {
+        if self.any_banned.load(Ordering::Relaxed) {
+            let banned = self.banned.read().unwrap_or_else(|e| e.into_inner());
+            if banned.contains(&source_key(source)) {
+                self.banned_rejected.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+        if self.protected.as_ref().is_some_and(|p| p.protects(req)) {
+            self.protected_rejected.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        match &self.acl {
+            Some(acl) if !acl.allows(source, req) => {
+                self.region_rejected.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Logs a pixel which isn't written because of `--dry-run`.
+pub fn log_dry_run(source: impl fmt::Display, req: &PixelRequest) {
+    let color = req.color16();
+    log::info!(
+        "Dry run: {} -> ({}, {}) size {} color {:04x}:{:04x}:{:04x}",
+        source,
+        req.pos.0,
+        req.pos.1,
+        req.size,
+        color.r,
+        color.g,
+        color.b
+    );
+}
+
+/// Groups sources by the part of the address a single host usually controls, ie. the /64 for IPv6.
+#[inline]
+pub(super) fn source_key(source: IpAddr) -> IpAddr {
+    match source {
+        IpAddr::V4(_) => source,
+        IpAddr::V6(addr) => {
+            let s = addr.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
+}
+
+/// Sending half of the pixel queue. Backends only parse packets and push the resulting pixels here,
+/// the canvas itself is written by a single `CanvasWriter`.
+pub struct PixelQueue {
+    shared: Arc<Shared>,
+}
+
+impl Clone for PixelQueue {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for PixelQueue {
+    fn drop(&mut self) {
+        self.shared.lock().senders -= 1;
+        self.shared.not_empty.notify_one();
+    }
+}
+
+impl PixelQueue {
+    /// Queues a pixel sent by `source`, dropping it if the writer can't keep up.
+    #[inline]
+    pub fn push(&self, source: IpAddr, req: PixelRequest) {
+        if self.shared.is_dry_run(source, &req) || self.shared.is_frozen() {
+            return;
+        }
+        let Some(req) = self.shared.bounded(req) else {
+            return;
+        };
+        if !self.shared.is_allowed(source, &req) {
+            return;
+        }
+
+        let mut queue = self.shared.lock();
+        let was_empty = queue.len == 0;
+        queue.push(source_key(source), req);
+        drop(queue);
+
+        if was_empty {
+            self.shared.not_empty.notify_one();
+        }
+    }
+
+    /// Queues a pixel, waiting for space if the queue is full. Must not be called from async code.
+    pub fn push_blocking(&self, source: IpAddr, req: PixelRequest) {
+        if self.shared.is_dry_run(source, &req) || self.shared.is_frozen() {
+            return;
+        }
+        let Some(req) = self.shared.bounded(req) else {
+            return;
+        };
+        if !self.shared.is_allowed(source, &req) {
+            return;
+        }
+
+        let mut queue = self.shared.lock();
+        while queue.len >= queue.capacity && queue.writer_alive {
+            queue = self
+                .shared
+                .not_full
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        let was_empty = queue.len == 0;
+        queue.push(source_key(source), req);
+        drop(queue);
+
+        if was_empty {
+            self.shared.not_empty.notify_one();
+        }
+    }
+
+    /// Dimensions of the canvas the pixels end up on.
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.shared.dimensions
+    }
+
+    /// Returns a handle for reading queue statistics, which doesn't keep the writer running.
+    pub fn monitor(&self) -> QueueMonitor {
+        QueueMonitor {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Read-only view of the pixel queue.
+#[derive(Clone)]
+pub struct QueueMonitor {
+    shared: Arc<Shared>,
+}
+
+impl QueueMonitor {
+    pub fn stats(&self) -> QueueStats {
+        let mut stats = self.shared.lock().stats();
+        stats.cooldown_rejected = self.shared.cooldown_rejected.load(Ordering::Relaxed);
+        stats.region_rejected = self.shared.region_rejected.load(Ordering::Relaxed);
+        stats.protected_rejected = self.shared.protected_rejected.load(Ordering::Relaxed);
+        stats.out_of_bounds = self.shared.out_of_bounds.load(Ordering::Relaxed);
+        stats.frozen_rejected = self.shared.frozen_rejected.load(Ordering::Relaxed);
+        stats.banned_rejected = self.shared.banned_rejected.load(Ordering::Relaxed);
+        stats
+    }
+
+    /// Rejects further pixels from the /64 of `source`, or the address itself for IPv4. Returns the
+    /// banned prefix and whether it was banned already.
+    pub fn ban(&self, source: IpAddr) -> (IpAddr, bool) {
+        let key = source_key(source);
+        let mut banned = self
+            .shared
+            .banned
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        let already_banned = !banned.insert(key);
+        self.shared.any_banned.store(true, Ordering::Relaxed);
+        (key, already_banned)
+    }
+
+    pub fn frozen(&self) -> bool {
+        self.shared.frozen.load(Ordering::Relaxed)
+    }
+
+    /// Freezes or unfreezes the canvas, returns whether it was frozen before.
+    pub fn set_frozen(&self, frozen: bool) -> bool {
+        self.shared.frozen.swap(frozen, Ordering::Relaxed)
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.shared.dry_run.load(Ordering::Relaxed)
+    }
+
+    /// Makes the queue log pixels instead of writing them, for debugging clients.
+    pub fn set_dry_run(&self, dry_run: bool) {
+        self.shared.dry_run.store(dry_run, Ordering::Relaxed);
+    }
+
+    /// Counts pixels rejected while frozen by writers bypassing the queue.
+    pub fn count_frozen_rejected(&self, count: u64) {
+        self.shared
+            .frozen_rejected
+            .fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Returns the `n` busiest source addresses, `None` if tracking is disabled.
+    pub fn top_talkers(&self, n: usize) -> Option<TopTalkers> {
+        let talkers = self.shared.talkers.as_ref()?;
+        let mut talkers = talkers.lock().unwrap_or_else(|e| e.into_inner());
+        Some(talkers.top(n, Instant::now()))
+    }
+
+    /// Returns the sources which wrote the pixels in the area given as [x, y, width, height] last,
+    /// `None` if attribution is disabled.
+    pub fn attribution(&self, rect: [u32; 4]) -> Option<Attribution> {
+        let last_writers = self.shared.last_writers.as_ref()?;
+        Some(attribution::sources_in(last_writers, rect))
+    }
+
+    /// Returns the grid of placement rates, `None` if it's disabled.
+    pub fn rate_grid(&self) -> Option<Arc<RateGrid>> {
+        self.shared.rate_grid.clone()
+    }
+
+    /// Returns the overlay of pixels in cooldown, `None` if it's disabled.
+    pub fn cooldown_overlay(&self) -> Option<Arc<CooldownOverlay>> {
+        self.shared.cooldown_overlay.clone()
+    }
+
+    /// Returns the colors pixels are snapped to, empty if they're kept as they are.
+    pub fn palette(&self) -> Vec<Color> {
+        let palette = self
+            .shared
+            .palette
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        palette
+            .as_ref()
+            .map_or_else(Vec::new, |palette| palette.colors().to_vec())
+    }
+
+    /// Replaces the palette pixels are snapped to, an empty one disables snapping. Returns the
+    /// previous palette.
+    pub fn set_palette(&self, colors: Vec<Color>) -> Vec<Color> {
+        let palette = PaletteSnapper::new(colors).map(Arc::new);
+        let mut current = self
+            .shared
+            .palette
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *current, palette)
+            .map_or_else(Vec::new, |palette| palette.colors().to_vec())
+    }
+
+    /// Appends the out-of-bounds counter in the Prometheus text format to `out`.
+    pub fn render_metrics(&self, out: &mut String) {
+        let policy = match self.shared.out_of_bounds_policy {
+            OutOfBoundsPolicy::Drop => "drop",
+            OutOfBoundsPolicy::Wrap => "wrap",
+            OutOfBoundsPolicy::Clamp => "clamp",
+        };
+        out.push_str(
+            "# HELP place_out_of_bounds_total Pixels outside of the canvas, by the action taken.\n",
+        );
+        out.push_str("# TYPE place_out_of_bounds_total counter\n");
+        let _ = writeln!(
+            out,
+            "place_out_of_bounds_total{{policy=\"{}\"}} {}",
+            policy,
+            self.shared.out_of_bounds.load(Ordering::Relaxed)
+        );
+
+        out.push_str(
+            "# HELP place_frozen_rejected_total Pixels rejected because the canvas was frozen.\n",
+        );
+        out.push_str("# TYPE place_frozen_rejected_total counter\n");
+        let _ = writeln!(
+            out,
+            "place_frozen_rejected_total {}",
+            self.shared.frozen_rejected.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Drains the pixel queue into the canvas on a dedicated thread.
+pub struct CanvasWriter {
+    shared: Arc<Shared>,
+    coalescer: PixelCoalescer,
+    cooldown: Option<Arc<PixelCooldown>>,
+    transform: Option<ColorTransformer>,
+    history: Option<Arc<PixelHistory>>,
+}
+
+impl Drop for CanvasWriter {
+    fn drop(&mut self) {
+        self.shared.lock().writer_alive = false;
+        self.shared.not_full.notify_all();
+    }
+}
+
+/// Creates the pixel queue for the given canvas. Pixels targeting `protected` regions are dropped.
+pub fn pixel_queue(
+    settings: &Settings,
+    image: SharedImageHandle,
+    regions: &[RegionRule],
+    protected: Option<Arc<ProtectedRegions>>,
+) -> (PixelQueue, CanvasWriter) {
+    let window = Duration::from_millis(settings.backend.coalesce_window_ms);
+    let cooldown = (settings.backend.cooldown_ms > 0).then(|| {
+        let (width, height) = image.get_dimensions();
+        Arc::new(PixelCooldown::new(
+            width,
+            height,
+            Duration::from_millis(settings.backend.cooldown_ms),
+            settings.backend.cooldown_resolution,
+        ))
+    });
+    let cooldown_overlay = cooldown
+        .as_ref()
+        .zip(settings.backend.cooldown_overlay_color)
+        .map(|(cooldown, tint)| Arc::new(CooldownOverlay::new(cooldown.clone(), tint)));
+
+    let acl = RegionAcl::new(regions);
+    let talkers = (settings.backend.top_talkers_size > 0)
+        .then(|| TalkerTracker::new(settings.backend.top_talkers_size));
+    let last_writers = settings.backend.track_attribution.then(|| {
+        let (width, height) = image.get_dimensions();
+        LastWriters::new(width, height)
+    });
+    let rate_grid = (settings.backend.rate_grid_cells > 0).then(|| {
+        let (width, height) = image.get_dimensions();
+        Arc::new(RateGrid::new(
+            width,
+            height,
+            settings.backend.rate_grid_cells,
+        ))
+    });
+    let (queue, mut writer) = new_queue(
+        settings.backend.queue_capacity,
+        image,
+        window,
+        QueueOptions {
+            acl,
+            protected,
+            out_of_bounds_policy: settings.backend.out_of_bounds,
+            talkers,
+            last_writers,
+            rate_grid,
+            cooldown_overlay,
+        },
+    );
+    queue.monitor().set_frozen(settings.backend.frozen);
+    queue
+        .monitor()
+        .set_palette(settings.backend.palette.clone());
+    writer.cooldown = cooldown;
+    writer.transform = ColorTransformer::new(settings.backend.color_transform);
+    (queue, writer)
+}
+
+/// Optional parts of a pixel queue, all of them disabled by default.
+#[derive(Default)]
+struct QueueOptions {
+    acl: Option<RegionAcl>,
+    protected: Option<Arc<ProtectedRegions>>,
+    out_of_bounds_policy: OutOfBoundsPolicy,
+    talkers: Option<TalkerTracker>,
+    last_writers: Option<LastWriters>,
+    rate_grid: Option<Arc<RateGrid>>,
+    cooldown_overlay: Option<Arc<CooldownOverlay>>,
+}
+
+fn new_queue(
+    capacity: usize,
+    image: SharedImageHandle,
+    window: Duration,
+    options: QueueOptions,
+) -> (PixelQueue, CanvasWriter) {
+    let QueueOptions {
+        acl,
+        protected,
+        out_of_bounds_policy,
+        talkers,
+        last_writers,
+        rate_grid,
+        cooldown_overlay,
+    } = options;
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(FairQueue::new(capacity)),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        dimensions: image.get_dimensions(),
+        out_of_bounds_policy,
+        out_of_bounds: AtomicU64::new(0),
+        cooldown_rejected: AtomicU64::new(0),
+        acl,
+        region_rejected: AtomicU64::new(0),
+        protected,
+        protected_rejected: AtomicU64::new(0),
+        talkers: talkers.map(Mutex::new),
+        last_writers: last_writers.map(Mutex::new),
+        rate_grid,
+        cooldown_overlay,
+        palette: Mutex::new(None),
+        frozen: AtomicBool::new(false),
+        frozen_rejected: AtomicU64::new(0),
+        dry_run: AtomicBool::new(false),
+        banned: RwLock::new(HashSet::new()),
+        any_banned: AtomicBool::new(false),
+        banned_rejected: AtomicU64::new(0),
+    });
+
+    let queue = PixelQueue {
+        shared: shared.clone(),
+    };
+    let writer = CanvasWriter {
+        shared,
+        coalescer: PixelCoalescer::new(image, window),
+        cooldown: None,
+        transform: None,
+        history: None,
+    };
+
+    (queue, writer)
+}
+
+impl CanvasWriter {
+    /// Records written pixels in `history`.
+    pub fn with_history(mut self, history: Option<Arc<PixelHistory>>) -> CanvasWriter {
+        self.history = history;
+        self
+    }
+
+    /// Writes pixels until all queues are dropped.
+    fn run(mut self) -> PResult<()> {
+        let mut last_report = Instant::now();
+        let mut batch = Vec::with_capacity(WRITE_BATCH);
+
+        loop {
+            let mut queue = self.shared.lock();
+            while queue.len == 0 {
+                if queue.senders == 0 {
+                    drop(queue);
+                    self.coalescer.flush();
+                    return Ok(());
+                }
+
+                queue = match self.coalescer.next_flush() {
+                    Some(timeout) if timeout.is_zero() => break,
+                    Some(timeout) => {
+                        self.shared
+                            .not_empty
+                            .wait_timeout(queue, timeout)
+                            .unwrap_or_else(|e| e.into_inner())
+                            .0
+                    }
+                    None => self
+                        .shared
+                        .not_empty
+                        .wait(queue)
+                        .unwrap_or_else(|e| e.into_inner()),
+                };
+            }
+            queue.pop_batch(&mut batch, WRITE_BATCH);
+
+            let dropped = if last_report.elapsed() >= Duration::from_secs(1) {
+                last_report = Instant::now();
+                std::mem::take(&mut queue.dropped)
+            } else {
+                0
+            };
+            drop(queue);
+            self.shared.not_full.notify_all();
+
+            // Counted before the cooldown, whether the pixels end up on the canvas or not.
+            if let Some(talkers) = &self.shared.talkers {
+                let mut talkers = talkers.lock().unwrap_or_else(|e| e.into_inner());
+                let now = Instant::now();
+                for (source, _) in &batch {
+                    talkers.record(*source, now);
+                }
+            }
+
+            let mut rejected = 0;
+            if let Some(cooldown) = &self.cooldown {
+                batch.retain(|(_, req)| {
+                    let allowed = cooldown.try_write(req);
+                    rejected += !allowed as u64;
+                    allowed
+                });
+            }
+            if let Some(transform) = &self.transform {
+                for (_, req) in &mut batch {
+                    transform.apply_to(req);
+                }
+            }
+            let palette = self
+                .shared
+                .palette
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone();
+            if let Some(palette) = palette {
+                for (_, req) in &mut batch {
+                    palette.apply_to(req);
+                }
+            }
+            if let Some(history) = &self.history {
+                history.record(batch.iter().map(|(_, req)| req));
+            }
+            if let Some(last_writers) = &self.shared.last_writers {
+                let mut last_writers = last_writers.lock().unwrap_or_else(|e| e.into_inner());
+                for (source, req) in &batch {
+                    last_writers.record(*source, req);
+                }
+            }
+            if let Some(rate_grid) = &self.shared.rate_grid {
+                for (_, req) in &batch {
+                    rate_grid.record(req);
+                }
+            }
+            for (_, req) in batch.drain(..) {
+                self.coalescer.put(req);
+            }
+            if rejected > 0 {
+                self.shared
+                    .cooldown_rejected
+                    .fetch_add(rejected, Ordering::Relaxed);
+            }
+            self.coalescer.flush_if_due();
+
+            if dropped > 0 {
+                log::warn!(
+                    "Pixel queue is full, dropped {} pixels. Consider raising queue_capacity.",
+                    dropped
+                );
+            }
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<PResult<()>> {
+        tokio::task::spawn_blocking(move || self.run())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::RgbaImage;
+
+    use super::*;
+    use crate::utils::Color;
+
+    fn pixel(x: u16) -> PixelRequest {
+        PixelRequest {
+            pos: (x, 0),
+            color: Color::rgb(255, 0, 0),
+            size: 1,
+            color_low: None,
+        }
+    }
+
+    fn test_queue(capacity: usize, image: SharedImageHandle) -> (PixelQueue, CanvasWriter) {
+        new_queue(capacity, image, Duration::ZERO, QueueOptions::default())
+    }
+
+    #[test]
+    fn drain_queue() {
+        let image = SharedImageHandle::new(RgbaImage::new(16, 16));
+        let (queue, writer) = test_queue(2, image.clone());
+        let source = "2001:db8::1".parse().unwrap();
+
+        for x in 0..3 {
+            queue.push(source, pixel(x));
+        }
+        assert_eq!(queue.monitor().stats().dropped, 1);
+
+        drop(queue);
+        writer.run().unwrap();
+
+        let get = |x| unsafe { *image.get_image().get_pixel(x, 0) };
+        assert_eq!(get(0), Color::rgb(255, 0, 0).into_rgba());
+        assert_eq!(get(1), Color::rgb(255, 0, 0).into_rgba());
+        assert_eq!(get(2), Color::new(0, 0, 0, 0).into_rgba());
+    }
+
+    #[test]
+    fn frozen_canvas() {
+        let image = SharedImageHandle::new(RgbaImage::new(16, 16));
+        let (queue, _writer) = test_queue(2, image);
+        let source = "2001:db8::1".parse().unwrap();
+        let monitor = queue.monitor();
+
+        assert!(!monitor.set_frozen(true));
+        queue.push(source, pixel(0));
+        let stats = monitor.stats();
+        assert_eq!((stats.pending, stats.frozen_rejected), (0, 1));
+
+        assert!(monitor.set_frozen(false));
+        queue.push(source, pixel(0));
+        assert_eq!(monitor.stats().pending, 1);
+    }
+
+    #[test]
+    fn banned_source() {
+        let image = SharedImageHandle::new(RgbaImage::new(16, 16));
+        let (queue, _writer) = test_queue(4, image);
+        let source: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+        let monitor = queue.monitor();
+
+        let prefix: IpAddr = "2001:db8:0:1::".parse().unwrap();
+        assert_eq!(monitor.ban(source), (prefix, false));
+        assert_eq!(monitor.ban(source), (prefix, true));
+
+        // The whole /64 is banned, other sources aren't.
+        queue.push("2001:db8:0:1::2".parse().unwrap(), pixel(0));
+        queue.push("2001:db8:0:2::1".parse().unwrap(), pixel(0));
+        let stats = monitor.stats();
+        assert_eq!((stats.pending, stats.banned_rejected), (1, 1));
+    }
+
+    #[test]
+    fn dry_run() {
+        let image = SharedImageHandle::new(RgbaImage::new(16, 16));
+        let (queue, writer) = test_queue(2, image.clone());
+        let source = "2001:db8::1".parse().unwrap();
+
+        queue.monitor().set_dry_run(true);
+        queue.push(source, pixel(0));
+        queue.push_blocking(source, pixel(1));
+        assert_eq!(queue.monitor().stats().pending, 0);
+
+        drop(queue);
+        writer.run().unwrap();
+        assert_eq!(image.get(0, 0), Some(Color::new(0, 0, 0, 0)));
+    }
+
+    #[test]
+    fn out_of_bounds_policy() {
+        let image = SharedImageHandle::new(RgbaImage::new(16, 16));
+        let bounded = |policy, x, y| {
+            let (queue, _writer) = new_queue(
+                2,
+                image.clone(),
+                Duration::ZERO,
+                QueueOptions {
+                    out_of_bounds_policy: policy,
+                    ..QueueOptions::default()
+                },
+            );
+            let req = PixelRequest {
+                pos: (x, y),
+                ..pixel(0)
+            };
+            let bounded = queue.shared.bounded(req).map(|req| req.pos);
+            (bounded, queue.monitor().stats().out_of_bounds)
+        };
+
+        assert_eq!(
+            bounded(OutOfBoundsPolicy::Drop, 15, 15),
+            (Some((15, 15)), 0)
+        );
+        assert_eq!(bounded(OutOfBoundsPolicy::Drop, 16, 0), (None, 1));
+        assert_eq!(bounded(OutOfBoundsPolicy::Wrap, 17, 33), (Some((1, 1)), 1));
+        assert_eq!(bounded(OutOfBoundsPolicy::Clamp, 17, 3), (Some((15, 3)), 1));
+    }
+
+    #[test]
+    fn fair_dequeue() {
+        let a: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:0:2::1".parse().unwrap();
+        let mut queue = FairQueue::new(4);
+
+        // Addresses within the same /64 share a sub-queue.
+        for x in 0..4 {
+            let source = format!("2001:db8:0:1::{}", x + 1).parse().unwrap();
+            assert!(queue.push(source_key(source), pixel(x)));
+        }
+        assert_eq!(queue.sources.len(), 1);
+
+        // The full queue makes room for a new source at the cost of the aggressive one.
+        assert!(queue.push(source_key(b), pixel(10)));
+        assert!(queue.push(source_key(b), pixel(11)));
+        assert!(!queue.push(source_key(b), pixel(12)));
+        assert!(!queue.push(source_key(a), pixel(4)));
+
+        let stats = queue.stats();
+        assert_eq!((stats.pending, stats.dropped, stats.sources), (4, 4, 2));
+        assert_eq!(
+            stats.top_sources[0].dropped + stats.top_sources[1].dropped,
+            4
+        );
+
+        let mut batch = Vec::new();
+        queue.pop_batch(&mut batch, 8);
+        let order: Vec<u16> = batch.iter().map(|(_, req)| req.pos.0).collect();
+        assert_eq!(order, [0, 10, 1, 11]);
+        assert_eq!(queue.len, 0);
+        assert!(queue.sources.is_empty() && queue.order.is_empty());
+        assert!(queue.by_len.is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+#[cfg(feature = "bench")]
+use std::alloc::{GlobalAlloc, Layout};
+use std::{
+    hint::black_box,
+    net::Ipv6Addr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use image::RgbaImage;
+use rand::Rng;
+
+use crate::{
+    backend::PixelRequest,
+    place::{FrameFormat, Palette, SharedImageHandle},
+    settings::PngCompression,
+    PResult,
+};
+
+/// Number of pixels per case if `--pixels` isn't given.
+const DEFAULT_PIXELS: usize = 10_000_000;
+/// Number of frames per format if `--frames` isn't given.
+const DEFAULT_FRAMES: usize = 100;
+/// Size of the canvas written to.
+const CANVAS_SIZE: u32 = 512;
+
+/// Wraps the global allocator to count allocations while a benchmark asks for it. Only installed
+/// with the `bench` feature, so regular builds don't pay for it.
+#[cfg(feature = "bench")]
+pub struct CountingAllocator<A>(pub A);
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "bench")]
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.load(Ordering::Relaxed) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        self.0.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.load(Ordering::Relaxed) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        self.0.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Entry point of `place-backend bench [--pixels <count>] [--frames <count>]`, which measures how
+/// fast synthetic pixel addresses are decoded and written to a canvas, and how long streamed frames
+/// take to encode and how often that allocates, without starting the server. Allocations are only
+/// counted in builds with the `bench` feature.
+pub fn run(mut args: impl Iterator<Item = String>) -> PResult<()> {
+    const USAGE: &str = "Usage: place-backend bench [--pixels <count>] [--frames <count>]";
+
+    let mut pixels = DEFAULT_PIXELS;
+    let mut frames = DEFAULT_FRAMES;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pixels" => pixels = args.next().ok_or(USAGE)?.parse()?,
+            "--frames" => frames = args.next().ok_or(USAGE)?.parse()?,
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let image = SharedImageHandle::new(RgbaImage::new(CANVAS_SIZE, CANVAS_SIZE));
+    let cases = [
+        ("1x1", 1, 0..CANVAS_SIZE as u16),
+        ("2x2", 2, 0..CANVAS_SIZE as u16),
+        ("out of bounds", 1, CANVAS_SIZE as u16..0x1000),
+    ];
+    for (name, size, coords) in cases {
+        let addresses = addresses(pixels, size, coords);
+        let elapsed = decode_and_write(&image, &addresses);
+        println!(
+            "{:<16}{} pixels in {:.3} s, {:.1} Mpx/s",
+            name,
+            pixels,
+            elapsed.as_secs_f64(),
+            pixels as f64 / elapsed.as_secs_f64() / 1_000_000.0
+        );
+    }
+
+    // Few enough colors for indexed PNGs.
+    let mut rng = rand::thread_rng();
+    let colors: Vec<[u8; 4]> = (0..16)
+        .map(|_| [rng.gen(), rng.gen(), rng.gen(), 255])
+        .collect();
+    let indexed = RgbaImage::from_fn(CANVAS_SIZE, CANVAS_SIZE, |x, y| {
+        image::Rgba(colors[((x / 8 + y / 8) % 16) as usize])
+    });
+    let cases = [
+        ("png", FrameFormat::Png, false, image.snapshot()),
+        ("indexed png", FrameFormat::Png, true, indexed),
+        ("raw", FrameFormat::Raw, false, image.snapshot()),
+        ("qoi", FrameFormat::Qoi, false, image.snapshot()),
+    ];
+    for (name, format, indexed, frame) in cases {
+        let (elapsed, allocations) = encode_frames(format, indexed, &frame, frames)?;
+        let allocations = match allocations {
+            Some(allocations) => format!(", {:.1} allocations", allocations as f64 / frames as f64),
+            None => String::new(),
+        };
+        println!(
+            "{:<16}{} frames in {:.3} s, {:.2} ms{} per frame",
+            name,
+            frames,
+            elapsed.as_secs_f64(),
+            elapsed.as_secs_f64() * 1000.0 / frames as f64,
+            allocations
+        );
+    }
+
+    Ok(())
+}
+
+/// Encodes `count` frames the way the stream does, returning the time taken and the number of
+/// allocations made, `None` without the `bench` feature.
+fn encode_frames(
+    format: FrameFormat,
+    indexed: bool,
+    image: &RgbaImage,
+    count: usize,
+) -> PResult<(Duration, Option<u64>)> {
+    let mut palette = indexed.then(Palette::default);
+    let mut size = 0;
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    COUNTING.store(true, Ordering::Relaxed);
+    let started = Instant::now();
+    for version in 0..count as u64 {
+        let frame = format.encode_frame(
+            black_box(image),
+            palette.as_mut(),
+            PngCompression::Fast,
+            (version, version),
+            size,
+        );
+        size = black_box(frame?).data.len();
+    }
+    let elapsed = started.elapsed();
+    COUNTING.store(false, Ordering::Relaxed);
+    let allocations = cfg!(feature = "bench").then(|| ALLOCATIONS.load(Ordering::Relaxed));
+    Ok((elapsed, allocations))
+}
+
+/// Generates random pixel addresses on the subnet of the given pixel size.
+fn addresses(count: usize, size: u16, coords: std::ops::Range<u16>) -> Vec<Ipv6Addr> {
+    let mut rng = rand::thread_rng();
+    (0..count)
+        .map(|_| {
+            Ipv6Addr::new(
+                0x2602,
+                0xfa9b,
+                0x42,
+                (size << 12) | rng.gen_range(coords.clone()),
+                rng.gen_range(coords.clone()),
+                rng.gen::<u8>() as u16,
+                rng.gen::<u8>() as u16,
+                rng.gen::<u8>() as u16,
+            )
+        })
+        .collect()
+}
+
+/// Runs the hot path of the backends and the canvas writer over all addresses.
+fn decode_and_write(image: &SharedImageHandle, addresses: &[Ipv6Addr]) -> Duration {
+    let (width, height) = image.get_dimensions();
+    let started = Instant::now();
+    for address in addresses {
+        let req = PixelRequest::from_ipv6(black_box(address));
+        if req.is_within(width, height) {
+            let (x, y) = req.pos;
+            image.put_block(x as _, y as _, req.color, req.size as _);
+        }
+    }
+    black_box(image.get(0, 0));
+    started.elapsed()
+}
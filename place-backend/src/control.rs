@@ -0,0 +1,92 @@
+use std::{io::ErrorKind, os::unix::fs::FileTypeExt, path::PathBuf};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+
+use crate::{admin::AdminCommand, settings::Settings, PResult, SharedContext};
+
+/// Unix domain socket accepting admin commands, one per line.
+///
+/// Every command gets a single line response, either `ok <json>` or `error <message>`.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn new(settings: &Settings) -> PResult<Option<ControlSocket>> {
+        let path = match &settings.admin.control_socket {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(None),
+        };
+
+        // Remove a stale socket left behind by a previous run, but nothing else that's in the way.
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&path)?,
+            Ok(_) => {
+                return Err(format!(
+                    "{} already exists and isn't a socket, refusing to replace it.",
+                    path.display()
+                )
+                .into())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        log::info!("Control socket listening on {}", path.display());
+
+        Ok(Some(ControlSocket { listener, path }))
+    }
+
+    async fn handle_connection(stream: UnixStream, shared_context: SharedContext) -> PResult<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let command = line.trim();
+            if command.is_empty() {
+                continue;
+            }
+
+            let response = match AdminCommand::parse(command) {
+                Some(command) => match command.execute(&shared_context).await {
+                    Ok(result) => format!("ok {}\n", result),
+                    Err(e) => format!("error {}\n", e),
+                },
+                None => format!("error Unknown command: {}\n", command),
+            };
+
+            writer.write_all(response.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn run(&self, shared_context: SharedContext) -> PResult<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let shared_context = shared_context.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = ControlSocket::handle_connection(stream, shared_context).await {
+                    log::error!("Error in control socket connection: {}", e);
+                }
+            });
+        }
+    }
+
+    pub fn start_server(self, shared_context: SharedContext) -> JoinHandle<PResult<()>> {
+        tokio::spawn(async move { self.run(shared_context).await })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,86 @@
+use std::{fmt::Write, path::Path};
+
+use image::{imageops, RgbaImage};
+
+use crate::{place::Place, PResult};
+
+/// Width of the output if neither `--width` nor `COLUMNS` is given.
+const DEFAULT_COLUMNS: u32 = 80;
+
+/// Entry point of `place-backend dump <file> [--width <columns>]`, which prints a canvas file
+/// to the terminal without starting the server.
+pub fn run(mut args: impl Iterator<Item = String>) -> PResult<()> {
+    const USAGE: &str = "Usage: place-backend dump <file> [--width <columns>]";
+
+    let mut path = None;
+    let mut columns = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(DEFAULT_COLUMNS);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--width" => columns = args.next().ok_or(USAGE)?.parse()?,
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let path = Path::new(&path);
+    let image = Place::load_image(path, Place::canvas_format(path)?)?;
+    print!("{}", render(&image, columns));
+    Ok(())
+}
+
+/// Renders the image downscaled to `columns` characters wide using 24-bit ANSI colors.
+/// Each character shows two pixels stacked on top of each other, so pixels stay square.
+pub fn render(image: &RgbaImage, columns: u32) -> String {
+    let width = columns.clamp(1, image.width().max(1));
+    // With an odd number of rows, the bottom half of the last line is left blank.
+    let height =
+        ((image.height() as u64 * width as u64 / image.width().max(1) as u64) as u32).max(1);
+    let image = imageops::resize(image, width, height, imageops::FilterType::Triangle);
+
+    let mut out = String::new();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let [r, g, b, _] = image.get_pixel(x, y).0;
+            let _ = write!(out, "\x1b[38;2;{};{};{}m", r, g, b);
+            match image.get_pixel_checked(x, y + 1) {
+                Some(pixel) => {
+                    let [r, g, b, _] = pixel.0;
+                    let _ = write!(out, "\x1b[48;2;{};{};{}m\u{2580}", r, g, b);
+                }
+                None => out.push_str("\x1b[49m\u{2580}"),
+            }
+        }
+        out.push_str("\x1b[0m\n");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn render_half_blocks() {
+        let mut image = RgbaImage::from_pixel(2, 2, Rgba([255, 0, 0, 255]));
+        image.put_pixel(0, 1, Rgba([0, 0, 255, 255]));
+        image.put_pixel(1, 1, Rgba([0, 0, 255, 255]));
+
+        let out = render(&image, 80);
+        assert_eq!(out.lines().count(), 1);
+        assert_eq!(out.matches('\u{2580}').count(), 2);
+        assert!(out.starts_with("\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m\u{2580}"));
+        assert!(out.ends_with("\x1b[0m\n"));
+
+        // Odd number of rows leaves the bottom half of the last line empty.
+        let out = render(&RgbaImage::new(3, 3), 3);
+        assert_eq!(out.lines().count(), 2);
+        assert!(out.contains("\x1b[49m"));
+    }
+}
@@ -0,0 +1,264 @@
+use std::fmt::Write;
+
+use crate::{
+    backend::schema::{EncodingSchema, FieldSchema},
+    settings::{Origin, Settings},
+    PResult,
+};
+
+/// Pixel encoded by the example at the end of the snippets: a red pixel of size 1 at (1, 2).
+const EXAMPLE: [u16; 6] = [1, 1, 2, 0xff, 0, 0];
+
+/// Entry point of `place-backend gen-client <rust|python>`, which prints a pixel address encoder
+/// for the configured prefix and canvas. The snippets are built from the same encoding schema as
+/// /prefixes.json, so they follow the decoder.
+pub fn run(mut args: impl Iterator<Item = String>) -> PResult<()> {
+    const USAGE: &str = "Usage: place-backend gen-client <rust|python> [--config <path>]";
+
+    let mut lang = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Read by Settings::new.
+            "--config" => {
+                args.next().ok_or(USAGE)?;
+            }
+            _ if arg.starts_with("--config=") => {}
+            _ if lang.is_none() => lang = Some(arg),
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let settings = Settings::new()?;
+    let schema = EncodingSchema::new(&settings);
+    let snippet = match lang.as_deref() {
+        Some("rust") => rust(&settings, &schema),
+        Some("python") => python(&settings, &schema),
+        _ => return Err(USAGE.into()),
+    };
+    print!("{}", snippet);
+    Ok(())
+}
+
+/// Subnets of the pixel sizes as their first four address segments, in order of size.
+fn subnets(schema: &EncodingSchema) -> Vec<[u16; 4]> {
+    schema
+        .prefixes
+        .iter()
+        .map(|prefix| {
+            let addr: std::net::Ipv6Addr =
+                prefix.prefix.split('/').next().unwrap().parse().unwrap();
+            addr.segments()[..4].try_into().unwrap()
+        })
+        .collect()
+}
+
+/// Encodes the example pixel the same way the snippets do, to show the expected output.
+fn example_address(schema: &EncodingSchema) -> std::net::Ipv6Addr {
+    let mut segments = [0; 8];
+    segments[..4].copy_from_slice(&subnets(schema)[EXAMPLE[0] as usize - 1]);
+    for field in schema.fields.iter().filter(|field| field.name != "size") {
+        let value = EXAMPLE[index(field)];
+        segments[field.segment as usize] |= (value << field.mask.trailing_zeros()) & field.mask;
+    }
+    segments.into()
+}
+
+/// Position of the field in the encoder arguments and `EXAMPLE`.
+fn index(field: &FieldSchema) -> usize {
+    ["size", "x", "y", "r", "g", "b"]
+        .iter()
+        .position(|&name| name == field.name)
+        .unwrap()
+}
+
+/// Comment lines describing what the snippet encodes for, prefixed with `comment`.
+fn header(settings: &Settings, schema: &EncodingSchema, lang: &str, comment: &str) -> String {
+    let mode = schema.coordinate_mode;
+    let scale = mode.scale.max(1) as u32;
+    let mut lines = vec![
+        format!(
+            "Pixel address encoder for the canvas at {}/48, generated by `place-backend gen-client {}`.",
+            settings.backend.prefix48, lang
+        ),
+        format!(
+            "Place a pixel by sending {} to its address.",
+            match (settings.backend.enable_icmp, settings.backend.enable_udp) {
+                (true, true) => "an ICMPv6 echo request or a UDP packet to port 7",
+                (true, false) => "an ICMPv6 echo request",
+                _ => "a UDP packet to port 7",
+            }
+        ),
+        format!(
+            "Coordinates address a {}x{} grid with the origin in the {} corner{}.",
+            schema.canvas_width / scale,
+            schema.canvas_height / scale,
+            match mode.origin {
+                Origin::TopLeft => "top left",
+                Origin::BottomLeft => "bottom left",
+            },
+            if mode.swap_axes { ", x and y swapped" } else { "" }
+        ),
+    ];
+    if scale > 1 {
+        lines.push(format!(
+            "Every cell covers {}x{} pixels of the {}x{} canvas.",
+            scale, scale, schema.canvas_width, schema.canvas_height
+        ));
+    }
+    if let Some(payload) = &schema.icmp_payload {
+        lines.push(format!(
+            "Echo request payloads have to start with the bytes {}.",
+            payload
+        ));
+    }
+
+    lines
+        .iter()
+        .map(|line| format!("{} {}\n", comment, line))
+        .collect()
+}
+
+fn rust(settings: &Settings, schema: &EncodingSchema) -> String {
+    let scale = schema.coordinate_mode.scale.max(1) as u32;
+    let mut out = header(settings, schema, "rust", "//");
+    out.push_str("\nuse std::net::Ipv6Addr;\n\n");
+    let _ = writeln!(
+        out,
+        "pub const WIDTH: u16 = {};",
+        schema.canvas_width / scale
+    );
+    let _ = writeln!(
+        out,
+        "pub const HEIGHT: u16 = {};",
+        schema.canvas_height / scale
+    );
+    out.push_str("/// First four address segments of the subnets of pixel sizes 1 and 2.\n");
+    let _ = writeln!(out, "const SUBNETS: [[u16; 4]; 2] = [");
+    for subnet in subnets(schema) {
+        let _ = writeln!(
+            out,
+            "    [{:#06x}, {:#06x}, {:#06x}, {:#06x}],",
+            subnet[0], subnet[1], subnet[2], subnet[3]
+        );
+    }
+    out.push_str("];\n\n");
+
+    out.push_str("/// Returns the address placing a pixel of `size` 1 or 2 at (x, y) with the given color.\n");
+    let _ = writeln!(
+        out,
+        "pub fn pixel_address(size: u8, x: u16, y: u16, r: u16, g: u16, b: u16) -> Ipv6Addr {{"
+    );
+    out.push_str("    let mut segments = [0u16; 8];\n");
+    out.push_str("    segments[..4].copy_from_slice(&SUBNETS[size.clamp(1, 2) as usize - 1]);\n");
+    for field in schema.fields.iter().filter(|field| field.name != "size") {
+        let _ = writeln!(
+            out,
+            "    segments[{}] |= {} & {:#06x};",
+            field.segment,
+            shifted(field.name, field.mask),
+            field.mask
+        );
+    }
+    out.push_str("    Ipv6Addr::from(segments)\n}\n\n");
+
+    let [size, x, y, r, g, b] = EXAMPLE;
+    out.push_str("fn main() {\n");
+    let _ = writeln!(out, "    // Prints {}", example_address(schema));
+    let _ = writeln!(
+        out,
+        "    println!(\"{{}}\", pixel_address({}, {}, {}, {:#x}, {:#x}, {:#x}));",
+        size, x, y, r, g, b
+    );
+    out.push_str("}\n");
+    out
+}
+
+fn python(settings: &Settings, schema: &EncodingSchema) -> String {
+    let scale = schema.coordinate_mode.scale.max(1) as u32;
+    let mut out = header(settings, schema, "python", "#");
+    out.push_str("\nimport ipaddress\n\n");
+    let _ = writeln!(out, "WIDTH = {}", schema.canvas_width / scale);
+    let _ = writeln!(out, "HEIGHT = {}", schema.canvas_height / scale);
+    out.push_str("# First four address segments of the subnets of pixel sizes 1 and 2.\n");
+    out.push_str("SUBNETS = [\n");
+    for subnet in subnets(schema) {
+        let _ = writeln!(
+            out,
+            "    ({:#06x}, {:#06x}, {:#06x}, {:#06x}),",
+            subnet[0], subnet[1], subnet[2], subnet[3]
+        );
+    }
+    out.push_str("]\n\n\n");
+
+    out.push_str("def pixel_address(size, x, y, r, g, b):\n");
+    out.push_str(
+        "    \"\"\"Returns the address placing a pixel of size 1 or 2 at (x, y) with the given color.\"\"\"\n",
+    );
+    out.push_str("    segments = list(SUBNETS[min(max(size, 1), 2) - 1]) + [0] * 4\n");
+    for field in schema.fields.iter().filter(|field| field.name != "size") {
+        let _ = writeln!(
+            out,
+            "    segments[{}] |= {} & {:#06x}",
+            field.segment,
+            shifted(field.name, field.mask),
+            field.mask
+        );
+    }
+    out.push_str(
+        "    return ipaddress.IPv6Address(b\"\".join(s.to_bytes(2, \"big\") for s in segments))\n\n\n",
+    );
+
+    let [size, x, y, r, g, b] = EXAMPLE;
+    out.push_str("if __name__ == \"__main__\":\n");
+    let _ = writeln!(out, "    # Prints {}", example_address(schema));
+    let _ = writeln!(
+        out,
+        "    print(pixel_address({}, {}, {}, {:#x}, {:#x}, {:#x}))",
+        size, x, y, r, g, b
+    );
+    out
+}
+
+/// The argument shifted into the position of the field's mask.
+fn shifted(name: &str, mask: u16) -> String {
+    match mask.trailing_zeros() {
+        0 => name.to_string(),
+        shift => format!("({} << {})", name, shift),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::{Config, File, FileFormat};
+
+    use super::*;
+    use crate::backend::PixelRequest;
+
+    #[test]
+    fn generated_encoders() {
+        let settings: Settings = Config::builder()
+            .add_source(File::from_str(
+                include_str!("../config.toml.example"),
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let schema = EncodingSchema::new(&settings);
+
+        // The example in the snippets decodes to the pixel it encodes.
+        let req = PixelRequest::from_ipv6(&example_address(&schema));
+        assert_eq!((req.size, req.pos), (1, (1, 2)));
+        assert_eq!(req.color, crate::utils::Color::rgb(0xff, 0, 0));
+
+        let rust = rust(&settings, &schema);
+        assert!(rust.contains("    [0x2602, 0xfa9b, 0x0042, 0x2000],\n"));
+        assert!(rust.contains("    segments[3] |= x & 0x0fff;\n"));
+        assert!(rust.contains("    // Prints 2602:fa9b:42:1001:2:ff::\n"));
+
+        let python = python(&settings, &schema);
+        assert!(python.contains("    segments[7] |= b & 0x00ff\n"));
+        assert!(python.contains("# Prints 2602:fa9b:42:1001:2:ff::\n"));
+    }
+}
@@ -0,0 +1,149 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+use hyper::{
+    body::Bytes,
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, VARY},
+    http::response::Builder,
+    Request,
+};
+
+/// Bodies smaller than this aren't worth compressing.
+const MIN_SIZE: usize = 256;
+
+/// Checks whether the request's `Accept-Encoding` header allows gzip.
+pub fn accepts_gzip<B>(request: &Request<B>) -> bool {
+    request
+        .headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            (name.eq_ignore_ascii_case("gzip") || name == "*")
+                // A quality of 0 means "not acceptable".
+                && !params.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        == Some(0.0)
+                })
+        })
+}
+
+/// Compresses `data` with gzip at the default level.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing to a Vec can't fail.
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Whether compressing a body of the content type makes it smaller, unlike eg. PNG images.
+pub fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || ["application/json", "application/wasm", "image/svg+xml"]
+            .iter()
+            .any(|t| content_type.starts_with(t))
+}
+
+/// Adds the headers of a body which is compressed if the client accepts it.
+fn with_headers(builder: Builder, gzip: bool) -> Builder {
+    let builder = builder.header(VARY, "Accept-Encoding");
+    if gzip {
+        builder.header(CONTENT_ENCODING, "gzip")
+    } else {
+        builder
+    }
+}
+
+/// Compresses `data` on the fly if the client accepts gzip and it's large enough to benefit.
+pub fn encode<B>(request: &Request<B>, builder: Builder, data: Vec<u8>) -> (Builder, Vec<u8>) {
+    let gzip = data.len() >= MIN_SIZE && accepts_gzip(request);
+    let data = if gzip { compress(&data) } else { data };
+    (with_headers(builder, gzip), data)
+}
+
+/// A body which never changes, compressed once upfront.
+pub struct Precompressed {
+    plain: Bytes,
+    gzip: Bytes,
+}
+
+impl Precompressed {
+    pub fn new(plain: impl Into<Bytes>) -> Precompressed {
+        let plain = plain.into();
+        Precompressed {
+            gzip: Bytes::from(compress(&plain)),
+            plain,
+        }
+    }
+
+    /// Picks the compressed body if the client accepts it.
+    pub fn encode<B>(&self, request: &Request<B>, builder: Builder) -> (Builder, Bytes) {
+        let gzip = accepts_gzip(request);
+        let data = if gzip { &self.gzip } else { &self.plain };
+        (with_headers(builder, gzip), data.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use hyper::Response;
+
+    use super::*;
+
+    fn request(accept_encoding: &str) -> Request<()> {
+        Request::builder()
+            .header(ACCEPT_ENCODING, accept_encoding)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn gzip_responses() {
+        assert!(accepts_gzip(&request("gzip")));
+        assert!(accepts_gzip(&request("br;q=1.0, GZIP;q=0.5")));
+        assert!(accepts_gzip(&request("*")));
+        assert!(!accepts_gzip(&request("gzip;q=0, deflate")));
+        assert!(!accepts_gzip(&Request::new(())));
+
+        let json = "{\"ipv6_prefix\": \"2602:fa9b:42::\"}".repeat(16);
+        let precompressed = Precompressed::new(json.clone());
+        let (builder, body) = precompressed.encode(&request("gzip"), Response::builder());
+        let response = builder.body(()).unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "Accept-Encoding");
+        let mut decompressed = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, json);
+
+        let (builder, body) = precompressed.encode(&request("br"), Response::builder());
+        assert!(builder
+            .body(())
+            .unwrap()
+            .headers()
+            .get(CONTENT_ENCODING)
+            .is_none());
+        assert_eq!(body, json);
+
+        // Tiny bodies are sent as they are.
+        let (builder, body) = encode(&request("gzip"), Response::builder(), b"{}".to_vec());
+        assert!(builder
+            .body(())
+            .unwrap()
+            .headers()
+            .get(CONTENT_ENCODING)
+            .is_none());
+        assert_eq!(body, b"{}");
+        assert!(is_compressible("text/javascript; charset=utf-8"));
+        assert!(!is_compressible("image/png"));
+    }
+}
@@ -1,33 +1,122 @@
 use futures::stream::StreamExt;
 use signal_hook::consts::signal::*;
 use signal_hook_tokio::Signals;
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicU32, Arc},
+};
 use tokio::{sync::broadcast, task::JoinSet};
 
+mod admin;
 mod backend;
+mod bench;
+mod control;
+mod dump;
+mod gen_client;
+mod gzip;
+mod metrics;
+mod mmap;
 mod place;
+mod selftest;
 mod settings;
+mod static_files;
+mod supervisor;
+mod svg;
+mod timelapse;
+mod tls;
 mod utils;
 mod websocket;
 
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
 
-#[cfg(not(target_env = "msvc"))]
+#[cfg(all(not(target_env = "msvc"), not(feature = "bench")))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+#[cfg(all(not(target_env = "msvc"), feature = "bench"))]
+#[global_allocator]
+static GLOBAL: bench::CountingAllocator<Jemalloc> = bench::CountingAllocator(Jemalloc);
+
+#[cfg(all(target_env = "msvc", feature = "bench"))]
+#[global_allocator]
+static GLOBAL: bench::CountingAllocator<std::alloc::System> =
+    bench::CountingAllocator(std::alloc::System);
+
+const USAGE: &str = "Usage: place-backend [--config <path>] [--dry-run] [--selftest], or \
+                     place-backend dump|bench|gen-client|timelapse [args...]";
+
 pub type PResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
 
+/// Capacity of the event broadcast channel. Receivers that fall further behind than this
+/// skip ahead to the most recent events.
+const EVENT_CHANNEL_CAPACITY: usize = 16;
+
+/// Events broadcasted to all connected clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// Number of pixels placed during the last second, and its exponential moving average.
+    Pps { raw: u32, smoothed: f32 },
+}
+
 pub struct SharedContext {
     pub image: place::SharedImageHandle,
-    pub pps_receiver: broadcast::Receiver<u32>,
+    pub place: Arc<place::Place>,
+    pub event_receiver: broadcast::Receiver<Event>,
+    pub connection_count: Arc<AtomicU32>,
+    pub packet_counter: Arc<backend::PacketCounter>,
+    pub frame_channels: place::FrameChannels,
+    pub pixel_queue: backend::writer::PixelQueue,
+    pub queue_monitor: backend::writer::QueueMonitor,
+    pub pixel_history: Option<Arc<backend::history::PixelHistory>>,
+    /// Additional canvases, by name.
+    pub canvases: Arc<HashMap<String, CanvasHandles>>,
+    /// Name of the additional canvas this context is for, `None` for the main canvas.
+    pub canvas: Option<Arc<str>>,
+}
+
+/// The parts of an additional canvas requests can be served from.
+pub struct CanvasHandles {
+    pub place: Arc<place::Place>,
+    pub pixel_queue: backend::writer::PixelQueue,
+    pub queue_monitor: backend::writer::QueueMonitor,
+}
+
+impl SharedContext {
+    /// Returns a context serving the additional canvas of the given name instead of the main one.
+    pub fn for_canvas(&self, name: &str) -> Option<SharedContext> {
+        let (name, handles) = self.canvases.get_key_value(name)?;
+        Some(SharedContext {
+            image: handles.place.image.clone(),
+            place: handles.place.clone(),
+            event_receiver: self.event_receiver.resubscribe(),
+            connection_count: self.connection_count.clone(),
+            packet_counter: self.packet_counter.clone(),
+            frame_channels: handles.place.frame_channels.clone(),
+            pixel_queue: handles.pixel_queue.clone(),
+            queue_monitor: handles.queue_monitor.clone(),
+            // Only the main canvas keeps a pixel history.
+            pixel_history: None,
+            canvases: self.canvases.clone(),
+            canvas: Some(name.as_str().into()),
+        })
+    }
 }
 
 impl Clone for SharedContext {
     fn clone(&self) -> Self {
         Self {
             image: self.image.clone(),
-            pps_receiver: self.pps_receiver.resubscribe(),
+            place: self.place.clone(),
+            event_receiver: self.event_receiver.resubscribe(),
+            connection_count: self.connection_count.clone(),
+            packet_counter: self.packet_counter.clone(),
+            frame_channels: self.frame_channels.clone(),
+            pixel_queue: self.pixel_queue.clone(),
+            queue_monitor: self.queue_monitor.clone(),
+            pixel_history: self.pixel_history.clone(),
+            canvases: self.canvases.clone(),
+            canvas: self.canvas.clone(),
         }
     }
 }
@@ -39,27 +128,320 @@ async fn main() -> PResult<()> {
         .filter_level(log_level.parse()?)
         .try_init()?;
 
-    let settings = settings::Settings::new()?;
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("dump") => return dump::run(args.skip(1)),
+        Some("bench") => return bench::run(args.skip(1)),
+        Some("gen-client") => return gen_client::run(args.skip(1)),
+        Some("timelapse") => return timelapse::run(args.skip(1)),
+        _ => {}
+    }
+
+    // With --dry-run, pixels are parsed and logged as usual, but not written to the canvas.
+    let (mut dry_run, mut selftest) = (false, false);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--selftest" => selftest = true,
+            // Read by `Settings::new`.
+            "--config" => {
+                args.next().ok_or("Missing path after --config argument.")?;
+            }
+            _ if arg.starts_with("--config=") => {}
+            _ => return Err(format!("Unknown argument {}. {}", arg, USAGE).into()),
+        }
+    }
+
+    let settings = Arc::new(settings::Settings::new()?);
     log::info!("settings = {:?}", settings);
+    if dry_run {
+        log::warn!("Dry run, received pixels are logged but not written to the canvas.");
+    }
 
-    let place = place::Place::new(&settings.canvas)?;
+    let packet_counter = backend::PacketCounter::new(&settings);
+    let mut place = place::Place::new(&settings.canvas)?
+        .with_frame_channel_capacity(settings.websocket.frame_channel_capacity)
+        .with_frame_compression(
+            settings.websocket.frame_compression,
+            settings
+                .websocket
+                .keyframe_compression
+                .unwrap_or(settings.websocket.frame_compression),
+        )
+        .with_preview(
+            settings.websocket.preview_dimension,
+            std::time::Duration::from_millis(settings.websocket.preview_interval_ms),
+        );
+    if settings.canvas.save_metadata {
+        place = place.with_metadata(place::SaveMetadata {
+            prefix48: settings.backend.prefix48,
+            packet_counter: packet_counter.clone(),
+        });
+    }
+    let place = Arc::new(place);
     let websocket = websocket::WebSocketServer::new(&settings).await?;
-    let packet_counter = backend::PacketCounter::new();
-    let backend = backend::backend_factory(&settings, place.image.clone(), packet_counter.clone())?;
-    let (pps_sender, pps_receiver) = broadcast::channel::<u32>(1);
+    let control_socket = control::ControlSocket::new(&settings)?;
+    let pixel_history = backend::history::PixelHistory::new(settings.websocket.event_history_size);
+    let protected_regions =
+        backend::acl::ProtectedRegions::new(&settings.backend.protected_regions)?;
+    if let Some(protected_regions) = &protected_regions {
+        protected_regions.stamp(&place.image);
+    }
+    let (pixel_queue, canvas_writer) = backend::writer::pixel_queue(
+        &settings,
+        place.image.clone(),
+        &settings.backend.regions,
+        protected_regions.clone(),
+    );
+    let canvas_writer = canvas_writer.with_history(pixel_history.clone());
+    pixel_queue.monitor().set_dry_run(dry_run);
+
+    let mut router = backend::CanvasRouter::new(backend::CanvasRoute::new(
+        settings.backend.prefix48,
+        &settings.canvas,
+        pixel_queue.clone(),
+    ));
+    let mut canvases = HashMap::new();
+    let mut canvas_writers = Vec::new();
+    for named in &settings.canvases {
+        let mut place = place::Place::new(&named.canvas)?
+            .with_frame_channel_capacity(settings.websocket.frame_channel_capacity)
+            .with_frame_compression(
+                settings.websocket.frame_compression,
+                settings
+                    .websocket
+                    .keyframe_compression
+                    .unwrap_or(settings.websocket.frame_compression),
+            )
+            .with_preview(
+                settings.websocket.preview_dimension,
+                std::time::Duration::from_millis(settings.websocket.preview_interval_ms),
+            );
+        if named.canvas.save_metadata {
+            place = place.with_metadata(place::SaveMetadata {
+                prefix48: named.prefix48,
+                packet_counter: packet_counter.clone(),
+            });
+        }
+        let place = Arc::new(place);
+        let (queue, writer) =
+            backend::writer::pixel_queue(&settings, place.image.clone(), &[], None);
+        queue.monitor().set_dry_run(dry_run);
+        router.add(backend::CanvasRoute::new(
+            named.prefix48,
+            &named.canvas,
+            queue.clone(),
+        ));
+        canvases.insert(
+            named.name.clone(),
+            CanvasHandles {
+                place,
+                pixel_queue: queue.clone(),
+                queue_monitor: queue.monitor(),
+            },
+        );
+        canvas_writers.push(writer);
+    }
+    let canvases = Arc::new(canvases);
+
+    let backend = backend::backend_factory(&settings, router.clone(), packet_counter.clone())?;
+    let udp_bridge = if settings.udp_bridge.enabled {
+        Some(backend::udp_bridge::UdpBridge::new(
+            &settings,
+            pixel_queue.clone(),
+            packet_counter.clone(),
+        )?)
+    } else {
+        None
+    };
+    #[cfg(feature = "backend-smoltcp")]
+    let ndp_responder = backend::ndp::NdpResponder::new(&settings)?;
+    let (event_sender, event_receiver) = broadcast::channel::<Event>(EVENT_CHANNEL_CAPACITY);
 
     let mut join_set = JoinSet::new();
 
     let shared_context = SharedContext {
         image: place.image.clone(),
-        pps_receiver,
+        place: place.clone(),
+        event_receiver,
+        connection_count: Arc::new(AtomicU32::new(0)),
+        packet_counter: packet_counter.clone(),
+        frame_channels: place.frame_channels.clone(),
+        pixel_queue: pixel_queue.clone(),
+        queue_monitor: pixel_queue.monitor(),
+        pixel_history,
+        canvases: canvases.clone(),
+        canvas: None,
     };
-    let diffing_task = place.start_diffing_task();
 
-    join_set.spawn(async move { packet_counter.start_pps_counter(pps_sender).await? });
-    join_set.spawn(async move { websocket.start_server(shared_context).await? });
-    join_set.spawn(async move { diffing_task.await? });
-    join_set.spawn(async move { backend.start().await? });
+    // Everything but the canvas writer can be set up again from scratch, so a transient failure
+    // (eg. a network interface going away) only restarts the affected task.
+    {
+        let packet_counter = packet_counter.clone();
+        let event_sender = event_sender.clone();
+        let handle = packet_counter
+            .clone()
+            .start_pps_counter(event_sender.clone());
+        join_set.spawn(supervisor::supervise("pps counter", handle, move || {
+            let handle = packet_counter
+                .clone()
+                .start_pps_counter(event_sender.clone());
+            async move { Ok(handle) }
+        }));
+    }
+    if let Some(control_socket) = control_socket {
+        let settings = settings.clone();
+        let shared_context = shared_context.clone();
+        let handle = control_socket.start_server(shared_context.clone());
+        join_set.spawn(supervisor::supervise("control socket", handle, move || {
+            let (settings, shared_context) = (settings.clone(), shared_context.clone());
+            async move {
+                let control_socket =
+                    control::ControlSocket::new(&settings)?.ok_or("Control socket is disabled")?;
+                Ok(control_socket.start_server(shared_context))
+            }
+        }));
+    }
+    {
+        let settings = settings.clone();
+        let shared_context = shared_context.clone();
+        let handle = websocket.start_server(shared_context.clone());
+        join_set.spawn(supervisor::supervise("websocket", handle, move || {
+            let (settings, shared_context) = (settings.clone(), shared_context.clone());
+            async move {
+                let websocket = websocket::WebSocketServer::new(&settings).await?;
+                Ok(websocket.start_server(shared_context))
+            }
+        }));
+    }
+    let diffed = std::iter::once(place.clone()).chain(canvases.values().map(|c| c.place.clone()));
+    for place in diffed {
+        let max_dimension = settings.websocket.max_stream_dimension;
+        let flicker_window = (settings.websocket.flicker_window_ms > 0)
+            .then(|| std::time::Duration::from_millis(settings.websocket.flicker_window_ms));
+        let encode_buffer_limit = settings.websocket.encode_buffer_limit;
+        let handle = place.start_diffing_task(max_dimension, flicker_window, encode_buffer_limit);
+        join_set.spawn(supervisor::supervise("diffing", handle, move || {
+            let handle =
+                place.start_diffing_task(max_dimension, flicker_window, encode_buffer_limit);
+            async move { Ok(handle) }
+        }));
+    }
+    let monitors = std::iter::once(pixel_queue.monitor())
+        .chain(canvases.values().map(|c| c.queue_monitor.clone()));
+    for overlay in monitors
+        .clone()
+        .filter_map(|monitor| monitor.cooldown_overlay())
+    {
+        let handle = overlay.clone().start_ticker();
+        join_set.spawn(supervisor::supervise(
+            "cooldown overlay",
+            handle,
+            move || {
+                let handle = overlay.clone().start_ticker();
+                async move { Ok(handle) }
+            },
+        ));
+    }
+    for rate_grid in monitors.filter_map(|monitor| monitor.rate_grid()) {
+        let handle = rate_grid.clone().start_ticker();
+        join_set.spawn(supervisor::supervise("rate grid", handle, move || {
+            let handle = rate_grid.clone().start_ticker();
+            async move { Ok(handle) }
+        }));
+    }
+    if let Some(protected_regions) =
+        protected_regions.filter(|_| settings.backend.protected_restamp_secs > 0)
+    {
+        let image = place.image.clone();
+        let interval = std::time::Duration::from_secs(settings.backend.protected_restamp_secs);
+        let handle = protected_regions
+            .clone()
+            .start_restamp(image.clone(), interval);
+        join_set.spawn(supervisor::supervise("watermark", handle, move || {
+            let handle = protected_regions
+                .clone()
+                .start_restamp(image.clone(), interval);
+            async move { Ok(handle) }
+        }));
+    }
+    // The writers aren't supervised, they own the pending pixels and only return once the queues
+    // are gone. A panic in one aborts the process in release builds, and ends it below otherwise.
+    for canvas_writer in std::iter::once(canvas_writer).chain(canvas_writers) {
+        join_set.spawn(async move {
+            canvas_writer
+                .start()
+                .await
+                .map_err(|e| format!("Canvas writer failed: {}", e))?
+        });
+    }
+    {
+        let settings = settings.clone();
+        let packet_counter = packet_counter.clone();
+        join_set.spawn(supervisor::supervise(
+            "backend",
+            backend.start(),
+            move || {
+                let (settings, router, packet_counter) =
+                    (settings.clone(), router.clone(), packet_counter.clone());
+                async move {
+                    let backend = backend::backend_factory(&settings, router, packet_counter)?;
+                    Ok(backend.start())
+                }
+            },
+        ));
+    }
+    if let Some(udp_bridge) = udp_bridge {
+        let settings = settings.clone();
+        join_set.spawn(supervisor::supervise(
+            "udp bridge",
+            udp_bridge.start(),
+            move || {
+                let (settings, pixel_queue, packet_counter) = (
+                    settings.clone(),
+                    pixel_queue.clone(),
+                    packet_counter.clone(),
+                );
+                async move {
+                    let udp_bridge = backend::udp_bridge::UdpBridge::new(
+                        &settings,
+                        pixel_queue,
+                        packet_counter,
+                    )?;
+                    Ok(udp_bridge.start())
+                }
+            },
+        ));
+    }
+
+    #[cfg(feature = "backend-smoltcp")]
+    if let Some(ndp_responder) = ndp_responder {
+        let settings = settings.clone();
+        join_set.spawn(supervisor::supervise(
+            "ndp responder",
+            ndp_responder.start(),
+            move || {
+                let settings = settings.clone();
+                async move {
+                    let ndp_responder = backend::ndp::NdpResponder::new(&settings)?
+                        .ok_or("NDP responder is disabled")?;
+                    Ok(ndp_responder.start())
+                }
+            },
+        ));
+    }
+
+    if selftest {
+        let (settings, image) = (settings.clone(), place.image.clone());
+        let (pixel_queue, packet_counter) = (
+            shared_context.pixel_queue.clone(),
+            shared_context.packet_counter.clone(),
+        );
+        tokio::spawn(async move {
+            let passed = selftest::run(&settings, &image, &pixel_queue, &packet_counter).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        });
+    }
 
     // We need to gracefully handle SIGINT and SIGQUIT, needed so saving PGO data works properly.
     // Also we can use this to save the image on exit.
@@ -73,14 +455,37 @@ async fn main() -> PResult<()> {
         }
 
         handle.close();
-        if let Err(e) = place.save() {
-            log::error!("Failed to save image: {}", e);
+        // A slow disk gives up after the save timeout rather than keeping the process around.
+        let result = place.write_in_background(|p| p.save().map(|_| ())).await;
+        match result {
+            Ok(()) => {}
+            Err(e) if e.is::<place::WriteInProgress>() => log::warn!("Saving image: {}", e),
+            Err(e) => log::error!("Failed to save image: {}", place::describe_save_error(&*e)),
+        }
+        for (name, canvas) in canvases.iter() {
+            let result = canvas
+                .place
+                .write_in_background(|p| p.save().map(|_| ()))
+                .await;
+            match result {
+                Ok(()) => {}
+                Err(e) if e.is::<place::WriteInProgress>() => {
+                    log::warn!("Saving canvas {}: {}", name, e)
+                }
+                Err(e) => log::error!(
+                    "Failed to save canvas {}: {}",
+                    name,
+                    place::describe_save_error(&*e)
+                ),
+            }
         }
         log::info!("Canvas saved.");
 
         std::process::exit(0);
     });
 
+    // Supervised tasks restart themselves when they return an error, so only unrecoverable
+    // failures end up here.
     while let Some(result) = join_set.join_next().await {
         result??;
     }
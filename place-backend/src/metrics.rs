@@ -0,0 +1,88 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds of histogram buckets, in milliseconds.
+const BUCKETS_MS: [u64; 10] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+/// Lock-free histogram of durations, rendered in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Observations per bucket, the last one catching everything above the largest bound.
+    buckets: [AtomicU64; BUCKETS_MS.len() + 1],
+    sum_us: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let ms = duration.as_secs_f64() * 1000.0;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|&bound| ms <= bound as f64)
+            .unwrap_or(BUCKETS_MS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Appends the samples of the histogram to `out`, with `labels` in form of `key="value"`.
+    /// The `# TYPE` line has to be written by the caller, once per metric name.
+    pub fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let separator = if labels.is_empty() { "" } else { "," };
+
+        // Prometheus buckets are cumulative.
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS_MS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, cumulative
+            );
+        }
+        cumulative += self.buckets[BUCKETS_MS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, cumulative
+        );
+
+        let sum_ms = self.sum_us.load(Ordering::Relaxed) as f64 / 1000.0;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum_ms);
+        let _ = writeln!(
+            out,
+            "{}_count{{{}}} {}",
+            name,
+            labels,
+            self.count.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(7));
+        histogram.observe(Duration::from_secs(2));
+
+        let mut out = String::new();
+        histogram.render("encode_ms", "format=\"png\"", &mut out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "encode_ms_bucket{format=\"png\",le=\"1\"} 1");
+        assert_eq!(lines[3], "encode_ms_bucket{format=\"png\",le=\"10\"} 2");
+        assert_eq!(lines[9], "encode_ms_bucket{format=\"png\",le=\"1000\"} 2");
+        assert_eq!(lines[10], "encode_ms_bucket{format=\"png\",le=\"+Inf\"} 3");
+        assert_eq!(lines[11], "encode_ms_sum{format=\"png\"} 2007.5");
+        assert_eq!(lines[12], "encode_ms_count{format=\"png\"} 3");
+    }
+}
@@ -0,0 +1,130 @@
+use std::{fs::OpenOptions, path::Path};
+
+use memmap2::MmapMut;
+
+use crate::PResult;
+
+/// Magic bytes at the start of a raw canvas file.
+const MAGIC: &[u8; 8] = b"PLACERAW";
+/// Magic, followed by little endian u32 width and height. RGBA pixels follow right after.
+const HEADER_SIZE: usize = 16;
+
+/// A canvas stored as raw RGBA pixels in a memory-mapped file.
+///
+/// Pixel writes land directly in the page cache, so the canvas survives crashes of the server
+/// and saving only needs to flush dirty pages to disk.
+pub struct MappedCanvas {
+    mmap: MmapMut,
+}
+
+impl MappedCanvas {
+    /// Maps an existing canvas file. Returns `None` if the file doesn't exist.
+    pub fn open(path: &Path, width: u32, height: u32) -> PResult<Option<MappedCanvas>> {
+        let file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        if file.metadata()?.len() < HEADER_SIZE as u64 {
+            return Err(format!("{} is not a raw canvas file", path.display()).into());
+        }
+
+        // SAFETY: The file is expected to be used only by us.
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        if &mmap[..8] != MAGIC {
+            return Err(format!("{} is not a raw canvas file", path.display()).into());
+        }
+
+        let dimensions = (
+            u32::from_le_bytes(mmap[8..12].try_into().unwrap()),
+            u32::from_le_bytes(mmap[12..16].try_into().unwrap()),
+        );
+        if dimensions != (width, height) {
+            return Err(format!(
+                "Dimensions of {} do not match configured canvas size: {:?} != {:?}",
+                path.display(),
+                dimensions,
+                (width, height)
+            )
+            .into());
+        }
+
+        if mmap.len() != HEADER_SIZE + Self::pixels_size(width, height) {
+            return Err(format!("{} is truncated", path.display()).into());
+        }
+
+        Ok(Some(MappedCanvas { mmap }))
+    }
+
+    /// Creates a new canvas file, overwriting any existing one, and fills it with given pixels.
+    pub fn create(path: &Path, width: u32, height: u32, pixels: &[u8]) -> PResult<MappedCanvas> {
+        let size = Self::pixels_size(width, height);
+        if pixels.len() != size {
+            return Err("Pixel data doesn't match canvas dimensions".into());
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len((HEADER_SIZE + size) as u64)?;
+
+        // SAFETY: The file is expected to be used only by us.
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        mmap[..8].copy_from_slice(MAGIC);
+        mmap[8..12].copy_from_slice(&width.to_le_bytes());
+        mmap[12..16].copy_from_slice(&height.to_le_bytes());
+        mmap[HEADER_SIZE..].copy_from_slice(pixels);
+        mmap.flush()?;
+        file.sync_all()?;
+
+        Ok(MappedCanvas { mmap })
+    }
+
+    /// Synchronously writes all modified pixels to disk.
+    pub fn flush(&self) -> PResult<()> {
+        Ok(self.mmap.flush()?)
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.mmap[HEADER_SIZE..]
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [u8] {
+        &mut self.mmap[HEADER_SIZE..]
+    }
+
+    fn pixels_size(width: u32, height: u32) -> usize {
+        width as usize * height as usize * 4
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn create_and_reopen() {
+        let path = std::env::temp_dir().join(format!("place-mmap-test-{}.raw", std::process::id()));
+
+        let mut pixels = vec![255u8; 16 * 16 * 4];
+        pixels[..4].copy_from_slice(&[1, 2, 3, 4]);
+        let mut canvas = MappedCanvas::create(&path, 16, 16, &pixels).unwrap();
+        canvas.pixels_mut()[4..8].copy_from_slice(&[5, 6, 7, 8]);
+        canvas.flush().unwrap();
+        drop(canvas);
+
+        let canvas = MappedCanvas::open(&path, 16, 16).unwrap().unwrap();
+        assert_eq!(&canvas.pixels()[..8], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(canvas.pixels().len(), pixels.len());
+        drop(canvas);
+
+        assert!(MappedCanvas::open(&path, 32, 32).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(MappedCanvas::open(&path, 16, 16).unwrap().is_none());
+    }
+}
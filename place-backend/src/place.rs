@@ -1,8 +1,109 @@
-use image::{ImageBuffer, ImageFormat, Rgba, RgbaImage};
-use std::{cell::UnsafeCell, fs::File, io::BufReader, path::PathBuf, sync::Arc};
-use tokio::{sync::broadcast, task::JoinHandle};
+use hyper::body::Bytes;
+use image::{
+    codecs::png, imageops, ColorType, DynamicImage, ImageBuffer, ImageEncoder, ImageFormat,
+    ImageOutputFormat, ImageResult, Rgba, RgbaImage,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::UnsafeCell,
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader, Cursor},
+    net::Ipv6Addr,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{sync::broadcast, task::JoinHandle, time::MissedTickBehavior};
 
-use crate::{settings::CanvasSettings, utils::Color, PResult};
+use crate::{
+    backend::PacketCounter,
+    metrics::Histogram,
+    mmap::MappedCanvas,
+    settings::{
+        CanvasSettings, LoadFailurePolicy, PngCompression, SaveBitDepth, SaveFailurePolicy,
+    },
+    utils::{Color, Color16},
+    PResult,
+};
+
+/// Interval between frames streamed to websocket clients.
+const FRAME_INTERVAL: Duration = Duration::from_millis(1000 / 15);
+/// Minimum time between warnings about frames taking longer than `FRAME_INTERVAL` to encode.
+const SLOW_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of thumbnail sizes kept encoded, the least recently requested one being dropped first.
+const THUMBNAIL_CACHE_SIZE: usize = 4;
+
+/// Backing storage of the canvas pixels.
+pub enum CanvasStorage {
+    Memory(Vec<u8>),
+    Mapped(MappedCanvas),
+}
+
+impl Deref for CanvasStorage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            CanvasStorage::Memory(data) => data,
+            CanvasStorage::Mapped(mapped) => mapped.pixels(),
+        }
+    }
+}
+
+impl DerefMut for CanvasStorage {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            CanvasStorage::Memory(data) => data,
+            CanvasStorage::Mapped(mapped) => mapped.pixels_mut(),
+        }
+    }
+}
+
+pub type Canvas = ImageBuffer<Rgba<u8>, CanvasStorage>;
+
+/// Canvas with 16 bits per channel, kept next to the regular one if `color_depth` is 16.
+pub type DeepCanvas = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+/// Fills a `size` x `size` block with its top left corner at `x`, `y`.
+/// Parts of the block outside of the canvas are skipped.
+#[inline]
+fn fill_block<T, C>(image: &mut ImageBuffer<Rgba<T>, C>, x: u32, y: u32, size: u32, rgba: [T; 4])
+where
+    Rgba<T>: image::Pixel<Subpixel = T>,
+    T: Copy,
+    C: DerefMut<Target = [T]>,
+{
+    let (width, height) = image.dimensions();
+    let fits = x.checked_add(size).is_some_and(|end| end <= width)
+        && y.checked_add(size).is_some_and(|end| end <= height);
+
+    if fits {
+        // Fast path, fill whole rows of the block without checking every pixel.
+        let row_len = size as usize * 4;
+        let raw: &mut [T] = image;
+        for row in y..y + size {
+            let start = (row as usize * width as usize + x as usize) * 4;
+            for pixel in raw[start..start + row_len].chunks_exact_mut(4) {
+                pixel.copy_from_slice(&rgba);
+            }
+        }
+    } else {
+        // Block straddles the edge of the canvas.
+        for dy in 0..size {
+            for dx in 0..size {
+                if let Some(i) = image.get_pixel_mut_checked(x + dx, y + dy) {
+                    *i = Rgba(rgba);
+                }
+            }
+        }
+    }
+}
 
 /// (UN)SAFETY NOTE:
 /// We avoid locking here to get a 10-25% performance boost.
@@ -13,44 +114,168 @@ use crate::{settings::CanvasSettings, utils::Color, PResult};
 /// to be corrupted, because the image changed while it was being encoded on another thread.
 /// This has been easily worked around by making a copy of the image before encoding it.
 pub struct SharedImageHandle {
-    data: Arc<UnsafeCell<RgbaImage>>,
+    data: Arc<UnsafeCell<Canvas>>,
+    /// 16 bits per channel copy of the canvas, written alongside it.
+    deep: Option<Arc<UnsafeCell<DeepCanvas>>>,
+    /// Set on every write, cleared when the version gets bumped.
+    dirty: Arc<AtomicBool>,
+    version: Arc<AtomicU64>,
+    /// Time of the last version bump, in milliseconds since the unix epoch.
+    last_modified: Arc<AtomicU64>,
+    epoch: u32,
 }
 
 impl SharedImageHandle {
     pub fn new(data: RgbaImage) -> SharedImageHandle {
+        let (width, height) = data.dimensions();
+        Self::from_storage(width, height, CanvasStorage::Memory(data.into_raw()))
+    }
+
+    pub fn new_mapped(width: u32, height: u32, mapped: MappedCanvas) -> SharedImageHandle {
+        Self::from_storage(width, height, CanvasStorage::Mapped(mapped))
+    }
+
+    fn from_storage(width: u32, height: u32, storage: CanvasStorage) -> SharedImageHandle {
+        // Storage size is checked by its constructors.
+        let data = Canvas::from_raw(width, height, storage).unwrap();
         SharedImageHandle {
             data: Arc::new(UnsafeCell::new(data)),
+            deep: None,
+            dirty: Arc::new(AtomicBool::new(true)),
+            version: Arc::new(AtomicU64::new(0)),
+            last_modified: Arc::new(AtomicU64::new(0)),
+            epoch: rand::random(),
         }
     }
 
+    /// Keeps a canvas with 16 bits per channel next to the regular one, starting out as `deep`.
+    pub fn with_deep(mut self, deep: DeepCanvas) -> SharedImageHandle {
+        assert_eq!(deep.dimensions(), self.get_dimensions());
+        self.deep = Some(Arc::new(UnsafeCell::new(deep)));
+        self
+    }
+
     pub fn put(&self, x: u32, y: u32, color: Color, big: bool) {
+        self.put_block(x, y, color, if big { 2 } else { 1 });
+    }
+
+    /// Fills a `size` x `size` block with its top left corner at `x`, `y`.
+    /// Parts of the block outside of the canvas are skipped.
+    pub fn put_block(&self, x: u32, y: u32, color: Color, size: u32) {
+        self.put_block16(x, y, Color16::from_color(color), size);
+    }
+
+    /// Like `put_block`, but keeps all 16 bits per channel if the canvas has them.
+    pub fn put_block16(&self, x: u32, y: u32, color: Color16, size: u32) {
         // SAFETY: See comment in SharedImageHandle for details.
         let image = unsafe { &mut *self.data.get() };
+        // A plain store is much cheaper than bumping a shared counter on every pixel.
+        self.dirty.store(true, Ordering::Relaxed);
 
-        if let Some(i) = image.get_pixel_mut_checked(x, y) {
-            *i = color.into_rgba()
-        };
-        if big {
-            if let Some(i) = image.get_pixel_mut_checked(x + 1, y) {
-                *i = color.into_rgba()
-            };
-            if let Some(i) = image.get_pixel_mut_checked(x, y + 1) {
-                *i = color.into_rgba()
-            };
-            if let Some(i) = image.get_pixel_mut_checked(x + 1, y + 1) {
-                *i = color.into_rgba()
-            };
+        fill_block(image, x, y, size, color.to_color().into_rgba().0);
+        if let Some(deep) = &self.deep {
+            // SAFETY: See comment in SharedImageHandle for details.
+            let deep = unsafe { &mut *deep.get() };
+            fill_block(deep, x, y, size, color.into_rgba16().0);
         }
     }
 
+    /// Returns the color of a pixel, `None` if it's outside of the canvas.
+    pub fn get(&self, x: u32, y: u32) -> Option<Color> {
+        // SAFETY: See comment in SharedImageHandle for details.
+        let image = unsafe { &*self.data.get() };
+        image
+            .get_pixel_checked(x, y)
+            .map(|&rgba| Color::from_rgba(rgba))
+    }
+
     pub fn get_dimensions(&self) -> (u32, u32) {
         // SAFETY: Image size is assumed to never change, so reading it is always safe.
         let image = unsafe { &mut *self.data.get() };
         image.dimensions()
     }
 
+    /// Replaces all pixels of color `from` with `to`, returns the number of replaced pixels.
+    pub fn replace_color(&self, from: Color, to: Color) -> u64 {
+        // SAFETY: See comment in SharedImageHandle for details.
+        let image = unsafe { &mut *self.data.get() };
+        self.dirty.store(true, Ordering::Relaxed);
+
+        // SAFETY: See comment in SharedImageHandle for details.
+        let mut deep = self.deep.as_ref().map(|deep| unsafe { &mut *deep.get() });
+        let to16 = Color16::from_color(to).into_rgba16();
+
+        let (from, to) = (from.into_rgba(), to.into_rgba());
+        let width = image.width();
+        let mut replaced = 0;
+        for (i, pixel) in image.pixels_mut().enumerate() {
+            if *pixel == from {
+                *pixel = to;
+                if let Some(deep) = deep.as_mut() {
+                    deep.put_pixel(i as u32 % width, i as u32 / width, to16);
+                }
+                replaced += 1;
+            }
+        }
+        replaced
+    }
+
+    /// Returns the current canvas version, bumping it if the canvas has been written to since the last call.
+    pub fn version(&self) -> CanvasVersion {
+        if self.dirty.swap(false, Ordering::Relaxed) {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64);
+            self.last_modified.store(now, Ordering::Relaxed);
+            self.version.fetch_add(1, Ordering::Relaxed);
+        }
+
+        CanvasVersion {
+            epoch: self.epoch,
+            version: self.version.load(Ordering::Relaxed),
+            last_modified: SystemTime::UNIX_EPOCH
+                + Duration::from_millis(self.last_modified.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Returns a copy of the canvas.
+    pub fn snapshot(&self) -> RgbaImage {
+        let mut image = RgbaImage::new(0, 0);
+        self.snapshot_into(&mut image);
+        image
+    }
+
+    /// Copies the canvas into `image`, which is only reallocated if its dimensions don't match.
+    pub fn snapshot_into(&self, image: &mut RgbaImage) {
+        let (width, height) = self.get_dimensions();
+        if image.dimensions() != (width, height) {
+            *image = RgbaImage::new(width, height);
+        }
+
+        let shared_image = unsafe { self.get_image() };
+        image.copy_from_slice(shared_image.as_raw());
+    }
+
+    /// Returns a copy of the canvas with 16 bits per channel, if it has one.
+    pub fn snapshot16(&self) -> Option<DeepCanvas> {
+        // SAFETY: See comment in SharedImageHandle for details.
+        self.deep
+            .as_ref()
+            .map(|deep| unsafe { &*deep.get() }.clone())
+    }
+
+    /// Writes pending changes of a memory-mapped canvas to disk, does nothing for in-memory ones.
+    pub fn flush(&self) -> PResult<()> {
+        // SAFETY: Flushing only reads the mapping.
+        let image = unsafe { self.get_image() };
+        match image.as_raw() {
+            CanvasStorage::Memory(_) => Ok(()),
+            CanvasStorage::Mapped(mapped) => mapped.flush(),
+        }
+    }
+
     /// SAFETY: See comment in SharedImageHandle for details.
-    pub unsafe fn get_image(&self) -> &RgbaImage {
+    pub unsafe fn get_image(&self) -> &Canvas {
         let image = unsafe { &mut *self.data.get() };
         image
     }
@@ -65,14 +290,876 @@ impl Clone for SharedImageHandle {
     fn clone(&self) -> Self {
         SharedImageHandle {
             data: Arc::clone(&self.data),
+            deep: self.deep.clone(),
+            dirty: Arc::clone(&self.dirty),
+            version: Arc::clone(&self.version),
+            last_modified: Arc::clone(&self.last_modified),
+            epoch: self.epoch,
+        }
+    }
+}
+
+/// Identifies a state of the canvas, the version is bumped whenever pixels get written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanvasVersion {
+    /// Random for every run of the server, so versions from before a restart never match current ones.
+    pub epoch: u32,
+    pub version: u64,
+    pub last_modified: SystemTime,
+}
+
+impl CanvasVersion {
+    /// Returns the version as a HTTP entity tag, including the quotes.
+    pub fn etag(&self) -> String {
+        format!("\"{:08x}-{}\"", self.epoch, self.version)
+    }
+}
+
+/// An encoded image of the canvas, along with the version it was encoded at.
+pub type EncodedCanvas = (CanvasVersion, Bytes);
+
+/// Returns the dimensions of streamed frames, scaled down to fit in `max_dimension` keeping the aspect ratio.
+pub fn stream_dimensions(width: u32, height: u32, max_dimension: Option<u32>) -> (u32, u32) {
+    let max_dimension = match max_dimension {
+        Some(max) if width.max(height) > max => max,
+        _ => return (width, height),
+    };
+
+    let scale =
+        |v: u32| ((v as u64 * max_dimension as u64) / width.max(height) as u64).max(1) as u32;
+    (scale(width), scale(height))
+}
+
+impl From<PngCompression> for png::CompressionType {
+    fn from(compression: PngCompression) -> Self {
+        match compression {
+            PngCompression::Fast => png::CompressionType::Fast,
+            PngCompression::Default => png::CompressionType::Default,
+            PngCompression::Best => png::CompressionType::Best,
+        }
+    }
+}
+
+impl From<PngCompression> for ::png::Compression {
+    fn from(compression: PngCompression) -> Self {
+        match compression {
+            PngCompression::Fast => ::png::Compression::Fast,
+            PngCompression::Default => ::png::Compression::Default,
+            PngCompression::Best => ::png::Compression::Best,
+        }
+    }
+}
+
+/// Encodes the canvas as PNG, favoring speed over size.
+pub fn encode_png(image: &RgbaImage) -> ImageResult<Vec<u8>> {
+    let mut data = Vec::new();
+    write_png(image, PngCompression::Fast, &mut data)?;
+    Ok(data)
+}
+
+/// Appends the canvas encoded as PNG at the given compression level to `out`.
+fn write_png(image: &RgbaImage, compression: PngCompression, out: &mut Vec<u8>) -> ImageResult<()> {
+    let encoder =
+        png::PngEncoder::new_with_quality(out, compression.into(), png::FilterType::Adaptive);
+    encoder.write_image(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ColorType::Rgba8,
+    )
+}
+
+/// Colors of an image using at most 256 of them, and the index of every pixel's color.
+///
+/// Can be reused for consecutive frames, which keeps their buffers and mostly their colors too.
+#[derive(Default)]
+pub struct Palette {
+    colors: Vec<[u8; 4]>,
+    lookup: HashMap<[u8; 4], u8>,
+    indices: Vec<u8>,
+}
+
+impl Palette {
+    /// Returns `None` if the image uses more than 256 colors.
+    pub fn of(image: &RgbaImage) -> Option<Palette> {
+        let mut palette = Palette::default();
+        palette.index(image).then_some(palette)
+    }
+
+    /// Indexes the pixels of `image`, keeping the colors of the previous image as long as there's
+    /// room for the new ones. Returns false if the image uses more than 256 colors.
+    pub fn index(&mut self, image: &RgbaImage) -> bool {
+        let reused = !self.colors.is_empty();
+        if self.index_with_current_colors(image) {
+            return true;
+        }
+        // Colors which are gone from the canvas may take up the room.
+        self.colors.clear();
+        self.lookup.clear();
+        if reused && self.index_with_current_colors(image) {
+            return true;
+        }
+        // Starting from scratch next time saves the retry while the canvas has too many colors.
+        self.colors.clear();
+        self.lookup.clear();
+        false
+    }
+
+    fn index_with_current_colors(&mut self, image: &RgbaImage) -> bool {
+        self.indices.clear();
+        self.indices
+            .reserve(image.width() as usize * image.height() as usize);
+        // Neighboring pixels mostly share their color, which saves most lookups.
+        let mut last = None;
+        for pixel in image.pixels() {
+            let index = match last {
+                Some((color, index)) if color == pixel.0 => index,
+                _ => {
+                    let index = match self.lookup.get(&pixel.0) {
+                        Some(&index) => index,
+                        None if self.colors.len() < 256 => {
+                            self.colors.push(pixel.0);
+                            self.lookup.insert(pixel.0, (self.colors.len() - 1) as u8);
+                            (self.colors.len() - 1) as u8
+                        }
+                        None => return false,
+                    };
+                    last = Some((pixel.0, index));
+                    index
+                }
+            };
+            self.indices.push(index);
+        }
+        true
+    }
+}
+
+/// Encodes an image as an indexed PNG with the given palette, with `text` as tEXt chunks.
+pub fn encode_indexed_png(
+    width: u32,
+    height: u32,
+    palette: &Palette,
+    text: &[(String, String)],
+) -> Result<Vec<u8>, ::png::EncodingError> {
+    let mut data = Vec::new();
+    write_indexed_png(
+        &mut data,
+        width,
+        height,
+        palette,
+        PngCompression::Fast,
+        text,
+    )?;
+    Ok(data)
+}
+
+/// Appends the image encoded like `encode_indexed_png` at the given compression level to `out`.
+fn write_indexed_png(
+    out: &mut Vec<u8>,
+    width: u32,
+    height: u32,
+    palette: &Palette,
+    compression: PngCompression,
+    text: &[(String, String)],
+) -> Result<(), ::png::EncodingError> {
+    let mut encoder = ::png::Encoder::new(out, width, height);
+    encoder.set_color(::png::ColorType::Indexed);
+    encoder.set_depth(::png::BitDepth::Eight);
+    encoder.set_compression(compression.into());
+    // Filters rarely help with indices, which aren't ordered by brightness.
+    encoder.set_filter(::png::FilterType::NoFilter);
+    encoder.set_palette(
+        palette
+            .colors
+            .iter()
+            .flat_map(|&[r, g, b, _]| [r, g, b])
+            .collect::<Vec<u8>>(),
+    );
+    if palette.colors.iter().any(|color| color[3] != 255) {
+        encoder.set_trns(
+            palette
+                .colors
+                .iter()
+                .map(|color| color[3])
+                .collect::<Vec<u8>>(),
+        );
+    }
+    for (keyword, value) in text {
+        encoder.add_text_chunk(keyword.clone(), value.clone())?;
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&palette.indices)?;
+    writer.finish()
+}
+
+/// Encodes the canvas as PNG favoring speed, indexed if `indexed` is set and it uses few enough colors.
+pub fn encode_canvas_png(image: &RgbaImage, indexed: bool) -> ImageResult<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut palette = indexed.then(Palette::default);
+    write_canvas_png(image, palette.as_mut(), PngCompression::Fast, &mut data)?;
+    Ok(data)
+}
+
+/// Appends the canvas encoded like `encode_canvas_png` at the given compression level to `out`,
+/// indexed with `palette` if given and the canvas uses few enough colors.
+fn write_canvas_png(
+    image: &RgbaImage,
+    palette: Option<&mut Palette>,
+    compression: PngCompression,
+    out: &mut Vec<u8>,
+) -> ImageResult<()> {
+    match palette.and_then(|palette| palette.index(image).then_some(palette)) {
+        Some(palette) => Ok(write_indexed_png(
+            out,
+            image.width(),
+            image.height(),
+            palette,
+            compression,
+            &[],
+        )
+        .map_err(std::io::Error::from)?),
+        None => write_png(image, compression, out),
+    }
+}
+
+/// Encodes the canvas as PNG in the given bit depth, with `text` as tEXt chunks.
+pub fn encode_saved_png(
+    image: &RgbaImage,
+    bit_depth: SaveBitDepth,
+    text: &[(String, String)],
+) -> PResult<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut encoder = ::png::Encoder::new(&mut data, image.width(), image.height());
+    encoder.set_depth(::png::BitDepth::Eight);
+    let indices: Vec<u8>;
+    let pixels = match bit_depth {
+        SaveBitDepth::Full => {
+            encoder.set_color(::png::ColorType::Rgba);
+            image.as_raw()
+        }
+        SaveBitDepth::Rgb332 => {
+            let palette: Vec<u8> = (0..=255)
+                .flat_map(|v| {
+                    let color = Color::from_rgb332(v);
+                    [color.r, color.g, color.b]
+                })
+                .collect();
+            indices = image
+                .pixels()
+                .map(|&rgba| Color::from_rgba(rgba).into_rgb332())
+                .collect();
+
+            encoder.set_color(::png::ColorType::Indexed);
+            encoder.set_palette(palette);
+            &indices
+        }
+    };
+    for (keyword, value) in text {
+        encoder.add_text_chunk(keyword.clone(), value.clone())?;
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+
+    Ok(data)
+}
+
+/// Encodes a canvas with 16 bits per channel as PNG, with `text` as tEXt chunks.
+pub fn encode_saved_png16(image: &DeepCanvas, text: &[(String, String)]) -> PResult<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut encoder = ::png::Encoder::new(&mut data, image.width(), image.height());
+    encoder.set_color(::png::ColorType::Rgba);
+    encoder.set_depth(::png::BitDepth::Sixteen);
+    for (keyword, value) in text {
+        encoder.add_text_chunk(keyword.clone(), value.clone())?;
+    }
+
+    // PNG stores samples in big endian.
+    let pixels: Vec<u8> = image.iter().flat_map(|v| v.to_be_bytes()).collect();
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+
+    Ok(data)
+}
+
+/// Describes an error of writing the canvas, pointing out a full disk which is easily missed among
+/// other I/O errors.
+pub fn describe_save_error(e: &(dyn std::error::Error + 'static)) -> String {
+    // ENOSPC and EDQUOT, ErrorKind::StorageFull and QuotaExceeded are too new.
+    const NO_SPACE: [i32; 2] = [28, 122];
+
+    let disk_full = std::iter::successors(Some(e), |e| e.source()).any(|e| {
+        let io_error = match e.downcast_ref::<image::ImageError>() {
+            Some(image::ImageError::IoError(e)) => Some(e),
+            _ => e.downcast_ref::<io::Error>(),
+        };
+        io_error
+            .and_then(|e| e.raw_os_error())
+            .is_some_and(|code| NO_SPACE.contains(&code))
+    });
+
+    if disk_full {
+        format!("{}. The disk is full, free up space to save again.", e)
+    } else {
+        e.to_string()
+    }
+}
+
+/// Path an applied patch is renamed to, so it's not applied again on the next start.
+fn applied_patch_path(patch: &Path) -> PathBuf {
+    let mut path = patch.as_os_str().to_owned();
+    path.push(".applied");
+    PathBuf::from(path)
+}
+
+/// Returned by `Place::write_in_background` while a write is still running, because waiting for it
+/// timed out or an earlier one hasn't finished yet. The write itself may still succeed.
+#[derive(Debug)]
+pub struct WriteInProgress(String);
+
+impl fmt::Display for WriteInProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for WriteInProgress {}
+
+/// Clears the flag when dropped, so it's reset even if the write panics.
+struct ClearOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for ClearOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Checks that the directory `path` is written to exists, creating it if `create` is set.
+fn ensure_parent_dir(path: &Path, create: bool) -> PResult<()> {
+    let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) else {
+        return Ok(());
+    };
+    if parent.is_dir() {
+        return Ok(());
+    }
+
+    if !create {
+        return Err(format!(
+            "Directory {} of canvas file {} doesn't exist. Create it or set create_dirs = true.",
+            parent.display(),
+            path.display()
+        )
+        .into());
+    }
+
+    log::info!("Creating directory {}.", parent.display());
+    std::fs::create_dir_all(parent)?;
+    Ok(())
+}
+
+/// Sources of the metadata embedded in saved canvas files.
+pub struct SaveMetadata {
+    pub prefix48: Ipv6Addr,
+    pub packet_counter: Arc<PacketCounter>,
+}
+
+impl SaveMetadata {
+    /// Returns the metadata as PNG text chunk keywords and values.
+    fn text_chunks(&self, width: u32, height: u32) -> Vec<(String, String)> {
+        vec![
+            (
+                "Creation Time".to_string(),
+                httpdate::fmt_http_date(SystemTime::now()),
+            ),
+            (
+                "Software".to_string(),
+                format!("ipv6-place {}", env!("CARGO_PKG_VERSION")),
+            ),
+            ("Canvas Size".to_string(), format!("{}x{}", width, height)),
+            (
+                "Packets Received".to_string(),
+                self.packet_counter.total().to_string(),
+            ),
+            ("Prefix".to_string(), format!("{}/48", self.prefix48)),
+        ]
+    }
+}
+
+/// An encoded canvas frame streamed to websocket clients.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Canvas version the frame was encoded at. Changes released by the flicker filter don't bump the
+    /// canvas version, so frames showing them share the version of the frame before.
+    pub version: u64,
+    /// Number of the frame in the stream, increasing whenever its content changes, including changes
+    /// released by the flicker filter. Identical frames share the same sequence number.
+    pub sequence: u64,
+    pub data: Bytes,
+    /// CRC32 of `data`, sent to clients which asked for checksums.
+    pub crc32: u32,
+}
+
+/// Copy of the canvas frames are encoded from. The dimensions are checked on every update instead of
+/// being fixed for the lifetime of the stream, so a resized canvas doesn't break it.
+struct FrameBuffer {
+    /// Shared with the keyframe encoder, which only costs a copy while a keyframe is being encoded.
+    buffer: Arc<RgbaImage>,
+    /// `buffer` downscaled to fit in `max_dimension`, if it's larger.
+    scaled: Option<Arc<RgbaImage>>,
+    max_dimension: Option<u32>,
+    flicker_filter: Option<FlickerFilter>,
+}
+
+impl FrameBuffer {
+    fn new(max_dimension: Option<u32>, flicker_window: Option<Duration>) -> FrameBuffer {
+        FrameBuffer {
+            buffer: Arc::new(RgbaImage::new(0, 0)),
+            scaled: None,
+            max_dimension,
+            flicker_filter: flicker_window.map(FlickerFilter::new),
+        }
+    }
+
+    /// Checks whether the canvas has been resized since the last update.
+    fn is_resized(&self, image: &SharedImageHandle) -> bool {
+        self.buffer.dimensions() != image.get_dimensions()
+    }
+
+    /// Checks whether the flicker filter holds back changes, which need another update to show up.
+    fn is_pending(&self) -> bool {
+        self.flicker_filter
+            .as_ref()
+            .is_some_and(|filter| filter.pending)
+    }
+
+    /// Copies the canvas, reallocating the buffers if its dimensions changed. Returns whether the
+    /// frame changed, which it always does unless the flicker filter held back all changes.
+    fn update(&mut self, image: &SharedImageHandle) -> bool {
+        match &mut self.flicker_filter {
+            Some(filter) => {
+                image.snapshot_into(&mut filter.canvas);
+                if !filter.apply(Arc::make_mut(&mut self.buffer), Instant::now()) {
+                    return false;
+                }
+            }
+            None => image.snapshot_into(Arc::make_mut(&mut self.buffer)),
         }
+
+        let (width, height) = self.buffer.dimensions();
+        let stream_dimensions = stream_dimensions(width, height, self.max_dimension);
+        self.scaled = (stream_dimensions != (width, height)).then(|| {
+            // Nearest neighbour keeps pixel art crisp and is cheap enough to run every frame.
+            Arc::new(imageops::resize(
+                &*self.buffer,
+                stream_dimensions.0,
+                stream_dimensions.1,
+                imageops::FilterType::Nearest,
+            ))
+        });
+        true
+    }
+
+    /// The frame to encode, at the streamed dimensions.
+    fn frame(&self) -> &RgbaImage {
+        self.shared_frame()
+    }
+
+    /// The frame to encode, as a handle which can outlive the next update.
+    fn shared_frame(&self) -> &Arc<RgbaImage> {
+        self.scaled.as_ref().unwrap_or(&self.buffer)
+    }
+
+    /// The frame at the full dimensions of the canvas.
+    fn full_frame(&self) -> &RgbaImage {
+        &self.buffer
+    }
+}
+
+/// Limits how often each pixel of the stream changes color, so pixels flipped back and forth during
+/// edit wars don't flicker in viewers. Changes arriving sooner than `window` after the last shown
+/// change of a pixel are held back until the window is over, only the latest color is shown then.
+struct FlickerFilter {
+    window: Duration,
+    started: Instant,
+    /// Scratch copy of the canvas.
+    canvas: RgbaImage,
+    /// Time of the last shown change of each pixel, in milliseconds since `started`. Wraps after
+    /// about 49 days, which at worst holds back a change once.
+    last_change: Vec<u32>,
+    /// Whether some changes are still held back.
+    pending: bool,
+}
+
+impl FlickerFilter {
+    fn new(window: Duration) -> FlickerFilter {
+        FlickerFilter {
+            window,
+            started: Instant::now(),
+            canvas: RgbaImage::new(0, 0),
+            last_change: Vec::new(),
+            pending: false,
+        }
+    }
+
+    /// Copies the changes of `canvas` which may be shown at `now` to `shown`. A resized canvas is
+    /// copied entirely. Returns whether `shown` changed.
+    fn apply(&mut self, shown: &mut RgbaImage, now: Instant) -> bool {
+        let window = self.window.as_millis() as u32;
+        let now = now.saturating_duration_since(self.started).as_millis() as u32;
+
+        if shown.dimensions() != self.canvas.dimensions() {
+            shown.clone_from(&self.canvas);
+            // Nothing is held back right after a resize.
+            self.last_change = vec![now.wrapping_sub(window); shown.pixels().len()];
+            self.pending = false;
+            return true;
+        }
+
+        let mut changed = false;
+        self.pending = false;
+        let pixels = shown.pixels_mut().zip(self.canvas.pixels());
+        for ((shown, canvas), last_change) in pixels.zip(&mut self.last_change) {
+            if shown == canvas {
+                continue;
+            }
+
+            if now.wrapping_sub(*last_change) >= window {
+                *shown = *canvas;
+                *last_change = now;
+                changed = true;
+            } else {
+                self.pending = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Encodings of frames streamed to websocket clients, picked per connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameFormat {
+    Png,
+    Qoi,
+    /// Bare RGBA pixels, row by row, of the streamed size announced in /config.json.
+    Raw,
+    Webp,
+}
+
+impl FrameFormat {
+    const ALL: [FrameFormat; 4] = [
+        FrameFormat::Png,
+        FrameFormat::Qoi,
+        FrameFormat::Raw,
+        FrameFormat::Webp,
+    ];
+
+    pub fn parse(s: &str) -> Option<FrameFormat> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(s))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FrameFormat::Png => "png",
+            FrameFormat::Qoi => "qoi",
+            FrameFormat::Raw => "raw",
+            FrameFormat::Webp => "webp",
+        }
+    }
+
+    /// Returns the formats the compiled-in codecs can encode.
+    pub fn supported() -> Vec<FrameFormat> {
+        Self::ALL
+            .into_iter()
+            .filter(|format| match format {
+                FrameFormat::Png | FrameFormat::Raw => true,
+                FrameFormat::Qoi => Self::can_encode(ImageFormat::Qoi),
+                FrameFormat::Webp => Self::can_encode(ImageFormat::WebP),
+            })
+            .collect()
+    }
+
+    fn can_encode(format: ImageFormat) -> bool {
+        !matches!(
+            ImageOutputFormat::from(format),
+            ImageOutputFormat::Unsupported(_)
+        )
+    }
+
+    /// Encodes a streamed frame into a buffer of `capacity` bytes, which is only reallocated if the
+    /// frame turns out larger and then becomes the frame's data without being copied.
+    pub fn encode_frame(
+        self,
+        image: &RgbaImage,
+        palette: Option<&mut Palette>,
+        compression: PngCompression,
+        (version, sequence): (u64, u64),
+        capacity: usize,
+    ) -> ImageResult<Frame> {
+        let mut data = Vec::with_capacity(capacity);
+        self.encode_into(image, palette, compression, &mut data)?;
+        Ok(Frame {
+            version,
+            sequence,
+            crc32: crc32fast::hash(&data),
+            data: Bytes::from(data),
+        })
+    }
+
+    /// Encodes the frame into `out`, replacing its contents.
+    /// `compression` only applies to PNG.
+    fn encode_into(
+        self,
+        image: &RgbaImage,
+        palette: Option<&mut Palette>,
+        compression: PngCompression,
+        out: &mut Vec<u8>,
+    ) -> ImageResult<()> {
+        out.clear();
+        let format = match self {
+            FrameFormat::Png => return write_canvas_png(image, palette, compression, out),
+            FrameFormat::Raw => {
+                out.extend_from_slice(image.as_raw());
+                return Ok(());
+            }
+            FrameFormat::Qoi => ImageFormat::Qoi,
+            FrameFormat::Webp => ImageFormat::WebP,
+        };
+
+        image.write_to(&mut Cursor::new(out), format)
+    }
+}
+
+/// How PNG frames are compressed.
+#[derive(Debug, Clone, Copy, Default)]
+struct FrameCompression {
+    /// Level of frames streamed to all clients.
+    frames: PngCompression,
+    /// Level of keyframes, sent to clients which don't have the previous frame.
+    keyframes: PngCompression,
+    indexed_png: bool,
+}
+
+/// Small downscale of the canvas streamed at a low rate, for minimaps.
+#[derive(Debug, Clone)]
+struct PreviewChannel {
+    sender: broadcast::Sender<Frame>,
+    max_dimension: u32,
+    interval: Duration,
+}
+
+impl PreviewChannel {
+    /// Encodes a preview of `image` as PNG, at the keyframe compression since it's small and rare.
+    fn encode(
+        &self,
+        image: &RgbaImage,
+        compression: FrameCompression,
+        (version, sequence): (u64, u64),
+    ) -> ImageResult<Frame> {
+        let (width, height) =
+            stream_dimensions(image.width(), image.height(), Some(self.max_dimension));
+        // Averaging keeps thin lines visible, unlike the nearest neighbour downscale of the stream.
+        let preview = imageops::thumbnail(image, width, height);
+        let mut data = Vec::new();
+        let mut palette = compression.indexed_png.then(Palette::default);
+        write_canvas_png(&preview, palette.as_mut(), compression.keyframes, &mut data)?;
+        Ok(Frame {
+            version,
+            sequence,
+            crc32: crc32fast::hash(&data),
+            data: Bytes::from(data),
+        })
+    }
+}
+
+/// Source and result of the keyframe encode of the latest frame.
+#[derive(Debug, Default)]
+struct Keyframes {
+    /// Latest streamed frame before encoding, kept while keyframes are compressed differently.
+    image: Option<(u64, Arc<RgbaImage>)>,
+    /// Keyframe of the given version, `None` while it's being encoded.
+    keyframe: Option<(u64, Option<Frame>)>,
+}
+
+/// Broadcast channels of encoded frames, one for each supported format.
+#[derive(Debug, Clone)]
+pub struct FrameChannels {
+    senders: Vec<(FrameFormat, broadcast::Sender<Frame>)>,
+    /// Time spent encoding frames, by format.
+    encode_times: Arc<Vec<(FrameFormat, Histogram)>>,
+    compression: FrameCompression,
+    /// Last PNG keyframe, reused by clients asking for one while the canvas doesn't change.
+    keyframes: Arc<Mutex<Keyframes>>,
+    /// The preview stream, if it's enabled.
+    preview: Option<PreviewChannel>,
+}
+
+/// Frames buffered per format unless configured otherwise.
+const DEFAULT_FRAME_CHANNEL_CAPACITY: usize = 8;
+
+impl FrameChannels {
+    fn new(capacity: usize) -> FrameChannels {
+        FrameChannels {
+            senders: FrameFormat::supported()
+                .into_iter()
+                .map(|format| (format, broadcast::channel(capacity).0))
+                .collect(),
+            encode_times: Arc::new(
+                FrameFormat::supported()
+                    .into_iter()
+                    .map(|format| (format, Histogram::default()))
+                    .collect(),
+            ),
+            compression: FrameCompression::default(),
+            keyframes: Arc::new(Mutex::new(Keyframes::default())),
+            preview: None,
+        }
+    }
+
+    /// Whether keyframes are encoded separately from streamed PNG frames.
+    fn has_keyframes(&self) -> bool {
+        self.compression.keyframes != self.compression.frames
+    }
+
+    /// Keeps the streamed frame `sequence` before encoding for encoding its keyframe, or lets go of
+    /// the previous one if `None`.
+    fn set_keyframe_source(&self, source: Option<(u64, Arc<RgbaImage>)>) {
+        let mut keyframes = self.keyframes.lock().unwrap_or_else(|e| e.into_inner());
+        keyframes.image = source;
+    }
+
+    /// Returns `frame` re-encoded at the keyframe compression level, for clients which get a
+    /// complete frame without having the previous one. Only PNG frames are re-encoded, if the level
+    /// differs from the one of streamed frames. The first client asking for the keyframe of a frame
+    /// starts encoding it in the background, until it's done clients get `frame` itself.
+    pub fn keyframe(&self, format: FrameFormat, frame: Frame) -> Frame {
+        if format != FrameFormat::Png || !self.has_keyframes() {
+            return frame;
+        }
+
+        let mut keyframes = self.keyframes.lock().unwrap_or_else(|e| e.into_inner());
+        match &keyframes.keyframe {
+            Some((sequence, keyframe)) if *sequence == frame.sequence => {
+                return keyframe.clone().unwrap_or(frame);
+            }
+            _ => {}
+        }
+        let Some((sequence, image)) = keyframes
+            .image
+            .clone()
+            .filter(|(sequence, _)| *sequence == frame.sequence)
+        else {
+            return frame;
+        };
+        keyframes.keyframe = Some((sequence, None));
+        drop(keyframes);
+
+        let (keyframes, compression, streamed) =
+            (self.keyframes.clone(), self.compression, frame.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut data = Vec::new();
+            let mut palette = compression.indexed_png.then(Palette::default);
+            let encoded =
+                write_canvas_png(&image, palette.as_mut(), compression.keyframes, &mut data);
+            let keyframe = match encoded {
+                Ok(()) if data.len() < streamed.data.len() => Frame {
+                    version: streamed.version,
+                    sequence,
+                    crc32: crc32fast::hash(&data),
+                    data: Bytes::from(data),
+                },
+                Ok(()) => streamed,
+                Err(e) => {
+                    log::error!("Failed to encode keyframe: {}", e);
+                    streamed
+                }
+            };
+            let mut keyframes = keyframes.lock().unwrap_or_else(|e| e.into_inner());
+            if matches!(keyframes.keyframe, Some((s, None)) if s == sequence) {
+                keyframes.keyframe = Some((sequence, Some(keyframe)));
+            }
+        });
+        frame
+    }
+
+    fn encode_time(&self, format: FrameFormat) -> Option<&Histogram> {
+        self.encode_times
+            .iter()
+            .find(|(f, _)| *f == format)
+            .map(|(_, histogram)| histogram)
+    }
+
+    /// Appends frame encode times in the Prometheus text format to `out`.
+    pub fn render_metrics(&self, out: &mut String) {
+        out.push_str(
+            "# HELP place_frame_encode_ms Time spent encoding websocket frames, in milliseconds.\n",
+        );
+        out.push_str("# TYPE place_frame_encode_ms histogram\n");
+        for (format, histogram) in self.encode_times.iter() {
+            let labels = format!("format=\"{}\"", format.name());
+            histogram.render("place_frame_encode_ms", &labels, out);
+        }
+    }
+
+    /// Subscribes to frames in the given format, `None` if it's not supported.
+    pub fn subscribe(&self, format: FrameFormat) -> Option<broadcast::Receiver<Frame>> {
+        self.senders
+            .iter()
+            .find(|(f, _)| *f == format)
+            .map(|(_, sender)| sender.subscribe())
+    }
+
+    /// Subscribes to preview frames, encoded as PNG. `None` if the preview stream is disabled.
+    pub fn subscribe_preview(&self) -> Option<broadcast::Receiver<Frame>> {
+        self.preview
+            .as_ref()
+            .map(|preview| preview.sender.subscribe())
+    }
+
+    fn has_receivers(&self) -> bool {
+        self.senders
+            .iter()
+            .map(|(_, sender)| sender)
+            .chain(self.preview.as_ref().map(|preview| &preview.sender))
+            .any(|sender| sender.receiver_count() > 0)
     }
 }
 
 pub struct Place {
     pub image: SharedImageHandle,
     pub path: PathBuf,
-    pub png_sender: broadcast::Sender<Arc<[u8]>>,
+    /// Backing file of the canvas, if it's memory-mapped.
+    pub mmap_path: Option<PathBuf>,
+    pub format: ImageFormat,
+    save_bit_depth: SaveBitDepth,
+    /// Whether to encode PNGs as indexed while the canvas uses few enough colors.
+    indexed_png: bool,
+    /// Whether to create missing parent directories of `path` when saving.
+    create_dirs: bool,
+    /// Set if metadata should be embedded in saved files.
+    metadata: Option<SaveMetadata>,
+    pub frame_channels: FrameChannels,
+    /// Current background color, stored as RGBA32 so it can be changed at runtime.
+    background_color: AtomicU32,
+    /// Last PNG encoded by `png`, along with the version it was encoded at.
+    png_cache: Mutex<Option<EncodedCanvas>>,
+    /// Thumbnails encoded by `thumbnail` along with their size, the most recently requested last.
+    thumbnail_cache: Mutex<Vec<(u32, EncodedCanvas)>>,
+    /// Copy of the canvas written by `export`, kept around so large canvases aren't reallocated
+    /// on every save. Also keeps concurrent exports from writing the same temporary file.
+    export_buffer: Mutex<RgbaImage>,
+    /// Longest time to wait for a write in the background, `None` to wait forever.
+    save_timeout: Option<Duration>,
+    /// Set while a write runs in the background, so writes stuck on a slow disk don't pile up.
+    saving: AtomicBool,
+    pub save_failure_policy: SaveFailurePolicy,
+    /// Set if the canvas was frozen because saving it failed, to unfreeze it once saving works again.
+    pub frozen_by_save_failure: AtomicBool,
 }
 
 impl Place {
@@ -82,88 +1169,697 @@ impl Place {
         }
 
         let path = PathBuf::from(&settings.filename);
-        let size = settings.size.get() as u32;
-
-        let data = if path.exists() {
-            let f = File::open(&path)?;
-            let image = BufReader::new(f);
-            let image = image::load(image, ImageFormat::Png)?.into_rgba8();
-            if image.dimensions() != (size, size) {
+        let format = Self::canvas_format(&path)?;
+        if settings.save_metadata && format != ImageFormat::Png {
+            return Err(format!(
+                "save_metadata requires a PNG canvas file, got {}.",
+                path.display()
+            )
+            .into());
+        }
+        if settings.save_bit_depth != SaveBitDepth::Full && format != ImageFormat::Png {
+            return Err(format!(
+                "save_bit_depth {:?} requires a PNG canvas file, got {}.",
+                settings.save_bit_depth,
+                path.display()
+            )
+            .into());
+        }
+        if settings.deep_color() {
+            if format != ImageFormat::Png {
                 return Err(format!(
-                    "Image dimensions do not match configured canvas size: {:?} != {:?}",
-                    image.dimensions(),
-                    (size, size)
+                    "color_depth 16 requires a PNG canvas file, got {}.",
+                    path.display()
                 )
                 .into());
             }
-            image
-        } else {
-            let mut data = RgbaImage::new(size, size);
-            for pixel in data.pixels_mut() {
-                *pixel = settings.background_color.into_rgba();
+            if settings.mmap_file.is_some() || settings.save_bit_depth != SaveBitDepth::Full {
+                return Err(
+                    "color_depth 16 can't be combined with mmap_file or save_bit_depth.".into(),
+                );
             }
-            data.save(&path)?;
-            data
+        }
+        let size = settings.size.get() as u32;
+
+        let image = match &settings.mmap_file {
+            Some(mmap_file) => {
+                let mmap_path = PathBuf::from(mmap_file);
+                let mapped = match MappedCanvas::open(&mmap_path, size, size)? {
+                    Some(mapped) => mapped,
+                    None => {
+                        let data = Self::load_or_create(settings, &path, format)?;
+                        log::info!(
+                            "Creating memory-mapped canvas {} from {}.",
+                            mmap_path.display(),
+                            path.display()
+                        );
+                        MappedCanvas::create(&mmap_path, size, size, data.as_raw())?
+                    }
+                };
+                SharedImageHandle::new_mapped(size, size, mapped)
+            }
+            None if settings.deep_color() => {
+                let mut data = Self::load_or_create(settings, &path, format)?;
+                let deep = Self::load_deep(&path, format, &data);
+                // Derive the 8-bit canvas from the deep one, so both agree on how colors are narrowed.
+                for (pixel, &deep_pixel) in data.pixels_mut().zip(deep.pixels()) {
+                    *pixel = Color16::from_rgba16(deep_pixel).to_color().into_rgba();
+                }
+                SharedImageHandle::new(data).with_deep(deep)
+            }
+            None => SharedImageHandle::new(Self::load_or_create(settings, &path, format)?),
+        };
+        let patch = settings
+            .apply_patch
+            .as_ref()
+            .map(PathBuf::from)
+            .filter(|patch| {
+                let pending = patch.exists() || !applied_patch_path(patch).exists();
+                if !pending {
+                    log::info!(
+                        "Patch {} was applied already, skipping it.",
+                        patch.display()
+                    );
+                }
+                pending
+            });
+        if let Some(patch) = &patch {
+            let applied = Self::apply_patch(&image, patch)?;
+            log::info!("Applied {} pixels from patch {}.", applied, patch.display());
+        }
+
+        let place = Place {
+            image,
+            path,
+            mmap_path: settings.mmap_file.as_ref().map(PathBuf::from),
+            format,
+            save_bit_depth: settings.save_bit_depth,
+            indexed_png: settings.indexed_png,
+            create_dirs: settings.create_dirs,
+            metadata: None,
+            frame_channels: FrameChannels::new(DEFAULT_FRAME_CHANNEL_CAPACITY),
+            background_color: AtomicU32::new(settings.background_color.into_rgba32()),
+            png_cache: Mutex::new(None),
+            thumbnail_cache: Mutex::new(Vec::new()),
+            export_buffer: Mutex::new(RgbaImage::new(0, 0)),
+            save_timeout: (settings.save_timeout_secs > 0)
+                .then(|| Duration::from_secs(settings.save_timeout_secs)),
+            saving: AtomicBool::new(false),
+            save_failure_policy: settings.save_failure_policy,
+            frozen_by_save_failure: AtomicBool::new(false),
         };
 
-        let (png_sender, _) = broadcast::channel(8);
+        // The patch is only moved out of the way once the patched canvas is saved, so a crash
+        // before that applies it again on the next start instead of losing it.
+        if let Some(patch) = patch {
+            place.save()?;
+            let applied = applied_patch_path(&patch);
+            std::fs::rename(&patch, &applied).map_err(|e| {
+                format!(
+                    "Failed to rename applied patch {} to {}: {}",
+                    patch.display(),
+                    applied.display(),
+                    e
+                )
+            })?;
+        }
+
+        Ok(place)
+    }
+
+    /// Composites an RGBA image of the same size onto the canvas, skipping fully transparent pixels.
+    /// Returns the number of pixels changed.
+    fn apply_patch(image: &SharedImageHandle, path: &Path) -> PResult<u64> {
+        let patch = image::open(path)
+            .map_err(|e| format!("Failed to load patch {}: {}", path.display(), e))?
+            .into_rgba8();
+        if patch.dimensions() != image.get_dimensions() {
+            return Err(format!(
+                "Patch {} is {}x{}, but the canvas is {}x{}.",
+                path.display(),
+                patch.width(),
+                patch.height(),
+                image.get_dimensions().0,
+                image.get_dimensions().1
+            )
+            .into());
+        }
+
+        let mut applied = 0;
+        for (x, y, &pixel) in patch.enumerate_pixels() {
+            let color = Color::from_rgba(pixel);
+            if color.a == 0 {
+                continue;
+            }
+
+            // Source-over blending, the result is opaque wherever the patch is.
+            let previous = image.get(x, y).unwrap_or(color);
+            let blended = previous.lerp(&Color { a: 255, ..color }, color.a as f32 / 255.0);
+            if blended != previous {
+                image.put(x, y, blended, false);
+                applied += 1;
+            }
+        }
+        Ok(applied)
+    }
+
+    pub fn new_memory(settings: &CanvasSettings) -> PResult<Place> {
+        let data = Self::blank_image(settings);
+        let mut image = SharedImageHandle::new(data.clone());
+        if settings.deep_color() {
+            image = image.with_deep(DynamicImage::ImageRgba8(data).into_rgba16());
+        }
 
         Ok(Place {
-            image: SharedImageHandle::new(data),
-            path,
-            png_sender,
+            image,
+            path: PathBuf::from(""),
+            mmap_path: None,
+            format: ImageFormat::Png,
+            save_bit_depth: settings.save_bit_depth,
+            indexed_png: settings.indexed_png,
+            create_dirs: settings.create_dirs,
+            metadata: None,
+            frame_channels: FrameChannels::new(DEFAULT_FRAME_CHANNEL_CAPACITY),
+            background_color: AtomicU32::new(settings.background_color.into_rgba32()),
+            png_cache: Mutex::new(None),
+            thumbnail_cache: Mutex::new(Vec::new()),
+            export_buffer: Mutex::new(RgbaImage::new(0, 0)),
+            save_timeout: (settings.save_timeout_secs > 0)
+                .then(|| Duration::from_secs(settings.save_timeout_secs)),
+            saving: AtomicBool::new(false),
+            save_failure_policy: settings.save_failure_policy,
+            frozen_by_save_failure: AtomicBool::new(false),
         })
     }
 
-    pub fn new_memory(settings: &CanvasSettings) -> PResult<Place> {
+    /// Buffers up to `capacity` frames per format for subscribers. Only has an effect before anyone
+    /// subscribed.
+    pub fn with_frame_channel_capacity(mut self, capacity: usize) -> Place {
+        let previous = std::mem::replace(&mut self.frame_channels, FrameChannels::new(capacity));
+        self.frame_channels.compression = previous.compression;
+        self.frame_channels.preview = previous.preview;
+        self
+    }
+
+    /// Streams a downscale of the canvas fitting in `max_dimension` x `max_dimension` pixels every
+    /// `interval` to preview subscribers. Only has an effect before anyone subscribed.
+    pub fn with_preview(mut self, max_dimension: u32, interval: Duration) -> Place {
+        self.frame_channels.preview = (max_dimension > 0).then(|| PreviewChannel {
+            // Viewers only ever need the latest preview.
+            sender: broadcast::channel(1).0,
+            max_dimension,
+            interval,
+        });
+        self
+    }
+
+    /// Compresses streamed PNG frames at the `frames` level, and the complete frames sent to
+    /// clients without the previous one at the `keyframes` level. Only has an effect before the
+    /// diffing task is started.
+    pub fn with_frame_compression(
+        mut self,
+        frames: PngCompression,
+        keyframes: PngCompression,
+    ) -> Place {
+        self.frame_channels.compression = FrameCompression {
+            frames,
+            keyframes,
+            indexed_png: self.indexed_png,
+        };
+        self
+    }
+
+    /// Embeds metadata from the given sources in saved canvas files.
+    pub fn with_metadata(mut self, metadata: SaveMetadata) -> Place {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn background_color(&self) -> Color {
+        Color::rgba32(self.background_color.load(Ordering::Relaxed))
+    }
+
+    /// Sets a new background color, returns the previous one.
+    pub fn set_background_color(&self, color: Color) -> Color {
+        Color::rgba32(
+            self.background_color
+                .swap(color.into_rgba32(), Ordering::Relaxed),
+        )
+    }
+
+    /// Infers the canvas file format from the filename's extension, falling back to PNG.
+    pub fn canvas_format(path: &Path) -> PResult<ImageFormat> {
+        let format = match ImageFormat::from_path(path) {
+            Ok(format) => format,
+            Err(_) => {
+                log::warn!(
+                    "Couldn't infer image format from filename {}, falling back to PNG.",
+                    path.display()
+                );
+                ImageFormat::Png
+            }
+        };
+
+        // Only allow formats that can store the canvas losslessly, including the alpha channel.
+        match format {
+            ImageFormat::Png | ImageFormat::WebP | ImageFormat::Qoi | ImageFormat::Bmp => Ok(format),
+            _ => Err(format!(
+                "Image format {:?} of {} can't be used for the canvas, supported formats are: png, webp, qoi, bmp.",
+                format,
+                path.display()
+            )
+            .into()),
+        }
+    }
+
+    pub fn load_image(path: &Path, format: ImageFormat) -> PResult<RgbaImage> {
+        let f = File::open(path)?;
+        let image = BufReader::new(f);
+        Ok(image::load(image, format)?.into_rgba8())
+    }
+
+    /// Loads the canvas from `path`, handling failures according to the load failure policy.
+    /// Creates a blank canvas file if there's none yet.
+    fn load_or_create(
+        settings: &CanvasSettings,
+        path: &Path,
+        format: ImageFormat,
+    ) -> PResult<RgbaImage> {
         let size = settings.size.get() as u32;
 
-        let data = {
-            let mut data = RgbaImage::new(size, size);
-            for pixel in data.pixels_mut() {
-                *pixel = settings.background_color.into_rgba();
+        let data = if path.exists() {
+            match Self::load_image(path, format) {
+                Ok(image) if image.dimensions() == (size, size) => image,
+                Ok(image) if settings.allow_resize => {
+                    log::warn!(
+                        "Canvas {} is {}x{}, {} it to the configured size of {}x{}.",
+                        path.display(),
+                        image.width(),
+                        image.height(),
+                        if image.width() > size || image.height() > size {
+                            "cropping"
+                        } else {
+                            "expanding"
+                        },
+                        size,
+                        size
+                    );
+                    Self::resize_canvas(settings, &image)
+                }
+                Ok(image) => {
+                    return Err(format!(
+                        "Image dimensions do not match configured canvas size: {:?} != {:?}. \
+                         Set allow_resize to migrate the canvas.",
+                        image.dimensions(),
+                        (size, size)
+                    )
+                    .into());
+                }
+                Err(e) => match settings.load_failure_policy {
+                    LoadFailurePolicy::Fail => {
+                        log::error!("Failed to load canvas, load_failure_policy is \"fail\".");
+                        return Err(format!(
+                            "Failed to load canvas from {}: {}",
+                            path.display(),
+                            e
+                        )
+                        .into());
+                    }
+                    LoadFailurePolicy::Backup => {
+                        let timestamp = SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)?
+                            .as_secs();
+                        let mut backup_path = path.as_os_str().to_os_string();
+                        backup_path.push(format!(".corrupt-{}", timestamp));
+
+                        log::warn!(
+                            "Failed to load canvas from {}: {}. load_failure_policy is \"backup\", moving it to {} and starting with a fresh canvas.",
+                            path.display(),
+                            e,
+                            backup_path.to_string_lossy()
+                        );
+                        std::fs::rename(path, &backup_path)?;
+
+                        let data = Self::blank_image(settings);
+                        Self::save_created(settings, path, format, &data)?;
+                        data
+                    }
+                },
             }
+        } else {
+            let data = Self::blank_image(settings);
+            Self::save_created(settings, path, format, &data)?;
             data
         };
 
-        let (png_sender, _) = broadcast::channel(8);
+        Ok(data)
+    }
 
-        Ok(Place {
-            image: SharedImageHandle::new(data),
-            path: PathBuf::from(""),
-            png_sender,
-        })
+    /// Writes a freshly created canvas to `path`, unless that's postponed to the first save.
+    fn save_created(
+        settings: &CanvasSettings,
+        path: &Path,
+        format: ImageFormat,
+        data: &RgbaImage,
+    ) -> PResult<()> {
+        if !settings.save_on_create {
+            log::info!(
+                "Created a fresh canvas, it will be written to {} on the first save.",
+                path.display()
+            );
+            return Ok(());
+        }
+
+        ensure_parent_dir(path, settings.create_dirs)?;
+        data.save_with_format(path, format)?;
+        Ok(())
+    }
+
+    /// Loads the canvas from `path` with 16 bits per channel. Falls back to widening `image`,
+    /// the canvas as loaded by `load_or_create`, if the file doesn't match it.
+    fn load_deep(path: &Path, format: ImageFormat, image: &RgbaImage) -> DeepCanvas {
+        let loaded = File::open(path)
+            .ok()
+            .and_then(|f| image::load(BufReader::new(f), format).ok())
+            .map(|loaded| loaded.into_rgba16());
+
+        match loaded {
+            Some(deep) if deep.dimensions() == image.dimensions() => deep,
+            // The canvas has just been migrated to a different size.
+            _ => DynamicImage::ImageRgba8(image.clone()).into_rgba16(),
+        }
+    }
+
+    /// Copies `image` into the top left corner of a blank canvas of the configured size, cropping it if needed.
+    fn resize_canvas(settings: &CanvasSettings, image: &RgbaImage) -> RgbaImage {
+        let mut data = Self::blank_image(settings);
+        imageops::replace(&mut data, image, 0, 0);
+        data
+    }
+
+    fn blank_image(settings: &CanvasSettings) -> RgbaImage {
+        let size = settings.size.get() as u32;
+        let mut data = RgbaImage::new(size, size);
+        for pixel in data.pixels_mut() {
+            *pixel = settings.background_color.into_rgba();
+        }
+        data
     }
 
-    pub fn save(&self) -> PResult<()> {
+    /// Returns the canvas encoded as PNG. The result is cached until the canvas changes.
+    pub fn png(&self) -> PResult<EncodedCanvas> {
+        let version = self.image.version();
+        let mut cache = self.png_cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(cached) = cache.as_ref().filter(|(v, _)| v.version == version.version) {
+            return Ok(cached.clone());
+        }
+
+        let png = Bytes::from(encode_canvas_png(&self.image.snapshot(), self.indexed_png)?);
+        *cache = Some((version, png.clone()));
+        Ok((version, png))
+    }
+
+    /// Returns the canvas downscaled to `size` x `size` pixels, encoded as PNG.
+    /// Results are cached until the canvas changes, for the few most recently requested sizes.
+    pub fn thumbnail(&self, size: u32) -> PResult<EncodedCanvas> {
+        let version = self.image.version();
+        {
+            let mut cache = self
+                .thumbnail_cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            cache.retain(|(_, (v, _))| v.version == version.version);
+            if let Some(i) = cache.iter().position(|(s, _)| *s == size) {
+                let entry = cache.remove(i);
+                let cached = entry.1.clone();
+                cache.push(entry);
+                return Ok(cached);
+            }
+        }
+
+        // Encoded without holding the lock, so requests for other sizes aren't held up.
+        let thumbnail = imageops::resize(
+            &self.image.snapshot(),
+            size,
+            size,
+            imageops::FilterType::Triangle,
+        );
+        let png = Bytes::from(encode_png(&thumbnail)?);
+
+        let mut cache = self
+            .thumbnail_cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        // Not cached if another request already encoded a newer version meanwhile.
+        if cache.iter().all(|(_, (v, _))| v.version <= version.version) {
+            cache.retain(|(s, (v, _))| *s != size && v.version == version.version);
+            if cache.len() >= THUMBNAIL_CACHE_SIZE {
+                cache.remove(0);
+            }
+            cache.push((size, (version, png.clone())));
+        }
+        Ok((version, png))
+    }
+
+    /// Runs `write`, eg. a save of the canvas, on the blocking thread pool so a slow disk doesn't hold
+    /// up the runtime. Waiting for it is given up after the save timeout, but the write carries on in
+    /// the background and further ones are refused until it's done.
+    pub async fn write_in_background<T: Send + 'static>(
+        self: &Arc<Self>,
+        write: impl FnOnce(&Place) -> PResult<T> + Send + 'static,
+    ) -> PResult<T> {
+        if self.saving.swap(true, Ordering::AcqRel) {
+            return Err(WriteInProgress("A previous save is still in progress".into()).into());
+        }
+
+        let place = self.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            let _saving = ClearOnDrop(&place.saving);
+            write(&place)
+        });
+        match self.save_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, handle).await {
+                Ok(result) => result?,
+                Err(_) => Err(WriteInProgress(format!(
+                    "Writing {} is still in progress after {:?}, leaving it to complete in the background",
+                    self.path.display(),
+                    timeout
+                ))
+                .into()),
+            },
+            None => handle.await?,
+        }
+    }
+
+    /// Persists the canvas and returns the path of the written file.
+    /// Memory-mapped canvases are only flushed to their backing file, others are exported to `path`.
+    pub fn save(&self) -> PResult<&Path> {
+        if let Some(mmap_path) = &self.mmap_path {
+            self.image.flush()?;
+            return Ok(mmap_path);
+        }
+
+        self.export()?;
+        Ok(&self.path)
+    }
+
+    /// Writes the canvas to `path` in the configured image format.
+    pub fn export(&self) -> PResult<()> {
         if self.path == PathBuf::from("") {
             return Err("No path to save to".into());
         }
 
-        let mut image = {
-            let (width, height) = self.image.get_dimensions();
-            ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, height)
-        };
-
-        let shared_image = unsafe { self.image.get_image() };
-        image.copy_from_slice(shared_image.as_raw().as_slice());
+        ensure_parent_dir(&self.path, self.create_dirs)?;
+        let mut image = self.export_buffer.lock().unwrap_or_else(|e| e.into_inner());
+        self.image.snapshot_into(&mut image);
 
-        image.save(&self.path)?;
+        // Write to a temporary file first and rename it afterwards, so a crash in the middle
+        // of saving never leaves a truncated canvas behind.
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let text = match &self.metadata {
+            Some(metadata) => metadata.text_chunks(image.width(), image.height()),
+            None => Vec::new(),
+        };
+        let palette = (self.indexed_png
+            && self.format == ImageFormat::Png
+            && self.save_bit_depth == SaveBitDepth::Full)
+            .then(|| Palette::of(&image))
+            .flatten();
+        if let Some(deep) = self.image.snapshot16() {
+            std::fs::write(&tmp_path, encode_saved_png16(&deep, &text)?)?;
+        } else if let Some(palette) = palette {
+            std::fs::write(
+                &tmp_path,
+                encode_indexed_png(image.width(), image.height(), &palette, &text)?,
+            )?;
+        } else if self.save_bit_depth == SaveBitDepth::Full && text.is_empty() {
+            image.save_with_format(&tmp_path, self.format)?;
+        } else {
+            std::fs::write(
+                &tmp_path,
+                encode_saved_png(&image, self.save_bit_depth, &text)?,
+            )?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
 
         Ok(())
     }
 
+    /// Broadcasts the canvas to all websocket clients once per frame interval.
+    /// The canvas is only re-encoded if it has changed since the previous frame, and only
+    /// in the formats someone is subscribed to.
+    /// Frames are downscaled to fit in `max_dimension` x `max_dimension` pixels, if set.
     async fn diffing_task(
         image: SharedImageHandle,
-        png_sender: broadcast::Sender<Arc<[u8]>>,
+        frame_channels: FrameChannels,
+        max_dimension: Option<u32>,
+        flicker_window: Option<Duration>,
+        indexed_png: bool,
+        encode_buffer_limit: usize,
     ) -> PResult<()> {
-        Ok(())
+        let mut buffer = FrameBuffer::new(max_dimension, flicker_window);
+        // Size of the last frame of every format, which the next one is likely close to.
+        let mut frame_sizes: HashMap<FrameFormat, usize> = HashMap::new();
+        // Reused between frames, which mostly share their colors.
+        let mut palette = indexed_png.then(Palette::default);
+        let mut version = None;
+        let mut frame_version = 0;
+        let mut sequence = 0;
+        let mut frames: HashMap<FrameFormat, Frame> = HashMap::new();
+        let mut last_slow_warning: Option<Instant> = None;
+        // The last preview frame and when it was sent.
+        let mut last_preview: Option<(Instant, Frame)> = None;
+
+        let mut interval = tokio::time::interval(FRAME_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            interval.tick().await;
+
+            // Nobody's watching, don't waste CPU time on encoding.
+            if !frame_channels.has_receivers() {
+                continue;
+            }
+
+            // Bump the version before copying, so writes during the copy end up in the next frame.
+            let current = image.version().version;
+            if version != Some(current) || buffer.is_resized(&image) || buffer.is_pending() {
+                version = Some(current);
+                // Let go of the frame first, so updating it in place doesn't have to copy it.
+                frame_channels.set_keyframe_source(None);
+                if buffer.update(&image) {
+                    // Released changes alter the frame without a new canvas version.
+                    frame_version = current;
+                    sequence += 1;
+                    frames.clear();
+                }
+                if frame_channels.has_keyframes() {
+                    let source = buffer.shared_frame().clone();
+                    frame_channels.set_keyframe_source(Some((sequence, source)));
+                }
+            }
+
+            for (format, sender) in &frame_channels.senders {
+                if sender.receiver_count() == 0 {
+                    continue;
+                }
+
+                let frame = match frames.get(format) {
+                    Some(frame) => frame,
+                    None => {
+                        let started = Instant::now();
+                        let capacity = frame_sizes.get(format).copied().unwrap_or(0);
+                        let frame = match format.encode_frame(
+                            buffer.frame(),
+                            palette.as_mut(),
+                            frame_channels.compression.frames,
+                            (frame_version, sequence),
+                            capacity.min(encode_buffer_limit),
+                        ) {
+                            Ok(frame) => frame,
+                            Err(e) => {
+                                log::error!("Failed to encode {:?} frame: {}", format, e);
+                                continue;
+                            }
+                        };
+                        frame_sizes.insert(*format, frame.data.len());
+
+                        let elapsed = started.elapsed();
+                        if let Some(histogram) = frame_channels.encode_time(*format) {
+                            histogram.observe(elapsed);
+                        }
+                        if elapsed > FRAME_INTERVAL
+                            && last_slow_warning
+                                .filter(|t| t.elapsed() < SLOW_WARNING_INTERVAL)
+                                .is_none()
+                        {
+                            log::warn!(
+                                "Encoding a {:?} frame took {:?}, longer than the frame interval of {:?}. \
+                                 Consider lowering max_stream_dimension or using a cheaper format.",
+                                format,
+                                elapsed,
+                                FRAME_INTERVAL
+                            );
+                            last_slow_warning = Some(Instant::now());
+                        }
+                        frames.entry(*format).or_insert(frame)
+                    }
+                };
+
+                // Sending only fails if all receivers went away in the meantime.
+                let _ = sender.send(frame.clone());
+            }
+
+            // The preview is downscaled once per interval for all viewers, and only re-encoded if
+            // the canvas changed since the last one.
+            if let Some(preview) = &frame_channels.preview {
+                let due =
+                    !matches!(&last_preview, Some((at, _)) if at.elapsed() < preview.interval);
+                if due && preview.sender.receiver_count() > 0 {
+                    let frame = match last_preview.take() {
+                        Some((_, frame)) if frame.sequence == sequence => Ok(frame),
+                        _ => preview.encode(
+                            buffer.full_frame(),
+                            frame_channels.compression,
+                            (frame_version, sequence),
+                        ),
+                    };
+                    match frame {
+                        Ok(frame) => {
+                            let _ = preview.sender.send(frame.clone());
+                            last_preview = Some((Instant::now(), frame));
+                        }
+                        Err(e) => log::error!("Failed to encode preview frame: {}", e),
+                    }
+                }
+            }
+        }
     }
 
-    pub fn start_diffing_task(&self) -> JoinHandle<PResult<()>> {
+    /// Streams frames of the canvas, held back by the flicker filter if `flicker_window` is set.
+    /// Frames are encoded into buffers preallocated to the previous frame's size, up to
+    /// `encode_buffer_limit` bytes.
+    pub fn start_diffing_task(
+        &self,
+        max_dimension: Option<u32>,
+        flicker_window: Option<Duration>,
+        encode_buffer_limit: usize,
+    ) -> JoinHandle<PResult<()>> {
         let image = self.image.clone();
-        let png_sender = self.png_sender.clone();
-        tokio::spawn(async move { Self::diffing_task(image, png_sender).await })
+        let frame_channels = self.frame_channels.clone();
+        let indexed_png = self.indexed_png;
+        tokio::spawn(async move {
+            Self::diffing_task(
+                image,
+                frame_channels,
+                max_dimension,
+                flicker_window,
+                indexed_png,
+                encode_buffer_limit,
+            )
+            .await
+        })
     }
 }
 
@@ -177,14 +1873,31 @@ mod test {
 
     use super::*;
 
-    #[test]
-    fn nyauwunyanyanyanya() {
-        let place = Place::new_memory(&CanvasSettings {
-            size: RangedU16::new(512).unwrap(),
+    fn canvas_settings(size: u16) -> CanvasSettings {
+        CanvasSettings {
+            size: RangedU16::new(size).unwrap(),
+            memory_limit_mb: 1024,
             background_color: Color::rgb(255, 255, 255),
             filename: String::new(),
-        })
-        .unwrap();
+            load_failure_policy: LoadFailurePolicy::Fail,
+            mmap_file: None,
+            coordinate_mode: Default::default(),
+            save_bit_depth: SaveBitDepth::Full,
+            indexed_png: false,
+            save_metadata: false,
+            allow_resize: false,
+            color_depth: 8,
+            save_on_create: true,
+            create_dirs: false,
+            save_timeout_secs: 30,
+            save_failure_policy: SaveFailurePolicy::Log,
+            apply_patch: None,
+        }
+    }
+
+    #[test]
+    fn nyauwunyanyanyanya() {
+        let place = Place::new_memory(&canvas_settings(512)).unwrap();
 
         let th = 10;
         let (x, y) = place.image.get_dimensions();
@@ -215,6 +1928,483 @@ mod test {
         }
     }
 
+    #[test]
+    fn canvas_version() {
+        let place = Place::new_memory(&canvas_settings(16)).unwrap();
+
+        let (v1, png1) = place.png().unwrap();
+        let (v2, png2) = place.png().unwrap();
+        assert_eq!(v1, v2);
+        assert_eq!(png1.as_ptr(), png2.as_ptr());
+
+        place.image.put(1, 1, Color::rgb(0, 0, 0), false);
+        let (v3, png3) = place.png().unwrap();
+        assert_eq!(v3.version, v1.version + 1);
+        assert_ne!(v3.etag(), v1.etag());
+        assert_ne!(png1.as_ptr(), png3.as_ptr());
+
+        let (_, thumbnail) = place.thumbnail(4).unwrap();
+        for size in 5..5 + THUMBNAIL_CACHE_SIZE as u32 {
+            place.thumbnail(size).unwrap();
+        }
+        let cached: Vec<u32> = place
+            .thumbnail_cache
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(size, _)| *size)
+            .collect();
+        assert_eq!(
+            cached,
+            (5..5 + THUMBNAIL_CACHE_SIZE as u32).collect::<Vec<_>>()
+        );
+        let (_, again) = place.thumbnail(4).unwrap();
+        assert_ne!(thumbnail.as_ptr(), again.as_ptr());
+        let (_, cached) = place.thumbnail(4).unwrap();
+        assert_eq!(again.as_ptr(), cached.as_ptr());
+    }
+
+    #[test]
+    fn resize_saved_canvas() {
+        let path =
+            std::env::temp_dir().join(format!("place-resize-test-{}.png", std::process::id()));
+        RgbaImage::from_pixel(16, 16, Rgba([255, 0, 0, 255]))
+            .save(&path)
+            .unwrap();
+
+        let mut settings = CanvasSettings {
+            filename: path.to_string_lossy().into_owned(),
+            ..canvas_settings(32)
+        };
+        assert!(Place::new(&settings).is_err());
+
+        settings.allow_resize = true;
+        let place = Place::new(&settings).unwrap();
+        assert_eq!(place.image.get_dimensions(), (32, 32));
+        assert_eq!(place.image.get(15, 15), Some(Color::rgb(255, 0, 0)));
+        assert_eq!(place.image.get(16, 15), Some(Color::rgb(255, 255, 255)));
+
+        settings.size = RangedU16::new(16).unwrap();
+        RgbaImage::from_pixel(20, 20, Rgba([0, 0, 255, 255]))
+            .save(&path)
+            .unwrap();
+        let place = Place::new(&settings).unwrap();
+        assert_eq!(place.image.get_dimensions(), (16, 16));
+        assert_eq!(place.image.get(15, 15), Some(Color::rgb(0, 0, 255)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn deep_color() {
+        let place = Place::new_memory(&CanvasSettings {
+            color_depth: 16,
+            ..canvas_settings(16)
+        })
+        .unwrap();
+
+        let color = Color16::rgb(0x1234, 0x00ff, 0xff00);
+        place.image.put_block16(15, 15, color, 2);
+        assert_eq!(place.image.get(15, 15), Some(color.to_color()));
+        let deep = place.image.snapshot16().unwrap();
+        assert_eq!(*deep.get_pixel(15, 15), color.into_rgba16());
+        assert_eq!(*deep.get_pixel(0, 0), Rgba([u16::MAX; 4]));
+
+        // Narrow writes keep both canvases in sync.
+        assert_eq!(
+            place
+                .image
+                .replace_color(color.to_color(), Color::rgb(0, 0, 0)),
+            1
+        );
+        let deep = place.image.snapshot16().unwrap();
+        assert_eq!(*deep.get_pixel(15, 15), Rgba([0, 0, 0, u16::MAX]));
+
+        place.image.put_block16(1, 2, color, 1);
+        let deep = place.image.snapshot16().unwrap();
+        let png = encode_saved_png16(&deep, &[]).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().into_rgba16();
+        assert_eq!(decoded, deep);
+    }
+
+    #[test]
+    fn apply_patch() {
+        let path =
+            std::env::temp_dir().join(format!("place-patch-test-{}.png", std::process::id()));
+        let mut patch = RgbaImage::new(4, 4);
+        patch.put_pixel(1, 1, Rgba([255, 0, 0, 255]));
+        patch.put_pixel(2, 2, Rgba([0, 0, 0, 128]));
+        patch.save(&path).unwrap();
+
+        let image = SharedImageHandle::new(RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255])));
+        assert_eq!(Place::apply_patch(&image, &path).unwrap(), 2);
+        assert_eq!(image.get(1, 1), Some(Color::rgb(255, 0, 0)));
+        assert_eq!(image.get(2, 2), Some(Color::rgb(127, 127, 127)));
+        assert_eq!(image.get(0, 0), Some(Color::rgb(255, 255, 255)));
+
+        let image = SharedImageHandle::new(RgbaImage::new(8, 8));
+        assert!(Place::apply_patch(&image, &path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn create_canvas_lazily() {
+        let dir = std::env::temp_dir().join(format!("place-lazy-test-{}", std::process::id()));
+        let path = dir.join("data").join("place.png");
+        let mut settings = CanvasSettings {
+            filename: path.to_string_lossy().into_owned(),
+            ..canvas_settings(16)
+        };
+        let err = Place::new(&settings).err().unwrap();
+        assert!(err.to_string().contains("doesn't exist"), "{}", err);
+
+        settings.save_on_create = false;
+        let place = Place::new(&settings).unwrap();
+        assert!(!path.exists());
+        assert!(place.save().is_err());
+
+        settings.create_dirs = true;
+        let place = Place::new(&settings).unwrap();
+        place.save().unwrap();
+        assert!(path.exists());
+
+        // Patches are applied once, the patched canvas is saved before the patch is renamed.
+        let patch = dir.join("patch.png");
+        RgbaImage::from_pixel(16, 16, Rgba([0, 0, 0, 128]))
+            .save(&patch)
+            .unwrap();
+        settings.apply_patch = Some(patch.to_string_lossy().into_owned());
+        let place = Place::new(&settings).unwrap();
+        assert_eq!(place.image.get(0, 0), Some(Color::rgb(127, 127, 127)));
+        assert!(!patch.exists());
+        assert!(dir.join("patch.png.applied").exists());
+        let place = Place::new(&settings).unwrap();
+        assert_eq!(place.image.get(0, 0), Some(Color::rgb(127, 127, 127)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn frame_buffer_resize() {
+        let canvas = |size, color| SharedImageHandle::new(RgbaImage::from_pixel(size, size, color));
+        let mut buffer = FrameBuffer::new(Some(8), None);
+
+        let image = canvas(16, Rgba([255, 0, 0, 255]));
+        assert!(buffer.is_resized(&image));
+        buffer.update(&image);
+        assert!(!buffer.is_resized(&image));
+        assert_eq!(buffer.frame().dimensions(), (8, 8));
+
+        // The canvas shrinks mid-stream, below the streamed size.
+        let image = canvas(4, Rgba([0, 0, 255, 255]));
+        assert!(buffer.is_resized(&image));
+        buffer.update(&image);
+        assert_eq!(buffer.frame().dimensions(), (4, 4));
+        assert_eq!(*buffer.frame().get_pixel(3, 3), Rgba([0, 0, 255, 255]));
+
+        let image = canvas(32, Rgba([0, 255, 0, 255]));
+        buffer.update(&image);
+        assert_eq!(buffer.frame().dimensions(), (8, 8));
+        assert_eq!(*buffer.frame().get_pixel(7, 7), Rgba([0, 255, 0, 255]));
+
+        // Frames are only copied while someone else holds on to them, eg. a keyframe encode.
+        let mut buffer = FrameBuffer::new(None, None);
+        buffer.update(&image);
+        let full = buffer.shared_frame().as_ptr();
+        buffer.update(&image);
+        assert_eq!(buffer.shared_frame().as_ptr(), full);
+        let held = buffer.shared_frame().clone();
+        buffer.update(&image);
+        assert_ne!(buffer.shared_frame().as_ptr(), held.as_ptr());
+    }
+
+    #[tokio::test]
+    async fn preview_stream() {
+        let image = SharedImageHandle::new(RgbaImage::from_pixel(32, 16, Rgba([255, 0, 0, 255])));
+        let mut channels = FrameChannels::new(1);
+        channels.preview = Some(PreviewChannel {
+            sender: broadcast::channel(1).0,
+            max_dimension: 8,
+            interval: Duration::from_millis(10),
+        });
+        let mut previews = channels.subscribe_preview().unwrap();
+        let task = tokio::spawn(Place::diffing_task(
+            image,
+            channels,
+            None,
+            None,
+            false,
+            usize::MAX,
+        ));
+
+        // Only preview subscribers are enough to get frames encoded.
+        let frame = tokio::time::timeout(Duration::from_secs(5), previews.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        task.abort();
+        let preview = image::load_from_memory(&frame.data).unwrap().into_rgba8();
+        assert_eq!(preview.dimensions(), (8, 4));
+        assert_eq!(*preview.get_pixel(7, 3), Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn flicker_filter() {
+        let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+        let mut filter = FlickerFilter::new(Duration::from_millis(100));
+        let start = filter.started;
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut shown = RgbaImage::new(0, 0);
+
+        filter.canvas = RgbaImage::from_pixel(2, 1, red);
+        assert!(filter.apply(&mut shown, ms(0)));
+        assert_eq!(shown, filter.canvas);
+
+        // The first change goes through, flipping back right away is held back.
+        filter.canvas.put_pixel(0, 0, blue);
+        assert!(filter.apply(&mut shown, ms(10)));
+        assert_eq!(*shown.get_pixel(0, 0), blue);
+        filter.canvas.put_pixel(0, 0, red);
+        filter.canvas.put_pixel(1, 0, blue);
+        assert!(filter.apply(&mut shown, ms(20)));
+        assert_eq!(
+            (*shown.get_pixel(0, 0), *shown.get_pixel(1, 0)),
+            (blue, blue)
+        );
+        assert!(filter.pending);
+        assert!(!filter.apply(&mut shown, ms(50)));
+
+        // Only the latest color shows up once the window is over.
+        filter.canvas.put_pixel(0, 0, Rgba([0, 255, 0, 255]));
+        assert!(filter.apply(&mut shown, ms(110)));
+        assert_eq!(*shown.get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+        assert!(!filter.pending);
+    }
+
+    #[test]
+    fn frame_formats() {
+        assert_eq!(FrameFormat::parse("QOI"), Some(FrameFormat::Qoi));
+        assert_eq!(FrameFormat::parse("gif"), None);
+
+        let image = RgbaImage::from_pixel(3, 2, Rgba([1, 2, 3, 4]));
+        // Leftovers of the previous frame are replaced.
+        let mut data = vec![0xff; 64];
+        for format in FrameFormat::supported() {
+            format
+                .encode_into(&image, None, PngCompression::Fast, &mut data)
+                .unwrap();
+            let decoded = match format {
+                FrameFormat::Raw => RgbaImage::from_raw(3, 2, data.clone()).unwrap(),
+                _ => image::load_from_memory(&data).unwrap().into_rgba8(),
+            };
+            assert_eq!(decoded, image, "{:?}", format);
+        }
+
+        let frame = FrameFormat::Png
+            .encode_frame(&image, None, PngCompression::Fast, (7, 8), data.len())
+            .unwrap();
+        assert_eq!((frame.version, frame.sequence), (7, 8));
+        assert_eq!(frame.crc32, crc32fast::hash(&frame.data));
+        assert_eq!(
+            image::load_from_memory(&frame.data).unwrap().into_rgba8(),
+            image
+        );
+    }
+
+    #[tokio::test]
+    async fn keyframes() {
+        let image = RgbaImage::from_fn(64, 64, |x, y| Rgba([(x * 4) as u8, (y * 4) as u8, 0, 255]));
+        let mut data = Vec::new();
+        FrameFormat::Png
+            .encode_into(&image, None, PngCompression::Fast, &mut data)
+            .unwrap();
+        let frame = Frame {
+            version: 1,
+            sequence: 1,
+            crc32: crc32fast::hash(&data),
+            data: Bytes::from(data),
+        };
+
+        let mut channels = FrameChannels::new(1);
+        // Same levels, nothing to re-encode.
+        let keyframe = channels.keyframe(FrameFormat::Png, frame.clone());
+        assert_eq!(keyframe.data.as_ptr(), frame.data.as_ptr());
+
+        // Clients get the streamed frame until the keyframe is encoded.
+        channels.compression.keyframes = PngCompression::Best;
+        channels.set_keyframe_source(Some((1, Arc::new(image.clone()))));
+        let mut keyframe = channels.keyframe(FrameFormat::Png, frame.clone());
+        assert_eq!(keyframe.data.as_ptr(), frame.data.as_ptr());
+        while keyframe.data.as_ptr() == frame.data.as_ptr() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            keyframe = channels.keyframe(FrameFormat::Png, frame.clone());
+        }
+        assert!(keyframe.data.len() < frame.data.len());
+        assert_eq!(keyframe.crc32, crc32fast::hash(&keyframe.data));
+        let decoded = image::load_from_memory(&keyframe.data)
+            .unwrap()
+            .into_rgba8();
+        assert_eq!(decoded, image);
+
+        // Clients asking for the same frame share the encode.
+        let again = channels.keyframe(FrameFormat::Png, frame.clone());
+        assert_eq!(again.data.as_ptr(), keyframe.data.as_ptr());
+        let raw = channels.keyframe(FrameFormat::Raw, frame.clone());
+        assert_eq!(raw.data.as_ptr(), frame.data.as_ptr());
+    }
+
+    #[test]
+    fn put_blocks() {
+        let image = SharedImageHandle::new(RgbaImage::new(4, 4));
+        let red = Color::rgb(255, 0, 0);
+        image.put_block(1, 1, red, 2);
+        // Straddles the bottom right corner.
+        image.put_block(3, 3, Color::rgb(0, 255, 0), 2);
+
+        let snapshot = image.snapshot();
+        let colored: Vec<_> = snapshot
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p.0[3] != 0)
+            .map(|(x, y, p)| (x, y, Color::from_rgba(*p)))
+            .collect();
+        assert_eq!(
+            colored,
+            [
+                (1, 1, red),
+                (2, 1, red),
+                (1, 2, red),
+                (2, 2, red),
+                (3, 3, Color::rgb(0, 255, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn downscaled_stream_dimensions() {
+        assert_eq!(stream_dimensions(4096, 4096, None), (4096, 4096));
+        assert_eq!(stream_dimensions(4096, 4096, Some(1024)), (1024, 1024));
+        assert_eq!(stream_dimensions(512, 512, Some(1024)), (512, 512));
+        assert_eq!(stream_dimensions(4096, 2048, Some(1000)), (1000, 500));
+    }
+
+    #[test]
+    fn indexed_png() {
+        let mut image = RgbaImage::from_pixel(32, 32, Rgba([255, 255, 255, 255]));
+        image.put_pixel(3, 4, Rgba([255, 0, 0, 255]));
+        image.put_pixel(5, 6, Rgba([0, 0, 100, 128]));
+
+        let png = encode_canvas_png(&image, true).unwrap();
+        let decoder = ::png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().color_type, ::png::ColorType::Indexed);
+        let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png)
+            .unwrap()
+            .into_rgba8();
+        assert_eq!(decoded, image);
+
+        // Too many colors for a palette.
+        for (i, pixel) in image.pixels_mut().enumerate() {
+            pixel.0[0] = i as u8;
+            pixel.0[1] = (i >> 8) as u8;
+        }
+        assert!(Palette::of(&image).is_none());
+        let png = encode_canvas_png(&image, true).unwrap();
+        let reader = ::png::Decoder::new(png.as_slice()).read_info().unwrap();
+        assert_eq!(reader.info().color_type, ::png::ColorType::Rgba);
+
+        // A reused palette keeps the colors of the previous image until they don't fit anymore.
+        let shades = |offset: u8, green: u8| {
+            RgbaImage::from_fn(200, 1, |x, _| Rgba([x as u8 + offset, green, 0, 255]))
+        };
+        let mut palette = Palette::default();
+        assert!(palette.index(&shades(0, 0)));
+        assert!(palette.index(&shades(10, 0)));
+        assert_eq!(palette.colors.len(), 210);
+        assert!(palette.index(&shades(0, 1)));
+        assert_eq!(palette.colors.len(), 200);
+        assert_eq!(palette.colors[palette.indices[0] as usize], [0, 1, 0, 255]);
+        assert!(!palette.index(&image));
+        assert!(palette.colors.is_empty());
+    }
+
+    #[test]
+    fn rgb332_png() {
+        let mut image = RgbaImage::new(4, 1);
+        image.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        image.put_pixel(1, 0, Rgba([250, 10, 0, 255]));
+        image.put_pixel(2, 0, Rgba([0, 0, 100, 128]));
+
+        let png = encode_saved_png(&image, SaveBitDepth::Rgb332, &[]).unwrap();
+        let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png)
+            .unwrap()
+            .into_rgba8();
+        assert_eq!(decoded.get_pixel(0, 0), &Rgba([255, 255, 255, 255]));
+        assert_eq!(decoded.get_pixel(1, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(decoded.get_pixel(2, 0), &Rgba([0, 0, 85, 255]));
+        assert_eq!(decoded.get_pixel(3, 0), &Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn png_text_chunks() {
+        let image = RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 4]));
+        let text = [("Prefix".to_string(), "2602:fa9b:42::/48".to_string())];
+        let png = encode_saved_png(&image, SaveBitDepth::Full, &text).unwrap();
+
+        let reader = ::png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let chunk = &reader.info().uncompressed_latin1_text[0];
+        assert_eq!(
+            (chunk.keyword.as_str(), chunk.text.as_str()),
+            ("Prefix", "2602:fa9b:42::/48")
+        );
+
+        let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png)
+            .unwrap()
+            .into_rgba8();
+        assert_eq!(decoded, image);
+    }
+
+    #[tokio::test]
+    async fn background_writes() {
+        let mut place = Place::new_memory(&CanvasSettings {
+            save_on_create: false,
+            ..canvas_settings(16)
+        })
+        .unwrap();
+        place.save_timeout = Some(Duration::from_millis(50));
+        let place = Arc::new(place);
+
+        // A write stuck on the disk times out and blocks further writes until it's done.
+        let (unblock, blocked) = std::sync::mpsc::channel::<()>();
+        let result = place
+            .write_in_background(move |_| Ok(blocked.recv().ok()))
+            .await;
+        assert!(result.unwrap_err().is::<WriteInProgress>());
+        let result = place.write_in_background(|_| Ok(())).await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("still in progress"));
+
+        unblock.send(()).unwrap();
+        while place.saving.load(Ordering::Acquire) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(place.write_in_background(|_| Ok(1)).await.unwrap(), 1);
+
+        // A panicking write doesn't block further ones.
+        let result = place
+            .write_in_background(|_| -> PResult<()> { panic!() })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(place.write_in_background(|_| Ok(2)).await.unwrap(), 2);
+
+        let full: Box<dyn std::error::Error> =
+            image::ImageError::IoError(io::Error::from_raw_os_error(28)).into();
+        assert!(describe_save_error(&*full).ends_with("free up space to save again."));
+        let missing: Box<dyn std::error::Error> = "No path to save to".into();
+        assert_eq!(describe_save_error(&*missing), "No path to save to");
+    }
+
     #[tokio::test]
     async fn ip6_wuuwuwuw() {
         let mut config = Config::new();
@@ -0,0 +1,283 @@
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    process::Command,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use surge_ping::{Client, Config, ICMP};
+
+use crate::{
+    backend::{
+        iface, writer::PixelQueue, PacketCounter, PixelRequest, COORD_MASK, SUBNET_PREFIX_LEN,
+    },
+    place::SharedImageHandle,
+    settings::{BackendType, CoordinateMode, Settings},
+    utils::{Color, Color16},
+};
+
+/// Time given to the backend to come up before sending the test pixels.
+const STARTUP_DELAY: Duration = Duration::from_secs(1);
+/// How long to wait for the test pixels to show up on the canvas, on top of the coalescing window.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Source the restored pixels are attributed to.
+const RESTORE_SOURCE: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+/// A test pixel, as sent on the wire and as expected on the canvas.
+struct Probe {
+    addr: Ipv6Addr,
+    pos: (u32, u32),
+    color: Color,
+    previous: Color,
+}
+
+/// Entry point of `place-backend --selftest`, run alongside the server. Pings a few pixels at known
+/// coordinates the same way clients do and checks that they end up on the canvas, printing PASS or
+/// FAIL along with diagnostics. The pixels are restored through the pixel queue afterwards, so the
+/// history, attribution and cooldown see the restore like any other write. Returns whether the test
+/// passed.
+pub async fn run(
+    settings: &Settings,
+    image: &SharedImageHandle,
+    pixel_queue: &PixelQueue,
+    packet_counter: &Arc<PacketCounter>,
+) -> bool {
+    tokio::time::sleep(STARTUP_DELAY).await;
+
+    let probes = probes(
+        settings.backend.prefix48,
+        settings.canvas.coordinate_mode,
+        settings.canvas.deep_color(),
+        image,
+    );
+    let packets_before = packet_counter.total();
+    let result = send_probes(settings, &probes).await;
+
+    let deadline =
+        Instant::now() + TIMEOUT + Duration::from_millis(settings.backend.coalesce_window_ms);
+    let mut arrived = vec![false; probes.len()];
+    while result.is_ok() && Instant::now() < deadline && !arrived.iter().all(|&a| a) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for (probe, arrived) in probes.iter().zip(&mut arrived) {
+            *arrived |= image.get(probe.pos.0, probe.pos.1) == Some(probe.color);
+        }
+    }
+
+    let restored = restore(settings, image, pixel_queue, &probes, &arrived).await;
+
+    let passed = result.is_ok() && arrived.iter().all(|&a| a);
+    println!("Self-test {}", if passed { "PASS" } else { "FAIL" });
+    if let Err(e) = &result {
+        println!("  Failed to send pings: {}", e);
+    }
+    for (probe, arrived) in probes.iter().zip(&arrived) {
+        println!(
+            "  {} -> ({}, {}) {}",
+            probe.addr,
+            probe.pos.0,
+            probe.pos.1,
+            if *arrived { "ok" } else { "missing" }
+        );
+    }
+    if !restored {
+        println!("  Some test pixels couldn't be restored and are still on the canvas.");
+    }
+
+    if !passed {
+        print_diagnostics(settings, &probes);
+        println!(
+            "  Packets received by the backend: {}",
+            packet_counter.total() - packets_before
+        );
+    }
+
+    passed
+}
+
+/// Picks pixels in the corners and the middle of the canvas, colored to differ from what's there.
+fn probes(
+    prefix48: Ipv6Addr,
+    coordinate_mode: CoordinateMode,
+    deep_color: bool,
+    image: &SharedImageHandle,
+) -> Vec<Probe> {
+    let (width, height) = image.get_dimensions();
+    let prefix = prefix48.segments();
+    // Probe the corners of the grid clients address, which is smaller if coordinates get scaled,
+    // and can't reach past the coordinates addresses have room for on large canvases.
+    let scale = coordinate_mode.scale.max(1) as u32;
+    let addressable = COORD_MASK as u32 + 1;
+    let (grid_width, grid_height) = (
+        (width / scale).min(addressable),
+        (height / scale).min(addressable),
+    );
+
+    [
+        (0, 0),
+        (grid_width - 1, grid_height - 1),
+        (grid_width / 2, grid_height / 2),
+    ]
+    .into_iter()
+    .filter_map(|(x, y)| {
+        let req = PixelRequest {
+            pos: (x as u16, y as u16),
+            color: Color::rgb(0, 0, 0),
+            size: 1,
+            color_low: None,
+        }
+        .oriented(coordinate_mode, height);
+        let pos = (req.pos.0 as u32, req.pos.1 as u32);
+        let previous = image.get(pos.0, pos.1)?;
+        let color = Color::rgb(255 - previous.r, 255 - previous.g, 255 - previous.b);
+        let wire = if deep_color {
+            Color16::from_color(color)
+        } else {
+            Color16::rgb(color.r as u16, color.g as u16, color.b as u16)
+        };
+
+        let addr = Ipv6Addr::new(
+            prefix[0],
+            prefix[1],
+            prefix[2],
+            0x1000 | x as u16,
+            y as u16,
+            wire.r,
+            wire.g,
+            wire.b,
+        );
+        Some(Probe {
+            addr,
+            pos,
+            color,
+            previous,
+        })
+    })
+    .collect()
+}
+
+async fn send_probes(settings: &Settings, probes: &[Probe]) -> Result<(), String> {
+    if !settings.backend.enable_icmp {
+        return Err("ICMP is disabled in the backend settings".to_string());
+    }
+
+    let mut config = Config::new();
+    config.kind = ICMP::V6;
+    let client = Client::new(&config)
+        .map_err(|e| format!("{} (raw sockets need root or CAP_NET_RAW)", e))?;
+    let payload = settings.backend.icmp_payload.clone().unwrap_or(vec![0; 8]);
+
+    for probe in probes {
+        let mut pinger = client.pinger(IpAddr::V6(probe.addr), 0.into()).await;
+        pinger
+            .send_ping(0.into(), &payload)
+            .await
+            .map_err(|e| e.to_string())?;
+        // The probes can share a cooldown cell if the cells are large compared to the canvas.
+        tokio::time::sleep(Duration::from_millis(settings.backend.cooldown_ms + 10)).await;
+    }
+
+    Ok(())
+}
+
+/// Queues the previous colors of the probes that arrived, once their cooldown is over, and waits for
+/// them to reach the canvas. Returns whether all of them did.
+async fn restore(
+    settings: &Settings,
+    image: &SharedImageHandle,
+    pixel_queue: &PixelQueue,
+    probes: &[Probe],
+    arrived: &[bool],
+) -> bool {
+    let probes: Vec<&Probe> = probes
+        .iter()
+        .zip(arrived)
+        .filter(|(_, &arrived)| arrived)
+        .map(|(probe, _)| probe)
+        .collect();
+    if probes.is_empty() {
+        return true;
+    }
+
+    tokio::time::sleep(Duration::from_millis(settings.backend.cooldown_ms + 10)).await;
+    for probe in &probes {
+        let req = PixelRequest {
+            pos: (probe.pos.0 as u16, probe.pos.1 as u16),
+            color: probe.previous,
+            size: 1,
+            color_low: None,
+        };
+        pixel_queue.push(RESTORE_SOURCE, req);
+    }
+
+    let deadline =
+        Instant::now() + TIMEOUT + Duration::from_millis(settings.backend.coalesce_window_ms);
+    loop {
+        let restored = probes
+            .iter()
+            .all(|probe| image.get(probe.pos.0, probe.pos.1) == Some(probe.previous));
+        if restored || Instant::now() >= deadline {
+            return restored;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+fn print_diagnostics(settings: &Settings, probes: &[Probe]) {
+    println!("  Backend: {:?}", settings.backend.backend_type);
+    if matches!(
+        settings.backend.backend_type,
+        BackendType::Smoltcp | BackendType::Tun
+    ) {
+        println!("  Interface: {}", settings.backend.smoltcp.tun_iface);
+    }
+    for subnet in iface::pixel_subnets(settings.backend.prefix48) {
+        println!("  Prefix: {}/{}", subnet, SUBNET_PREFIX_LEN);
+    }
+    if !settings.backend.regions.is_empty() {
+        println!("  Region rules are configured and may reject the test pixels.");
+    }
+
+    let Some(probe) = probes.first() else {
+        return;
+    };
+    match Command::new("ip")
+        .args(["-6", "route", "get", &probe.addr.to_string()])
+        .output()
+    {
+        Ok(output) => println!(
+            "  Route: {}",
+            String::from_utf8_lossy(if output.status.success() {
+                &output.stdout
+            } else {
+                &output.stderr
+            })
+            .trim()
+        ),
+        Err(e) => println!("  Route: unknown, failed to run ip: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::RgbaImage;
+
+    use super::*;
+
+    #[test]
+    fn probes_fit_in_addresses() {
+        let prefix48: Ipv6Addr = "2602:fa9b:42::".parse().unwrap();
+        let image = SharedImageHandle::new(RgbaImage::new(5000, 16));
+
+        let probes = probes(prefix48, CoordinateMode::default(), false, &image);
+        assert_eq!(probes.len(), 3);
+        for probe in &probes {
+            // Decoded like the backends do, the address hits the probed pixel at size 1.
+            let req = PixelRequest::from_ipv6(&probe.addr);
+            assert_eq!(req.size, 1, "{}", probe.addr);
+            assert_eq!((req.pos.0 as u32, req.pos.1 as u32), probe.pos);
+            assert_eq!(req.color, probe.color);
+        }
+        assert_eq!(probes[1].pos, (4095, 15));
+    }
+}
@@ -1,40 +1,157 @@
-use std::net::Ipv6Addr;
+use std::{net::Ipv6Addr, path::Path};
 
 use config::Config;
-use serde::Deserialize;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 
 use crate::{
-    utils::{Color, RangedU16},
+    backend::attribution,
+    utils::{Color, IpPrefix, RangedU16},
     PResult,
 };
 
+/// Config file read if none is given explicitly.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub backend: BackendSettings,
     pub canvas: CanvasSettings,
     pub websocket: WebSocketSettings,
+    #[serde(default)]
+    pub udp_bridge: UdpBridgeSettings,
+    #[serde(default)]
+    pub admin: AdminSettings,
+    /// Additional canvases served by the same process, each with its own prefix and canvas file.
+    /// Clients pick one with /ws/<name> and ?canvas=<name> on the other routes, the main canvas
+    /// is used otherwise. Region rules, protected regions, pixel history, the UDP bridge and the
+    /// control socket only apply to the main canvas, the NDP responder answers for all prefixes.
+    /// The smoltcp backend supports up to 6 additional canvases. None by default.
+    #[serde(default)]
+    pub canvases: Vec<NamedCanvasSettings>,
+}
+
+/// A canvas served next to the main one, with its own prefix and file.
+#[derive(Debug, Deserialize)]
+pub struct NamedCanvasSettings {
+    /// Name of the canvas in URLs: /ws/<name>, and ?canvas=<name> for all other routes.
+    /// Only letters, digits, "-" and "_" are allowed.
+    pub name: String,
+
+    /// A /48 IPv6 prefix to listen for pings on, different from the one of the main canvas.
+    pub prefix48: Ipv6Addr,
+
+    pub canvas: CanvasSettings,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CanvasSettings {
-    /// Size of the canvas in pixels. Acceptable values are 16-4096, default is 512.
+    /// Size of the canvas in pixels. Acceptable values are 16-16384, default is 512.
+    /// Pixel addresses carry 12 bit coordinates, so clients can only reach pixels beyond 4096
+    /// with `coordinate_mode.scale`. The canvas buffers have to fit in `memory_limit_mb`.
     #[serde(default = "CanvasSettings::default_size")]
-    pub size: RangedU16<16, 4096>,
+    pub size: RangedU16<16, 16384>,
+
+    /// Memory in MiB the buffers of this canvas may take: the canvas itself, its copies for streaming
+    /// and saving, the 16-bit canvas, the flicker filter and the pixel cooldown. The server refuses to
+    /// start if their estimated size exceeds it, instead of running out of memory later. Encoded frames
+    /// and caches come on top. Default is 1024.
+    #[serde(default = "CanvasSettings::default_memory_limit_mb")]
+    pub memory_limit_mb: u64,
 
     /// The background color of the canvas in form of "#rrggbb" string, default is "#ffffff".
     #[serde(default = "CanvasSettings::default_background_color")]
     pub background_color: Color,
 
     /// The filename to save the canvas to, default is "place.png".
+    /// The image format is picked based on the extension: "png", "webp", "qoi" or "bmp".
     #[serde(default = "CanvasSettings::default_filename")]
     pub filename: String,
+
+    /// What to do if the existing canvas file can't be decoded. Available options are: "fail", "backup".
+    /// "backup" renames the corrupt file and starts with a fresh canvas. Default is "fail".
+    #[serde(default = "CanvasSettings::default_load_failure_policy")]
+    pub load_failure_policy: LoadFailurePolicy,
+
+    /// Path of a raw file to keep the canvas memory-mapped in. When set, pixels are written straight
+    /// to this file and saving only flushes it, while `filename` is only written by the "export"
+    /// admin command. The file is created from `filename` if it doesn't exist. Not set by default.
+    #[serde(default)]
+    pub mmap_file: Option<String>,
+
+    /// How pixel coordinates sent by clients map onto the canvas, default is top-left origin without axis swap.
+    #[serde(default)]
+    pub coordinate_mode: CoordinateMode,
+
+    /// Color depth of the saved canvas file. Available options are: "full", "rgb332".
+    /// "rgb332" snaps colors to 256 levels and writes an indexed PNG, which is lossy and drops transparency.
+    /// Only the saved file is affected, the canvas itself keeps full colors until restart. Default is "full".
+    #[serde(default)]
+    pub save_bit_depth: SaveBitDepth,
+
+    /// Whether to encode /canvas.png, PNG frames and the saved canvas as indexed PNGs while the canvas
+    /// uses at most 256 colors, which makes them several times smaller. Images with more colors, eg.
+    /// downscaled frames, are encoded as RGBA as usual. Default is false.
+    #[serde(default)]
+    pub indexed_png: bool,
+
+    /// Whether to embed metadata as PNG text chunks in the saved canvas file: creation time, server version,
+    /// canvas size, packets received since startup and the prefix. Some viewers don't handle extra chunks well.
+    /// Requires a PNG canvas file, default is false.
+    #[serde(default)]
+    pub save_metadata: bool,
+
+    /// Whether to migrate a saved canvas of a different size instead of refusing to start. Smaller canvases
+    /// are pasted into the top left corner of a blank one, larger ones are cropped. Doesn't apply to
+    /// `mmap_file`, delete it to have it recreated from the migrated canvas. Default is false.
+    #[serde(default)]
+    pub allow_resize: bool,
+
+    /// Bits per color channel of the canvas, 8 or 16. With 16, the color segments of pixel addresses carry
+    /// the full 16 bits (RRRR:GGGG:BBBB instead of 00RR:00GG:00BB) and the canvas is saved as a 16-bit PNG.
+    /// Streamed frames and other images keep 8 bits per channel. Requires a PNG canvas file and can't be
+    /// combined with `mmap_file` or `save_bit_depth`. Default is 8.
+    #[serde(default = "CanvasSettings::default_color_depth")]
+    pub color_depth: u8,
+
+    /// Whether to write a freshly created canvas to `filename` right away. If disabled, the file is
+    /// only written on the first save, so the server can start on a read-only filesystem. Default is true.
+    #[serde(default = "CanvasSettings::default_save_on_create")]
+    pub save_on_create: bool,
+
+    /// Whether to create missing parent directories of `filename` before writing it,
+    /// instead of refusing to save. Default is false.
+    #[serde(default)]
+    pub create_dirs: bool,
+
+    /// Time in seconds to wait for a save of the canvas before giving up, eg. on a slow or hanging disk.
+    /// Saves run off the async runtime, so the canvas keeps being served meanwhile, and the write carries
+    /// on in the background. Further saves are refused until it's done. 0 waits forever, default is 30.
+    #[serde(default = "CanvasSettings::default_save_timeout_secs")]
+    pub save_timeout_secs: u64,
+
+    /// What to do when saving the canvas fails, eg. because the disk is full. Available options are:
+    /// "log", "freeze". "freeze" freezes the canvas like the "freeze" admin command, so no pixels are
+    /// placed that couldn't be persisted, and unfreezes it once a save succeeds again. The canvas keeps
+    /// being served either way. Default is "log".
+    #[serde(default)]
+    pub save_failure_policy: SaveFailurePolicy,
+
+    /// Path of an RGBA PNG composited onto the canvas on startup, eg. additions prepared for an event.
+    /// Fully transparent pixels are skipped, the rest is blended over the canvas. The image has to be
+    /// the same size as the canvas. Once the patched canvas is saved, the patch is renamed to <path>.applied
+    /// so it's only applied once. Not set by default.
+    pub apply_patch: Option<String>,
 }
 
 impl CanvasSettings {
-    fn default_size() -> RangedU16<16, 4096> {
+    fn default_size() -> RangedU16<16, 16384> {
         RangedU16::new(512).unwrap()
     }
 
+    fn default_memory_limit_mb() -> u64 {
+        1024
+    }
+
     fn default_background_color() -> Color {
         Color::rgb(255, 255, 255)
     }
@@ -42,6 +159,133 @@ impl CanvasSettings {
     fn default_filename() -> String {
         "place.png".to_string()
     }
+
+    fn default_load_failure_policy() -> LoadFailurePolicy {
+        LoadFailurePolicy::Fail
+    }
+
+    fn default_color_depth() -> u8 {
+        8
+    }
+
+    fn default_save_on_create() -> bool {
+        true
+    }
+
+    fn default_save_timeout_secs() -> u64 {
+        30
+    }
+
+    /// Whether the canvas keeps 16 bits per color channel.
+    pub fn deep_color(&self) -> bool {
+        self.color_depth == 16
+    }
+
+    fn sanity_check(&self) -> PResult<()> {
+        if !(1..=127).contains(&self.coordinate_mode.scale) {
+            return Err("coordinate_mode.scale must be in range 1-127.".into());
+        }
+
+        if !matches!(self.color_depth, 8 | 16) {
+            return Err("color_depth must be either 8 or 16.".into());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadFailurePolicy {
+    Fail,
+    Backup,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SaveFailurePolicy {
+    #[default]
+    Log,
+    Freeze,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutOfBoundsPolicy {
+    #[default]
+    Drop,
+    Wrap,
+    Clamp,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorTransform {
+    #[default]
+    Identity,
+    Grayscale,
+    Invert,
+    Gamma(f32),
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PngCompression {
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SaveBitDepth {
+    #[default]
+    Full,
+    Rgb332,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct CoordinateMode {
+    /// Where the (0, 0) coordinate is. Available options are: "top_left", "bottom_left".
+    /// Default is "top_left".
+    #[serde(default)]
+    pub origin: Origin,
+
+    /// Whether the first coordinate (XXX) is the row and the second one (YYY) the column.
+    /// The swap is applied before the origin. Default is false.
+    #[serde(default)]
+    pub swap_axes: bool,
+
+    /// Factor coordinates and pixel sizes are multiplied with after the swap and the origin, so a lower
+    /// resolution template maps onto the canvas. Eg. at 4, pixel (1, 2) fills the 4x4 block at (4, 8) and the
+    /// origin is flipped within the 4 times smaller grid. Acceptable values are 1-127, default is 1.
+    #[serde(default = "CoordinateMode::default_scale")]
+    pub scale: u8,
+}
+
+impl CoordinateMode {
+    fn default_scale() -> u8 {
+        1
+    }
+}
+
+impl Default for CoordinateMode {
+    fn default() -> Self {
+        CoordinateMode {
+            origin: Origin::default(),
+            swap_axes: false,
+            scale: Self::default_scale(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    #[default]
+    TopLeft,
+    BottomLeft,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -49,6 +293,7 @@ impl CanvasSettings {
 pub enum BackendType {
     Smoltcp,
     Tun,
+    Pcap,
 }
 
 #[derive(Debug, Deserialize)]
@@ -56,11 +301,218 @@ pub struct BackendSettings {
     /// A /48 IPv6 prefix to listen for pings on.
     pub prefix48: Ipv6Addr,
 
-    /// The backend to use. Available options are: "smoltcp", "tun".
+    /// Whether to reject prefixes (prefix48 and those of named canvases) with bits set below the /48, eg.
+    /// when one was copied from a /64. Otherwise these bits are zeroed out with a warning. Default is false.
+    #[serde(default)]
+    pub strict_prefix: bool,
+
+    /// The backend to use. Available options are: "smoltcp", "tun", "pcap".
     pub backend_type: BackendType,
 
+    /// Whether to place pixels from ICMPv6 packets (pings). Default is true.
+    #[serde(default = "BackendSettings::default_enable_protocol")]
+    pub enable_icmp: bool,
+
+    /// If set, only ICMPv6 echo requests whose payload starts with these bytes place pixels,
+    /// in form of a hex string, eg. "0101010101010101". Not set by default, accepting any ICMPv6 packet.
+    #[serde(default, deserialize_with = "deserialize_hex")]
+    pub icmp_payload: Option<Vec<u8>>,
+
+    /// Whether to read pixels from the payload of ICMPv6 echo requests instead of their destination
+    /// address. The payload holds "PX" XX YY R G B A S right after the icmp_payload prefix, if set,
+    /// where XX and YY are big endian u16 coordinates, R G B A the color and S the pixel size of 1 or 2.
+    /// The destination still has to be in a pixel subnet of the canvas it's for. Echo requests
+    /// without such a payload fall back to their destination address. Default is false.
+    #[serde(default)]
+    pub payload_pixels: bool,
+
+    /// Whether to place pixels from UDP packets sent to port 7. Default is true.
+    #[serde(default = "BackendSettings::default_enable_protocol")]
+    pub enable_udp: bool,
+
+    /// Whether to verify the checksums of ICMPv6 and UDP packets, dropping corrupt ones. The kernel
+    /// doesn't check them for packets routed to a tun interface, but corruption is rare enough that
+    /// this is mostly useful for links that mangle packets. Default is false.
+    #[serde(default)]
+    pub verify_checksums: bool,
+
+    /// Time window in milliseconds during which repeated writes to the same pixel are merged,
+    /// so only the last color reaches the canvas. 0 disables coalescing, default is 0.
+    #[serde(default)]
+    pub coalesce_window_ms: u64,
+
+    /// Number of pixels that can wait to be written to the canvas. Pixels received while the queue
+    /// is full are dropped, default is 65536. When the queue is backed up, it's shared fairly between
+    /// source addresses (grouped by /64 for IPv6), which are served round-robin.
+    #[serde(default = "BackendSettings::default_queue_capacity")]
+    pub queue_capacity: usize,
+
+    /// Time in milliseconds after a write during which a pixel can't be changed again,
+    /// pixels arriving earlier are rejected. 0 disables the cooldown, default is 0.
+    #[serde(default)]
+    pub cooldown_ms: u64,
+
+    /// Size of the square areas sharing a cooldown, in pixels. The cooldown keeps 8 bytes per area,
+    /// eg. 128 MiB for a 4096x4096 canvas at 1, so larger canvases may want a coarser one. Default is 1.
+    #[serde(default = "BackendSettings::default_cooldown_resolution")]
+    pub cooldown_resolution: u32,
+
+    /// Color tinting pixels still in cooldown in an overlay websocket clients can opt into by sending
+    /// {"cooldown_overlay": true}, eg. "#00000080". The overlay has one pixel per cooldown_resolution
+    /// area, grouping areas on canvases where it would be larger than 512x512, and is sent as a "cooldown"
+    /// event whenever it changes. Not set by default, disabling the overlay.
+    #[serde(default)]
+    pub cooldown_overlay_color: Option<Color>,
+
+    /// What to do with pixels outside of the canvas. Available options are: "drop", "wrap", "clamp".
+    /// "wrap" takes the coordinates modulo the canvas size, "clamp" moves them to the nearest edge.
+    /// Default is "drop".
+    #[serde(default)]
+    pub out_of_bounds: OutOfBoundsPolicy,
+
+    /// Transform applied to the color of every pixel before it's written. Available options are: "identity",
+    /// "grayscale", "invert", or { gamma = <value> } for gamma correction, eg. { gamma = 2.2 } brightens dark
+    /// colors. With color_depth 16, transformed colors keep only 8 bits per channel. Default is "identity".
+    #[serde(default)]
+    pub color_transform: ColorTransform,
+
+    /// Colors pixels are snapped to after color_transform, the nearest one by distance in RGB, eg.
+    /// ["#000000", "#ffffff", "#ff4500"]. Can be swapped at runtime with POST /admin/palette, or read from
+    /// the settings again with POST /admin/reload. At most 256 colors, with color_depth 16 snapped colors
+    /// keep only 8 bits per channel. Empty by default, keeping colors as they are.
+    #[serde(default)]
+    pub palette: Vec<Color>,
+
+    /// Number of sources (/64 networks) whose pixel counts are tracked for GET /admin/top?n=<count>, which lists
+    /// the busiest sources and their rates over the last minute. Sources idle for two minutes are forgotten,
+    /// and new sources aren't tracked while it's full. 0 disables tracking, default is 4096.
+    #[serde(default = "BackendSettings::default_top_talkers_size")]
+    pub top_talkers_size: usize,
+
+    /// Whether to remember the source (grouped by /64) which wrote each pixel last, for GET
+    /// /admin/sources?rect=<x>,<y>,<width>,<height> listing who drew what in an area. Takes another 4 bytes
+    /// per pixel of the canvas, default is false.
+    #[serde(default)]
+    pub track_attribution: bool,
+
+    /// Number of cells along each side of the grid served as GET /rates.json, which lists the pixels placed
+    /// per second in every cell over the last second, eg. for spotting bots or popular areas on a dashboard.
+    /// Much cheaper than a per-pixel heatmap, but still takes 12 bytes per cell. Limited to the size of the
+    /// canvas. 0 disables it, default is 0.
+    #[serde(default)]
+    pub rate_grid_cells: u32,
+
+    /// Whether the canvas starts out frozen, rejecting all pixels while frames and /canvas.png keep being
+    /// served, eg. to keep the final state after an event. Can be toggled at runtime with the "freeze" and
+    /// "unfreeze" admin commands. Default is false.
+    #[serde(default)]
+    pub frozen: bool,
+
+    /// Source address of packets sent by the backend, eg. echo replies. Has to lie within one of the
+    /// pixel subnets of the prefix. Default is the first address of the 1 pixel subnet, eg. "2602:fa9b:42:1000::".
+    #[serde(default)]
+    pub reply_source_addr: Option<Ipv6Addr>,
+
+    /// Canvas regions only certain sources may draw on, eg. team zones. Pixels from other sources are
+    /// dropped and counted. Pixels outside of all regions are open to everyone. Empty by default.
+    #[serde(default)]
+    pub regions: Vec<RegionRule>,
+
+    /// Canvas regions nobody may draw on, eg. for an event logo. Pixels touching them are dropped and
+    /// counted. Empty by default.
+    #[serde(default)]
+    pub protected_regions: Vec<ProtectedRegion>,
+
+    /// Interval in seconds to stamp the images of protected regions onto the canvas again, in case admin
+    /// commands painted over them. 0 only stamps them on startup, default is 0.
+    #[serde(default)]
+    pub protected_restamp_secs: u64,
+
     /// Settings for the smoltcp backend.
     pub smoltcp: SmoltcpSettings,
+
+    /// Settings for the pcap replay backend.
+    #[serde(default)]
+    pub pcap: PcapSettings,
+
+    /// Smoothing factor of the exponential moving average of pixels per second, in range (0, 1].
+    /// Lower values give a more stable number, 1 disables smoothing. Default is 0.3.
+    #[serde(default = "BackendSettings::default_pps_ema_alpha")]
+    pub pps_ema_alpha: f32,
+}
+
+impl BackendSettings {
+    fn default_pps_ema_alpha() -> f32 {
+        0.3
+    }
+
+    fn default_enable_protocol() -> bool {
+        true
+    }
+
+    fn default_queue_capacity() -> usize {
+        65536
+    }
+
+    fn default_top_talkers_size() -> usize {
+        4096
+    }
+
+    fn default_cooldown_resolution() -> u32 {
+        1
+    }
+
+    /// Returns the configured reply source address, or the first address of the 1 pixel subnet.
+    pub fn reply_source_addr(&self) -> Ipv6Addr {
+        self.reply_source_addr.unwrap_or_else(|| {
+            let mut segments = self.prefix48.segments();
+            segments[3] = 0x1000;
+            segments.into()
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegionRule {
+    /// Area as [x, y, width, height] in pixels of the saved canvas, ie. before `coordinate_mode` is applied.
+    pub rect: [u32; 4],
+    /// Source prefixes allowed to draw in the area, eg. ["2001:db8:1::/48", "192.0.2.0/24"].
+    pub allow: Vec<IpPrefix>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProtectedRegion {
+    /// Area as [x, y, width, height] in pixels of the saved canvas, ie. before `coordinate_mode` is applied.
+    pub rect: [u32; 4],
+    /// Image stamped onto the area on startup, anchored at its top left corner and cropped to it.
+    /// Fully transparent pixels are skipped. Not set by default.
+    #[serde(default)]
+    pub image: Option<String>,
+}
+
+/// Deserializes an optional hex string, eg. "01ff", into bytes.
+fn deserialize_hex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+    let Some(s) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    if s.len() % 2 != 0 {
+        return Err(D::Error::custom(
+            "Hex string must have an even number of digits",
+        ));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| D::Error::custom(format!("Invalid hex string \"{}\"", s)))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
 }
 
 #[derive(Debug, Deserialize)]
@@ -72,6 +524,63 @@ pub struct SmoltcpSettings {
     /// Size of receive buffer (in number of packets). Default is 65536.
     #[serde(default = "SmoltcpSettings::default_recv_buffer_size")]
     pub recv_buffer_size: usize,
+
+    /// Memory of the receive buffer in bytes, overrides `recv_buffer_size` if set. The number of packets
+    /// is derived from it at 512 bytes per packet, eg. 33554432 (32 MiB) holds 65536 packets. Not set by default.
+    #[serde(default)]
+    pub recv_buffer_bytes: Option<usize>,
+
+    /// Whether to bring the TUN interface up and route the prefix to it on startup (requires
+    /// CAP_NET_ADMIN). If disabled, a warning with the commands to run is logged when routes are missing.
+    /// Default is false.
+    #[serde(default)]
+    pub configure_interface: bool,
+
+    /// Shortest time in milliseconds to wait for packets between polls of the interface, even if smoltcp
+    /// asks to be polled again right away. Avoids busy-spinning at the cost of latency. Default is 0.
+    #[serde(default)]
+    pub min_poll_delay_ms: u64,
+
+    /// Longest time in milliseconds to wait for packets between polls of the interface.
+    /// 0 waits until a packet arrives or smoltcp has work to do, default is 0.
+    #[serde(default)]
+    pub max_poll_delay_ms: u64,
+
+    /// Uplink interface to answer Neighbor Solicitations for pixel addresses on, for networks where
+    /// the prefix is on-link instead of routed to this host. Requires CAP_NET_RAW and IPv6 forwarding
+    /// (`sysctl net.ipv6.conf.all.forwarding=1`), so the kernel passes the packets on to the tun interface.
+    /// Not set by default.
+    #[serde(default)]
+    pub ndp_proxy_iface: Option<String>,
+
+    /// Whether to run the packet loop on its own OS thread instead of tokio's blocking pool, where it
+    /// competes with other blocking work like saving the canvas. Default is false.
+    #[serde(default)]
+    pub dedicated_thread: bool,
+
+    /// Index of the CPU core to pin the packet loop to, eg. one isolated from the scheduler. Implies
+    /// `dedicated_thread`. Not set by default.
+    #[serde(default)]
+    pub cpu_affinity: Option<usize>,
+
+    /// Whether to answer echo requests. Under a flood of pings every reply is as large as its request,
+    /// doubling the traffic, which the options below keep in check. Default is true.
+    #[serde(default = "SmoltcpSettings::default_echo_replies")]
+    pub echo_replies: bool,
+
+    /// Answer only 1 in this many echo requests, eg. 10 replies to every tenth, so pings stay somewhat
+    /// responsive at a fraction of the egress. Applied before the rate limits, default is 1.
+    #[serde(default = "SmoltcpSettings::default_reply_sample_rate")]
+    pub reply_sample_rate: u32,
+
+    /// Most echo replies sent per second in total, further ones are suppressed. 0 is unlimited, default is 0.
+    #[serde(default)]
+    pub reply_rate_limit: u32,
+
+    /// Most echo replies sent per second to a single source, grouped by /64. Replies to sources beyond the
+    /// first 65536 of a second are suppressed as well. 0 is unlimited, default is 0.
+    #[serde(default)]
+    pub reply_rate_limit_per_source: u32,
 }
 
 impl SmoltcpSettings {
@@ -82,6 +591,52 @@ impl SmoltcpSettings {
     fn default_recv_buffer_size() -> usize {
         65536
     }
+
+    fn default_echo_replies() -> bool {
+        true
+    }
+
+    fn default_reply_sample_rate() -> u32 {
+        1
+    }
+
+    /// Returns the number of packets and bytes of the receive buffer.
+    pub fn recv_buffer(&self) -> (usize, usize) {
+        match self.recv_buffer_bytes {
+            Some(bytes) => (bytes / RECV_PACKET_SIZE, bytes),
+            None => (
+                self.recv_buffer_size,
+                self.recv_buffer_size * RECV_PACKET_SIZE,
+            ),
+        }
+    }
+}
+
+/// Returns the /48 prefix of `addr`, with all lower bits set to 0.
+fn canonical_prefix48(addr: Ipv6Addr) -> Ipv6Addr {
+    let s = addr.segments();
+    Ipv6Addr::new(s[0], s[1], s[2], 0, 0, 0, 0, 0)
+}
+
+/// Bytes of receive buffer reserved per packet.
+pub const RECV_PACKET_SIZE: usize = 512;
+
+/// Largest number of colors in a palette pixels are snapped to, each pixel is compared against all.
+pub const MAX_PALETTE_SIZE: usize = 256;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct PcapSettings {
+    /// Path of a pcap or pcapng capture to replay pixels from.
+    #[serde(default)]
+    pub file: String,
+
+    /// Whether to replay packets with their original timing instead of at full speed. Default is false.
+    #[serde(default)]
+    pub realtime: bool,
+
+    /// Whether to start over once the end of the capture is reached. Default is false.
+    #[serde(default, rename = "loop")]
+    pub looped: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -89,32 +644,657 @@ pub struct WebSocketSettings {
     /// Listening address:port for the WebSocket server, default is "[::]:2137".
     #[serde(default = "WebSocketSettings::default_listen_addr")]
     pub listen_addr: String,
+
+    /// Length of the queue of connections waiting to be accepted. Raise it if connections are refused
+    /// during spikes of new viewers, the kernel caps it at net.core.somaxconn. Default is 1024.
+    #[serde(default = "WebSocketSettings::default_listen_backlog")]
+    pub listen_backlog: u32,
+
+    /// Maximum size of HTTP request bodies in bytes, larger requests are rejected. Default is 1048576 (1 MiB).
+    #[serde(default = "WebSocketSettings::default_max_body_size")]
+    pub max_body_size: usize,
+
+    /// Secret required in the "Authorization: Bearer <secret>" header by /admin/* endpoints.
+    /// Admin endpoints are disabled if not set.
+    #[serde(default)]
+    pub admin_secret: Option<String>,
+
+    /// Whether to skip sending frames to clients when the canvas hasn't changed. Default is true.
+    #[serde(default = "WebSocketSettings::default_skip_idle_frames")]
+    pub skip_idle_frames: bool,
+
+    /// Longest time in seconds a client goes without a frame while skip_idle_frames holds back
+    /// frames of an idle canvas. Once it passes the current frame is sent again as a keyframe, so
+    /// viewers behind caching proxies or which missed a frame catch up. 0 disables it, default is 0.
+    #[serde(default)]
+    pub keyframe_interval_secs: u64,
+
+    /// Maximum number of concurrently served connections, including websockets. Connections beyond
+    /// the limit get an immediate 503 response and are closed. Default is 4096.
+    #[serde(default = "WebSocketSettings::default_max_connections")]
+    pub max_connections: usize,
+
+    /// Directory with static files to serve on all other paths, eg. a built viewer frontend.
+    /// Requests for directories are answered with their index.html. Not set by default.
+    #[serde(default)]
+    pub static_dir: Option<String>,
+
+    /// Paths of a PEM certificate chain and private key. When both are set, the server only accepts
+    /// HTTPS and secure websocket (wss://) connections. Not set by default, serving plain HTTP.
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+    #[serde(default)]
+    pub tls_key: Option<String>,
+
+    /// Largest side length of frames streamed over the websocket. Larger canvases are downscaled
+    /// before streaming, /canvas.png and pixel queries stay at full resolution. Not set by default.
+    #[serde(default)]
+    pub max_stream_dimension: Option<u32>,
+
+    /// Number of recently written pixels kept for GET /events?since=<ms>, which returns a snapshot
+    /// of them as newline-delimited JSON. Clients poll it with the last timestamp they saw.
+    /// Best-effort, older pixels are dropped once it's full. 0 disables the endpoint, default is 0.
+    #[serde(default)]
+    pub event_history_size: usize,
+
+    /// Number of encoded frames buffered per stream format for websocket clients. Clients falling
+    /// further behind skip to the latest frame. Up to this many frames of each format are kept in
+    /// memory, so with large canvases the memory use is roughly the capacity times the encoded frame
+    /// size times the number of formats. Default is 8.
+    #[serde(default = "WebSocketSettings::default_frame_channel_capacity")]
+    pub frame_channel_capacity: usize,
+
+    /// Largest buffer in bytes allocated up front for encoding a frame. Every frame is encoded into a
+    /// buffer the size of the previous one, so it rarely has to grow, which is then shared with all
+    /// clients without copying it. Default is 16777216 (16 MiB), enough for raw frames of a 2048x2048 canvas.
+    #[serde(default = "WebSocketSettings::default_encode_buffer_limit")]
+    pub encode_buffer_limit: usize,
+
+    /// Compression level of PNG frames streamed over the websocket. Available options are: "fast",
+    /// "default", "best". Higher levels make frames smaller but slower to encode, which delays every
+    /// frame. Default is "fast".
+    #[serde(default)]
+    pub frame_compression: PngCompression,
+
+    /// Compression level of the complete PNG frame a client gets when it connects, resumes the stream or
+    /// asks for a resend, and of keyframes sent after keyframe_interval_secs. Keyframes are encoded in the
+    /// background once per frame for all clients, which get the streamed frame until it's done, so they can
+    /// afford to be smaller at the cost of encoding time. Available options are the same as for
+    /// frame_compression. Defaults to frame_compression, sending the streamed frame as is.
+    #[serde(default)]
+    pub keyframe_compression: Option<PngCompression>,
+
+    /// Largest side length of frames of the preview stream at /ws/preview (/ws/<name>/preview for
+    /// named canvases), a small downscale of the canvas for minimaps. Preview frames are encoded once for
+    /// all viewers and only while someone watches. 0 disables the preview stream, default is 128.
+    #[serde(default = "WebSocketSettings::default_preview_dimension")]
+    pub preview_dimension: u32,
+
+    /// Time in milliseconds between two frames of the preview stream. Default is 1000.
+    #[serde(default = "WebSocketSettings::default_preview_interval_ms")]
+    pub preview_interval_ms: u64,
+
+    /// Whether to stream PNG frames as Server-Sent Events at GET /sse (?canvas=<name> for named canvases),
+    /// a fallback for networks which block websockets. Frames are base64-encoded and thus a third larger
+    /// than over the websocket. Default is true.
+    #[serde(default = "WebSocketSettings::default_enable_sse")]
+    pub enable_sse: bool,
+
+    /// Minimum time in milliseconds between two color changes of the same pixel in the stream, to
+    /// avoid flicker when a pixel is flipped back and forth. Faster changes are held back and only the
+    /// latest one is shown once the time has passed. The canvas itself is updated at full speed,
+    /// /canvas.png and pixel queries aren't affected. 0 disables it, default is 0.
+    #[serde(default)]
+    pub flicker_window_ms: u64,
 }
 
 impl WebSocketSettings {
     fn default_listen_addr() -> String {
         "[::]:2137".to_string()
     }
+
+    fn default_listen_backlog() -> u32 {
+        1024
+    }
+
+    fn default_max_body_size() -> usize {
+        1024 * 1024
+    }
+
+    fn default_enable_sse() -> bool {
+        true
+    }
+
+    fn default_skip_idle_frames() -> bool {
+        true
+    }
+
+    fn default_max_connections() -> usize {
+        4096
+    }
+
+    fn default_frame_channel_capacity() -> usize {
+        8
+    }
+
+    fn default_encode_buffer_limit() -> usize {
+        16 * 1024 * 1024
+    }
+
+    fn default_preview_dimension() -> u32 {
+        128
+    }
+
+    fn default_preview_interval_ms() -> u64 {
+        1000
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UdpBridgeSettings {
+    /// Whether to accept pixels over plain UDP, for clients without IPv6 connectivity. Default is false.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Listening address:port for the UDP bridge, default is "0.0.0.0:2138".
+    #[serde(default = "UdpBridgeSettings::default_listen_addr")]
+    pub listen_addr: String,
+}
+
+impl UdpBridgeSettings {
+    fn default_listen_addr() -> String {
+        "0.0.0.0:2138".to_string()
+    }
+}
+
+impl Default for UdpBridgeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_addr: Self::default_listen_addr(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct AdminSettings {
+    /// Path of a Unix domain socket accepting admin commands, one per line:
+    /// "save", "stats", "top [count]", "freeze", "unfreeze", "background #rrggbb [repaint]",
+    /// "palette [#rrggbb...]", "reload" to apply the palette and background color from the settings again,
+    /// "ban <address>" to reject pixels from its /64 until a restart. Disabled if not set. An existing
+    /// file at the path is only replaced if it's a socket.
+    #[serde(default)]
+    pub control_socket: Option<String>,
 }
 
 impl Settings {
+    /// Loads settings from the file given by `--config <path>` command line argument or `PLACE_CONFIG`
+    /// environment variable, falling back to "config.toml" in the working directory if it exists.
+    /// Environment variables like `PLACE_BACKEND__PREFIX48` override settings from the file, or make
+    /// up all of them without one.
     pub fn new() -> PResult<Self> {
-        let settings = Config::builder()
-            .add_source(config::File::with_name("config.toml"))
-            .add_source(config::Environment::with_prefix("PLACE_"))
-            .build()?;
+        let config_path = Self::config_path()?;
+        let environment = config::Environment::with_prefix("PLACE")
+            .prefix_separator("_")
+            .separator("__");
+        Self::from_sources(config_path.as_deref(), environment)
+    }
 
-        let settings = settings.try_deserialize::<Settings>()?;
+    fn from_sources(config_path: Option<&str>, environment: config::Environment) -> PResult<Self> {
+        let mut builder = Config::builder();
+        // Sections without required settings may be left out, eg. when configuring through the environment.
+        for section in ["canvas", "websocket", "backend.smoltcp"] {
+            builder = builder.set_default(section, config::Map::<String, config::Value>::new())?;
+        }
+
+        match config_path {
+            Some(path) => {
+                log::info!("Loading settings from {}", path);
+                builder = builder.add_source(config::File::with_name(path));
+            }
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                log::info!("Loading settings from {}", DEFAULT_CONFIG_PATH);
+                builder = builder.add_source(config::File::with_name(DEFAULT_CONFIG_PATH));
+            }
+            None => log::info!(
+                "No {} found, loading settings from the environment only",
+                DEFAULT_CONFIG_PATH
+            ),
+        }
+
+        let settings = builder.add_source(environment).build()?;
+        let mut settings = settings.try_deserialize::<Settings>().map_err(|e| {
+            format!(
+                "Invalid settings: {}. Settings can be given in the config file or as environment \
+                 variables named PLACE_<SECTION>__<KEY>, eg. PLACE_BACKEND__PREFIX48.",
+                e
+            )
+        })?;
+        settings.canonicalize_prefixes();
         settings.sanity_check()?;
         Ok(settings)
     }
 
-    fn sanity_check(&self) -> PResult<()> {
+    /// Returns the config file given on the command line or in `PLACE_CONFIG`, if any.
+    fn config_path() -> PResult<Option<String>> {
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                return args
+                    .next()
+                    .map(Some)
+                    .ok_or_else(|| "Missing path after --config argument.".into());
+            } else if let Some(path) = arg.strip_prefix("--config=") {
+                return Ok(Some(path.to_string()));
+            }
+        }
+
+        Ok(std::env::var("PLACE_CONFIG").ok())
+    }
+
+    /// Zeroes out the bits below the /48 of all prefixes with a warning, unless `strict_prefix` is
+    /// set, in which case `sanity_check` rejects them instead.
+    fn canonicalize_prefixes(&mut self) {
+        if self.backend.strict_prefix {
+            return;
+        }
+
+        let prefixes = std::iter::once(("prefix48".to_string(), &mut self.backend.prefix48)).chain(
+            self.canvases.iter_mut().map(|named| {
+                (
+                    format!("The prefix48 of canvas {}", named.name),
+                    &mut named.prefix48,
+                )
+            }),
+        );
+        for (name, prefix) in prefixes {
+            let canonical = canonical_prefix48(*prefix);
+            if canonical != *prefix {
+                log::warn!(
+                    "{} {} has bits set below the /48, using {} instead. Set strict_prefix to reject it.",
+                    name,
+                    prefix,
+                    canonical
+                );
+                *prefix = canonical;
+            }
+        }
+    }
+
+    fn sanity_check(&mut self) -> PResult<()> {
         let addr = self.backend.prefix48.segments();
         if addr[3..].iter().any(|&v| v != 0) {
             return Err("The specified /48 prefix must have it's lower bits set to 0.".into());
         }
 
+        if !self.backend.enable_icmp && !self.backend.enable_udp {
+            return Err("At least one of enable_icmp and enable_udp must be set.".into());
+        }
+
+        // Grids are never finer than a cell per pixel.
+        let largest = (self.canvases.iter().map(|named| &named.canvas))
+            .chain(std::iter::once(&self.canvas))
+            .map(|canvas| canvas.size.get() as u32)
+            .max()
+            .unwrap_or(0);
+        self.backend.rate_grid_cells = self.backend.rate_grid_cells.min(largest);
+
+        self.canvas.sanity_check()?;
+        self.check_memory("The canvas", &self.canvas)?;
+        self.check_canvases()?;
+
+        if let ColorTransform::Gamma(gamma) = self.backend.color_transform {
+            if !(gamma.is_finite() && gamma > 0.0) {
+                return Err("color_transform gamma must be a positive number.".into());
+            }
+        }
+
+        if self.backend.palette.len() > MAX_PALETTE_SIZE {
+            return Err(format!("palette can have at most {} colors.", MAX_PALETTE_SIZE).into());
+        }
+
+        if self.websocket.tls_cert.is_some() != self.websocket.tls_key.is_some() {
+            return Err("tls_cert and tls_key must be set together.".into());
+        }
+
+        if self.websocket.max_connections == 0 {
+            return Err("max_connections must be at least 1.".into());
+        }
+
+        if self.websocket.max_stream_dimension == Some(0) {
+            return Err("max_stream_dimension must be at least 1.".into());
+        }
+
+        if self.websocket.frame_channel_capacity == 0 {
+            return Err("frame_channel_capacity must be at least 1.".into());
+        }
+
+        let reply = self.backend.reply_source_addr().segments();
+        if reply[..3] != addr[..3] || !matches!(reply[3] >> 12, 1 | 2) {
+            return Err(format!(
+                "reply_source_addr {} must lie within one of the /52 pixel subnets of the prefix.",
+                self.backend.reply_source_addr()
+            )
+            .into());
+        }
+
+        if let Some(rect) = (self.backend.regions.iter().map(|rule| rule.rect))
+            .chain(
+                self.backend
+                    .protected_regions
+                    .iter()
+                    .map(|region| region.rect),
+            )
+            .find(|rect| rect[2] == 0 || rect[3] == 0)
+        {
+            return Err(format!("Region {:?} has to be at least 1x1 pixels.", rect).into());
+        }
+
+        let smoltcp = &self.backend.smoltcp;
+        if smoltcp.recv_buffer().0 == 0 {
+            return Err(format!(
+                "The receive buffer has to hold at least one packet of {} bytes.",
+                RECV_PACKET_SIZE
+            )
+            .into());
+        }
+        if smoltcp.max_poll_delay_ms != 0 && smoltcp.min_poll_delay_ms > smoltcp.max_poll_delay_ms {
+            return Err("min_poll_delay_ms must not be larger than max_poll_delay_ms.".into());
+        }
+        if smoltcp.reply_sample_rate == 0 {
+            return Err("reply_sample_rate must be at least 1.".into());
+        }
+
+        if self.backend.cooldown_resolution == 0 {
+            return Err("cooldown_resolution must be at least 1.".into());
+        }
+
+        let alpha = self.backend.pps_ema_alpha;
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            return Err(format!("pps_ema_alpha must be in range (0, 1], got {}.", alpha).into());
+        }
+
         Ok(())
     }
+
+    /// Estimates the memory taken by the buffers of a canvas, in bytes.
+    fn canvas_memory(&self, canvas: &CanvasSettings) -> u64 {
+        let size = canvas.size.get() as u64;
+        // The canvas, and the copies frames are encoded and saved from.
+        let mut per_pixel = 3 * 4;
+        if canvas.deep_color() {
+            per_pixel += 8;
+        }
+        if self.websocket.flicker_window_ms > 0 {
+            // A scratch copy and the time of the last change.
+            per_pixel += 4 + 4;
+        }
+        if self.backend.track_attribution {
+            per_pixel += 4;
+        }
+
+        let mut bytes = size * size * per_pixel;
+        if self.backend.track_attribution {
+            // Every pixel can be owned by a different source.
+            let sources = (size * size).min(attribution::MAX_SOURCES as u64);
+            bytes += sources * attribution::BYTES_PER_SOURCE;
+        }
+        if self.backend.cooldown_ms > 0 {
+            let cells = size.div_ceil(self.backend.cooldown_resolution.max(1) as u64);
+            bytes += cells * cells * 8;
+        }
+        if self.backend.rate_grid_cells > 0 {
+            // A counter, the rates of the last tick and the copy they are served from.
+            let cells = size.min(self.backend.rate_grid_cells as u64);
+            bytes += cells * cells * 12;
+        }
+        bytes
+    }
+
+    fn check_memory(&self, name: &str, canvas: &CanvasSettings) -> PResult<()> {
+        let needed = self.canvas_memory(canvas).div_ceil(1024 * 1024);
+        if needed > canvas.memory_limit_mb {
+            return Err(format!(
+                "{} of {}x{} pixels needs about {} MiB, more than memory_limit_mb = {}. \
+                 Raise the limit if the machine has enough memory, or use a smaller canvas.",
+                name,
+                canvas.size.get(),
+                canvas.size.get(),
+                needed,
+                canvas.memory_limit_mb
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the additional canvases don't clash with each other or the main one.
+    fn check_canvases(&self) -> PResult<()> {
+        let mut prefixes = vec![self.backend.prefix48];
+        let mut files = vec![&self.canvas.filename];
+        let mut names = Vec::new();
+
+        for named in &self.canvases {
+            let name = &named.name;
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(format!(
+                    "Canvas name {:?} must be non-empty and only contain letters, digits, \"-\" and \"_\".",
+                    name
+                )
+                .into());
+            }
+            // /ws/admin is the admin channel, /ws/preview the preview of the main canvas.
+            if name == "admin" || name == "preview" || names.contains(&name) {
+                return Err(format!("Canvas name {:?} is reserved or already taken.", name).into());
+            }
+
+            if named.prefix48.segments()[3..].iter().any(|&v| v != 0) {
+                return Err(format!(
+                    "The /48 prefix of canvas {} must have it's lower bits set to 0.",
+                    name
+                )
+                .into());
+            }
+            if prefixes.contains(&named.prefix48) {
+                return Err(format!(
+                    "Canvas {} uses the prefix {} of another canvas.",
+                    name, named.prefix48
+                )
+                .into());
+            }
+            if files.contains(&&named.canvas.filename) {
+                return Err(format!(
+                    "Canvas {} uses the file {} of another canvas.",
+                    name, named.canvas.filename
+                )
+                .into());
+            }
+
+            named
+                .canvas
+                .sanity_check()
+                .map_err(|e| format!("Canvas {}: {}", name, e))?;
+            self.check_memory(&format!("Canvas {}", name), &named.canvas)?;
+            names.push(name);
+            prefixes.push(named.prefix48);
+            files.push(&named.canvas.filename);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Parses a minimal config, `extra` is appended to the [canvas] table.
+    fn parse(extra: &str) -> PResult<Settings> {
+        parse_with("", extra)
+    }
+
+    /// Like `parse`, with `backend` appended to the [backend] table.
+    fn parse_with(backend: &str, extra: &str) -> PResult<Settings> {
+        let toml = format!(
+            r#"
+[backend]
+prefix48 = "2602:fa9b:42::"
+backend_type = "smoltcp"
+{}
+[backend.smoltcp]
+[websocket]
+[canvas]
+{}"#,
+            backend, extra
+        );
+        let mut settings = Config::builder()
+            .add_source(config::File::from_str(&toml, config::FileFormat::Toml))
+            .build()?
+            .try_deserialize::<Settings>()?;
+        settings.canonicalize_prefixes();
+        settings.sanity_check()?;
+        Ok(settings)
+    }
+
+    #[test]
+    fn color_transforms() {
+        let transform = |value: &str| {
+            parse_with(&format!("color_transform = {}", value), "")
+                .map(|settings| settings.backend.color_transform)
+        };
+        assert_eq!(
+            parse("").unwrap().backend.color_transform,
+            ColorTransform::Identity
+        );
+        assert_eq!(transform(r#""invert""#).unwrap(), ColorTransform::Invert);
+        assert_eq!(
+            transform("{ gamma = 2.2 }").unwrap(),
+            ColorTransform::Gamma(2.2)
+        );
+        assert!(transform("{ gamma = 0 }").is_err());
+        assert!(transform(r#""sepia""#).is_err());
+    }
+
+    #[test]
+    fn named_canvases() {
+        let canvas = |name: &str, prefix: &str, filename: &str| {
+            format!(
+                r#"
+[[canvases]]
+name = "{}"
+prefix48 = "{}"
+[canvases.canvas]
+size = 64
+filename = "{}"
+"#,
+                name, prefix, filename
+            )
+        };
+
+        let settings = parse(&canvas("small", "2602:fa9b:43::", "small.png")).unwrap();
+        assert_eq!(settings.canvases.len(), 1);
+        assert_eq!(settings.canvases[0].canvas.size.get(), 64);
+        assert!(parse(&format!(
+            "{}{}",
+            canvas("a", "2602:fa9b:43::", "a.png"),
+            canvas("b", "2602:fa9b:44::", "b.png")
+        ))
+        .is_ok());
+
+        // Clashes with the main canvas or each other.
+        assert!(parse(&canvas("small", "2602:fa9b:42::", "small.png")).is_err());
+        assert!(parse(&canvas("small", "2602:fa9b:43::", "place.png")).is_err());
+        assert!(parse(&format!(
+            "{}{}",
+            canvas("a", "2602:fa9b:43::", "a.png"),
+            canvas("a", "2602:fa9b:44::", "b.png")
+        ))
+        .is_err());
+
+        assert!(parse(&canvas("admin", "2602:fa9b:43::", "small.png")).is_err());
+        assert!(parse(&canvas("preview", "2602:fa9b:43::", "small.png")).is_err());
+        assert!(parse(&canvas("a/b", "2602:fa9b:43::", "small.png")).is_err());
+        // Bits below the /48 are only rejected with strict_prefix.
+        let named = canvas("small", "2602:fa9b:43:1::1", "small.png");
+        let settings = parse(&named).unwrap();
+        assert_eq!(
+            settings.canvases[0].prefix48,
+            "2602:fa9b:43::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert!(parse_with("strict_prefix = true", &named).is_err());
+        assert!(parse_with(
+            "strict_prefix = true",
+            &canvas("small", "2602:fa9b:43::1", "small.png")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn environment_only() {
+        let environment = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|&(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            config::Environment::with_prefix("PLACE")
+                .prefix_separator("_")
+                .separator("__")
+                .source(Some(vars))
+        };
+
+        let settings = Settings::from_sources(
+            None,
+            environment(&[
+                ("PLACE_BACKEND__PREFIX48", "2602:fa9b:42::"),
+                ("PLACE_BACKEND__BACKEND_TYPE", "smoltcp"),
+                ("PLACE_CANVAS__SIZE", "256"),
+                ("PLACE_WEBSOCKET__SKIP_IDLE_FRAMES", "false"),
+                ("PLACE_CONFIG", "ignored.toml"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(settings.canvas.size.get(), 256);
+        assert!(!settings.websocket.skip_idle_frames);
+        assert_eq!(settings.canvas.filename, "place.png");
+
+        let err = Settings::from_sources(
+            None,
+            environment(&[("PLACE_BACKEND__BACKEND_TYPE", "smoltcp")]),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("prefix48"), "{}", err);
+    }
+
+    #[test]
+    fn memory_limit() {
+        assert!(parse("size = 8192").is_ok());
+        let err = parse("size = 16384").unwrap_err().to_string();
+        assert!(err.contains("3072 MiB"), "{}", err);
+        assert!(parse("size = 16384\nmemory_limit_mb = 4096").is_ok());
+        assert!(parse("size = 8192\ncolor_depth = 16").is_err());
+        assert!(parse("size = 16385\nmemory_limit_mb = 4096").is_err());
+    }
+
+    #[test]
+    fn rate_grid_limits() {
+        let cells = |backend: &str, canvas: &str| {
+            parse_with(backend, canvas).map(|settings| settings.backend.rate_grid_cells)
+        };
+        assert_eq!(cells("rate_grid_cells = 64", "size = 512").unwrap(), 64);
+        assert_eq!(
+            cells("rate_grid_cells = 100000", "size = 512").unwrap(),
+            512
+        );
+        // A cell per pixel on a large canvas takes gigabytes.
+        assert!(cells(
+            "rate_grid_cells = 16384",
+            "size = 16384\nmemory_limit_mb = 4096"
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::{gzip::Precompressed, PResult};
+
+/// A static file, along with the modification time and size it was read at.
+struct CompressedFile {
+    modified: Option<SystemTime>,
+    len: u64,
+    body: Arc<Precompressed>,
+}
+
+/// Compressible static files, compressed once and reused until they change on disk.
+#[derive(Default)]
+pub struct CompressedFiles {
+    files: Mutex<HashMap<PathBuf, CompressedFile>>,
+}
+
+impl CompressedFiles {
+    /// Returns the contents of the file at `path`, compressing them again only if `metadata` shows
+    /// that the file changed. `None` if it can't be read.
+    pub async fn get(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+    ) -> PResult<Option<Arc<Precompressed>>> {
+        let (modified, len) = (metadata.modified().ok(), metadata.len());
+        {
+            let files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+            match files.get(path) {
+                Some(file) if file.modified == modified && file.len == len => {
+                    return Ok(Some(file.body.clone()))
+                }
+                _ => {}
+            }
+        }
+
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(_) => return Ok(None),
+        };
+        let body = Arc::new(tokio::task::spawn_blocking(move || Precompressed::new(data)).await?);
+        self.files.lock().unwrap_or_else(|e| e.into_inner()).insert(
+            path.to_path_buf(),
+            CompressedFile {
+                modified,
+                len,
+                body: body.clone(),
+            },
+        );
+        Ok(Some(body))
+    }
+}
+
+/// Maps a request path to a file below `root`. Returns `None` for paths trying to escape it.
+pub fn resolve(root: &Path, uri_path: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for segment in uri_path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            // Would be a path separator or a drive prefix on Windows.
+            s if s.contains(['\\', ':']) => return None,
+            s => path.push(s),
+        }
+    }
+
+    Some(path)
+}
+
+/// Guesses the content type of a file from its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "wasm" => "application/wasm",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "txt" => "text/plain; charset=utf-8",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolve_paths() {
+        let root = Path::new("/srv/place");
+        assert_eq!(resolve(root, "/"), Some(PathBuf::from("/srv/place")));
+        assert_eq!(
+            resolve(root, "/assets/./app.js"),
+            Some(PathBuf::from("/srv/place/assets/app.js"))
+        );
+        assert_eq!(resolve(root, "/../etc/passwd"), None);
+        assert_eq!(
+            resolve(root, "/assets/..%2f/x"),
+            Some(PathBuf::from("/srv/place/assets/..%2f/x"))
+        );
+        assert_eq!(resolve(root, "/a\\..\\b"), None);
+
+        assert_eq!(
+            content_type(Path::new("index.HTML")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type(Path::new("place_bg.wasm")), "application/wasm");
+        assert_eq!(
+            content_type(Path::new("LICENSE")),
+            "application/octet-stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_files() {
+        let path = std::env::temp_dir().join(format!("place-static-{}.js", std::process::id()));
+        std::fs::write(&path, "let a = 1;").unwrap();
+        let files = CompressedFiles::default();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        let first = files.get(&path, &metadata).await.unwrap().unwrap();
+        let again = files.get(&path, &metadata).await.unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+
+        std::fs::write(&path, "let a = 12;").unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let changed = files.get(&path, &metadata).await.unwrap().unwrap();
+        assert!(!Arc::ptr_eq(&first, &changed));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(files.get(&path, &metadata).await.unwrap().is_some());
+        assert!(files
+            .get(&path, &std::fs::metadata(std::env::temp_dir()).unwrap())
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
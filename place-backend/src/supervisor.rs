@@ -0,0 +1,119 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use tokio::task::JoinHandle;
+
+use crate::PResult;
+
+/// Delay before the first restart of a failed task.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Upper bound for the delay between restarts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Tasks that ran for at least this long before failing are restarted with the minimal delay again.
+const BACKOFF_RESET: Duration = Duration::from_secs(300);
+
+/// Waits for a task, restarting it with exponential backoff whenever it returns an error.
+///
+/// Panics are only restarted in builds that unwind. Release builds use `panic = "abort"`, so a
+/// panic ends the whole process there.
+///
+/// `restart` has to set the task up from scratch, eg. reopen its sockets. If that fails,
+/// it's retried after the next backoff delay. Returns once the task finishes successfully.
+pub async fn supervise<F, Fut>(
+    name: &'static str,
+    handle: JoinHandle<PResult<()>>,
+    restart: F,
+) -> PResult<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = PResult<JoinHandle<PResult<()>>>>,
+{
+    supervise_with_backoff(name, handle, restart, MIN_BACKOFF).await
+}
+
+async fn supervise_with_backoff<F, Fut>(
+    name: &'static str,
+    mut handle: JoinHandle<PResult<()>>,
+    mut restart: F,
+    min_backoff: Duration,
+) -> PResult<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = PResult<JoinHandle<PResult<()>>>>,
+{
+    let mut backoff = min_backoff;
+    let mut started = Instant::now();
+
+    loop {
+        let error = match handle.await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => e,
+            Err(e) => e.into(),
+        };
+
+        if started.elapsed() >= BACKOFF_RESET {
+            backoff = min_backoff;
+        }
+        log::error!(
+            "Task {} failed: {}. Restarting in {:?}.",
+            name,
+            error,
+            backoff
+        );
+
+        handle = loop {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+
+            match restart().await {
+                Ok(handle) => break handle,
+                Err(e) => log::error!(
+                    "Failed to restart task {}: {}. Retrying in {:?}.",
+                    name,
+                    e,
+                    backoff
+                ),
+            }
+        };
+        started = Instant::now();
+        log::info!("Task {} restarted.", name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn restart_failed_task() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let start = {
+            let attempts = attempts.clone();
+            move || {
+                let attempts = attempts.clone();
+                async move {
+                    Ok(tokio::spawn(async move {
+                        match attempts.fetch_add(1, Ordering::Relaxed) {
+                            0 => Err("transient".into()),
+                            1 => panic!("also transient"),
+                            _ => Ok(()),
+                        }
+                    }))
+                }
+            }
+        };
+
+        let handle = (start.clone())().await.unwrap();
+        supervise_with_backoff("test", handle, start, Duration::from_millis(1))
+            .await
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+}
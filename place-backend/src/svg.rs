@@ -0,0 +1,84 @@
+use std::fmt::Write;
+
+use image::RgbaImage;
+
+/// Largest width or height of an exported SVG, in pixels after downscaling.
+/// Anything bigger produces files that most design tools choke on.
+pub const MAX_SIZE: u32 = 1024;
+
+/// Encodes the canvas as an SVG, with every horizontal run of same-colored pixels becoming a `<rect>`.
+///
+/// With `downscale` > 1, every `downscale` x `downscale` block is represented by its top-left pixel.
+pub fn encode(image: &RgbaImage, downscale: u32) -> String {
+    let downscale = downscale.max(1);
+    let width = image.width() / downscale;
+    let height = image.height() / downscale;
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\" shape-rendering=\"crispEdges\">",
+        width, height
+    );
+
+    let pixel = |x: u32, y: u32| image.get_pixel(x * downscale, y * downscale).0;
+
+    for y in 0..height {
+        let mut start = 0;
+        while start < width {
+            let color = pixel(start, y);
+            let mut end = start + 1;
+            while end < width && pixel(end, y) == color {
+                end += 1;
+            }
+
+            let [r, g, b, a] = color;
+            let _ = write!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"1\" fill=\"#{:02x}{:02x}{:02x}\"",
+                start,
+                y,
+                end - start,
+                r,
+                g,
+                b
+            );
+            if a != 255 {
+                let _ = write!(svg, " fill-opacity=\"{:.3}\"", a as f32 / 255.0);
+            }
+            svg.push_str("/>\n");
+
+            start = end;
+        }
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn encode_runs() {
+        let mut image = RgbaImage::from_pixel(4, 2, Rgba([255, 255, 255, 255]));
+        image.put_pixel(1, 0, Rgba([255, 0, 0, 255]));
+        image.put_pixel(2, 0, Rgba([255, 0, 0, 255]));
+
+        let svg = encode(&image, 1);
+        assert!(
+            svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"4\" height=\"2\"")
+        );
+        assert_eq!(svg.matches("<rect").count(), 4);
+        assert!(svg.contains("<rect x=\"1\" y=\"0\" width=\"2\" height=\"1\" fill=\"#ff0000\"/>"));
+        assert!(svg.contains("<rect x=\"0\" y=\"1\" width=\"4\" height=\"1\" fill=\"#ffffff\"/>"));
+
+        let svg = encode(&image, 2);
+        assert!(svg.contains("width=\"2\" height=\"1\" viewBox=\"0 0 2 1\""));
+        assert!(svg.contains("<rect x=\"0\" y=\"0\" width=\"1\" height=\"1\" fill=\"#ffffff\"/>"));
+        assert!(svg.contains("<rect x=\"1\" y=\"0\" width=\"1\" height=\"1\" fill=\"#ff0000\"/>"));
+    }
+}
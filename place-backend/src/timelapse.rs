@@ -0,0 +1,187 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use image::{imageops, RgbaImage};
+
+use crate::PResult;
+
+/// Frame rate of the output if `--fps` isn't given.
+const DEFAULT_FPS: u16 = 10;
+
+/// Entry point of `place-backend timelapse <dir> <out> [--fps <n>]`, which assembles the numbered
+/// frame PNGs in `dir` into an animated PNG if `out` ends in .png or .apng, or otherwise into a
+/// directory of consecutively numbered frames for ffmpeg.
+pub fn run(mut args: impl Iterator<Item = String>) -> PResult<()> {
+    const USAGE: &str = "Usage: place-backend timelapse <dir> <out> [--fps <n>]";
+
+    let mut paths = Vec::new();
+    let mut fps = DEFAULT_FPS;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fps" => fps = args.next().ok_or(USAGE)?.parse()?,
+            _ if paths.len() < 2 => paths.push(PathBuf::from(arg)),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let [dir, out] = <[PathBuf; 2]>::try_from(paths).map_err(|_| USAGE)?;
+    if fps == 0 {
+        return Err("The frame rate has to be at least 1.".into());
+    }
+
+    let frames = numbered_frames(&dir)?;
+    if frames.is_empty() {
+        return Err(format!("No numbered frame PNGs in {}.", dir.display()).into());
+    }
+
+    let is_apng = out
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png") || ext.eq_ignore_ascii_case("apng"));
+    if is_apng {
+        write_apng(&frames, &out, fps)?;
+        println!(
+            "Wrote {} frames at {} fps to {}",
+            frames.len(),
+            fps,
+            out.display()
+        );
+    } else {
+        write_sequence(&frames, &out)?;
+        println!(
+            "Wrote {} frames to {}, encode them with:\n  ffmpeg -framerate {} -i {} -pix_fmt yuv420p timelapse.mp4",
+            frames.len(),
+            out.display(),
+            fps,
+            out.join("%06d.png").display()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the PNGs in `dir` with a number in their name, ordered by it. The last number of the
+/// name counts, so eg. "place-2023-10-01-000042.png" is frame 42.
+fn numbered_frames(dir: &Path) -> PResult<Vec<PathBuf>> {
+    let mut frames = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+        {
+            continue;
+        }
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let stem = stem.trim_end_matches(|c: char| !c.is_ascii_digit());
+        let digits = &stem[stem.trim_end_matches(|c: char| c.is_ascii_digit()).len()..];
+        if let Ok(number) = digits.parse::<u64>() {
+            frames.push((number, path));
+        }
+    }
+
+    frames.sort();
+    Ok(frames.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Encodes the frames as an APNG looping forever, one frame decoded at a time. Frames of a
+/// different size than the first one, eg. recorded before the canvas was resized, are cropped or
+/// padded with transparency to it.
+fn write_apng(frames: &[PathBuf], out: &Path, fps: u16) -> PResult<()> {
+    let first = image::open(&frames[0])?.into_rgba8();
+    let (width, height) = first.dimensions();
+
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(out)?), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Best);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    encoder.set_frame_delay(1, fps)?;
+    let mut writer = encoder.write_header()?;
+
+    writer.write_image_data(first.as_raw())?;
+    for path in &frames[1..] {
+        let mut frame = image::open(path)?.into_rgba8();
+        if frame.dimensions() != (width, height) {
+            log::warn!(
+                "{} is {}x{}, fitting it to {}x{}.",
+                path.display(),
+                frame.width(),
+                frame.height(),
+                width,
+                height
+            );
+            let mut fitted = RgbaImage::new(width, height);
+            imageops::replace(&mut fitted, &frame, 0, 0);
+            frame = fitted;
+        }
+        writer.write_image_data(frame.as_raw())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Copies the frames into `out` as 000001.png, 000002.png, ..., without gaps in the numbering.
+fn write_sequence(frames: &[PathBuf], out: &Path) -> PResult<()> {
+    std::fs::create_dir_all(out)?;
+    for (i, path) in frames.iter().enumerate() {
+        std::fs::copy(path, out.join(format!("{:06}.png", i + 1)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn assemble_frames() {
+        let dir = std::env::temp_dir().join(format!("place-timelapse-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let frames_dir = dir.join("frames");
+        std::fs::create_dir_all(&frames_dir).unwrap();
+
+        // Numbers sort numerically, files without one are skipped.
+        for (name, shade) in [("frame-2.png", 2), ("frame-10.png", 10), ("frame-1.png", 1)] {
+            RgbaImage::from_pixel(4, 4, Rgba([shade, 0, 0, 255]))
+                .save(frames_dir.join(name))
+                .unwrap();
+        }
+        RgbaImage::new(2, 2)
+            .save(frames_dir.join("latest.png"))
+            .unwrap();
+        let frames = numbered_frames(&frames_dir).unwrap();
+        let names: Vec<_> = frames
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["frame-1.png", "frame-2.png", "frame-10.png"]);
+
+        let apng = dir.join("out.png");
+        write_apng(&frames, &apng, 5).unwrap();
+        let decoder = png::Decoder::new(File::open(&apng).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let control = reader.info().animation_control.unwrap();
+        assert_eq!((control.num_frames, control.num_plays), (3, 0));
+        let mut buf = vec![0; reader.output_buffer_size()];
+        for shade in [1, 2, 10] {
+            reader.next_frame(&mut buf).unwrap();
+            assert_eq!(buf[..4], [shade, 0, 0, 255]);
+            let frame = reader.info().frame_control.unwrap();
+            assert_eq!((frame.delay_num, frame.delay_den), (1, 5));
+        }
+
+        let sequence = dir.join("sequence");
+        write_sequence(&frames, &sequence).unwrap();
+        let frame = image::open(sequence.join("000003.png"))
+            .unwrap()
+            .into_rgba8();
+        assert_eq!(frame.get_pixel(0, 0).0, [10, 0, 0, 255]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
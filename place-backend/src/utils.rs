@@ -2,6 +2,7 @@ use std::cmp::{Eq, Ord, PartialOrd};
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Formatter;
+use std::net::IpAddr;
 use std::ops::RangeInclusive;
 
 use image::Rgba;
@@ -11,7 +12,7 @@ macro_rules! make_ranged_int {
         #[repr(transparent)]
         #[derive(Clone, Copy, Hash, Eq, Ord)]
         /// Range checked integer type.
-        /// Provides a custom serde::Deserialize implementation that performs range checking and returns 
+        /// Provides a custom serde::Deserialize implementation that performs range checking and returns
         /// an error if the parsed value is out of range.
         pub struct $name<const MIN: $type, const MAX: $type>($type);
 
@@ -111,6 +112,8 @@ impl Color {
         Self { r, g, b, a: 255 }
     }
 
+    /// Unpacks a color from `0xRRGGBBAA`, the same order as the `#rrggbbaa` string form.
+    /// Inverse of `into_rgba32`.
     #[inline]
     pub const fn rgba32(rgba: u32) -> Self {
         Self {
@@ -157,10 +160,111 @@ impl Color {
         Rgba([self.r, self.g, self.b, self.a])
     }
 
+    #[inline]
+    pub const fn from_rgba(rgba: Rgba<u8>) -> Self {
+        let Rgba([r, g, b, a]) = rgba;
+        Self { r, g, b, a }
+    }
+
+    /// Packs the color as `0xRRGGBBAA`. Inverse of `rgba32`.
     #[inline]
     pub const fn into_rgba32(&self) -> u32 {
         ((self.r as u32) << 24) | ((self.g as u32) << 16) | ((self.b as u32) << 8) | (self.a as u32)
     }
+
+    /// Snaps the color to the nearest RGB332 value (3 bits red, 3 bits green, 2 bits blue), dropping alpha.
+    #[inline]
+    pub const fn into_rgb332(self) -> u8 {
+        let r = (self.r as u16 * 7 + 127) / 255;
+        let g = (self.g as u16 * 7 + 127) / 255;
+        let b = (self.b as u16 * 3 + 127) / 255;
+        ((r << 5) | (g << 2) | b) as u8
+    }
+
+    /// Expands a RGB332 value to an opaque color.
+    #[inline]
+    pub const fn from_rgb332(v: u8) -> Self {
+        let r = (v >> 5) as u16 * 255 / 7;
+        let g = ((v >> 2) & 7) as u16 * 255 / 7;
+        let b = (v & 3) as u16 * 255 / 3;
+        Self::rgb(r as u8, g as u8, b as u8)
+    }
+
+    /// Squared euclidean distance between the RGB components of both colors, alpha is ignored.
+    #[inline]
+    pub const fn distance(&self, other: &Color) -> u32 {
+        let dr = self.r as i32 - other.r as i32;
+        let dg = self.g as i32 - other.g as i32;
+        let db = self.b as i32 - other.b as i32;
+        (dr * dr + dg * dg + db * db) as u32
+    }
+
+    /// Linearly interpolates all components towards `other`, `t` is clamped to 0.0..=1.0.
+    #[inline]
+    pub fn lerp(&self, other: &Color, t: f32) -> Self {
+        let t = t.clamp(0.0, 1.0);
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        Self {
+            r: mix(self.r, other.r),
+            g: mix(self.g, other.g),
+            b: mix(self.b, other.b),
+            a: mix(self.a, other.a),
+        }
+    }
+}
+
+/// A color with 16 bits per channel, kept by canvases with a `color_depth` of 16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color16 {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
+    pub a: u16,
+}
+
+impl Color16 {
+    #[inline]
+    pub const fn rgb(r: u16, g: u16, b: u16) -> Self {
+        Self {
+            r,
+            g,
+            b,
+            a: u16::MAX,
+        }
+    }
+
+    /// Widens an 8-bit color, mapping 0xff to 0xffff. Inverse of `to_color`.
+    #[inline]
+    pub const fn from_color(color: Color) -> Self {
+        Self {
+            r: color.r as u16 * 257,
+            g: color.g as u16 * 257,
+            b: color.b as u16 * 257,
+            a: color.a as u16 * 257,
+        }
+    }
+
+    /// Narrows the color to 8 bits per channel by keeping the high byte of every channel.
+    #[inline]
+    pub const fn to_color(self) -> Color {
+        Color::new(
+            (self.r >> 8) as u8,
+            (self.g >> 8) as u8,
+            (self.b >> 8) as u8,
+            (self.a >> 8) as u8,
+        )
+    }
+
+    #[inline]
+    pub const fn into_rgba16(self) -> Rgba<u16> {
+        Rgba([self.r, self.g, self.b, self.a])
+    }
+
+    #[inline]
+    pub const fn from_rgba16(rgba: Rgba<u16>) -> Self {
+        let Rgba([r, g, b, a]) = rgba;
+        Self { r, g, b, a }
+    }
 }
 
 impl serde::Serialize for Color {
@@ -191,3 +295,140 @@ impl<'de> serde::Deserialize<'de> for Color {
         Color::parse(&s).ok_or_else(|| serde::de::Error::custom("Invalid color"))
     }
 }
+
+/// An IPv4 or IPv6 prefix in CIDR notation, eg. "2001:db8::/32".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+impl IpPrefix {
+    /// Parses a prefix in form of `addr/len`, a plain address is treated as a single host prefix.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr.parse::<IpAddr>().ok()?, Some(len.parse::<u8>().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let len = len.unwrap_or(max_len);
+        (len <= max_len).then_some(Self { addr, len })
+    }
+
+    /// Checks whether the address lies within the prefix. IPv4 addresses never match IPv6 prefixes and vice versa.
+    #[inline]
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(prefix), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(prefix) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(prefix), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(prefix) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for IpPrefix {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        IpPrefix::parse(&s).ok_or_else(|| serde::de::Error::custom("Invalid IP prefix"))
+    }
+}
+
+/// Compares two byte strings in a time which doesn't depend on where they differ, so the time a
+/// wrong secret takes to reject doesn't tell how much of it was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn color_math() {
+        let black = Color::rgb(0, 0, 0);
+        let white = Color::rgb(255, 255, 255);
+        assert_eq!(black.distance(&white), 3 * 255 * 255);
+        assert_eq!(white.distance(&black), black.distance(&white));
+        assert_eq!(Color::rgb(1, 2, 3).distance(&Color::new(1, 2, 3, 0)), 0);
+
+        assert_eq!(black.lerp(&white, 0.0), black);
+        assert_eq!(black.lerp(&white, 0.5), Color::rgb(128, 128, 128));
+        assert_eq!(black.lerp(&white, 2.0), white);
+        assert_eq!(
+            Color::new(0, 0, 0, 0).lerp(&Color::new(100, 200, 50, 255), 0.25),
+            Color::new(25, 50, 13, 64)
+        );
+
+        let color = Color::new(1, 2, 3, 4);
+        assert_eq!(Color::from_rgba(color.into_rgba()), color);
+    }
+
+    #[test]
+    fn rgba32_byte_order() {
+        let color = Color::rgba32(0x11223344);
+        assert_eq!(color, Color::new(0x11, 0x22, 0x33, 0x44));
+        assert_eq!(color.into_rgba32(), 0x11223344);
+        assert_eq!(Color::parse("#11223344"), Some(color));
+
+        for packed in [0, 0xffffffff, 0x12345678, 0xff000080, 0x000000ff] {
+            let color = Color::rgba32(packed);
+            assert_eq!(color.into_rgba32(), packed);
+            let s = format!("#{:08x}", packed);
+            assert_eq!(Color::parse(&s).unwrap().into_rgba32(), packed, "{}", s);
+        }
+        assert_eq!(Color::parse("#ff0080").unwrap().into_rgba32(), 0xff0080ff);
+    }
+
+    #[test]
+    fn color16_conversions() {
+        for color in [Color::rgb(0, 0, 0), Color::new(0xff, 0x80, 0x01, 0x7f)] {
+            assert_eq!(Color16::from_color(color).to_color(), color);
+        }
+        assert_eq!(
+            Color16::from_color(Color::rgb(255, 0, 1)),
+            Color16::rgb(0xffff, 0, 0x0101)
+        );
+        assert_eq!(
+            Color16::rgb(0x12ff, 0x3400, 0xffff).to_color(),
+            Color::rgb(0x12, 0x34, 0xff)
+        );
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn ip_prefix() {
+        let prefix = IpPrefix::parse("2001:db8:1::/48").unwrap();
+        assert!(prefix.contains("2001:db8:1:ffff::1".parse().unwrap()));
+        assert!(!prefix.contains("2001:db8:2::1".parse().unwrap()));
+        assert!(!prefix.contains("192.0.2.1".parse().unwrap()));
+
+        let prefix = IpPrefix::parse("192.0.2.0/24").unwrap();
+        assert!(prefix.contains("192.0.2.255".parse().unwrap()));
+        assert!(!prefix.contains("192.0.3.0".parse().unwrap()));
+
+        assert!(IpPrefix::parse("::/0")
+            .unwrap()
+            .contains("::1".parse().unwrap()));
+        assert_eq!(IpPrefix::parse("192.0.2.1").unwrap().len, 32);
+        assert_eq!(IpPrefix::parse("192.0.2.0/33"), None);
+        assert_eq!(IpPrefix::parse("2001:db8::/x"), None);
+    }
+}
@@ -1,32 +1,523 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use crate::SharedContext;
-use crate::{settings::Settings, PResult};
+use crate::{
+    admin::{parse_rect, AdminCommand, Stats, DEFAULT_TOP},
+    backend::{cooldown::CooldownOverlay, schema::EncodingSchema, PixelRequest},
+    gzip::{self, Precompressed},
+    place::{self, Frame, FrameFormat},
+    settings::{CanvasSettings, CoordinateMode, Settings},
+    static_files, svg, tls,
+    utils::{self, Color},
+    PResult,
+};
+use crate::{Event, SharedContext};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use futures::{stream::StreamExt, SinkExt};
-use hyper::{Body, Request, Response};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH, LAST_MODIFIED},
+    Body, Method, Request, Response,
+};
 use hyper_tungstenite::{tungstenite::Message, HyperWebsocket};
-use image::{codecs::png, ColorType};
-use image::{ImageBuffer, ImageEncoder, Rgba};
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpListener, task::JoinHandle};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{
+        broadcast::{
+            self,
+            error::{RecvError, TryRecvError},
+        },
+        mpsc, OwnedSemaphorePermit, Semaphore,
+    },
+    task::JoinHandle,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Side length of thumbnails if not specified in the request.
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+/// Largest side length of thumbnails, anything bigger should just use /canvas.png.
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+/// Sent to connections accepted while the connection limit is reached.
+const SERVICE_UNAVAILABLE_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+/// Number of replies to client messages that can wait for the sender task,
+/// reading further messages is paused while it's full.
+const REPLY_CHANNEL_CAPACITY: usize = 16;
+/// Websocket subprotocol of clients which want a `checksum` event before every frame.
+const CHECKSUM_PROTOCOL: &str = "place.crc32";
+/// Longest time a Server-Sent Events stream of an idle canvas goes without sending anything.
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
 
 pub struct WebSocketServer {
     socket: TcpListener,
     http: hyper::server::conn::Http,
     config_info: ServerConfigInfo,
+    canvas_configs: HashMap<String, ServerConfigInfo>,
+    max_body_size: usize,
+    admin_secret: Option<String>,
+    skip_idle_frames: bool,
+    keyframe_interval: Option<Duration>,
+    static_dir: Option<PathBuf>,
+    prefixes_json: String,
+    tls_acceptor: Option<TlsAcceptor>,
+    connection_limit: Arc<Semaphore>,
+}
+
+/// Permit of a connection counting towards the connection limit. Handed over to the websocket task
+/// if the connection is upgraded, released once it's closed otherwise.
+type ConnectionPermit = Arc<Mutex<Option<OwnedSemaphorePermit>>>;
+
+/// State shared by all HTTP requests, which doesn't change during lifetime of the server.
+struct HttpState {
+    config_info: ServerConfigInfo,
+    /// Configs of the additional canvases, by name.
+    canvas_configs: HashMap<String, ServerConfigInfo>,
+    max_body_size: usize,
+    admin_secret: Option<String>,
+    skip_idle_frames: bool,
+    /// Longest time between frames sent to a client while the canvas is idle.
+    keyframe_interval: Option<Duration>,
+    static_dir: Option<PathBuf>,
+    static_files: static_files::CompressedFiles,
+    /// Serialized `EncodingSchema`, which never changes at runtime.
+    prefixes_json: Precompressed,
+}
+
+impl HttpState {
+    /// Checks the "Authorization: Bearer <secret>" header against the configured admin secret.
+    fn is_admin<B>(&self, request: &Request<B>) -> bool {
+        let secret = match &self.admin_secret {
+            Some(secret) => secret,
+            None => return false,
+        };
+
+        request
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| utils::constant_time_eq(v.as_bytes(), secret.as_bytes()))
+    }
+
+    /// Returns the config of the canvas the context is for.
+    fn config_info(&self, shared_context: &SharedContext) -> &ServerConfigInfo {
+        shared_context
+            .canvas
+            .as_deref()
+            .and_then(|name| self.canvas_configs.get(name))
+            .unwrap_or(&self.config_info)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ServerConfigInfo {
     ipv6_prefix: String,
     canvas_size: u16,
+    /// Side length of frames streamed over the websocket, smaller than `canvas_size` if downscaled.
+    stream_size: u16,
+    /// Side length of PNG frames streamed at /ws/preview, 0 if the preview stream is disabled.
+    preview_size: u16,
+    /// Values accepted by the `format` query parameter of /ws.
+    frame_formats: Vec<FrameFormat>,
+    /// Whether PNG frames are also streamed as Server-Sent Events at /sse, for networks blocking websockets.
+    sse: bool,
+    /// Whether clients can opt into `cooldown` events showing which pixels are in cooldown.
+    cooldown_overlay: bool,
+    coordinate_mode: CoordinateMode,
+    background_color: Color,
+    /// Colors pixels are snapped to, empty if they're kept as they are.
+    palette: Vec<Color>,
+    /// Bumped whenever the canvas changes.
+    canvas_version: u64,
+    /// Time of the last canvas change, in milliseconds since the unix epoch.
+    last_modified: u64,
+    /// Whether pixels are currently rejected because the canvas is frozen.
+    frozen: bool,
+    events: Vec<EventSchema>,
+}
+
+impl ServerConfigInfo {
+    fn new(settings: &Settings, prefix48: Ipv6Addr, canvas: &CanvasSettings) -> ServerConfigInfo {
+        let prefix48 = prefix48.segments();
+        ServerConfigInfo {
+            ipv6_prefix: format!(
+                "{:x}:{:x}:{:x}::SXXX:YYY:RR:GG:BB",
+                prefix48[0], prefix48[1], prefix48[2]
+            ),
+            canvas_size: canvas.size.get(),
+            stream_size: place::stream_dimensions(
+                canvas.size.get() as u32,
+                canvas.size.get() as u32,
+                settings.websocket.max_stream_dimension,
+            )
+            .0 as u16,
+            preview_size: match settings.websocket.preview_dimension {
+                0 => 0,
+                max => {
+                    let size = canvas.size.get() as u32;
+                    place::stream_dimensions(size, size, Some(max)).0 as u16
+                }
+            },
+            frame_formats: FrameFormat::supported(),
+            sse: settings.websocket.enable_sse,
+            cooldown_overlay: settings.backend.cooldown_ms > 0
+                && settings.backend.cooldown_overlay_color.is_some(),
+            coordinate_mode: canvas.coordinate_mode,
+            background_color: canvas.background_color,
+            palette: settings.backend.palette.clone(),
+            canvas_version: 0,
+            last_modified: 0,
+            frozen: false,
+            events: ServerEvent::schema(),
+        }
+    }
+}
+
+/// Body of a `POST /admin/background` request.
+#[derive(Debug, Clone, Deserialize)]
+struct BackgroundRequest {
+    color: Color,
+    /// Whether to repaint pixels which still have the old background color.
+    #[serde(default)]
+    repaint: bool,
+}
+
+/// Body of a `POST /admin/ban` request.
+#[derive(Debug, Clone, Deserialize)]
+struct BanRequest {
+    /// Address whose /64 is banned.
+    source: IpAddr,
+}
+
+/// Body of a `POST /admin/palette` request.
+#[derive(Debug, Clone, Deserialize)]
+struct PaletteRequest {
+    /// Colors pixels are snapped to, empty to keep them as they are.
+    colors: Vec<Color>,
+    /// New background color, kept if not set.
+    #[serde(default)]
+    background: Option<Color>,
+}
+
+/// A single pixel in a `POST /pixels` JSON request.
+#[derive(Debug, Clone, Deserialize)]
+struct PixelEntry {
+    x: u16,
+    y: u16,
+    color: Color,
+    #[serde(default = "PixelEntry::default_size")]
+    size: u8,
+}
+
+impl PixelEntry {
+    fn default_size() -> u8 {
+        1
+    }
+}
+
+impl From<PixelEntry> for PixelRequest {
+    fn from(entry: PixelEntry) -> Self {
+        PixelRequest {
+            pos: (entry.x, entry.y),
+            color: entry.color,
+            size: entry.size,
+            color_low: None,
+        }
+    }
+}
+
+/// Result of `POST /pixels`. Applied pixels are queued, so cooldowns and rate limits can still
+/// drop them before they're written.
+#[derive(Debug, Clone, Serialize)]
+struct PixelsSummary {
+    applied: usize,
+    rejected: usize,
+}
+
+/// Describes a single event type sent as a text frame over the websocket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EventSchema {
+    evt: String,
+    data: String,
+    description: String,
+}
+
+/// Events sent to websocket clients as JSON text frames, in form of `{"evt":"<name>","data":<data>}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "evt", content = "data", rename_all = "snake_case")]
+enum ServerEvent {
+    /// Number of pixels placed during the last second, and its exponential moving average.
+    Pps { raw: u32, smoothed: f32 },
+    /// Number of currently connected websocket clients.
+    Connections(u32),
+    /// Reply to a `get` message, `color` is null if the pixel is outside of the canvas.
+    Pixel {
+        x: u16,
+        y: u16,
+        color: Option<Color>,
+    },
+    /// Server statistics, pushed every second to `/ws/admin` clients only.
+    Stats(Stats),
+    /// CRC32 of the binary frame following right after, for clients using the checksum subprotocol.
+    Checksum { version: u64, crc32: u32 },
+    /// Base64-encoded PNG tinting the pixels in cooldown, one pixel per `cell_size` square.
+    Cooldown {
+        version: u64,
+        cell_size: u32,
+        png: String,
+    },
+}
+
+/// Messages sent by websocket clients as JSON text frames, eg. `{"get":{"x":1,"y":2}}`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClientMessage {
+    /// Queries the current color of a pixel, answered with a `pixel` event.
+    Get { x: u16, y: u16 },
+    /// Pauses (`false`) or resumes (`true`) the frame stream, eg. while the page is hidden.
+    /// Events keep being sent while paused, and the first frame after resuming is always sent.
+    Stream(bool),
+    /// Asks for the next frame to be sent even if the canvas hasn't changed, eg. after a checksum mismatch.
+    Resend,
+    /// Starts (`true`) or stops (`false`) sending `cooldown` events with the overlay of pixels in cooldown.
+    CooldownOverlay(bool),
+}
+
+impl ServerEvent {
+    fn schema() -> Vec<EventSchema> {
+        let event = |evt: &str, data: &str, description: &str| EventSchema {
+            evt: evt.to_string(),
+            data: data.to_string(),
+            description: description.to_string(),
+        };
+
+        vec![
+            event(
+                "pps",
+                "{raw: u32, smoothed: f32}",
+                "Number of pixels placed during the last second, and its exponential moving average.",
+            ),
+            event(
+                "connections",
+                "u32",
+                "Number of currently connected websocket clients.",
+            ),
+            event(
+                "pixel",
+                "{x: u16, y: u16, color: string | null}",
+                "Color of a pixel queried with {\"get\":{\"x\":u16,\"y\":u16}}, null if outside of the canvas.",
+            ),
+            event(
+                "checksum",
+                "{version: u64, crc32: u32}",
+                "CRC32 of the next binary frame. Only sent to clients requesting the \"place.crc32\" websocket subprotocol, which can send {\"resend\":null} on a mismatch.",
+            ),
+            event(
+                "stats",
+                "{pps: u32, smoothed_pps: f32, bad_checksums: u64, suppressed_replies: u64, connections: u32, frozen: bool, queue: object, top_talkers: object | null}",
+                "Server statistics, same as GET /admin/stats. Only sent over /ws/admin, every second.",
+            ),
+            event(
+                "cooldown",
+                "{version: u64, cell_size: u32, png: string}",
+                "Base64-encoded PNG tinting the pixels in cooldown, each of its pixels covering cell_size x cell_size pixels of the canvas. Only sent to clients which sent {\"cooldown_overlay\":true}, right away and whenever it changes.",
+            ),
+        ]
+    }
+
+    fn to_message(&self) -> PResult<Message> {
+        Ok(Message::Text(serde_json::to_string(self)?))
+    }
+}
+
+/// Forwards the cooldown overlay to a websocket client as `cooldown` events, starting with the
+/// current one, until the connection is gone.
+async fn forward_cooldown_overlay(
+    overlay: Arc<CooldownOverlay>,
+    reply_sender: mpsc::Sender<ServerEvent>,
+) {
+    let (mut frame, mut receiver) = overlay.subscribe();
+    loop {
+        if let Some(frame) = frame.take() {
+            let event = ServerEvent::Cooldown {
+                version: frame.version,
+                cell_size: overlay.cell_size(),
+                png: STANDARD.encode(&frame.data),
+            };
+            if reply_sender.send(event).await.is_err() {
+                return;
+            }
+        }
+        frame = match receiver.recv().await {
+            Ok(frame) => Some(frame),
+            Err(RecvError::Lagged(_)) => None,
+            Err(RecvError::Closed) => return,
+        };
+    }
+}
+
+/// Formats a frame as a Server-Sent Event with the base64-encoded frame as data and its version as id.
+fn sse_frame(frame: &Frame) -> Bytes {
+    Bytes::from(format!(
+        "event: frame\nid: {}\ndata: {}\n\n",
+        frame.version,
+        STANDARD.encode(&frame.data)
+    ))
+}
+
+/// Returns the value of a query string parameter, if present.
+fn query_param<'a, T>(request: &'a Request<T>, name: &str) -> Option<&'a str> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Returns the name of the additional canvas a request is for, given by the path of websocket
+/// upgrades to /ws/<name> or by the `canvas` query parameter of other requests.
+fn canvas_name<T>(request: &Request<T>) -> Option<&str> {
+    if hyper_tungstenite::is_upgrade_request(request) {
+        request
+            .uri()
+            .path()
+            .strip_prefix("/ws/")
+            .map(|name| name.strip_suffix("/preview").unwrap_or(name))
+            .filter(|&name| name != "admin" && name != "preview")
+    } else {
+        query_param(request, "canvas")
+    }
+}
+
+/// Checks whether the `If-None-Match` header of the request matches the given entity tag.
+fn is_not_modified<T>(request: &Request<T>, etag: &str) -> bool {
+    request
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        })
+}
+
+/// Keeps track of the number of connected websocket clients, decrementing the count on drop.
+struct ConnectionGuard(Arc<AtomicU32>);
+
+impl ConnectionGuard {
+    fn new(connection_count: Arc<AtomicU32>) -> Self {
+        connection_count.fetch_add(1, Ordering::Relaxed);
+        Self(connection_count)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Traffic sent to a single websocket client, logged when the connection closes.
+#[derive(Debug, Default)]
+struct ConnectionStats {
+    bytes_sent: AtomicU64,
+    frames_sent: AtomicU64,
+    /// Frames which were never sent because the client couldn't keep up.
+    frames_skipped: AtomicU64,
+}
+
+/// Why a websocket connection was closed.
+#[derive(Debug)]
+enum CloseReason {
+    /// The client sent a close frame.
+    ClientClose,
+    /// The connection ended without a close frame.
+    ConnectionLost,
+    /// Sending to the client failed, usually because it has already disconnected.
+    SendFailed,
+    /// Reading from the client failed.
+    Error(String),
+    /// The frame stream has ended.
+    ServerShutdown,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::ClientClose => write!(f, "closed by client"),
+            CloseReason::ConnectionLost => write!(f, "connection lost"),
+            CloseReason::SendFailed => write!(f, "send failed"),
+            CloseReason::Error(e) => write!(f, "error: {}", e),
+            CloseReason::ServerShutdown => write!(f, "server shutdown"),
+        }
+    }
+}
+
+/// Binds the listening socket with the given accept backlog, which `TcpListener::bind` doesn't allow
+/// to change.
+async fn bind_listener(listen_addr: &str, backlog: u32) -> PResult<TcpListener> {
+    let addr = tokio::net::lookup_host(listen_addr)
+        .await?
+        .next()
+        .ok_or_else(|| format!("{} doesn't resolve to any address", listen_addr))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Same as TcpListener::bind, so restarts don't fail on connections in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+
+    // The kernel silently caps the backlog.
+    let somaxconn = std::fs::read_to_string("/proc/sys/net/core/somaxconn")
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok());
+    match somaxconn {
+        Some(max) if max < backlog => log::warn!(
+            "Listen backlog of {} is capped to {} by net.core.somaxconn.",
+            backlog,
+            max
+        ),
+        _ => {}
+    }
+    log::info!(
+        "Listen backlog is {}, TCP_NODELAY is set on accepted connections.",
+        somaxconn.map_or(backlog, |max| backlog.min(max))
+    );
+
+    Ok(TcpListener::from_std(socket.into())?)
 }
 
 impl WebSocketServer {
     pub async fn new(settings: &Settings) -> PResult<WebSocketServer> {
-        let socket = TcpListener::bind(&settings.websocket.listen_addr).await?;
+        let socket = bind_listener(
+            &settings.websocket.listen_addr,
+            settings.websocket.listen_backlog,
+        )
+        .await?;
+        let tls_acceptor = match (&settings.websocket.tls_cert, &settings.websocket.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::load_acceptor(cert, key)?),
+            _ => None,
+        };
         log::info!(
-            "HTTP/WebSocket listening on on http://{}",
+            "HTTP/WebSocket listening on on {}://{}",
+            if tls_acceptor.is_some() {
+                "https"
+            } else {
+                "http"
+            },
             socket.local_addr()?
         );
 
@@ -34,178 +525,1311 @@ impl WebSocketServer {
         http.http1_only(true);
         http.http1_keep_alive(true);
 
-        let config_info = {
-            let prefix48 = settings.backend.prefix48.segments();
-            ServerConfigInfo {
-                ipv6_prefix: format!(
-                    "{:x}:{:x}:{:x}::SXXX:YYY:RR:GG:BB",
-                    prefix48[0], prefix48[1], prefix48[2]
-                ),
-                canvas_size: settings.canvas.size.get(),
-            }
-        };
+        let config_info =
+            ServerConfigInfo::new(settings, settings.backend.prefix48, &settings.canvas);
+        let canvas_configs = settings
+            .canvases
+            .iter()
+            .map(|named| {
+                let config_info = ServerConfigInfo::new(settings, named.prefix48, &named.canvas);
+                (named.name.clone(), config_info)
+            })
+            .collect();
 
         Ok(WebSocketServer {
             socket,
             http,
             config_info,
+            canvas_configs,
+            max_body_size: settings.websocket.max_body_size,
+            admin_secret: settings.websocket.admin_secret.clone(),
+            skip_idle_frames: settings.websocket.skip_idle_frames,
+            keyframe_interval: Some(settings.websocket.keyframe_interval_secs)
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            static_dir: settings.websocket.static_dir.as_ref().map(PathBuf::from),
+            prefixes_json: serde_json::to_string(&EncodingSchema::new(settings))?,
+            tls_acceptor,
+            connection_limit: Arc::new(Semaphore::new(settings.websocket.max_connections)),
         })
     }
 
+    /// Upgrades a request for one of the frame streams, `format` is `None` for the preview stream.
+    fn upgrade_websocket(
+        mut request: Request<Body>,
+        addr: SocketAddr,
+        state: &'static HttpState,
+        shared_context: SharedContext,
+        permit: ConnectionPermit,
+        format: Option<FrameFormat>,
+        frame_receiver: broadcast::Receiver<Frame>,
+    ) -> PResult<Response<Body>> {
+        let checksums = request
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.split(',').any(|p| p.trim() == CHECKSUM_PROTOCOL));
+
+        let (mut response, websocket) = hyper_tungstenite::upgrade(&mut request, None)?;
+        if checksums {
+            response
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", CHECKSUM_PROTOCOL.parse()?);
+        }
+        let permit = permit.lock().unwrap_or_else(|e| e.into_inner()).take();
+
+        // Spawn a task to handle the websocket connection.
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = WebSocketServer::serve_websocket(
+                websocket,
+                addr,
+                state,
+                shared_context,
+                format,
+                frame_receiver,
+                checksums,
+            )
+            .await
+            {
+                log::error!("Error in websocket connection: {}", e);
+            }
+        });
+
+        // Return the response so the spawned future can continue.
+        Ok(response)
+    }
+
     async fn handle_request(
         mut request: Request<Body>,
-        serialized_config: &'static str,
+        addr: SocketAddr,
+        state: &'static HttpState,
         shared_context: SharedContext,
+        permit: ConnectionPermit,
     ) -> PResult<Response<Body>> {
+        let shared_context = match canvas_name(&request) {
+            Some(name) => match shared_context.for_canvas(name) {
+                Some(shared_context) => shared_context,
+                None => {
+                    let response = Response::builder()
+                        .status(404)
+                        .body(Body::from("Unknown canvas"))?;
+                    return Ok(response);
+                }
+            },
+            None => shared_context,
+        };
+
         if hyper_tungstenite::is_upgrade_request(&request) {
-            if request.uri().path() == "/ws" {
+            let path = request.uri().path();
+            let is_stream =
+                path == "/ws" || path == "/ws/preview" || shared_context.canvas.is_some();
+            if is_stream && path.ends_with("/preview") {
+                let Some(frame_receiver) = shared_context.frame_channels.subscribe_preview() else {
+                    let response = Response::builder()
+                        .status(404)
+                        .body(Body::from("The preview stream is disabled"))?;
+                    return Ok(response);
+                };
+                return Self::upgrade_websocket(
+                    request,
+                    addr,
+                    state,
+                    shared_context,
+                    permit,
+                    None,
+                    frame_receiver,
+                );
+            }
+
+            if is_stream {
+                let format = match query_param(&request, "format") {
+                    Some(format) => FrameFormat::parse(format),
+                    None => Some(FrameFormat::Png),
+                };
+                let Some((format, frame_receiver)) = format.and_then(|format| {
+                    Some((format, shared_context.frame_channels.subscribe(format)?))
+                }) else {
+                    let response = Response::builder().status(400).body(Body::from(format!(
+                        "Unsupported frame format, supported formats are: {}",
+                        serde_json::to_string(&FrameFormat::supported())?
+                    )))?;
+                    return Ok(response);
+                };
+
+                return Self::upgrade_websocket(
+                    request,
+                    addr,
+                    state,
+                    shared_context,
+                    permit,
+                    Some(format),
+                    frame_receiver,
+                );
+            }
+
+            if request.uri().path() == "/ws/admin" {
+                if !state.is_admin(&request) {
+                    let response = Response::builder()
+                        .status(401)
+                        .body(Body::from("Unauthorized"))?;
+                    return Ok(response);
+                }
+
                 let (response, websocket) = hyper_tungstenite::upgrade(&mut request, None)?;
+                let permit = permit.lock().unwrap_or_else(|e| e.into_inner()).take();
 
-                // Spawn a task to handle the websocket connection.
                 tokio::spawn(async move {
+                    let _permit = permit;
                     if let Err(e) =
-                        WebSocketServer::serve_websocket(websocket, shared_context).await
+                        WebSocketServer::serve_admin_websocket(websocket, addr, shared_context)
+                            .await
                     {
-                        log::error!("Error in websocket connection: {}", e);
+                        log::error!("Error in admin websocket connection: {}", e);
                     }
                 });
 
-                // Return the response so the spawned future can continue.
                 return Ok(response);
             }
-        } else if request.uri().path() == "/config.json" {
+
             let response = Response::builder()
-                .status(200)
-                .header("Content-Type", "application/json")
-                .body(Body::from(serialized_config))?;
+                .status(404)
+                .body(Body::from("Not Found"))?;
             return Ok(response);
         }
 
+        let (parts, body) = request.into_parts();
+        let body = match Self::read_body(body, state.max_body_size).await? {
+            Some(body) => body,
+            None => {
+                let response = Response::builder()
+                    .status(413)
+                    .body(Body::from("Payload Too Large"))?;
+                return Ok(response);
+            }
+        };
+        let request = Request::from_parts(parts, body);
+
+        Self::handle_http_request(request, addr, state, shared_context).await
+    }
+
+    async fn handle_http_request(
+        request: Request<Bytes>,
+        addr: SocketAddr,
+        state: &'static HttpState,
+        shared_context: SharedContext,
+    ) -> PResult<Response<Body>> {
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/config.json") => {
+                // The background color, palette and version change at runtime, so the config is
+                // serialized on demand.
+                let version = shared_context.image.version();
+                let config_info = ServerConfigInfo {
+                    background_color: shared_context.place.background_color(),
+                    palette: shared_context.queue_monitor.palette(),
+                    canvas_version: version.version,
+                    last_modified: version
+                        .last_modified
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_millis() as u64),
+                    frozen: shared_context.queue_monitor.frozen(),
+                    ..state.config_info(&shared_context).clone()
+                };
+                let builder = Response::builder()
+                    .status(200)
+                    .header("Content-Type", "application/json");
+                let (builder, body) =
+                    gzip::encode(&request, builder, serde_json::to_vec(&config_info)?);
+                return Ok(builder.body(Body::from(body))?);
+            }
+            (&Method::GET, "/prefixes.json") => {
+                let builder = Response::builder()
+                    .status(200)
+                    .header("Content-Type", "application/json");
+                let (builder, body) = state.prefixes_json.encode(&request, builder);
+                return Ok(builder.body(Body::from(body))?);
+            }
+            (&Method::POST, "/pixels") => {
+                return Self::handle_pixels(request, addr, state, shared_context)
+            }
+            (&Method::GET, "/canvas.png") => {
+                return Self::handle_canvas_png(request, shared_context).await
+            }
+            (&Method::GET, "/thumbnail") => {
+                return Self::handle_thumbnail(request, shared_context).await
+            }
+            (&Method::GET, "/canvas.svg") => {
+                return Self::handle_svg(request, shared_context).await
+            }
+            (&Method::GET, "/metrics") => {
+                let mut metrics = String::new();
+                shared_context.frame_channels.render_metrics(&mut metrics);
+                shared_context.queue_monitor.render_metrics(&mut metrics);
+                shared_context.packet_counter.render_metrics(&mut metrics);
+                let response = Response::builder()
+                    .status(200)
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .body(Body::from(metrics))?;
+                return Ok(response);
+            }
+            (&Method::GET, "/rates.json") => {
+                if let Some(rate_grid) = shared_context.queue_monitor.rate_grid() {
+                    let response = Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(serde_json::to_string(&rate_grid.rates())?))?;
+                    return Ok(response);
+                }
+            }
+            (&Method::GET, "/events") if shared_context.pixel_history.is_some() => {
+                return Self::handle_events(request, shared_context)
+            }
+            (&Method::GET, "/sse") if state.config_info(&shared_context).sse => {
+                return Self::handle_sse(state, shared_context)
+            }
+            _ => {}
+        }
+
+        if request.uri().path().starts_with("/admin/") {
+            if !state.is_admin(&request) {
+                let response = Response::builder()
+                    .status(403)
+                    .body(Body::from("Forbidden"))?;
+                return Ok(response);
+            }
+
+            let command = match (request.method(), request.uri().path()) {
+                (&Method::POST, "/admin/save") => Some(AdminCommand::Save),
+                (&Method::POST, "/admin/export") => Some(AdminCommand::Export),
+                (&Method::GET, "/admin/stats") => Some(AdminCommand::Stats),
+                (&Method::POST, "/admin/reload") => Some(AdminCommand::Reload),
+                (&Method::POST, "/admin/freeze") => Some(AdminCommand::SetFrozen(true)),
+                (&Method::POST, "/admin/unfreeze") => Some(AdminCommand::SetFrozen(false)),
+                (&Method::GET, "/admin/top") => {
+                    match query_param(&request, "n").map(str::parse).transpose() {
+                        Ok(n) => Some(AdminCommand::Top {
+                            n: n.unwrap_or(DEFAULT_TOP),
+                        }),
+                        Err(e) => {
+                            let response = Response::builder()
+                                .status(400)
+                                .body(Body::from(format!("Invalid n: {}", e)))?;
+                            return Ok(response);
+                        }
+                    }
+                }
+                (&Method::GET, "/admin/sources") => match query_param(&request, "rect") {
+                    None => Some(AdminCommand::Sources { rect: None }),
+                    Some(rect) => match parse_rect(rect) {
+                        Some(rect) => Some(AdminCommand::Sources { rect: Some(rect) }),
+                        None => {
+                            let response = Response::builder()
+                                .status(400)
+                                .body(Body::from("Invalid rect, expected x,y,width,height"))?;
+                            return Ok(response);
+                        }
+                    },
+                },
+                (&Method::POST, "/admin/background") => {
+                    match serde_json::from_slice::<BackgroundRequest>(request.body()) {
+                        Ok(req) => Some(AdminCommand::SetBackground {
+                            color: req.color,
+                            repaint: req.repaint,
+                        }),
+                        Err(e) => {
+                            let response = Response::builder()
+                                .status(400)
+                                .body(Body::from(format!("Invalid JSON: {}", e)))?;
+                            return Ok(response);
+                        }
+                    }
+                }
+                (&Method::POST, "/admin/ban") => {
+                    match serde_json::from_slice::<BanRequest>(request.body()) {
+                        Ok(req) => Some(AdminCommand::Ban(req.source)),
+                        Err(e) => {
+                            let response = Response::builder()
+                                .status(400)
+                                .body(Body::from(format!("Invalid JSON: {}", e)))?;
+                            return Ok(response);
+                        }
+                    }
+                }
+                (&Method::POST, "/admin/palette") => {
+                    match serde_json::from_slice::<PaletteRequest>(request.body()) {
+                        Ok(req) => Some(AdminCommand::SetPalette {
+                            colors: req.colors,
+                            background: req.background,
+                        }),
+                        Err(e) => {
+                            let response = Response::builder()
+                                .status(400)
+                                .body(Body::from(format!("Invalid JSON: {}", e)))?;
+                            return Ok(response);
+                        }
+                    }
+                }
+                _ => None,
+            };
+            if let Some(command) = command {
+                return Self::handle_admin_command(command, shared_context).await;
+            }
+        } else if let (&Method::GET, Some(static_dir)) = (request.method(), &state.static_dir) {
+            if let Some(response) = Self::handle_static(&request, static_dir, state).await? {
+                return Ok(response);
+            }
+        }
+
         let response = Response::builder()
             .status(404)
             .body(Body::from("Not Found"))?;
         return Ok(response);
     }
 
+    /// Serves a file from the static directory, `None` if there's no such file.
+    async fn handle_static(
+        request: &Request<Bytes>,
+        static_dir: &Path,
+        state: &'static HttpState,
+    ) -> PResult<Option<Response<Body>>> {
+        let Some(mut path) = static_files::resolve(static_dir, request.uri().path()) else {
+            return Ok(None);
+        };
+        let mut metadata = tokio::fs::metadata(&path).await;
+        if metadata.as_ref().is_ok_and(|m| m.is_dir()) {
+            path.push("index.html");
+            metadata = tokio::fs::metadata(&path).await;
+        }
+        let Ok(metadata) = metadata else {
+            return Ok(None);
+        };
+
+        let content_type = static_files::content_type(&path);
+        let builder = Response::builder()
+            .status(200)
+            .header("Content-Type", content_type)
+            .header(CACHE_CONTROL, "no-cache");
+        // Compressed files are cached, others are sent as they are.
+        if gzip::is_compressible(content_type) {
+            let Some(body) = state.static_files.get(&path, &metadata).await? else {
+                return Ok(None);
+            };
+            let (builder, data) = body.encode(request, builder);
+            return Ok(Some(builder.body(Body::from(data))?));
+        }
+
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(builder.body(Body::from(data))?))
+    }
+
+    /// Serves the current canvas as PNG. Clients sending a matching `If-None-Match` get 304.
+    async fn handle_canvas_png(
+        request: Request<Bytes>,
+        shared_context: SharedContext,
+    ) -> PResult<Response<Body>> {
+        let version = shared_context.image.version();
+        let etag = version.etag();
+        if is_not_modified(&request, &etag) {
+            let response = Response::builder()
+                .status(304)
+                .header(ETAG, etag)
+                .header(
+                    LAST_MODIFIED,
+                    httpdate::fmt_http_date(version.last_modified),
+                )
+                .body(Body::empty())?;
+            return Ok(response);
+        }
+
+        let place = shared_context.place.clone();
+        // The canvas might have changed in the meantime, so tag the response with the encoded version.
+        let (version, png) = tokio::task::spawn_blocking(move || place.png()).await??;
+
+        let response = Response::builder()
+            .status(200)
+            .header("Content-Type", "image/png")
+            .header(CACHE_CONTROL, "no-cache")
+            .header(ETAG, version.etag())
+            .header(
+                LAST_MODIFIED,
+                httpdate::fmt_http_date(version.last_modified),
+            )
+            .body(Body::from(png))?;
+        Ok(response)
+    }
+
+    /// Serves the canvas downscaled to `?size=N` x N pixels as PNG, 256 x 256 by default.
+    async fn handle_thumbnail(
+        request: Request<Bytes>,
+        shared_context: SharedContext,
+    ) -> PResult<Response<Body>> {
+        let size = query_param(&request, "size")
+            .map(|v| v.parse::<u32>())
+            .unwrap_or(Ok(DEFAULT_THUMBNAIL_SIZE));
+
+        let size = match size {
+            Ok(size) if (1..=MAX_THUMBNAIL_SIZE).contains(&size) => size,
+            _ => {
+                let response = Response::builder().status(400).body(Body::from(format!(
+                    "Thumbnail size must be between 1 and {}",
+                    MAX_THUMBNAIL_SIZE
+                )))?;
+                return Ok(response);
+            }
+        };
+
+        let version = shared_context.image.version();
+        if is_not_modified(&request, &version.etag()) {
+            let response = Response::builder()
+                .status(304)
+                .header(ETAG, version.etag())
+                .body(Body::empty())?;
+            return Ok(response);
+        }
+
+        let place = shared_context.place.clone();
+        let (version, png) = tokio::task::spawn_blocking(move || place.thumbnail(size)).await??;
+
+        let response = Response::builder()
+            .status(200)
+            .header("Content-Type", "image/png")
+            .header(CACHE_CONTROL, "no-cache")
+            .header(ETAG, version.etag())
+            .body(Body::from(png))?;
+        Ok(response)
+    }
+
+    /// Lists pixels written after `?since=<ms>` (unix epoch) as newline-delimited JSON, all buffered
+    /// ones by default. This is a snapshot of the history, not a stream: the response ends after the
+    /// pixels buffered at the time of the request, and clients poll again with the last `ts`.
+    fn handle_events(
+        request: Request<Bytes>,
+        shared_context: SharedContext,
+    ) -> PResult<Response<Body>> {
+        let since = match query_param(&request, "since").map(|v| v.parse::<u64>()) {
+            None => 0,
+            Some(Ok(since)) => since,
+            Some(Err(_)) => {
+                let response = Response::builder()
+                    .status(400)
+                    .body(Body::from("since must be a timestamp in milliseconds"))?;
+                return Ok(response);
+            }
+        };
+
+        let events = match &shared_context.pixel_history {
+            Some(history) => history.snapshot_since(since),
+            None => Vec::new(),
+        };
+        let mut body = Vec::new();
+        for event in events {
+            serde_json::to_writer(&mut body, &event)?;
+            body.push(b'\n');
+        }
+
+        let response = Response::builder()
+            .status(200)
+            .header("Content-Type", "application/x-ndjson")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(Body::from(body))?;
+        Ok(response)
+    }
+
+    /// Streams PNG frames as Server-Sent Events for clients which can't use websockets. Frames are
+    /// the ones encoded for websocket clients, skipped and resent as keyframes the same way.
+    fn handle_sse(
+        state: &'static HttpState,
+        shared_context: SharedContext,
+    ) -> PResult<Response<Body>> {
+        let mut frame_receiver = shared_context
+            .frame_channels
+            .subscribe(FrameFormat::Png)
+            .ok_or("PNG frames aren't streamed")?;
+        let (mut body, response_body) = Body::channel();
+
+        tokio::spawn(async move {
+            let _connection_guard = ConnectionGuard::new(shared_context.connection_count.clone());
+            let mut last_sequence = None;
+            let mut last_sent_at = Instant::now();
+
+            loop {
+                let mut frame = match frame_receiver.recv().await {
+                    Ok(frame) => frame,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                loop {
+                    match frame_receiver.try_recv() {
+                        Ok(newer) => frame = newer,
+                        Err(TryRecvError::Lagged(_)) => {}
+                        Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                    }
+                }
+
+                let idle = last_sent_at.elapsed();
+                let keyframe_due = state
+                    .keyframe_interval
+                    .is_some_and(|interval| idle >= interval);
+                let chunk = if state.skip_idle_frames
+                    && last_sequence == Some(frame.sequence)
+                    && !keyframe_due
+                {
+                    if idle < SSE_KEEPALIVE {
+                        continue;
+                    }
+                    // Comments are ignored by clients, but keep proxies from closing the stream.
+                    Bytes::from_static(b": keepalive\n\n")
+                } else {
+                    if last_sequence.is_none() || keyframe_due {
+                        frame = shared_context
+                            .frame_channels
+                            .keyframe(FrameFormat::Png, frame);
+                    }
+                    last_sequence = Some(frame.sequence);
+                    sse_frame(&frame)
+                };
+
+                if body.send_data(chunk).await.is_err() {
+                    break;
+                }
+                last_sent_at = Instant::now();
+            }
+        });
+
+        let response = Response::builder()
+            .status(200)
+            .header("Content-Type", "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            // Stops nginx from buffering the stream.
+            .header("X-Accel-Buffering", "no")
+            .body(response_body)?;
+        Ok(response)
+    }
+
+    /// Exports the canvas as SVG. Large canvases have to be downscaled with `?downscale=N`,
+    /// so that neither side of the result exceeds `svg::MAX_SIZE`.
+    async fn handle_svg(
+        request: Request<Bytes>,
+        shared_context: SharedContext,
+    ) -> PResult<Response<Body>> {
+        let downscale = query_param(&request, "downscale")
+            .map(|v| v.parse::<u32>())
+            .unwrap_or(Ok(1));
+
+        let downscale = match downscale {
+            Ok(downscale) if downscale > 0 => downscale,
+            _ => {
+                let response = Response::builder()
+                    .status(400)
+                    .body(Body::from("Invalid downscale factor"))?;
+                return Ok(response);
+            }
+        };
+
+        let (width, height) = shared_context.image.get_dimensions();
+        if width / downscale > svg::MAX_SIZE || height / downscale > svg::MAX_SIZE {
+            let min_downscale = width.max(height).div_ceil(svg::MAX_SIZE);
+            let response = Response::builder().status(400).body(Body::from(format!(
+                "Canvas too large for SVG export, use ?downscale={} or more",
+                min_downscale
+            )))?;
+            return Ok(response);
+        }
+
+        let image = shared_context.image.snapshot();
+        let svg = tokio::task::spawn_blocking(move || svg::encode(&image, downscale)).await?;
+
+        let response = Response::builder()
+            .status(200)
+            .header("Content-Type", "image/svg+xml")
+            .body(Body::from(svg))?;
+        Ok(response)
+    }
+
+    /// Places a batch of pixels, either as a JSON array of `{"x":0,"y":0,"color":"#rrggbb","size":1}`
+    /// objects, or as `application/octet-stream` containing 8 byte records in the UDP bridge format.
+    fn handle_pixels(
+        request: Request<Bytes>,
+        addr: SocketAddr,
+        state: &'static HttpState,
+        shared_context: SharedContext,
+    ) -> PResult<Response<Body>> {
+        let is_binary = request
+            .headers()
+            .get("Content-Type")
+            .map_or(false, |v| v == "application/octet-stream");
+
+        let pixels = if is_binary {
+            let body = request.body();
+            if body.len() % PixelRequest::RECORD_SIZE != 0 {
+                let response = Response::builder().status(400).body(Body::from(format!(
+                    "Body length must be a multiple of {} bytes",
+                    PixelRequest::RECORD_SIZE
+                )))?;
+                return Ok(response);
+            }
+
+            body.chunks_exact(PixelRequest::RECORD_SIZE)
+                // chunks_exact guarantees the record length.
+                .map(|record| PixelRequest::from_bytes(record.try_into().unwrap()))
+                .collect::<Vec<_>>()
+        } else {
+            match serde_json::from_slice::<Vec<PixelEntry>>(request.body()) {
+                Ok(entries) => entries.into_iter().map(PixelRequest::from).collect(),
+                Err(e) => {
+                    let response = Response::builder()
+                        .status(400)
+                        .body(Body::from(format!("Invalid JSON: {}", e)))?;
+                    return Ok(response);
+                }
+            }
+        };
+
+        if shared_context.queue_monitor.frozen() {
+            shared_context
+                .queue_monitor
+                .count_frozen_rejected(pixels.len() as u64);
+            let response = Response::builder()
+                .status(423)
+                .body(Body::from("Canvas is frozen"))?;
+            return Ok(response);
+        }
+
+        // Validate everything first, then queue the valid pixels like ones sent over the network,
+        // so they're subject to the same cooldowns, protected regions and rate limits.
+        let (width, height) = shared_context.image.get_dimensions();
+        let coordinate_mode = state.config_info(&shared_context).coordinate_mode;
+        let (valid, invalid): (Vec<_>, Vec<_>) = pixels
+            .into_iter()
+            .map(|req| {
+                (
+                    matches!(req.size, 1 | 2),
+                    req.oriented(coordinate_mode, height),
+                )
+            })
+            .partition(|(valid_size, req)| *valid_size && req.is_within(width, height));
+
+        let summary = PixelsSummary {
+            applied: valid.len(),
+            rejected: invalid.len(),
+        };
+        for (_, req) in valid {
+            shared_context.pixel_queue.push(addr.ip(), req);
+        }
+        let response = Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&summary)?))?;
+        Ok(response)
+    }
+
+    async fn handle_admin_command(
+        command: AdminCommand,
+        shared_context: SharedContext,
+    ) -> PResult<Response<Body>> {
+        let response = match command.clone().execute(&shared_context).await {
+            Ok(result) => Response::builder()
+                .status(200)
+                .header("Content-Type", "application/json")
+                .body(Body::from(result.to_string()))?,
+            Err(e) => {
+                log::error!("Admin command {:?} failed: {}", command, e);
+                Response::builder()
+                    .status(500)
+                    .body(Body::from(format!("Admin command failed: {}", e)))?
+            }
+        };
+        Ok(response)
+    }
+
+    /// Reads the whole request body, returns None if it's larger than `limit` bytes.
+    async fn read_body(mut body: Body, limit: usize) -> PResult<Option<Bytes>> {
+        // Reject early if the client tells us upfront that the body is too large.
+        if body.size_hint().lower() > limit as u64 {
+            return Ok(None);
+        }
+
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if data.len() + chunk.len() > limit {
+                return Ok(None);
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(Some(Bytes::from(data)))
+    }
+
     async fn serve_websocket(
         websocket: HyperWebsocket,
+        addr: SocketAddr,
+        state: &'static HttpState,
         mut shared_context: SharedContext,
+        format: Option<FrameFormat>,
+        mut frame_receiver: broadcast::Receiver<Frame>,
+        checksums: bool,
     ) -> PResult<()> {
         let websocket = websocket.await?;
         let (mut sender, mut receiver) = websocket.split();
+        let _connection_guard = ConnectionGuard::new(shared_context.connection_count.clone());
+        let connected_at = Instant::now();
+        let stats = Arc::new(ConnectionStats::default());
+        log::info!("Websocket client {} connected", addr);
 
-        let sender_future = tokio::spawn(async move {
-            let mut image = {
-                let (width, height) = shared_context.image.get_dimensions();
-                ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, height)
-            };
+        let (reply_sender, mut reply_receiver) =
+            mpsc::channel::<ServerEvent>(REPLY_CHANNEL_CAPACITY);
+        let image = shared_context.image.clone();
+        let coordinate_mode = state.config_info(&shared_context).coordinate_mode;
+        let paused = Arc::new(AtomicBool::new(false));
+        let resend = Arc::new(AtomicBool::new(false));
+        let cooldown_overlay = shared_context.queue_monitor.cooldown_overlay();
+        let mut overlay_task: Option<JoinHandle<()>> = None;
 
-            let frame_interval = std::time::Duration::from_millis(1000) / 15;
+        let sender_stats = stats.clone();
+        let sender_paused = paused.clone();
+        let sender_resend = resend.clone();
+        let mut sender_future = tokio::spawn(async move {
+            let stats = sender_stats;
+            let mut last_connections = None;
+            let mut last_sequence = None;
+            let mut last_frame_at = Instant::now();
 
             loop {
-                let start = std::time::Instant::now();
-                if let Ok(pps) = shared_context.pps_receiver.try_recv() {
-                    if sender
-                        .feed(Message::Text(format!("{{\"evt\":{}}}", pps)))
-                        .await
-                        .is_err()
-                    {
-                        break;
+                let received = tokio::select! {
+                    received = frame_receiver.recv() => received,
+                    Some(reply) = reply_receiver.recv() => {
+                        let message = match reply.to_message() {
+                            Ok(message) => message,
+                            Err(e) => {
+                                log::error!("Failed to serialize event {:?}: {}", reply, e);
+                                continue;
+                            }
+                        };
+
+                        let len = message.len() as u64;
+                        if sender.send(message).await.is_err() {
+                            return CloseReason::SendFailed;
+                        }
+                        stats.bytes_sent.fetch_add(len, Ordering::Relaxed);
+                        continue;
+                    }
+                };
+                let mut frame = match received {
+                    Ok(frame) => frame,
+                    Err(RecvError::Lagged(skipped)) => {
+                        // The receiver now points at the oldest frame still buffered, continuing
+                        // picks it up and the draining below skips ahead to the latest one.
+                        log::debug!(
+                            "Websocket client {} lagged behind, skipped {} frames",
+                            addr,
+                            skipped
+                        );
+                        stats.frames_skipped.fetch_add(skipped, Ordering::Relaxed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return CloseReason::ServerShutdown,
+                };
+
+                // Slow clients only get the most recent frame, skipping everything
+                // encoded while they were busy.
+                loop {
+                    match frame_receiver.try_recv() {
+                        Ok(newer) => {
+                            stats.frames_skipped.fetch_add(1, Ordering::Relaxed);
+                            frame = newer;
+                        }
+                        Err(TryRecvError::Lagged(skipped)) => {
+                            stats.frames_skipped.fetch_add(skipped, Ordering::Relaxed);
+                        }
+                        Err(TryRecvError::Empty | TryRecvError::Closed) => break,
                     }
                 }
 
-                let data = {
-                    {
-                        let shared_image = unsafe { shared_context.image.get_image() };
-                        image.copy_from_slice(shared_image.as_raw().as_slice());
-                    }
-
-                    let mut writer = Vec::new();
-                    let encoder = png::PngEncoder::new_with_quality(
-                        &mut writer,
-                        png::CompressionType::Fast,
-                        png::FilterType::Adaptive,
-                    );
-                    if encoder
-                        .write_image(
-                            image.as_raw(),
-                            image.width(),
-                            image.height(),
-                            ColorType::Rgba8,
-                        )
-                        .is_err()
-                    {
-                        continue;
+                // Drain all pending events, only the latest PPS value is relevant to the client.
+                let mut latest_pps = None;
+                loop {
+                    match shared_context.event_receiver.try_recv() {
+                        Ok(Event::Pps { raw, smoothed }) => latest_pps = Some((raw, smoothed)),
+                        Err(TryRecvError::Lagged(skipped)) => {
+                            log::debug!(
+                                "Websocket client lagged behind, skipped {} events",
+                                skipped
+                            );
+                        }
+                        Err(TryRecvError::Empty | TryRecvError::Closed) => break,
                     }
+                }
 
-                    writer
+                let connections = shared_context.connection_count.load(Ordering::Relaxed);
+                let connections = if last_connections != Some(connections) {
+                    last_connections = Some(connections);
+                    Some(connections)
+                } else {
+                    None
                 };
 
-                if sender.send(Message::Binary(data)).await.is_err() {
-                    break;
+                let events = latest_pps
+                    .map(|(raw, smoothed)| ServerEvent::Pps { raw, smoothed })
+                    .into_iter()
+                    .chain(connections.map(ServerEvent::Connections));
+                for event in events {
+                    let message = match event.to_message() {
+                        Ok(message) => message,
+                        Err(e) => {
+                            log::error!("Failed to serialize event {:?}: {}", event, e);
+                            continue;
+                        }
+                    };
+
+                    let len = message.len() as u64;
+                    if sender.feed(message).await.is_err() {
+                        return CloseReason::SendFailed;
+                    }
+                    stats.bytes_sent.fetch_add(len, Ordering::Relaxed);
                 }
 
-                let now = std::time::Instant::now();
-                let elapsed = now - start;
+                // Paused client, forget the last frame so a frame is sent as soon as it resumes.
+                if sender_paused.load(Ordering::Relaxed) {
+                    last_sequence = None;
+                    if sender.flush().await.is_err() {
+                        return CloseReason::SendFailed;
+                    }
+                    continue;
+                }
 
-                log::debug!("Elapsed = {:?}, interval = {:?}", elapsed, frame_interval);
+                if sender_resend.swap(false, Ordering::Relaxed) {
+                    last_sequence = None;
+                }
 
-                if elapsed < frame_interval {
-                    tokio::time::sleep(frame_interval - elapsed).await;
-                } else {
-                    // give some time to calm down in case we're starting to get laggy
-                    tokio::time::sleep(Duration::from_millis(100)).await;
+                // Idle canvas, the events sent above serve as a heartbeat. Frames are complete
+                // images, so resending the current one is enough for a keyframe.
+                let keyframe_due = state
+                    .keyframe_interval
+                    .is_some_and(|interval| last_frame_at.elapsed() >= interval);
+                if state.skip_idle_frames && last_sequence == Some(frame.sequence) && !keyframe_due
+                {
+                    if sender.flush().await.is_err() {
+                        return CloseReason::SendFailed;
+                    }
+                    continue;
+                }
+
+                // Clients without the previous frame get a keyframe, which can afford a better
+                // compression since it isn't sent to everyone on every change. Preview frames are
+                // small and already compressed like keyframes.
+                if let Some(format) = format.filter(|_| last_sequence.is_none() || keyframe_due) {
+                    frame = shared_context.frame_channels.keyframe(format, frame);
+                }
+
+                if checksums {
+                    let checksum = ServerEvent::Checksum {
+                        version: frame.version,
+                        crc32: frame.crc32,
+                    };
+                    // Serializing two integers can't fail.
+                    let message = checksum.to_message().unwrap();
+                    let len = message.len() as u64;
+                    if sender.feed(message).await.is_err() {
+                        return CloseReason::SendFailed;
+                    }
+                    stats.bytes_sent.fetch_add(len, Ordering::Relaxed);
                 }
-//                tokio::task::yield_now().await;
+
+                // Frames are encoded once for all clients, but the tungstenite version hyper-tungstenite
+                // builds on only takes messages as an owned Vec, so every client needs its own copy.
+                if sender
+                    .send(Message::Binary(frame.data.to_vec()))
+                    .await
+                    .is_err()
+                {
+                    return CloseReason::SendFailed;
+                }
+                stats
+                    .bytes_sent
+                    .fetch_add(frame.data.len() as u64, Ordering::Relaxed);
+                stats.frames_sent.fetch_add(1, Ordering::Relaxed);
+                last_sequence = Some(frame.sequence);
+                last_frame_at = Instant::now();
             }
         });
 
-        while let Some(message) = receiver.next().await {
-            match message? {
-                Message::Close(_) => break,
-                _ => {}
+        let receiver_future = async {
+            while let Some(message) = receiver.next().await {
+                match message {
+                    Ok(Message::Close(_)) => return CloseReason::ClientClose,
+                    Ok(Message::Text(text)) => {
+                        let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                            Ok(ClientMessage::Get { x, y }) => {
+                                let (_, height) = image.get_dimensions();
+                                let req = PixelRequest {
+                                    pos: (x, y),
+                                    color: Color::new(0, 0, 0, 0),
+                                    size: 1,
+                                    color_low: None,
+                                }
+                                .oriented(coordinate_mode, height);
+                                let (cx, cy) = req.pos;
+                                ServerEvent::Pixel {
+                                    x,
+                                    y,
+                                    color: image.get(cx as u32, cy as u32),
+                                }
+                            }
+                            Ok(ClientMessage::Stream(stream)) => {
+                                paused.store(!stream, Ordering::Relaxed);
+                                continue;
+                            }
+                            Ok(ClientMessage::Resend) => {
+                                resend.store(true, Ordering::Relaxed);
+                                continue;
+                            }
+                            Ok(ClientMessage::CooldownOverlay(enabled)) => {
+                                if let Some(task) = overlay_task.take() {
+                                    task.abort();
+                                }
+                                if enabled {
+                                    overlay_task = cooldown_overlay.clone().map(|overlay| {
+                                        tokio::spawn(forward_cooldown_overlay(
+                                            overlay,
+                                            reply_sender.clone(),
+                                        ))
+                                    });
+                                }
+                                continue;
+                            }
+                            Err(e) => {
+                                log::debug!(
+                                    "Invalid message from websocket client {}: {}",
+                                    addr,
+                                    e
+                                );
+                                continue;
+                            }
+                        };
+
+                        if reply_sender.send(reply).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => return CloseReason::Error(e.to_string()),
+                }
             }
-        }
+            CloseReason::ConnectionLost
+        };
 
+        let reason = tokio::select! {
+            reason = &mut sender_future => {
+                reason.unwrap_or_else(|e| CloseReason::Error(e.to_string()))
+            }
+            reason = receiver_future => reason,
+        };
         sender_future.abort();
+        if let Some(task) = overlay_task {
+            task.abort();
+        }
+
+        log::info!(
+            "Websocket client {} disconnected ({}) after {:.1?}, sent {} frames ({} bytes), skipped {} frames",
+            addr,
+            reason,
+            connected_at.elapsed(),
+            stats.frames_sent.load(Ordering::Relaxed),
+            stats.bytes_sent.load(Ordering::Relaxed),
+            stats.frames_skipped.load(Ordering::Relaxed),
+        );
 
         Ok(())
     }
 
+    /// Pushes server statistics to an operator dashboard whenever the PPS counter updates.
+    /// Admin connections don't count towards the connection count shown to clients.
+    async fn serve_admin_websocket(
+        websocket: HyperWebsocket,
+        addr: SocketAddr,
+        mut shared_context: SharedContext,
+    ) -> PResult<()> {
+        let websocket = websocket.await?;
+        let (mut sender, mut receiver) = websocket.split();
+        log::info!("Admin websocket client {} connected", addr);
+
+        let reason = loop {
+            tokio::select! {
+                received = shared_context.event_receiver.recv() => {
+                    match received {
+                        Ok(Event::Pps { .. }) => {}
+                        // Only the latest stats matter, the next event follows right away.
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break CloseReason::ServerShutdown,
+                    }
+
+                    let message = ServerEvent::Stats(Stats::collect(&shared_context)).to_message()?;
+                    if sender.send(message).await.is_err() {
+                        break CloseReason::SendFailed;
+                    }
+                }
+                message = receiver.next() => match message {
+                    Some(Ok(Message::Close(_))) => break CloseReason::ClientClose,
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => break CloseReason::Error(e.to_string()),
+                    None => break CloseReason::ConnectionLost,
+                },
+            }
+        };
+
+        log::info!("Admin websocket client {} disconnected ({})", addr, reason);
+        Ok(())
+    }
+
     async fn run(&mut self, shared_context: SharedContext) -> PResult<()> {
-        // The config doesn't change during lifetime of the server, so we can serialize it and turn it
-        // into &'static str to avoid making redundant copies of the string on every request.
-        let serialized_config: &'static str =
-            Box::leak(serde_json::to_string(&self.config_info)?.into_boxed_str());
+        // The state doesn't change during lifetime of the server, so we can turn it into &'static
+        // to avoid making redundant copies of it on every request.
+        let state: &'static HttpState = Box::leak(Box::new(HttpState {
+            config_info: self.config_info.clone(),
+            canvas_configs: std::mem::take(&mut self.canvas_configs),
+            max_body_size: self.max_body_size,
+            admin_secret: self.admin_secret.take(),
+            skip_idle_frames: self.skip_idle_frames,
+            keyframe_interval: self.keyframe_interval,
+            static_dir: self.static_dir.take(),
+            static_files: Default::default(),
+            prefixes_json: Precompressed::new(std::mem::take(&mut self.prefixes_json)),
+        }));
 
         loop {
             let (stream, addr) = self.socket.accept().await?;
+            // Frames are small and latency-sensitive, don't let Nagle's algorithm hold them back.
+            if let Err(e) = stream.set_nodelay(true) {
+                log::debug!("Failed to set TCP_NODELAY for {}: {}", addr, e);
+            }
+            let permit = match self.connection_limit.clone().try_acquire_owned() {
+                Ok(permit) => Arc::new(Mutex::new(Some(permit))),
+                Err(_) => {
+                    log::debug!("Connection limit reached, rejecting {}", addr);
+                    // Best effort, the socket is closed right away either way.
+                    if self.tls_acceptor.is_none() {
+                        let _ = stream.try_write(SERVICE_UNAVAILABLE_RESPONSE);
+                    }
+                    continue;
+                }
+            };
             log::info!("New connection from {}", addr);
 
+            let http = self.http.clone();
             let shared_context = shared_context.clone();
-            let connection = self
-                .http
-                .serve_connection(
-                    stream,
-                    hyper::service::service_fn(move |request| {
-                        WebSocketServer::handle_request(
-                            request,
-                            serialized_config,
-                            shared_context.clone(),
-                        )
-                    }),
-                )
-                .with_upgrades();
-
+            let tls_acceptor = self.tls_acceptor.clone();
+            // The TLS handshake happens on the connection's task, so slow clients can't hold up accepting.
             tokio::spawn(async move {
-                if let Err(err) = connection.await {
+                let result = match tls_acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            Self::serve_connection(
+                                http,
+                                stream,
+                                addr,
+                                state,
+                                shared_context,
+                                permit,
+                            )
+                            .await
+                        }
+                        Err(err) => {
+                            log::debug!("TLS handshake with {} failed: {}", addr, err);
+                            return;
+                        }
+                    },
+                    None => {
+                        Self::serve_connection(http, stream, addr, state, shared_context, permit)
+                            .await
+                    }
+                };
+
+                if let Err(err) = result {
                     println!("Error serving HTTP connection: {:?}", err);
                 }
             });
         }
     }
 
+    async fn serve_connection<I>(
+        http: hyper::server::conn::Http,
+        io: I,
+        addr: SocketAddr,
+        state: &'static HttpState,
+        shared_context: SharedContext,
+        permit: ConnectionPermit,
+    ) -> hyper::Result<()>
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        http.serve_connection(
+            io,
+            hyper::service::service_fn(move |request| {
+                WebSocketServer::handle_request(
+                    request,
+                    addr,
+                    state,
+                    shared_context.clone(),
+                    permit.clone(),
+                )
+            }),
+        )
+        .with_upgrades()
+        .await
+    }
+
     pub fn start_server(mut self, shared_context: SharedContext) -> JoinHandle<PResult<()>> {
         tokio::spawn(async move { self.run(shared_context).await })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn server_event_json() {
+        assert_eq!(
+            serde_json::to_string(&ServerEvent::Pps {
+                raw: 1337,
+                smoothed: 1200.5
+            })
+            .unwrap(),
+            r#"{"evt":"pps","data":{"raw":1337,"smoothed":1200.5}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerEvent::Connections(3)).unwrap(),
+            r#"{"evt":"connections","data":3}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerEvent::Pixel {
+                x: 1,
+                y: 2,
+                color: Some(Color::rgb(255, 0, 128))
+            })
+            .unwrap(),
+            r##"{"evt":"pixel","data":{"x":1,"y":2,"color":"#ff0080"}}"##
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerEvent::Checksum {
+                version: 7,
+                crc32: 0xcbf43926
+            })
+            .unwrap(),
+            r#"{"evt":"checksum","data":{"version":7,"crc32":3421780262}}"#
+        );
+    }
+
+    #[test]
+    fn event_schema_matches_json() {
+        let events = [
+            ServerEvent::Pps {
+                raw: 1,
+                smoothed: 1.0,
+            },
+            ServerEvent::Connections(1),
+            ServerEvent::Pixel {
+                x: 1,
+                y: 2,
+                color: None,
+            },
+            ServerEvent::Checksum {
+                version: 1,
+                crc32: 0,
+            },
+            ServerEvent::Stats(Stats::default()),
+            ServerEvent::Cooldown {
+                version: 1,
+                cell_size: 1,
+                png: String::new(),
+            },
+        ];
+        let schema = ServerEvent::schema();
+        assert_eq!(schema.len(), events.len());
+
+        for event in events {
+            let json = serde_json::to_value(&event).unwrap();
+            let evt = json["evt"].as_str().unwrap();
+            let entry = schema.iter().find(|entry| entry.evt == evt).unwrap();
+
+            // Objects are documented as "{key: type, ...}", anything else as a plain type.
+            let documented: Option<Vec<&str>> = entry
+                .data
+                .strip_prefix('{')
+                .and_then(|fields| fields.strip_suffix('}'))
+                .map(|fields| {
+                    fields
+                        .split(", ")
+                        .map(|field| field.split(':').next().unwrap())
+                        .collect()
+                });
+            let serialized: Option<Vec<&str>> = json["data"]
+                .as_object()
+                .map(|data| data.keys().map(String::as_str).collect());
+            assert_eq!(
+                documented.map(|mut keys| {
+                    keys.sort_unstable();
+                    keys
+                }),
+                serialized.map(|mut keys| {
+                    keys.sort_unstable();
+                    keys
+                }),
+                "{}",
+                evt
+            );
+        }
+    }
+
+    #[test]
+    fn sse_frames() {
+        let frame = Frame {
+            version: 42,
+            sequence: 43,
+            data: Bytes::from_static(b"\x89PNG"),
+            crc32: 0,
+        };
+        assert_eq!(
+            sse_frame(&frame),
+            "event: frame\nid: 42\ndata: iVBORw==\n\n"
+        );
+    }
+
+    #[test]
+    fn client_message_json() {
+        assert_eq!(
+            serde_json::from_str::<ClientMessage>(r#"{"get":{"x":1,"y":2}}"#).unwrap(),
+            ClientMessage::Get { x: 1, y: 2 }
+        );
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"get":{"x":-1,"y":2}}"#).is_err());
+        assert_eq!(
+            serde_json::from_str::<ClientMessage>(r#"{"stream":false}"#).unwrap(),
+            ClientMessage::Stream(false)
+        );
+        assert_eq!(
+            serde_json::from_str::<ClientMessage>(r#"{"resend":null}"#).unwrap(),
+            ClientMessage::Resend
+        );
+        assert_eq!(
+            serde_json::from_str::<ClientMessage>(r#"{"cooldown_overlay":true}"#).unwrap(),
+            ClientMessage::CooldownOverlay(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn listener_backlog() {
+        let listener = bind_listener("127.0.0.1:0", 16).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        assert!(stream.nodelay().unwrap());
+        drop(client);
+
+        assert!(bind_listener("nope", 16).await.is_err());
+    }
+}
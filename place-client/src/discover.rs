@@ -0,0 +1,215 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+/// Canvas size if the record doesn't specify one.
+const DEFAULT_SIZE: u32 = 512;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const QUERY_ATTEMPTS: usize = 3;
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+
+/// Canvas parameters published in a TXT record, eg. `prefix=2602:fa9b:42::;size=512`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    /// The /48 the pixel addresses are in.
+    pub prefix: Ipv6Addr,
+    pub size: u32,
+}
+
+impl Target {
+    /// Parses the `key=value` pairs of a record, separated by `;`. Unknown keys are ignored.
+    pub fn parse(record: &str) -> Option<Target> {
+        let mut prefix = None;
+        let mut size = DEFAULT_SIZE;
+        for pair in record.split(';') {
+            let Some((key, value)) = pair.split_once('=') else {
+                continue;
+            };
+            match key.trim() {
+                "prefix" => prefix = Some(value.trim().parse().ok()?),
+                "size" => size = value.trim().parse().ok()?,
+                _ => {}
+            }
+        }
+
+        Some(Target {
+            prefix: prefix?,
+            size,
+        })
+    }
+}
+
+/// Looks up the TXT records of `domain` with the first nameserver in /etc/resolv.conf and returns
+/// the first one describing a canvas.
+pub async fn discover(domain: &str) -> Result<Target, Box<dyn std::error::Error>> {
+    let nameserver = nameserver()?;
+    let query = build_query(rand_id(), domain)?;
+    let socket = UdpSocket::bind(match nameserver {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    })
+    .await?;
+    socket.connect(nameserver).await?;
+
+    let mut buf = [0; 4096];
+    for _ in 0..QUERY_ATTEMPTS {
+        socket.send(&query).await?;
+        let Ok(len) = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf)).await else {
+            continue;
+        };
+        let records = parse_txt_response(&buf[..len?], &query[..2])?;
+        return records
+            .iter()
+            .find_map(|record| Target::parse(record))
+            .ok_or_else(|| format!("No TXT record of {} describes a canvas", domain).into());
+    }
+
+    Err(format!("No response from nameserver {}", nameserver).into())
+}
+
+fn nameserver() -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        // Scoped IPv6 addresses like fe80::1%eth0 aren't supported.
+        .find_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .map(|addr| SocketAddr::new(addr, 53))
+        .ok_or_else(|| "No nameserver in /etc/resolv.conf".into())
+}
+
+fn rand_id() -> u16 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    (nanos ^ std::process::id()) as u16
+}
+
+fn build_query(id: u16, domain: &str) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(domain.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("Invalid domain name: {}", domain));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Returns the position right after the (possibly compressed) name starting at `pos`.
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // A pointer ends the name.
+            0xc0.. => return Some(pos + 2),
+            _ => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        packet.get(pos..pos + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Extracts the TXT records from the answer section, concatenating the strings of each record.
+fn parse_txt_response(packet: &[u8], id: &[u8]) -> Result<Vec<String>, String> {
+    const MALFORMED: &str = "Malformed DNS response";
+
+    if packet.get(..2) != Some(id) {
+        return Err("DNS response doesn't match the query".into());
+    }
+    let flags = read_u16(packet, 2).ok_or(MALFORMED)?;
+    match flags & 0xf {
+        0 => {}
+        3 => return Err("Domain doesn't exist".into()),
+        rcode => return Err(format!("DNS query failed with rcode {}", rcode)),
+    }
+    let questions = read_u16(packet, 4).ok_or(MALFORMED)?;
+    let answers = read_u16(packet, 6).ok_or(MALFORMED)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos).ok_or(MALFORMED)? + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(packet, pos).ok_or(MALFORMED)?;
+        let kind = read_u16(packet, pos).ok_or(MALFORMED)?;
+        let len = read_u16(packet, pos + 8).ok_or(MALFORMED)? as usize;
+        let data = packet.get(pos + 10..pos + 10 + len).ok_or(MALFORMED)?;
+        pos += 10 + len;
+        // CNAMEs and other records may come along.
+        if kind != TYPE_TXT {
+            continue;
+        }
+
+        let mut record = Vec::new();
+        let mut data = data;
+        while let Some((&len, rest)) = data.split_first() {
+            let string = rest.get(..len as usize).ok_or(MALFORMED)?;
+            record.extend_from_slice(string);
+            data = &rest[len as usize..];
+        }
+        records.push(String::from_utf8_lossy(&record).into_owned());
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_target() {
+        assert_eq!(
+            Target::parse("prefix=2602:fa9b:42::;size=256"),
+            Some(Target {
+                prefix: "2602:fa9b:42::".parse().unwrap(),
+                size: 256
+            })
+        );
+        assert_eq!(
+            Target::parse(" size = 1024 ; v=1; prefix = 2001:db8:1:: "),
+            Some(Target {
+                prefix: "2001:db8:1::".parse().unwrap(),
+                size: 1024
+            })
+        );
+        assert_eq!(Target::parse("prefix=2001:db8::").unwrap().size, 512);
+        assert_eq!(Target::parse("v=spf1 -all"), None);
+        assert_eq!(Target::parse("prefix=nope"), None);
+    }
+
+    #[test]
+    fn txt_response() {
+        let query = build_query(0x1234, "place.example.com.").unwrap();
+        let mut response = query.clone();
+        // Response, no error, one answer.
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[6..8].copy_from_slice(&[0, 1]);
+        // Pointer to the name in the question, TXT, IN, TTL, a record split into two strings.
+        response.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 1, 0, 0, 27]);
+        response.extend_from_slice(b"\x11prefix=2001:db8::\x08;size=64");
+
+        let records = parse_txt_response(&response, &query[..2]).unwrap();
+        assert_eq!(records, ["prefix=2001:db8::;size=64"]);
+        assert!(parse_txt_response(&response, &[0, 0]).is_err());
+    }
+}
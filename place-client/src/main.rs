@@ -1,54 +1,201 @@
+use discover::Target;
 use futures::future;
-use image::{GenericImageView, ImageFormat};
+use image::{codecs::gif::GifDecoder, AnimationDecoder, ImageFormat, RgbaImage};
 use std::{
     fs::File,
     io::BufReader,
     net::{IpAddr, Ipv6Addr},
-    sync::Arc, time::Duration,
+    time::{Duration, Instant},
 };
 use surge_ping::{Client, Config, ICMP};
 
+mod discover;
+mod verify;
+
+/// Canvas drawn on unless `--discover` finds another one.
+const DEFAULT_TARGET: Target = Target {
+    prefix: Ipv6Addr::new(0x2602, 0xfa9b, 0x42, 0, 0, 0, 0, 0),
+    size: 512,
+};
+
+const USAGE: &str = "Usage: place-client [--discover <domain>] [--gif <file> [--hold-ms <ms>]] [--verify-interval <s> --canvas-url <url>]";
+
+/// Pings the address of a single pixel.
+async fn send_pixel(
+    client: &Client,
+    target: Target,
+    x: u32,
+    y: u32,
+    [r, g, b]: [u8; 3],
+) -> tokio::task::JoinHandle<()> {
+    let mut pinger = client
+        .pinger(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0.into())
+        .await;
+    let prefix = target.prefix.segments();
+    tokio::spawn(async move {
+        let parsed = Ipv6Addr::new(
+            prefix[0],
+            prefix[1],
+            prefix[2],
+            0x1000 | x as u16,
+            0x0000 | y as u16,
+            r as u16,
+            g as u16,
+            b as u16,
+        );
+        pinger.host = parsed.into();
+        unsafe { pinger.send_ping(0.into(), &[1; 8]).await.unwrap_unchecked() };
+    })
+}
+
+/// Sends all pixels of `frame` which differ from `previous`, or all of them if there's no previous frame.
+async fn send_frame(
+    client: &Client,
+    target: Target,
+    frame: &RgbaImage,
+    previous: Option<&RgbaImage>,
+) {
+    let mut handles = Vec::new();
+
+    for x in 0..frame.width().min(target.size) {
+        for y in 0..frame.height().min(target.size) {
+            let pixel = frame.get_pixel(x, y);
+            if previous.is_some_and(|previous| previous.get_pixel_checked(x, y) == Some(pixel)) {
+                continue;
+            }
+            let [r, g, b, _] = pixel.0;
+
+            handles.push(send_pixel(client, target, x, y, [r, g, b]).await);
+            std::thread::sleep(std::time::Duration::from_nanos(50))
+        }
+    }
+
+    future::join_all(handles).await;
+}
+
+/// Resends the pixels of `image` at the given coordinates.
+async fn send_pixels(client: &Client, target: Target, image: &RgbaImage, pixels: &[(u32, u32)]) {
+    let mut handles = Vec::new();
+    for &(x, y) in pixels {
+        let [r, g, b, _] = image.get_pixel(x, y).0;
+        handles.push(send_pixel(client, target, x, y, [r, g, b]).await);
+        std::thread::sleep(std::time::Duration::from_nanos(50))
+    }
+
+    future::join_all(handles).await;
+}
+
+/// Draws `image` once, then every `interval` downloads the canvas from `canvas_url`, reports how
+/// much of the image is on it and resends only the pixels which didn't take.
+async fn draw_verified(
+    client: &Client,
+    target: Target,
+    image: &RgbaImage,
+    interval: Duration,
+    canvas_url: &str,
+) -> ! {
+    send_frame(client, target, image, None).await;
+    loop {
+        tokio::time::sleep(interval).await;
+        let canvas = match verify::fetch_canvas(canvas_url).await {
+            Ok(canvas) => canvas,
+            Err(e) => {
+                eprintln!("Failed to read back the canvas: {}", e);
+                continue;
+            }
+        };
+
+        let report = verify::compare(image, &canvas, target.size);
+        println!("{}", report);
+        send_pixels(client, target, image, &report.missing).await;
+    }
+}
+
+/// Loops the frames of an animated GIF, holding each one for `hold` or the GIF's own frame delay.
+async fn play_gif(
+    client: &Client,
+    target: Target,
+    path: &str,
+    hold: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+    let frames = decoder.into_frames().collect_frames()?;
+    if frames.is_empty() {
+        return Err(format!("{} has no frames", path).into());
+    }
+    println!("Playing {} frames of {}", frames.len(), path);
+
+    let mut previous: Option<&RgbaImage> = None;
+    loop {
+        for frame in &frames {
+            let started = Instant::now();
+            let buffer = frame.buffer();
+            send_frame(client, target, buffer, previous).await;
+            previous = Some(buffer);
+
+            let hold = hold.unwrap_or_else(|| Duration::from(frame.delay()));
+            tokio::time::sleep(hold.saturating_sub(started.elapsed())).await;
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut discover = None;
+    let mut gif = None;
+    let mut hold = None;
+    let mut verify_interval = None;
+    let mut canvas_url = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--discover" => discover = Some(args.next().ok_or(USAGE)?),
+            "--gif" => gif = Some(args.next().ok_or(USAGE)?),
+            "--hold-ms" => {
+                let ms = args.next().ok_or(USAGE)?.parse()?;
+                hold = Some(Duration::from_millis(ms));
+            }
+            "--verify-interval" => {
+                let secs = args.next().ok_or(USAGE)?.parse()?;
+                verify_interval = Some(Duration::try_from_secs_f64(secs)?);
+            }
+            "--canvas-url" => canvas_url = Some(args.next().ok_or(USAGE)?),
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    if verify_interval.is_some() && (canvas_url.is_none() || gif.is_some()) {
+        return Err("--verify-interval needs --canvas-url and can't be used with --gif".into());
+    }
+
+    let target = match discover {
+        Some(domain) => {
+            let target = discover::discover(&domain).await?;
+            println!(
+                "Discovered canvas {}/48, {}x{} pixels",
+                target.prefix, target.size, target.size
+            );
+            target
+        }
+        None => DEFAULT_TARGET,
+    };
+
     let mut config = Config::new();
     config.kind = ICMP::V6;
     let client = Client::new(&config).unwrap();
 
+    if let Some(gif) = gif {
+        return play_gif(&client, target, &gif, hold).await;
+    }
+
     let file = BufReader::new(File::open("based.png")?);
-    let image = Arc::new(image::load(file, ImageFormat::Png)?);
+    let image = image::load(file, ImageFormat::Png)?.into_rgba8();
+
+    if let (Some(interval), Some(canvas_url)) = (verify_interval, canvas_url) {
+        draw_verified(&client, target, &image, interval, &canvas_url).await;
+    }
 
     loop {
-        let mut handles = Vec::new();
-
-        for x in 0..512 {
-            for y in 0..512 {
-                let mut pinger = client
-                    .pinger(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0.into())
-                    .await;
-                let image = Arc::clone(&image);
-                let handle = tokio::spawn(async move {
-                    let [r, g, b, _] = image.get_pixel(x, y).0;
-                    let parsed = Ipv6Addr::new(
-                        0x2602,
-                        0xfa9b,
-                        0x42,
-                        0x1000 | x as u16,
-                        0x0000 | y as u16,
-                        r as u16,
-                        g as u16,
-                        b as u16, // ((x as f64 / 512.0) * 255.0) as u16,
-                                  // ((y as f64 / 512.0) * 255.0) as u16,
-                                  // 0xff,
-                    );
-                    pinger.host = parsed.into();
-                    unsafe { pinger.send_ping(0.into(), &[1; 8]).await.unwrap_unchecked() };
-                });
-
-                handles.push(handle);
-
-                std::thread::sleep(std::time::Duration::from_nanos(50))
-            }
-        }
-        future::join_all(handles).await;
+        send_frame(&client, target, &image, None).await;
     }
 }
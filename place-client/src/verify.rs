@@ -0,0 +1,216 @@
+use std::fmt;
+
+use image::RgbaImage;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Number of missing pixels listed in a report.
+const LISTED_PIXELS: usize = 10;
+
+/// How much of the target image is on the canvas.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Report {
+    pub total: usize,
+    /// Coordinates of pixels whose color differs from the target.
+    pub missing: Vec<(u32, u32)>,
+}
+
+impl Report {
+    pub fn percent(&self) -> f64 {
+        match self.total {
+            0 => 100.0,
+            total => (total - self.missing.len()) as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} pixels drawn ({:.1}%)",
+            self.total - self.missing.len(),
+            self.total,
+            self.percent()
+        )?;
+        if self.missing.is_empty() {
+            return Ok(());
+        }
+
+        write!(f, ", missing:")?;
+        for (x, y) in self.missing.iter().take(LISTED_PIXELS) {
+            write!(f, " ({}, {})", x, y)?;
+        }
+        if self.missing.len() > LISTED_PIXELS {
+            write!(f, " and {} more", self.missing.len() - LISTED_PIXELS)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the colors of the part of `target` which fits on a canvas of `size` pixels against the
+/// canvas. Transparency is ignored, since pixels are sent without it.
+pub fn compare(target: &RgbaImage, canvas: &RgbaImage, size: u32) -> Report {
+    let mut report = Report {
+        total: 0,
+        missing: Vec::new(),
+    };
+    for y in 0..target.height().min(size) {
+        for x in 0..target.width().min(size) {
+            report.total += 1;
+            let [r, g, b, _] = target.get_pixel(x, y).0;
+            match canvas.get_pixel_checked(x, y) {
+                Some(pixel) if pixel.0[..3] == [r, g, b] => {}
+                _ => report.missing.push((x, y)),
+            }
+        }
+    }
+    report
+}
+
+/// Downloads and decodes the canvas from an http:// URL, eg. "http://example.com:2137/canvas.png".
+pub async fn fetch_canvas(url: &str) -> Result<RgbaImage, Box<dyn std::error::Error>> {
+    let body = http_get(url).await?;
+    Ok(image::load_from_memory(&body)?.into_rgba8())
+}
+
+/// Splits an http:// URL into the address to connect to, the host and the path.
+fn parse_url(url: &str) -> Option<(String, &str, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    // Bracketed IPv6 addresses contain colons of their own.
+    let has_port = host
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'));
+    let addr = if has_port {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Some((addr, host, path))
+}
+
+/// Sends a plain HTTP/1.1 GET request and returns the body of a successful response.
+async fn http_get(url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (addr, host, path) =
+        parse_url(url).ok_or_else(|| format!("Invalid http:// URL: {}", url))?;
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    parse_response(&response).map_err(|e| format!("{}: {}", url, e).into())
+}
+
+/// Returns the body of a 200 response, decoding it if it was sent in chunks.
+fn parse_response(response: &[u8]) -> Result<Vec<u8>, String> {
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("Malformed HTTP response")?;
+    let head = String::from_utf8_lossy(&response[..header_end]);
+    let body = &response[header_end + 4..];
+
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("Unexpected response {}", status));
+    }
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("transfer-encoding") && value.trim() == "chunked"
+        })
+    });
+    if !chunked {
+        return Ok(body.to_vec());
+    }
+
+    let mut decoded = Vec::new();
+    let mut rest = body;
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or("Malformed chunk")?;
+        let size = std::str::from_utf8(&rest[..line_end])
+            .ok()
+            .and_then(|size| usize::from_str_radix(size.split(';').next()?.trim(), 16).ok())
+            .ok_or("Malformed chunk size")?;
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let chunk = rest
+            .get(line_end + 2..line_end + 2 + size)
+            .ok_or("Truncated chunk")?;
+        decoded.extend_from_slice(chunk);
+        rest = rest.get(line_end + 4 + size..).ok_or("Truncated chunk")?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn compare_canvas() {
+        let target = RgbaImage::from_pixel(3, 3, Rgba([255, 0, 0, 128]));
+        let mut canvas = RgbaImage::from_pixel(2, 3, Rgba([255, 0, 0, 255]));
+        canvas.put_pixel(1, 1, Rgba([0, 0, 0, 255]));
+
+        // The third column is outside of the canvas, the third row outside of the drawn area.
+        let report = compare(&target, &canvas, 2);
+        assert_eq!(
+            report,
+            Report {
+                total: 4,
+                missing: vec![(1, 1)]
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "3/4 pixels drawn (75.0%), missing: (1, 1)"
+        );
+
+        let report = compare(&target, &canvas, 3);
+        assert_eq!(report.missing, [(2, 0), (1, 1), (2, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn http_response() {
+        assert_eq!(
+            parse_url("http://[2001:db8::1]:2137/canvas.png"),
+            Some((
+                "[2001:db8::1]:2137".to_string(),
+                "[2001:db8::1]:2137",
+                "/canvas.png"
+            ))
+        );
+        assert_eq!(
+            parse_url("http://[2001:db8::1]"),
+            Some(("[2001:db8::1]:80".to_string(), "[2001:db8::1]", "/"))
+        );
+        assert_eq!(parse_url("https://example.com/canvas.png"), None);
+
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabc";
+        assert_eq!(parse_response(response).unwrap(), b"abc");
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        assert_eq!(parse_response(response).unwrap(), b"abcde");
+        let response = b"HTTP/1.1 404 Not Found\r\n\r\n";
+        assert!(parse_response(response).is_err());
+    }
+}